@@ -0,0 +1,13 @@
+//! Runs the benchmark suite and prints the results, see `colang::bench`.
+
+use colang::bench::{report, run_suite, Sizes};
+
+fn main() {
+    match run_suite(&Sizes::default()) {
+        Ok(measurements) => print!("{}", report(&measurements)),
+        Err(error) => {
+            eprintln!("benchmark failed: {:?}", error);
+            std::process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,433 @@
+//! Benchmarks for the compilers and both Vms, built with the `bench`
+//! feature. `cargo bench --features bench` runs the suite and prints a
+//! table comparing the Vms on each workload.
+//!
+//! Programs written in the language measure compile time as well as
+//! execution. Workloads the language can't express yet, structs and
+//! tables, are written as bytecode for each Vm. Instructions are counted
+//! with fuel so instructions per second are comparable between the Vms
+//! even where their code differs. Counting slows the Vm, so it is done on
+//! a first run which isn't timed and warms up the caches, then each
+//! workload is timed over several runs without fuel.
+
+use std::fmt::{self, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::dyn_vm;
+use crate::typed_vm::{self, Kind, TableType};
+use crate::limits::Limits;
+use crate::Type;
+
+/// Recursive fibonacci of `{n}`.
+const FIB: &str = "
+fn fib(n) {
+    if n < 2 {
+        return n;
+    }
+
+    let a = n - 1;
+    let b = n - 2;
+    let x = fib(a);
+    let y = fib(b);
+    return x + y;
+}
+
+fn main() {
+    fib({n});
+}
+";
+
+/// A loop of `{n}` iterations doing integer and float arithmetic.
+const LOOP: &str = "
+fn main() {
+    let i = 0u64;
+    let total = 0u64;
+    let x = 0.0;
+    while i < {n}u64 {
+        total = total + i;
+        x = x + 0.5;
+        i = i + 1u64;
+    }
+    total;
+}
+";
+
+/// A program of `calls` calls to a function with a few locals, each
+/// result kept in a local of its own, so the time goes on setting up
+/// frames and reading and writing vars.
+fn frames_source(calls: u64) -> String {
+    let mut source = String::from(
+        "fn f(a, b, c) { let x = a + b; let y = x + c; let z = y + a; z + x; }\n"
+    );
+    source.push_str("fn main() {\n");
+    for i in 0..calls.max(1) {
+        let _ = writeln!(source, "    let v{} = f({}, 2, 3);", i, i);
+    }
+    let _ = writeln!(source, "    v{};", calls.max(1) - 1);
+    source.push_str("}\n");
+    source
+}
+
+/// How much work each benchmark does.
+#[derive(Debug, Clone)]
+pub struct Sizes {
+    pub fib: u64,
+    pub loop_iterations: u64,
+    /// The number of calls in the frames program, see `frames_source`.
+    pub frame_calls: u64,
+    pub structs: u64,
+    pub table_rows: u64,
+    pub table_queries: u64,
+    /// The number of times each program is compiled to time the compiler.
+    pub compiles: u32,
+    /// The number of timed runs of each workload.
+    pub runs: u32,
+}
+
+impl Default for Sizes {
+    fn default() -> Self {
+        Sizes {
+            fib: 25,
+            loop_iterations: 1_000_000,
+            frame_calls: 10_000,
+            structs: 200_000,
+            table_rows: 2_000,
+            table_queries: 200,
+            compiles: 200,
+            runs: 5,
+        }
+    }
+}
+
+impl Sizes {
+    /// Sizes small enough to run as a test.
+    pub fn small() -> Self {
+        Sizes {
+            fib: 10,
+            loop_iterations: 100,
+            frame_calls: 10,
+            structs: 10,
+            table_rows: 10,
+            table_queries: 3,
+            compiles: 1,
+            runs: 1,
+        }
+    }
+}
+
+/// One benchmark run on one Vm.
+#[derive(Debug)]
+pub struct Measurement {
+    pub benchmark: &'static str,
+    pub vm: &'static str,
+    /// The mean time to compile the program, None for bytecode.
+    pub compile: Option<Duration>,
+    pub instructions: u64,
+    /// The median time of the timed runs.
+    pub run: Duration,
+    /// The value left on the top of the stack, for checking the Vms agree.
+    pub result: String,
+}
+
+impl Measurement {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.run.as_secs_f64()
+    }
+}
+
+#[derive(Debug)]
+pub enum BenchError {
+    Compile(String),
+    Vm(String),
+}
+
+impl fmt::Display for BenchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchError::Compile(error) => write!(f, "compile error: {}", error),
+            BenchError::Vm(error) => write!(f, "vm error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for BenchError {}
+
+/// Runs every benchmark on every Vm that supports it.
+pub fn run_suite(sizes: &Sizes) -> Result<Vec<Measurement>, BenchError> {
+    let fib = FIB.replace("{n}", &sizes.fib.to_string());
+    let loop_source = LOOP.replace("{n}", &sizes.loop_iterations.to_string());
+    let frames = frames_source(sizes.frame_calls);
+
+    let table = typed_table(sizes.table_rows, sizes.table_queries);
+
+    Ok(vec![
+        dyn_source("fib", &fib, sizes)?,
+        typed_source("fib", &fib, sizes, Kind::I64)?,
+        dyn_source("loop", &loop_source, sizes)?,
+        typed_source("loop", &loop_source, sizes, Kind::U64)?,
+        dyn_source("frames", &frames, sizes)?,
+        typed_source("frames", &frames, sizes, Kind::I64)?,
+        run_dyn("structs", None, dyn_structs(sizes.structs), sizes.runs)?,
+        run_typed("structs", None, typed_structs(sizes.structs), sizes.runs, Kind::U64)?,
+        run_typed("table", None, table, sizes.runs, Kind::U64)?,
+    ])
+}
+
+/// Formats the measurements as a table.
+pub fn report(measurements: &[Measurement]) -> String {
+    let mut table = String::new();
+    let _ = writeln!(table, "{:<10} {:<6} {:>12} {:>14} {:>12} {:>14}",
+        "benchmark", "vm", "compile", "instructions", "run", "instr/sec");
+
+    for m in measurements {
+        let compile = match m.compile {
+            Some(compile) => format!("{:.1?}", compile),
+            None => "-".to_string(),
+        };
+        let _ = writeln!(table, "{:<10} {:<6} {:>12} {:>14} {:>12} {:>13.1}M",
+            m.benchmark,
+            m.vm,
+            compile,
+            m.instructions,
+            format!("{:.1?}", m.run),
+            m.instructions_per_second() / 1e6,
+        );
+    }
+    table
+}
+
+fn time_compiles<M, E: std::fmt::Debug>(
+    source: &str,
+    compiles: u32,
+    compile: impl Fn(&str) -> Result<M, E>,
+) -> Result<(M, Duration), BenchError> {
+    let compiles = compiles.max(1);
+    let start = Instant::now();
+    for _ in 1..compiles {
+        compile(source).map_err(|e| BenchError::Compile(format!("{:?}", e)))?;
+    }
+    let module = compile(source).map_err(|e| BenchError::Compile(format!("{:?}", e)))?;
+    Ok((module, start.elapsed() / compiles))
+}
+
+fn dyn_source(benchmark: &'static str, source: &str, sizes: &Sizes)
+-> Result<Measurement, BenchError> {
+    let (module, compile) = time_compiles(source, sizes.compiles, dyn_vm::compile::parse_colang)?;
+    run_dyn(benchmark, Some(compile), module, sizes.runs)
+}
+
+fn typed_source(benchmark: &'static str, source: &str, sizes: &Sizes, result: Kind)
+-> Result<Measurement, BenchError> {
+    let (module, compile) = time_compiles(source, sizes.compiles, typed_vm::compile::parse_colang)?;
+    run_typed(benchmark, Some(compile), module, sizes.runs, result)
+}
+
+/// Runs the workload `runs` times, returning the median time and the
+/// result of the last run.
+fn time_runs(runs: u32, mut run: impl FnMut() -> Result<(Duration, String), BenchError>)
+-> Result<(Duration, String), BenchError> {
+    let mut times = Vec::new();
+    let mut result = String::new();
+    for _ in 0..runs.max(1) {
+        let (time, last) = run()?;
+        times.push(time);
+        result = last;
+    }
+    times.sort();
+    Ok((times[times.len() / 2], result))
+}
+
+fn run_dyn(benchmark: &'static str, compile: Option<Duration>, module: dyn_vm::Module, runs: u32)
+-> Result<Measurement, BenchError> {
+    let module = Arc::new(module);
+    let halt = |vm: &mut dyn_vm::Vm| match vm.run() {
+        Ok(dyn_vm::Status::Halted) => Ok(()),
+        Ok(status) => Err(BenchError::Vm(format!("{:?}", status))),
+        Err(error) => Err(BenchError::Vm(format!("{:?}", error))),
+    };
+
+    let mut vm = dyn_vm::Vm::with_limits(module.clone(), Limits::unlimited().with_fuel(u64::MAX));
+    halt(&mut vm)?;
+    let instructions = u64::MAX - vm.fuel().unwrap_or(u64::MAX);
+
+    let (run, result) = time_runs(runs, || {
+        let mut vm = dyn_vm::Vm::with_limits(module.clone(), Limits::unlimited());
+        let start = Instant::now();
+        halt(&mut vm)?;
+        Ok((start.elapsed(), format!("{:?}", vm.stack().last())))
+    })?;
+
+    Ok(Measurement { benchmark, vm: "dyn", compile, instructions, run, result })
+}
+
+/// Runs the module on an unchecked typed Vm, so the kind of the result
+/// must be given to read it.
+fn run_typed(
+    benchmark: &'static str,
+    compile: Option<Duration>,
+    module: typed_vm::Module,
+    runs: u32,
+    result: Kind,
+) -> Result<Measurement, BenchError> {
+    let module = Arc::new(module);
+    let halt = |vm: &mut typed_vm::Vm| match vm.run() {
+        Ok(typed_vm::Status::Halted) => Ok(()),
+        Ok(status) => Err(BenchError::Vm(format!("{:?}", status))),
+        Err(error) => Err(BenchError::Vm(format!("{:?}", error))),
+    };
+
+    let mut vm = typed_vm::Vm::with_limits(module.clone(), Limits::unlimited().with_fuel(u64::MAX));
+    halt(&mut vm)?;
+    let instructions = u64::MAX - vm.fuel().unwrap_or(u64::MAX);
+
+    let (run, result) = time_runs(runs, || {
+        let mut vm = typed_vm::Vm::with_limits(module.clone(), Limits::unlimited());
+        let start = Instant::now();
+        halt(&mut vm)?;
+        Ok((start.elapsed(), format!("{:?}", vm.top(result).ok())))
+    })?;
+
+    Ok(Measurement { benchmark, vm: "typed", compile, instructions, run, result })
+}
+
+/// Builds, copies, reads and drops a three field struct `count` times.
+fn dyn_structs(count: u64) -> dyn_vm::Module {
+    use dyn_vm::Op;
+
+    // The loop counter stays on the top of the stack between iterations.
+    let code = vec![
+        Op::U64(0),
+        // 1: loop
+        Op::Copy,
+        Op::U64(count),
+        Op::Lt,
+        Op::JumpIfFalse(19),
+        Op::U32(1),
+        Op::I64(2),
+        Op::F64(3.0),
+        Op::Usize(3),
+        Op::Struct,
+        Op::Copy,
+        Op::Usize(1),
+        Op::StructRead,
+        Op::Pop,
+        Op::Pop,
+        Op::Pop,
+        Op::U64(1),
+        Op::Add,
+        Op::Jump(1),
+        // 19: end
+        Op::Halt,
+    ];
+
+    dyn_vm::Module::new(0, code, Vec::new())
+}
+
+/// Builds, copies and drops a three field struct `count` times.
+fn typed_structs(count: u64) -> typed_vm::Module {
+    use typed_vm::Op;
+
+    let code = vec![
+        Op::U64(0),
+        // 1: loop
+        Op::Copy,
+        Op::U64(count),
+        Op::LtU64,
+        Op::JumpIfFalse(17),
+        Op::U32(1),
+        Op::I64(2),
+        Op::F64(3.0),
+        Op::Usize(3),
+        Op::Struct,
+        Op::Usize(4),
+        Op::CopyMany,
+        Op::Usize(8),
+        Op::PopN,
+        Op::U64(1),
+        Op::AddU64,
+        Op::Jump(1),
+        // 17: end
+        Op::Halt,
+    ];
+
+    typed_module(code)
+}
+
+/// Inserts `rows` records into a table then looks one up `queries` times.
+fn typed_table(rows: u64, queries: u64) -> typed_vm::Module {
+    use typed_vm::Op;
+
+    // The counter sits below the cursor while inserting.
+    let code = vec![
+        Op::U64(0),
+        Op::Usize(0),
+        Op::Table,
+        Op::None,
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b11),
+        Op::Query,
+        // 9: insert loop
+        Op::Usize(2),
+        Op::CopyFrom,
+        Op::U64(rows),
+        Op::LtU64,
+        Op::JumpIfFalse(25),
+        Op::Usize(2),
+        Op::CopyFrom,
+        Op::I64(1),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Insert,
+        Op::Swap,
+        Op::U64(1),
+        Op::AddU64,
+        Op::Swap,
+        Op::Jump(9),
+        // 25: swap the row counter for a query counter
+        Op::Close,
+        Op::Swap,
+        Op::Pop,
+        Op::U64(0),
+        Op::Swap,
+        // 30: query loop
+        Op::Usize(2),
+        Op::CopyFrom,
+        Op::U64(queries),
+        Op::LtU64,
+        Op::JumpIfFalse(49),
+        Op::U64(rows / 2),
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b10),
+        Op::Query,
+        Op::Found,
+        Op::Pop,
+        Op::Close,
+        Op::Swap,
+        Op::U64(1),
+        Op::AddU64,
+        Op::Swap,
+        Op::Jump(30),
+        // 49: end, leaving the query count
+        Op::Pop,
+        Op::Halt,
+    ];
+
+    let mut module = typed_module(code);
+    module.types.insert(0, TableType::new("t", vec![Type::U64, Type::I64]));
+    module
+}
+
+fn typed_module(code: Vec<typed_vm::Op>) -> typed_vm::Module {
+    // Compiling an empty main gives a module with the function table set
+    // up, its code is replaced.
+    let mut module = typed_vm::compile::parse_colang("fn main() {}")
+        .expect("empty main compiles");
+    module.start = 0;
+    module.code = code;
+    module
+}
@@ -1,9 +1,25 @@
+//! The dynamic Vm, whose values carry their type and are checked as each
+//! op runs.
+
 use super::*;
 
-mod compile;
+pub mod compile;
+pub mod convert;
+mod optimize;
+mod snapshot;
+#[cfg(test)]
 use crate::dyn_vm::compile::*;
+// Named so it isn't confused with the typed Vm's error exported by the crate.
+#[cfg(test)]
+use crate::dyn_vm::compile::LangError;
 
 use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::limits::Limits;
+use crate::trace::Tracer;
+
+pub use self::convert::{Args, FromValue, ToValue};
 
 #[derive(Debug, Clone)]
 pub struct VarValue {
@@ -36,7 +52,7 @@ pub enum Value {
     },
     Bool(bool),
     Struct(Vec<Value>), 
-    Function(FunctionValue),
+    Function(Arc<FunctionValue>),
 }
 
 impl From<Value> for Type {
@@ -105,12 +121,13 @@ pub enum Op {
     ///  provided by the usize
     CopyFrom,
 
-    /// ( Symbol -- Value ): Copy the var from frame.
+    /// ( Usize -- Value ): Copy the var from the frame slot indexed by
+    /// the Usize.
     Load,
 
 
-    /// ( Symbol Value -- ): Write the to the value to the variable
-    /// which matches the Symbol based on the current scope.
+    /// ( Value Usize -- ): Write the value to the frame slot indexed by
+    /// the Usize.
     Store,
 
     /// ( Symbol -- Function ): Pushes the function named by the symbol on
@@ -118,14 +135,27 @@ pub enum Op {
     GetFn,
 
 
-    /// (Symbol -- ): Call the given function name.
+    /// (Function -- ): Call the function, giving it a frame with a slot
+    /// for each of its vars.
     Call,
 
+    /// ( -- ): Call the function at the index in `Module.functions`. Calls
+    /// by name are resolved to this when the module is linked.
+    CallDirect(usize),
 
-    /// ( -- ): Pop the address of the top of the call stack and jump
-    /// to the popped address.
+
+    /// ( Value(s) -- Value ): Pop the address of the top of the call stack
+    /// and jump to the popped address, dropping all but the top value the
+    /// function left on the stack.
     Return,
 
+    /// ( -- ): Continue from the instruction at the index.
+    Jump(usize),
+
+    /// ( Bool -- ): Continue from the instruction at the index if the Bool
+    /// is false.
+    JumpIfFalse(usize),
+
     /// ( -- None): Push None on to the stack.
     None,
 
@@ -165,34 +195,102 @@ pub enum Op {
     StructRead,
 
     /// (Number<T>, Number<T> --Number<T>): Add two numbers of a matching type
-    /// and put the result with the same type on the stack.
+    /// and put the result with the same type on the stack. Integers wrap
+    /// on overflow.
     Add,
+
+    /// (Number<T>, Number<T> --Number<T>): Subtract the top number from the
+    /// one below it. Integers wrap on overflow.
+    Sub,
+
+    /// (Number<T>, Number<T> -- Bool): Push true if the number below the
+    /// top is less than the top number.
+    Lt,
+
+    /// (Value -- Value): Suspend the Vm handing the Value to the host.
+    /// The value passed to `Vm::resume` is pushed when execution continues.
+    Yield,
 }
 
 
 
 #[derive(Debug)]
 pub struct Module {
-    pub start: usize,
-    pub code: Vec<Op>,
-    pub functions: BTreeMap<String,FunctionValue>,
+    pub(crate) start: usize,
+    pub(crate) code: Vec<Op>,
+    /// The functions indexed by `CallDirect`.
+    pub(crate) functions: Vec<Arc<FunctionValue>>,
+    /// The index of each function by name, used by `GetFn`.
+    pub(crate) names: BTreeMap<String, usize>,
+}
+
+impl Module {
+    pub fn new(start: usize, code: Vec<Op>, functions: Vec<FunctionValue>) -> Self {
+        let names = functions.iter()
+            .enumerate()
+            .map(|(index, function)| (function.name.clone(), index))
+            .collect();
+
+        Module {
+            start,
+            code,
+            functions: functions.into_iter().map(Arc::new).collect(),
+            names,
+        }
+    }
+
+    pub fn function(&self, name: &str) -> Option<&Arc<FunctionValue>> {
+        let index = self.names.get(name)?;
+        self.functions.get(*index)
+    }
 }
 
 #[derive(Debug)]
 struct CallStackEntry {
     instruction: usize,
-    scope: BTreeMap<usize, Value>
+    /// The caller's frame pointer.
+    frame_ptr: usize,
+    /// The stack length below the called function's arguments.
+    stack_base: usize,
+}
+
+
+/// Why `Vm::run` or `Vm::resume` returned control to the host.
+#[derive(Debug)]
+pub enum Status {
+    /// The Vm executed `Halt` and can not be resumed.
+    Halted,
+
+    /// The Vm executed `Yield` with the value. Call `Vm::resume` to
+    /// continue.
+    Yielded(Value),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ready,
+    Suspended,
+    Halted,
+    /// An op failed, leaving the Vm part way through it.
+    Failed,
+}
 
 #[derive(Debug)]
 pub struct Vm {
     instruction_pointer: usize,
-    frame: BTreeMap<usize, Value>,
+    /// The start of the current function's locals in `slots`.
+    frame_ptr: usize,
+    /// The locals of every active call, one slot per var with the
+    /// current function's vars at the end.
+    slots: Vec<Value>,
     stack: Vec<Value>,
     call_stack: Vec<CallStackEntry>,
-    functions:BTreeMap<String,FunctionValue>,
-    code: Vec<Op>,
+    module: Arc<Module>,
+    state: State,
+    yielded: Option<Value>,
+    tracer: Option<Box<dyn Tracer<Op, Value>>>,
+    limits: Limits,
+    fuel: Option<u64>,
 }
 
 #[derive(Debug)]
@@ -201,61 +299,273 @@ pub enum VmError {
     TypeCheck,
     UnknownVar(usize),
     UnknownFunction(String),
+    /// `CallDirect` was given an index past the module's functions.
+    InvalidFunction(usize),
+    OutOfFuel,
+    StackOverflow,
+    CallDepthExceeded,
+    /// `run` was called on a Vm waiting in `Yield`, use `resume` instead.
+    Suspended,
+    /// `resume` was called on a Vm which is not waiting in `Yield`.
+    NotSuspended,
+    /// The Vm was run after an op failed. Only running out of fuel leaves
+    /// a Vm which can be run again.
+    Failed,
+    /// An op needed more values than the stack holds.
+    StackUnderflow,
+    /// The instruction pointer is outside of the code.
+    InvalidAddress(usize),
+    /// `Vm::call` was given the wrong number of arguments for the function.
+    WrongArgCount(String),
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::InvalidOperation => write!(f, "invalid operation"),
+            VmError::TypeCheck => write!(f, "a value was not of the expected type"),
+            VmError::UnknownVar(index) => write!(f, "unknown variable {}", index),
+            VmError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            VmError::InvalidFunction(index) => write!(f, "invalid function index {}", index),
+            VmError::OutOfFuel => write!(f, "out of fuel"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::CallDepthExceeded => write!(f, "call depth exceeded"),
+            VmError::Suspended => write!(f, "the vm is suspended, use resume"),
+            VmError::NotSuspended => write!(f, "the vm is not suspended"),
+            VmError::Failed => write!(f, "the vm failed and can't be run again"),
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::InvalidAddress(address) => write!(f, "invalid address {}", address),
+            VmError::WrongArgCount(name) => {
+                write!(f, "wrong number of arguments to `{}`", name)
+            },
+        }
+    }
 }
 
+impl std::error::Error for VmError {}
+
 impl Vm {
-    pub fn new(module: Module) -> Self {
+    /// Creates a Vm for the module. Passing an `Arc<Module>` lets several
+    /// Vms run independently over the same code.
+    pub fn new(module: impl Into<Arc<Module>>) -> Self {
+        Vm::with_limits(module, Limits::unlimited())
+    }
+
+    pub fn with_limits(module: impl Into<Arc<Module>>, limits: Limits) -> Self {
+        let module = module.into();
         let stack = vec![];
 
         let bottom = CallStackEntry { 
             instruction: 0, 
-            scope: BTreeMap::new(),
+            frame_ptr: 0,
+            stack_base: 0,
         };
 
+        let start = module.functions.iter()
+            .find(|function| function.offset == module.start);
+        let mut slots = Vec::new();
+        slots.resize_with(start.map_or(0, |function| function.vars.len()), || Value::None);
 
          Vm {
             instruction_pointer: module.start,
+            frame_ptr: 0,
+            slots,
             stack,
-            frame: BTreeMap::new(),
             call_stack: vec![bottom],
-            functions: module.functions,
-            code: module.code,
+            module,
+            state: State::Ready,
+            yielded: None,
+            tracer: None,
+            fuel: limits.fuel,
+            limits,
         }
     }
 
+    /// Adds fuel to a Vm created with a fuel limit. A Vm which stopped
+    /// with `VmError::OutOfFuel` can be `run` again once topped up.
+    pub fn add_fuel(&mut self, amount: u64) {
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel = fuel.saturating_add(amount);
+        }
+    }
+
+    /// The remaining fuel, or None if execution is not metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Attaches a tracer which is called around every executed op,
+    /// replacing any previously attached tracer.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer<Op, Value>>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Detaches and returns the current tracer.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer<Op, Value>>> {
+        self.tracer.take()
+    }
+
 
     pub fn stack_len(&self) -> usize {
         self.stack.len()
     }
 
-    pub fn stack<'a>(&'a self) -> &'a Vec<Value> {
+    pub fn stack(&self) -> &Vec<Value> {
         &self.stack
     }
 
-    pub fn code<'a>(&'a self) -> &'a Vec<Op> {
-        &self.code
+    pub fn code(&self) -> &Vec<Op> {
+        &self.module.code
     }
 
-    pub fn stack_get<'a>(&'a self, index: usize) -> Option<&'a Value> {
+    pub fn module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    pub fn stack_get(&self, index: usize) -> Option<&Value> {
         self.stack.get(index)
     }
 
-    pub fn run(&mut self) -> Result<(), VmError> {
+    /// Runs until the Vm halts or yields.
+    pub fn run(&mut self) -> Result<Status, VmError> {
+        match self.state {
+            State::Ready => {},
+            State::Suspended => return Err(VmError::Suspended),
+            State::Halted => return Ok(Status::Halted),
+            State::Failed => return Err(VmError::Failed),
+        }
 
         let mut halt = false;
 
-        while halt == false {
+        while !halt {
             halt = self.step()?;
         }
 
-        Ok(())
+        match self.yielded.take() {
+            Some(value) => {
+                self.state = State::Suspended;
+                Ok(Status::Yielded(value))
+            },
+            None => {
+                self.state = State::Halted;
+                Ok(Status::Halted)
+            },
+        }
+    }
+
+    /// Calls the function with the arguments and converts what it returns,
+    /// running until the function returns.
+    ///
+    /// ```
+    /// let source = "fn add(a, b) { return a + b; } fn main() {}";
+    /// let module = colang::dynamic::compile(source)?;
+    /// let mut vm = colang::dynamic::Vm::new(module);
+    ///
+    /// assert_eq!(vm.call::<(i64, i64), i64>("add", (5, 7))?, 12);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// The Vm can be called before or after `run`, but not while it is
+    /// suspended or once it has failed. If the function yields or fails
+    /// the Vm is left where it stopped and the call returns
+    /// `VmError::Suspended` or the error.
+    pub fn call<A: Args, R: FromValue>(&mut self, name: &str, args: A) -> Result<R, VmError> {
+        match self.state {
+            State::Suspended => return Err(VmError::Suspended),
+            State::Failed => return Err(VmError::Failed),
+            State::Ready | State::Halted => {},
+        }
+
+        let Some(function) = self.module.function(name) else {
+            return Err(VmError::UnknownFunction(name.to_string()));
+        };
+        let (offset, arg_count, var_count) = (function.offset, function.args, function.vars.len());
+
+        let args = args.into_values();
+        if args.len() != arg_count {
+            return Err(VmError::WrongArgCount(name.to_string()));
+        }
+
+        let (instruction_pointer, state) = (self.instruction_pointer, self.state);
+        self.reserve(args.len())?;
+        self.stack.extend(args);
+
+        // Returning lands on the Halt at the start of the code.
+        self.enter(offset, arg_count, var_count, 0)?;
+        self.state = State::Ready;
+
+        if let Status::Yielded(_) = self.run()? {
+            return Err(VmError::Suspended);
+        }
+
+        let result = self.pop()?;
+        self.instruction_pointer = instruction_pointer;
+        self.state = state;
+        R::from_value(result)
+    }
+
+    /// Continues a Vm suspended by `Yield`, pushing `value` as the result
+    /// of the yield.
+    pub fn resume(&mut self, value: Value) -> Result<Status, VmError> {
+        if self.state == State::Failed {
+            return Err(VmError::Failed);
+        }
+        if self.state != State::Suspended {
+            return Err(VmError::NotSuspended);
+        }
+
+        self.push(value)?;
+        self.state = State::Ready;
+        self.run()
     }
 
+    /// Executes one op, returning true if the Vm halted or yielded. An
+    /// error other than running out of fuel stops the Vm for good.
     pub fn step(&mut self) -> Result<bool, VmError> {
+        if self.state == State::Failed {
+            return Err(VmError::Failed);
+        }
+
+        let result = self.step_op();
+        if let Err(error) = &result {
+            if !matches!(error, VmError::OutOfFuel) {
+                self.state = State::Failed;
+            }
+        }
+        result
+    }
+
+    fn step_op(&mut self) -> Result<bool, VmError> {
         let ptr = self.instruction_pointer;
-        dbg!(&self.stack);
-        dbg!(&self.code[ptr]);
-        match &self.code[ptr] {
+
+        // Checked before the op runs so that the Vm can be resumed at
+        // this instruction once more fuel is added.
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                return Err(VmError::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+
+        if ptr >= self.module.code.len() {
+            return Err(VmError::InvalidAddress(ptr));
+        }
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.before(ptr, &self.module.code[ptr], &self.stack);
+        }
+
+        let result = self.execute(ptr);
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.after(ptr, &self.module.code[ptr], &self.stack);
+        }
+
+        result
+    }
+
+    fn execute(&mut self, ptr: usize) -> Result<bool, VmError> {
+        match &self.module.code[ptr] {
             Op::Noop => {
                 self.inc_op();
             },
@@ -272,8 +582,8 @@ impl Vm {
 
             Op::Swap => {
 
-                let last = self.stack.len() - 1;
-                let second = last - 1;
+                let last = self.depth_index(1)?;
+                let second = self.depth_index(2)?;
 
                 self.stack.swap(last, second);
                 
@@ -281,7 +591,7 @@ impl Vm {
             },
 
             Op::Copy => {
-                let index = self.stack.len() - 1;
+                let index = self.depth_index(1)?;
                 self.copy(index)?;
                 self.inc_op();
             },
@@ -291,7 +601,7 @@ impl Vm {
                     return Err(VmError::TypeCheck);
                 };
 
-                let index = self.stack.len() - depth;
+                let index = self.depth_index(depth)?;
                 self.copy(index)?;
                 self.inc_op();
             },
@@ -301,11 +611,9 @@ impl Vm {
                     return Err(VmError::TypeCheck);
                 };
 
-                let Some(value) = self.frame.get(&index) else {
-                    return Err(VmError::UnknownVar(index));
-                };
-
-                self.stack.push(self.copy_value(value)?);
+                let slot = self.slot_index(index)?;
+                let value = self.copy_value(&self.slots[slot])?;
+                self.push(value)?;
 
                 self.inc_op();
             },
@@ -316,9 +624,8 @@ impl Vm {
                     return Err(VmError::TypeCheck);
                 };
                 
-                let value = self.pop()?;
-
-                self.frame.insert(index, value);
+                let slot = self.slot_index(index)?;
+                self.slots[slot] = self.pop()?;
 
                 self.inc_op();
             },
@@ -328,32 +635,32 @@ impl Vm {
                     return Err(VmError::TypeCheck);
                 };
 
-                let Some(function) = self.functions.get(&name) else {
+                let Some(function) = self.module.function(&name) else {
                     return Err(VmError::UnknownFunction(name));
                 };
 
-                self.stack.push(Value::Function((*function).clone()));
+                self.push(Value::Function(function.clone()))?;
                 self.inc_op();
             },
 
 
             Op::Call => {
                 let Value::Function(function) = self.pop()? else {
-                    unimplemented!()
+                    return Err(VmError::TypeCheck);
                 };
 
-                let mut frame = BTreeMap::new();
-
-                std::mem::swap(&mut frame, &mut self.frame);
+                let ret = self.instruction_pointer + 1;
+                self.enter(function.offset, function.args, function.vars.len(), ret)?;
+            },
 
-                let call_value = CallStackEntry {
-                    instruction: self.instruction_pointer + 1,
-                    scope: frame,
+            Op::CallDirect(index) => {
+                let Some(function) = self.module.functions.get(*index) else {
+                    return Err(VmError::InvalidFunction(*index));
                 };
 
-                self.call_stack.push(call_value);
-                // self.frame set to BTreeMap::new() in swap
-                self.instruction_pointer = function.offset;
+                let (offset, args, var_count) = (function.offset, function.args, function.vars.len());
+                let ret = self.instruction_pointer + 1;
+                self.enter(offset, args, var_count, ret)?;
             },
 
             Op::Return => {
@@ -361,74 +668,100 @@ impl Vm {
                     return Err(VmError::InvalidOperation);
                 };
 
-                self.frame = entry.scope;
+                // Only the top value is returned, anything else the
+                // function left on the stack is dropped.
+                if self.stack.len() > entry.stack_base {
+                    let result = self.pop()?;
+                    self.stack.truncate(entry.stack_base);
+                    self.push(result)?;
+                }
+
+                self.slots.truncate(self.frame_ptr);
+                self.frame_ptr = entry.frame_ptr;
                 self.instruction_pointer = entry.instruction;
             }
 
+
+            Op::Jump(target) => {
+                self.instruction_pointer = *target;
+            },
+
+            Op::JumpIfFalse(target) => {
+                let target = *target;
+                let Value::Bool(condition) = self.pop()? else {
+                    return Err(VmError::TypeCheck);
+                };
+
+                if condition {
+                    self.inc_op();
+                } else {
+                    self.instruction_pointer = target;
+                }
+            },
             
             Op::None => {
-                self.stack.push(Value::None);
+                self.push(Value::None)?;
                 self.inc_op();
             },
 
             Op::Symbol(v) => {
-                self.stack.push(Value::Symbol(v.clone()));
+                self.push(Value::Symbol(v.clone()))?;
                 self.inc_op(); 
             },
 
             Op::F32(value) => {
-                self.stack.push(Value::F32(*value));
+                self.push(Value::F32(*value))?;
                 self.inc_op();
             },
 
             Op::F64(value) => {
-                self.stack.push(Value::F64(*value));
+                self.push(Value::F64(*value))?;
                 self.inc_op();
             },
             Op::I32(value) => {
-                self.stack.push(Value::I32(*value));
+                self.push(Value::I32(*value))?;
                 self.inc_op();
             },
 
             Op::I64(value) => {
-                self.stack.push(Value::I64(*value));
+                self.push(Value::I64(*value))?;
                 self.inc_op();
             },
             Op::U32(value) => {
-                self.stack.push(Value::U32(*value));
+                self.push(Value::U32(*value))?;
                 self.inc_op();
             },
 
             Op::U64(value) => {
-                self.stack.push(Value::U64(*value));
+                self.push(Value::U64(*value))?;
                 self.inc_op();
             },
 
             Op::Usize(value) => {
-                self.stack.push(Value::Usize(*value));
+                self.push(Value::Usize(*value))?;
                 self.inc_op();
             },
 
             Op::Bool(value) => {
-                self.stack.push(Value::Bool(*value));
+                self.push(Value::Bool(*value))?;
                 self.inc_op();
             },
 
             Op::Struct => {
-                let Value::Usize(field_count) = self.stack.pop().unwrap() else {
-                    unreachable!();
+                let Value::Usize(field_count) = self.pop()? else {
+                    return Err(VmError::TypeCheck);
                 };
-                let at = self.stack_len() - field_count;
+                let at = self.depth_index(field_count)?;
                 let mut values = self.stack.split_off(at);
 
                 values.reverse();
 
-                self.stack.push(Value::Struct(values));
+                self.push(Value::Struct(values))?;
                 self.inc_op();
             },
 
             Op::StructRead => {
-                let Value::Usize(index) = self.stack.pop().unwrap() else {
+                let Value::Usize(index) = self.pop()? else {
                     return Err(VmError::TypeCheck);
                 };
 
@@ -441,40 +774,88 @@ impl Vm {
                 };
 
                 let new = self.copy_value(value)?;
-                self.stack.push(new);
+                self.push(new)?;
                 self.inc_op();
             }
 
             Op::Add => {
-                let first = self.stack.pop().unwrap();
-                let second = self.stack.pop().unwrap();
-                use Value::*;
+                let first = self.pop()?;
+                let second = self.pop()?;
+                use self::Value::*;
 
+                // Integer addition wraps on overflow.
                 let sum = match (first, second) {
                     (F32(a), F32(b)) => F32(a + b),
                     (F64(a), F64(b)) => F64(a + b),
 
-                    (I32(a), I32(b)) => I32(a + b),
-                    (I64(a), I64(b)) => I64(a + b),
+                    (I32(a), I32(b)) => I32(a.wrapping_add(b)),
+                    (I64(a), I64(b)) => I64(a.wrapping_add(b)),
 
-                    (U32(a), U32(b)) => U32(a + b),
-                    (U64(a), U64(b)) => U64(a + b),
+                    (U32(a), U32(b)) => U32(a.wrapping_add(b)),
+                    (U64(a), U64(b)) => U64(a.wrapping_add(b)),
                     _ => return Err(VmError::TypeCheck),
                 };
-                self.stack.push(sum);
+                self.push(sum)?;
                 self.inc_op();
             },
+
+            Op::Sub => {
+                let first = self.pop()?;
+                let second = self.pop()?;
+                use self::Value::*;
+
+                let difference = match (second, first) {
+                    (F32(a), F32(b)) => F32(a - b),
+                    (F64(a), F64(b)) => F64(a - b),
+
+                    (I32(a), I32(b)) => I32(a.wrapping_sub(b)),
+                    (I64(a), I64(b)) => I64(a.wrapping_sub(b)),
+
+                    (U32(a), U32(b)) => U32(a.wrapping_sub(b)),
+                    (U64(a), U64(b)) => U64(a.wrapping_sub(b)),
+                    _ => return Err(VmError::TypeCheck),
+                };
+                self.push(difference)?;
+                self.inc_op();
+            },
+
+            Op::Lt => {
+                let first = self.pop()?;
+                let second = self.pop()?;
+                use self::Value::*;
+
+                let less = match (second, first) {
+                    (F32(a), F32(b)) => a < b,
+                    (F64(a), F64(b)) => a < b,
+                    (I32(a), I32(b)) => a < b,
+                    (I64(a), I64(b)) => a < b,
+                    (U32(a), U32(b)) => a < b,
+                    (U64(a), U64(b)) => a < b,
+                    _ => return Err(VmError::TypeCheck),
+                };
+                self.push(Bool(less))?;
+                self.inc_op();
+            },
+
+            Op::Yield => {
+                let value = self.pop()?;
+                self.yielded = Some(value);
+                self.inc_op();
+                return Ok(true);
+            },
         }
         Ok(false)
     }
 
      
     fn copy(&mut self, index: usize) -> Result<(), VmError> {
-        let value = self.stack.get(index).unwrap();
+        let Some(value) = self.stack.get(index) else {
+            return Err(VmError::StackUnderflow);
+        };
 
     
         let copy = self.copy_value(value)?;
-        self.stack.push(copy);
+        self.push(copy)?;
 
         Ok(())
     }
@@ -504,11 +885,12 @@ impl Vm {
                 Value::Struct(values)
             }, 
            
-            Value::Function(ptr) => Value::Function((*ptr).clone()),
+            Value::Function(function) => Value::Function(function.clone()),
         };
         Ok(result)
     }
 
+    #[cfg(test)]
     fn eq_value(a: &Value, b: &Value) -> Result<bool, VmError> {
         let result = match (a, b) {
             (Value::None, Value::None) => false,
@@ -537,11 +919,70 @@ impl Vm {
     }
 
     fn pop(&mut self) -> Result<Value, VmError> {
-        Ok(self.stack.pop().unwrap())
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    /// Pushes the value unless the stack already holds `max_stack` values.
+    fn push(&mut self, value: Value) -> Result<(), VmError> {
+        self.reserve(1)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Fails unless `count` more values fit on the stack.
+    fn reserve(&self, count: usize) -> Result<(), VmError> {
+        match self.limits.max_stack {
+            Some(max) if self.stack.len() + count > max => Err(VmError::StackOverflow),
+            _ => Ok(()),
+        }
+    }
+
+    /// Enters the function at `offset`, which returns to the instruction
+    /// `ret`.
+    fn enter(&mut self, offset: usize, args: usize, var_count: usize, ret: usize) -> Result<(), VmError> {
+        if let Some(max_depth) = self.limits.max_call_depth {
+            // The bottom entry returns from main.
+            if self.call_stack.len() > max_depth {
+                return Err(VmError::CallDepthExceeded);
+            }
+        }
+
+        let Some(stack_base) = self.stack.len().checked_sub(args) else {
+            return Err(VmError::StackUnderflow);
+        };
+
+        let call_value = CallStackEntry {
+            instruction: ret,
+            frame_ptr: self.frame_ptr,
+            stack_base,
+        };
+
+        self.call_stack.push(call_value);
+
+        // Vars read before they are stored are None.
+        self.frame_ptr = self.slots.len();
+        self.slots.resize_with(self.frame_ptr + var_count, || Value::None);
+        self.instruction_pointer = offset;
+        Ok(())
+    }
+
+    /// The index in `slots` of the current function's var.
+    fn slot_index(&self, index: usize) -> Result<usize, VmError> {
+        match self.frame_ptr.checked_add(index) {
+            Some(slot) if slot < self.slots.len() => Ok(slot),
+            _ => Err(VmError::UnknownVar(index)),
+        }
+    }
+
+    /// The index of the value `depth` down from the top of the stack where
+    /// a depth of 1 is the top value. A depth of 0 gives the stack length,
+    /// the start of an empty run of values.
+    fn depth_index(&self, depth: usize) -> Result<usize, VmError> {
+        self.stack.len().checked_sub(depth).ok_or(VmError::StackUnderflow)
     }
 
     fn pop_value(&mut self) -> Result<(), VmError> {
-        let value = self.pop()?;
+        self.pop()?;
         Ok(())
     }
 }
@@ -1,9 +1,11 @@
 
 use pest::Parser;
+use pest::Span;
 use pest::iterators::Pair;
 use pest::error::Error;
-use std::{fs, collections::BTreeMap, ops::Index};
+use std::collections::BTreeMap;
 use super::{Op, Module};
+use crate::source::{Location, SourceMap};
 
 use crate::lang::*;
 
@@ -13,9 +15,32 @@ use super::*;
 pub enum LangError {
     NoMain,
     ParserError(Error<Rule>),
+    /// A source file could not be read.
+    Io(std::io::Error),
     UnknownVar(String),
     UnknownFunction(String),
     VarAlreadyDeclared(String),
+    /// A second definition of the function, with where the first one is
+    /// when it's in a named source.
+    FunctionAlreadyDefined(String, Option<Location>),
+    InvalidNumber(String),
+    /// An operator the grammar parses but the Vm has no op for.
+    UnsupportedOperator(String),
+    /// The statement uses tables, which only the typed Vm has.
+    Unsupported(String),
+    /// An error found in a named source, with where it was found.
+    Located(Location, Box<LangError>),
+}
+
+impl LangError {
+    /// Gives the error the location, unless it already has one.
+    fn at(self, location: Location) -> Self {
+        match self {
+            LangError::NoMain | LangError::ParserError(_) | LangError::Io(_)
+            | LangError::Located(..) => self,
+            error => LangError::Located(location, Box::new(error)),
+        }
+    }
 }
 
 impl From<pest::error::Error<Rule>> for LangError {
@@ -24,64 +49,187 @@ impl From<pest::error::Error<Rule>> for LangError {
     }
 }
 
+impl From<std::io::Error> for LangError {
+    fn from(value: std::io::Error) -> Self {
+        LangError::Io(value)
+    }
+}
+
+impl std::fmt::Display for LangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LangError::NoMain => write!(f, "no main function"),
+            LangError::ParserError(error) => write!(f, "{}", error),
+            LangError::Io(error) => write!(f, "{}", error),
+            LangError::UnknownVar(name) => write!(f, "unknown variable `{}`", name),
+            LangError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            LangError::VarAlreadyDeclared(name) => {
+                write!(f, "variable `{}` is already declared", name)
+            },
+            LangError::FunctionAlreadyDefined(name, None) => {
+                write!(f, "function `{}` is already defined", name)
+            },
+            LangError::FunctionAlreadyDefined(name, Some(first)) => {
+                write!(f, "function `{}` is already defined at {}", name, first)
+            },
+            LangError::InvalidNumber(text) => write!(f, "invalid number `{}`", text),
+            LangError::UnsupportedOperator(op) => write!(f, "unsupported operator `{}`", op),
+            LangError::Unsupported(statement) => {
+                write!(f, "`{}` is not supported by the dyn vm", statement)
+            },
+            LangError::Located(location, error) => write!(f, "{}: {}", location, error),
+        }
+    }
+}
+
+impl std::error::Error for LangError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LangError::Io(error) => Some(error),
+            LangError::Located(_, error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
 
 
 #[derive(Debug)]
-pub struct ModuleBuilder<'a> {
+pub(crate) struct ModuleBuilder<'a> {
     code: Vec<Op> ,
-    functions: BTreeMap<String,FunctionValue>,
+    /// Functions by the index calls are resolved to, None until the
+    /// function's definition is compiled.
+    functions: Vec<Option<FunctionValue>>,
+    names: BTreeMap<String, usize>,
+    /// The functions defined so far, and where when they're in a named
+    /// source.
+    definitions: BTreeMap<&'a str, Option<Location>>,
+    /// The name of the source being compiled, None for a lone unnamed
+    /// source whose errors aren't located.
+    source: Option<&'a str>,
     scope: BTreeMap<&'a str, VarValue>,
+    /// Vars in the order they were declared so blocks can drop theirs.
+    declared: Vec<&'a str>,
+    /// Vars of closed blocks, which still need a slot in the frame.
+    hidden: Vec<VarValue>,
     arg_count: usize,
     next_index: usize,
     function_start: usize,
+    /// Whether each function's code is optimized once compiled.
+    optimize: bool,
 }
 
 impl<'a> ModuleBuilder<'a> {
     pub fn new() -> Self {
         ModuleBuilder {
             code: vec![Op::Halt],
-            functions: BTreeMap::new(),
+            functions: Vec::new(),
+            names: BTreeMap::new(),
+            definitions: BTreeMap::new(),
+            source: None,
             scope: BTreeMap::new(),
+            declared: Vec::new(),
+            hidden: Vec::new(),
             arg_count: 0,
             next_index: 0,
             function_start: 0,
+            optimize: true,
         }
     } 
 
     pub fn new_frame(&mut self) {
         self.scope = BTreeMap::new();
+        self.declared.clear();
+        self.hidden.clear();
         self.next_index = 0;
         self.arg_count = 0;
         self.function_start = self.code.len();
     }
 
+    /// Links the module. Calls were compiled to `CallDirect` with the
+    /// index of the function's name so every called function must have
+    /// been defined.
     pub fn into_module(self) -> Result<Module, LangError> {
-        let Some(main) = self.functions.get("main") else {
+        let main = self.names.get("main")
+            .and_then(|index| self.functions[*index].as_ref());
+        let Some(main) = main else {
             return Err(LangError::NoMain);
         };
+        let start = main.offset;
+
+        let mut functions = Vec::with_capacity(self.functions.len());
+        for (index, function) in self.functions.into_iter().enumerate() {
+            let Some(function) = function else {
+                let name = self.names.iter()
+                    .find(|(_, i)| **i == index)
+                    .map(|(name, _)| name.clone())
+                    .unwrap_or_default();
+                return Err(LangError::UnknownFunction(name));
+            };
+            functions.push(Arc::new(function));
+        }
 
         let resulst = Module {
-            start: main.offset,
+            start,
             code: self.code,
-            functions: self.functions,
+            functions,
+            names: self.names,
         };
 
         Ok(resulst)
     }
 
+    /// The index calls to the named function are resolved to.
+    fn function_index(&mut self, name: &str) -> usize {
+        if let Some(index) = self.names.get(name) {
+            return *index;
+        }
+
+        let index = self.functions.len();
+        self.functions.push(None);
+        self.names.insert(name.to_string(), index);
+        index
+    }
+
     fn new_function(&mut self, name: &'a str)  {
         
         let function = FunctionValue {
             name: name.to_string(),
             offset: self.function_start,
             args: self.arg_count,
-            vars: self.scope.values().map(|v|(*v).clone()).collect(),
+            vars: self.scope.values()
+                .chain(self.hidden.iter())
+                .cloned()
+                .collect(),
         };
 
-        self.functions.insert(name.to_string(), function);
+        let index = self.function_index(name);
+        self.functions[index] = Some(function);
         
     }
 
+    /// Optimizes the code of the function just compiled. Its jumps are
+    /// made relative to the start of the function while it's optimized.
+    fn optimize_function(&mut self) {
+        let start = self.function_start;
+        let mut code = self.code.split_off(start);
+
+        for op in code.iter_mut() {
+            if let Op::Jump(to) | Op::JumpIfFalse(to) = op {
+                *to -= start;
+            }
+        }
+
+        super::optimize::optimize(&mut code);
+
+        for op in code.iter_mut() {
+            if let Op::Jump(to) | Op::JumpIfFalse(to) = op {
+                *to += start;
+            }
+        }
+        self.code.append(&mut code);
+    }
+
     fn add_op(&mut self, op:Op) {
         self.code.push(op);
     }
@@ -100,9 +248,43 @@ impl<'a> ModuleBuilder<'a> {
             var_type: Type::Unknown,
         };
         self.scope.insert(name, var);
+        self.declared.push(name);
         Ok(index)
     }
 
+    /// Drops the vars declared since `declared` vars were in scope.
+    fn end_block(&mut self, declared: usize) {
+        for name in self.declared.split_off(declared) {
+            if let Some(var) = self.scope.remove(name) {
+                self.hidden.push(var);
+            }
+        }
+    }
+
+    /// Emits a jump to be pointed at its target with `patch`.
+    fn jump(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match &mut self.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Locates an error found in the span if the source being compiled
+    /// is named.
+    fn locate(&self, error: LangError, span: Span) -> LangError {
+        match self.source {
+            Some(source) => error.at(Location::of(source, span)),
+            None => error,
+        }
+    }
+
     fn get_var<'b>(&'b self, name: &str) -> Result<&'b VarValue, LangError> {
         match self.scope.get(name) {
             None => Err(LangError::UnknownVar(name.to_string())),
@@ -111,12 +293,50 @@ impl<'a> ModuleBuilder<'a> {
     }
 }
 
-pub fn parse_colang_file<'a>(file: &'a str) -> Result<Module, LangError> {
-    let data = fs::read_to_string(file).expect("Unable to read file");
-    let pairs = LangParser::parse(Rule::program, &data)?;
+pub fn parse_colang_file(file: &str) -> Result<Module, LangError> {
+    let mut sources = SourceMap::new();
+    sources.add_file(file)?;
+    compile_sources(&sources)
+}
+
+/// Compiles colang source to a module for the dyn Vm.
+pub fn parse_colang(source: &str) -> Result<Module, LangError> {
+    let pairs = LangParser::parse(Rule::program, source)?;
+    compile(pairs.map(|pair| (None, pair)), true)
+}
+
+/// Compiles without optimizing, for checking what the optimizer does.
+#[cfg(test)]
+pub fn parse_colang_unoptimized(source: &str) -> Result<Module, LangError> {
+    let pairs = LangParser::parse(Rule::program, source)?;
+    compile(pairs.map(|pair| (None, pair)), false)
+}
+
+/// Compiles the source naming it in any parse error, for source which
+/// doesn't come from a file.
+pub fn compile_str(name: &str, source: &str) -> Result<Module, LangError> {
+    let mut sources = SourceMap::new();
+    sources.add(name, source);
+    compile_sources(&sources)
+}
+
+/// Compiles the sources together, as if their functions were defined in
+/// one source. Errors are located in the source they were found in.
+pub fn compile_sources(sources: &SourceMap) -> Result<Module, LangError> {
+    let pairs = sources.parse()?;
+    compile(pairs.into_iter().map(|(name, pair)| (Some(name), pair)), true)
+}
+
+/// Compiles the pairs, each with the name of its source if it has one.
+fn compile<'a>(
+    pairs: impl IntoIterator<Item = (Option<&'a str>, Pair<'a, Rule>)>,
+    optimize: bool,
+) -> Result<Module, LangError> {
     let mut builder = ModuleBuilder::new();
+    builder.optimize = optimize;
 
-    for pair in pairs {
+    for (source, pair) in pairs {
+        builder.source = source;
         parse_pair(&mut builder, pair)?;
     }
 
@@ -124,16 +344,46 @@ pub fn parse_colang_file<'a>(file: &'a str) -> Result<Module, LangError> {
     Ok(result)
 }
 
+fn parse_literal<T: std::str::FromStr>(pair: &Pair<Rule>) -> Result<T, LangError> {
+    match parse_number(pair.as_str()) {
+        Some(v) => Ok(v),
+        None => Err(LangError::InvalidNumber(pair.as_str().to_string())),
+    }
+}
+
+/// Statements which push a value, rather than declarations, assignments,
+/// returns and blocks.
+fn is_expression(rule: Rule) -> bool {
+    !matches!(rule,
+        Rule::declaration
+        | Rule::assignment
+        | Rule::ret
+        | Rule::if_block
+        | Rule::while_block)
+}
+
+/// Compiles the pair, locating errors in a named source at the innermost
+/// pair they were found in.
 fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Result<(), LangError> {
+    let span = pair.as_span();
+    compile_pair(builder, pair).map_err(|error| builder.locate(error, span))
+}
+
+fn compile_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Result<(), LangError> {
     use Rule::*;
 
     match pair.as_rule() {
         WHITESPACE
+        | COMMENT
         | number
         | value
         | op
         | expression
         | statment
+        | term
+        | group_value
+        | operand
+        | where_test
         | program
         // These rules are silent
         => unreachable!(),
@@ -141,28 +391,56 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             // Noop
         },
 
+        for_block | insert | aggregate_into | transaction_block | rollback => {
+            let statement = pair.as_str().split_whitespace().next().unwrap_or_default();
+            return Err(LangError::Unsupported(statement.to_string()));
+        },
+
+        aggregate => {
+            let name = pair.as_str().split('(').next().unwrap_or_default();
+            return Err(LangError::Unsupported(name.trim().to_string()));
+        },
+
+        join | pattern | field_value | fold | fold_fn | group_field | group_by | order_by
+        | sort_key | descending | limit_clause | offset_clause | row_count | where_clause
+        | comparison | compare_op | membership | prefix_test | string => {
+            // Only found in the statements above
+            unreachable!()
+        },
+
+        arg_decl => {
+            // Handled with their args
+            unreachable!()
+        },
+
+        table_decl | field_decl | index_decl | file_decl | order_decl | file_path | type_name
+        | rule_decl | atom => {
+            // Table types are only used by the typed Vm, the dyn Vm checks
+            // records as they are inserted.
+        },
+
         F32 => {
-            let v = pair.as_str().parse().unwrap();
+            let v = parse_literal(&pair)?;
             builder.code.push(Op::F32(v));
         },
         F64 => {
-            let v = pair.as_str().parse().unwrap();
+            let v = parse_literal(&pair)?;
             builder.code.push(Op::F64(v));
         },
         I32 => {
-            let v = pair.as_str().parse().unwrap();
+            let v = parse_literal(&pair)?;
             builder.code.push(Op::I32(v));
         },
         I64 => {
-            let v = pair.as_str().parse().unwrap();
+            let v = parse_literal(&pair)?;
             builder.code.push(Op::I64(v));
         },
         U32 => {
-            let v = pair.as_str().parse().unwrap();
+            let v = parse_literal(&pair)?;
             builder.code.push(Op::U32(v));
         },
         U64 => {
-            let v = pair.as_str().parse().unwrap();
+            let v = parse_literal(&pair)?;
             builder.code.push(Op::U64(v));
         },
 
@@ -184,16 +462,13 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             builder.code.push(Op::Add);
         },
         sub => {
-            //builder.code.push(Op::Sub);
-        },
-        mul => {
-            //builder.code.push(Op::Mul);
+            builder.code.push(Op::Sub);
         },
-        div => {
-            //builder.code.push(Op::Div);
+        lt => {
+            builder.code.push(Op::Lt);
         },
-        exp => {
-            //builder.code.push(Op::Exp);
+        mul | div | exp => {
+            return Err(LangError::UnsupportedOperator(pair.as_str().to_string()));
         },
         opperation => {
             let mut parts = pair.into_inner();
@@ -207,6 +482,14 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             parse_pair(builder, operator)?;
         },
 
+        yield_expr => {
+            for pair in pair.into_inner() {
+                parse_pair(builder, pair)?;
+            }
+
+            builder.code.push(Op::Yield);
+        },
+
         params => {
             let parts = pair.into_inner();
             for p in parts {
@@ -221,9 +504,8 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             let arguments = parts.next().unwrap();
 
             parse_pair(builder, arguments)?;
-            parse_pair(builder, name)?;
-            builder.code.push(Op::GetFn);
-            builder.code.push(Op::Call);
+            let index = builder.function_index(name.as_str());
+            builder.code.push(Op::CallDirect(index));
 
   
         },
@@ -241,6 +523,68 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             builder.code.push(Op::Store);
         },
 
+        assignment => {
+            let mut parts = pair.into_inner();
+
+            let l_value = parts.next().unwrap();
+            let r_value = parts.next().unwrap();
+
+            parse_pair(builder, r_value)?;
+            let index = builder.get_var(l_value.as_str())?.index;
+            builder.add_op(Op::Usize(index));
+            builder.code.push(Op::Store);
+        },
+
+        block => {
+            // Values left by expression statements are dropped so a block
+            // leaves the stack as it found it.
+            let declared = builder.declared.len();
+            for statement in pair.into_inner() {
+                let pushes_value = is_expression(statement.as_rule());
+                parse_pair(builder, statement)?;
+                if pushes_value {
+                    builder.code.push(Op::Pop);
+                }
+            }
+            builder.end_block(declared);
+        },
+
+        if_block => {
+            let mut parts = pair.into_inner();
+
+            let condition = parts.next().unwrap();
+            let then_block = parts.next().unwrap();
+            let else_block = parts.next();
+
+            parse_pair(builder, condition)?;
+            let to_else = builder.jump(Op::JumpIfFalse(0));
+            parse_pair(builder, then_block)?;
+
+            match else_block {
+                Some(else_block) => {
+                    let to_end = builder.jump(Op::Jump(0));
+                    builder.patch(to_else);
+                    parse_pair(builder, else_block)?;
+                    builder.patch(to_end);
+                },
+                None => builder.patch(to_else),
+            }
+        },
+
+        while_block => {
+            let mut parts = pair.into_inner();
+
+            let condition = parts.next().unwrap();
+            let loop_body = parts.next().unwrap();
+
+            let start = builder.code.len();
+            parse_pair(builder, condition)?;
+            let to_end = builder.jump(Op::JumpIfFalse(0));
+            parse_pair(builder, loop_body)?;
+            builder.code.push(Op::Jump(start));
+            builder.patch(to_end);
+        },
+
         ret => {
             for pair in pair.into_inner() {
                 parse_pair(builder, pair)?;
@@ -251,9 +595,17 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
 
    
         args => {
+            let mut indexes = Vec::new();
+            // The dyn Vm checks values as it runs, so argument types are
+            // ignored.
             for arg_n in pair.into_inner() {
-                let name = arg_n.as_str();
-                let index = builder.new_var(name)?;
+                let name = arg_n.into_inner().next().unwrap().as_str();
+                indexes.push(builder.new_var(name)?);
+            }
+            builder.arg_count = indexes.len();
+
+            // The last argument is on the top of the stack.
+            for index in indexes.into_iter().rev() {
                 builder.add_op(Op::Usize(index));
                 builder.code.push(Op::Store);
             }
@@ -268,6 +620,7 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
         function => {
             builder.new_frame();
 
+            let location = builder.source.map(|source| Location::of(source, pair.as_span()));
             let mut parts = pair.into_inner();
             
             let fn_name = parts.next().unwrap();
@@ -275,7 +628,15 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             let fn_body = parts.next().unwrap();
 
             let name = fn_name.as_str();
+            if let Some(first) = builder.definitions.insert(name, location) {
+                return Err(LangError::FunctionAlreadyDefined(name.to_string(), first));
+            }
 
+            // Without a return the function evaluates to the last value it
+            // pushed, or None if no statement pushed one.
+            let pushes_value = fn_body.clone().into_inner()
+                .any(|statement| is_expression(statement.as_rule()) 
+                    || statement.as_rule() == Rule::ret);
 
             // Process fn args
             parse_pair(builder, fn_args)?;
@@ -285,8 +646,15 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
 
             builder.new_function(name);
 
+            if !pushes_value {
+                builder.code.push(Op::None);
+            }
             builder.code.push(Op::Return);
 
+            if builder.optimize {
+                builder.optimize_function();
+            }
+
         }, 
     };
         // program => ... BUG
@@ -0,0 +1,196 @@
+use super::*;
+
+/// A Rust value which can be passed to colang code. Tuples become
+/// structs, strings become symbols and `None` becomes `Value::None`.
+pub trait ToValue {
+    fn to_value(self) -> Value;
+}
+
+/// A Rust value which can be read from a value colang code produced.
+/// Values of another type give `VmError::TypeCheck`.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, VmError>;
+}
+
+/// The arguments of `Vm::call`, a tuple with one value per argument.
+pub trait Args {
+    fn into_values(self) -> Vec<Value>;
+}
+
+impl ToValue for Value {
+    fn to_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, VmError> {
+        Ok(value)
+    }
+}
+
+macro_rules! primitive {
+    ($($rust:ty => $variant:ident),*) => {
+        $(
+            impl ToValue for $rust {
+                fn to_value(self) -> Value {
+                    Value::$variant(self)
+                }
+            }
+
+            impl FromValue for $rust {
+                fn from_value(value: Value) -> Result<Self, VmError> {
+                    match value {
+                        Value::$variant(v) => Ok(v),
+                        _ => Err(VmError::TypeCheck),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+primitive!(
+    usize => Usize,
+    f32 => F32,
+    f64 => F64,
+    u32 => U32,
+    u64 => U64,
+    i32 => I32,
+    i64 => I64,
+    bool => Bool,
+    String => Symbol
+);
+
+impl ToValue for &str {
+    fn to_value(self) -> Value {
+        Value::Symbol(self.to_string())
+    }
+}
+
+impl ToValue for () {
+    fn to_value(self) -> Value {
+        Value::None
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, VmError> {
+        match value {
+            Value::None => Ok(()),
+            _ => Err(VmError::TypeCheck),
+        }
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(self) -> Value {
+        match self {
+            Some(value) => value.to_value(),
+            None => Value::None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self, VmError> {
+        match value {
+            Value::None => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+/// The fields of a struct value which must have `count` fields. Used by
+/// `value_struct!`.
+#[doc(hidden)]
+pub fn struct_fields(value: Value, count: usize) -> Result<std::vec::IntoIter<Value>, VmError> {
+    match value {
+        Value::Struct(fields) if fields.len() == count => Ok(fields.into_iter()),
+        _ => Err(VmError::TypeCheck),
+    }
+}
+
+macro_rules! tuple {
+    ($count:expr => $($name:ident),+) => {
+        impl<$($name: ToValue),+> ToValue for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn to_value(self) -> Value {
+                let ($($name,)+) = self;
+                Value::Struct(vec![$($name.to_value()),+])
+            }
+        }
+
+        impl<$($name: FromValue),+> FromValue for ($($name,)+) {
+            fn from_value(value: Value) -> Result<Self, VmError> {
+                let mut fields = struct_fields(value, $count)?;
+                // struct_fields checked there is a field for each element.
+                Ok(($($name::from_value(fields.next().unwrap())?,)+))
+            }
+        }
+
+        impl<$($name: ToValue),+> Args for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Vec<Value> {
+                let ($($name,)+) = self;
+                vec![$($name.to_value()),+]
+            }
+        }
+    };
+}
+
+impl Args for () {
+    fn into_values(self) -> Vec<Value> {
+        Vec::new()
+    }
+}
+
+tuple!(1 => A);
+tuple!(2 => A, B);
+tuple!(3 => A, B, C);
+tuple!(4 => A, B, C, D);
+tuple!(5 => A, B, C, D, E);
+tuple!(6 => A, B, C, D, E, F);
+
+/// Implements `ToValue` and `FromValue` for a struct with named fields,
+/// mapping it to a `Value::Struct` with the fields in the order given.
+///
+/// ```
+/// use colang::dynamic::{FromValue, ToValue};
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Point {
+///     x: i64,
+///     y: i64,
+/// }
+///
+/// colang::value_struct!(Point { x, y });
+///
+/// let value = Point { x: 1, y: 2 }.to_value();
+/// assert_eq!(Point::from_value(value)?, Point { x: 1, y: 2 });
+/// # Ok::<(), colang::dynamic::VmError>(())
+/// ```
+#[macro_export]
+macro_rules! value_struct {
+    ($name:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::dynamic::ToValue for $name {
+            fn to_value(self) -> $crate::dynamic::Value {
+                $crate::dynamic::Value::Struct(vec![
+                    $($crate::dynamic::ToValue::to_value(self.$field)),*
+                ])
+            }
+        }
+
+        impl $crate::dynamic::FromValue for $name {
+            fn from_value(
+                value: $crate::dynamic::Value,
+            ) -> Result<Self, $crate::dynamic::VmError> {
+                let count = [$(stringify!($field)),*].len();
+                let mut fields = $crate::dynamic::struct_fields(value, count)?;
+                Ok($name {
+                    $($field: $crate::dynamic::FromValue::from_value(fields.next().unwrap())?),*
+                })
+            }
+        }
+    };
+}
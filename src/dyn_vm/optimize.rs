@@ -0,0 +1,142 @@
+use super::*;
+
+use crate::optimize::{self, is_block, Instruction};
+
+impl Instruction for Op {
+    fn noop() -> Self {
+        Op::Noop
+    }
+
+    fn is_noop(&self) -> bool {
+        matches!(self, Op::Noop)
+    }
+
+    fn target(&self) -> Option<usize> {
+        match self {
+            Op::Jump(target) | Op::JumpIfFalse(target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    fn target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Op::Jump(target) | Op::JumpIfFalse(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    fn is_jump(&self) -> bool {
+        matches!(self, Op::Jump(_))
+    }
+
+    fn ends_flow(&self) -> bool {
+        matches!(self, Op::Jump(_) | Op::Return | Op::Halt)
+    }
+}
+
+/// Optimizes the code of one function, see `crate::optimize`.
+pub fn optimize(code: &mut Vec<Op>) {
+    optimize::optimize(code, peephole);
+}
+
+fn peephole(code: &mut [Op], targets: &[bool]) -> bool {
+    let mut changed = false;
+
+    for index in 0..code.len() {
+        let window = &mut code[index..];
+
+        match window {
+            // Constant folding.
+            [a, b, operator, ..] if is_block(targets, index, 3) => {
+                if let Some(folded) = fold(a, b, operator) {
+                    window[0] = folded;
+                    window[1] = Op::Noop;
+                    window[2] = Op::Noop;
+                    changed = true;
+                    continue;
+                }
+            },
+            _ => {},
+        }
+
+        match window {
+            // A value stored and then loaded straight back is kept on the
+            // stack instead.
+            [Op::Usize(a), Op::Store, Op::Usize(b), Op::Load, ..]
+            if a == b && is_block(targets, index, 4) => {
+                let slot = *a;
+                window[0] = Op::Copy;
+                window[1] = Op::Usize(slot);
+                window[2] = Op::Store;
+                window[3] = Op::Noop;
+                changed = true;
+            },
+
+            // A constant condition either always or never jumps.
+            [Op::Bool(condition), Op::JumpIfFalse(target), ..]
+            if is_block(targets, index, 2) => {
+                window[1] = match condition {
+                    true => Op::Noop,
+                    false => Op::Jump(*target),
+                };
+                window[0] = Op::Noop;
+                changed = true;
+            },
+
+            // A constant which is dropped straight away.
+            [constant, Op::Pop, ..]
+            if is_constant(constant) && is_block(targets, index, 2) => {
+                window[0] = Op::Noop;
+                window[1] = Op::Noop;
+                changed = true;
+            },
+
+            _ => {},
+        }
+    }
+
+    changed
+}
+
+fn is_constant(op: &Op) -> bool {
+    matches!(op,
+        Op::None
+        | Op::F32(_)
+        | Op::F64(_)
+        | Op::I32(_)
+        | Op::I64(_)
+        | Op::U32(_)
+        | Op::U64(_)
+        | Op::Usize(_)
+        | Op::Bool(_)
+    )
+}
+
+/// The constant the operator gives for two constants, matching what the
+/// Vm computes. Operands the Vm would reject aren't folded.
+fn fold(a: &Op, b: &Op, operator: &Op) -> Option<Op> {
+    let op = match (a, b, operator) {
+        (Op::F32(a), Op::F32(b), Op::Add) => Op::F32(a + b),
+        (Op::F64(a), Op::F64(b), Op::Add) => Op::F64(a + b),
+        (Op::I32(a), Op::I32(b), Op::Add) => Op::I32(a.wrapping_add(*b)),
+        (Op::I64(a), Op::I64(b), Op::Add) => Op::I64(a.wrapping_add(*b)),
+        (Op::U32(a), Op::U32(b), Op::Add) => Op::U32(a.wrapping_add(*b)),
+        (Op::U64(a), Op::U64(b), Op::Add) => Op::U64(a.wrapping_add(*b)),
+
+        (Op::F32(a), Op::F32(b), Op::Sub) => Op::F32(a - b),
+        (Op::F64(a), Op::F64(b), Op::Sub) => Op::F64(a - b),
+        (Op::I32(a), Op::I32(b), Op::Sub) => Op::I32(a.wrapping_sub(*b)),
+        (Op::I64(a), Op::I64(b), Op::Sub) => Op::I64(a.wrapping_sub(*b)),
+        (Op::U32(a), Op::U32(b), Op::Sub) => Op::U32(a.wrapping_sub(*b)),
+        (Op::U64(a), Op::U64(b), Op::Sub) => Op::U64(a.wrapping_sub(*b)),
+
+        (Op::F32(a), Op::F32(b), Op::Lt) => Op::Bool(a < b),
+        (Op::F64(a), Op::F64(b), Op::Lt) => Op::Bool(a < b),
+        (Op::I32(a), Op::I32(b), Op::Lt) => Op::Bool(a < b),
+        (Op::I64(a), Op::I64(b), Op::Lt) => Op::Bool(a < b),
+        (Op::U32(a), Op::U32(b), Op::Lt) => Op::Bool(a < b),
+        (Op::U64(a), Op::U64(b), Op::Lt) => Op::Bool(a < b),
+        _ => return None,
+    };
+    Some(op)
+}
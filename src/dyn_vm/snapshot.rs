@@ -0,0 +1,306 @@
+use super::*;
+
+use crate::snapshot::{module_hash, write_type, Reader, SnapshotError, Writer, DYN_VM};
+
+/// Structs deeper than this are rejected when restoring so corrupt data
+/// can't overflow the host stack.
+const MAX_STRUCT_DEPTH: usize = 256;
+
+impl Vm {
+    /// Serializes the complete execution state of the Vm. The module is
+    /// not included, only its hash, so the snapshot must be restored with
+    /// `Vm::restore` against the same module. Tracers are not saved.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = Writer::new(DYN_VM, module_hash(|w| write_module(w, &self.module)));
+
+        w.usize(self.instruction_pointer);
+        w.u8(match self.state {
+            State::Ready => 0,
+            State::Suspended => 1,
+            State::Halted => 2,
+            State::Failed => 3,
+        });
+        w.option_u64(self.fuel);
+
+        w.usize(self.frame_ptr);
+        write_values(&mut w, &self.slots);
+
+        write_values(&mut w, &self.stack);
+
+        w.usize(self.call_stack.len());
+        for entry in &self.call_stack {
+            w.usize(entry.instruction);
+            w.usize(entry.frame_ptr);
+            w.usize(entry.stack_base);
+        }
+
+        w.into_bytes()
+    }
+
+    /// Recreates a Vm from a snapshot taken while running `module`. The
+    /// remaining fuel is restored from the snapshot while the other limits
+    /// are taken from `limits`.
+    pub fn restore(module: impl Into<Arc<Module>>, limits: Limits, bytes: &[u8])
+    -> Result<Self, SnapshotError> {
+        let module = module.into();
+        let mut r = Reader::new(bytes, DYN_VM, module_hash(|w| write_module(w, &module)))?;
+
+        let instruction_pointer = r.usize()?;
+        let state = match r.u8()? {
+            0 => State::Ready,
+            1 => State::Suspended,
+            2 => State::Halted,
+            3 => State::Failed,
+            tag => return Err(SnapshotError::InvalidTag(tag)),
+        };
+        let fuel = r.option_u64()?;
+
+        let frame_ptr = r.usize()?;
+        let slots = read_values(&mut r, &module)?;
+        let stack = read_values(&mut r, &module)?;
+
+        let count = r.count()?;
+        let mut call_stack = Vec::with_capacity(count);
+        for _ in 0..count {
+            let instruction = r.usize()?;
+            let frame_ptr = r.usize()?;
+            let stack_base = r.usize()?;
+            call_stack.push(CallStackEntry { instruction, frame_ptr, stack_base });
+        }
+
+        r.finish()?;
+
+        // Execution continues from the pointers, so they must be inside
+        // the code, the slots and the stack. The instruction pointer is
+        // past the op that last ran.
+        let frames = call_stack.iter()
+            .map(|entry| (entry.instruction, entry.frame_ptr))
+            .chain([(instruction_pointer, frame_ptr)]);
+        for (instruction, frame_ptr) in frames {
+            if instruction > module.code.len() {
+                return Err(SnapshotError::InvalidInstruction(instruction));
+            }
+            if frame_ptr > slots.len() {
+                return Err(SnapshotError::InvalidFrame(frame_ptr));
+            }
+        }
+        if call_stack.iter().any(|entry| entry.stack_base > stack.len()) {
+            return Err(SnapshotError::InvalidStack);
+        }
+
+        let mut vm = Vm::with_limits(module, limits);
+        vm.instruction_pointer = instruction_pointer;
+        vm.state = state;
+        vm.fuel = fuel;
+        vm.frame_ptr = frame_ptr;
+        vm.slots = slots;
+        vm.stack = stack;
+        vm.call_stack = call_stack;
+        Ok(vm)
+    }
+}
+
+/// Writes the parts of the module a snapshot depends on for its hash.
+fn write_module(w: &mut Writer, module: &Module) {
+    w.usize(module.start);
+    w.usize(module.code.len());
+    for op in &module.code {
+        write_op(w, op);
+    }
+
+    w.usize(module.functions.len());
+    for function in &module.functions {
+        w.str(&function.name);
+        w.usize(function.offset);
+        w.usize(function.args);
+        w.usize(function.vars.len());
+        for var in &function.vars {
+            w.str(&var.name);
+            w.usize(var.index);
+            write_type(w, &var.var_type);
+        }
+    }
+}
+
+fn write_op(w: &mut Writer, op: &Op) {
+    match op {
+        Op::Noop => w.u8(0),
+        Op::Halt => w.u8(1),
+        Op::Pop => w.u8(2),
+        Op::Swap => w.u8(3),
+        Op::Copy => w.u8(4),
+        Op::CopyFrom => w.u8(5),
+        Op::Load => w.u8(6),
+        Op::Store => w.u8(7),
+        Op::GetFn => w.u8(8),
+        Op::Call => w.u8(9),
+        Op::CallDirect(index) => {
+            w.u8(10);
+            w.usize(*index);
+        },
+        Op::Return => w.u8(11),
+        Op::Jump(ptr) => {
+            w.u8(12);
+            w.usize(*ptr);
+        },
+        Op::JumpIfFalse(ptr) => {
+            w.u8(13);
+            w.usize(*ptr);
+        },
+        Op::None => w.u8(14),
+        Op::Symbol(name) => {
+            w.u8(15);
+            w.str(name);
+        },
+        Op::F32(v) => {
+            w.u8(16);
+            w.f32(*v);
+        },
+        Op::F64(v) => {
+            w.u8(17);
+            w.f64(*v);
+        },
+        Op::I32(v) => {
+            w.u8(18);
+            w.i32(*v);
+        },
+        Op::I64(v) => {
+            w.u8(19);
+            w.i64(*v);
+        },
+        Op::U32(v) => {
+            w.u8(20);
+            w.u32(*v);
+        },
+        Op::U64(v) => {
+            w.u8(21);
+            w.u64(*v);
+        },
+        Op::Usize(v) => {
+            w.u8(22);
+            w.usize(*v);
+        },
+        Op::Bool(v) => {
+            w.u8(23);
+            w.bool(*v);
+        },
+        Op::Struct => w.u8(24),
+        Op::StructRead => w.u8(25),
+        Op::Add => w.u8(26),
+        Op::Sub => w.u8(27),
+        Op::Lt => w.u8(28),
+        Op::Yield => w.u8(29),
+    }
+}
+
+fn write_values(w: &mut Writer, values: &[Value]) {
+    w.usize(values.len());
+    for value in values {
+        write_value(w, value);
+    }
+}
+
+fn read_values(r: &mut Reader, module: &Module) -> Result<Vec<Value>, SnapshotError> {
+    let count = r.count()?;
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(read_value(r, module, 0)?);
+    }
+    Ok(values)
+}
+
+fn write_value(w: &mut Writer, value: &Value) {
+    match value {
+        Value::None => w.u8(0),
+        Value::Usize(v) => {
+            w.u8(1);
+            w.usize(*v);
+        },
+        Value::F32(v) => {
+            w.u8(2);
+            w.f32(*v);
+        },
+        Value::F64(v) => {
+            w.u8(3);
+            w.f64(*v);
+        },
+        Value::U32(v) => {
+            w.u8(4);
+            w.u32(*v);
+        },
+        Value::U64(v) => {
+            w.u8(5);
+            w.u64(*v);
+        },
+        Value::I32(v) => {
+            w.u8(6);
+            w.i32(*v);
+        },
+        Value::I64(v) => {
+            w.u8(7);
+            w.i64(*v);
+        },
+        Value::Symbol(v) => {
+            w.u8(8);
+            w.str(v);
+        },
+        Value::StringRef { index } => {
+            w.u8(9);
+            w.usize(*index);
+        },
+        Value::Bool(v) => {
+            w.u8(10);
+            w.bool(*v);
+        },
+        Value::Struct(fields) => {
+            w.u8(11);
+            w.usize(fields.len());
+            for field in fields {
+                write_value(w, field);
+            }
+        },
+        Value::Function(function) => {
+            // Functions are restored from the module by name.
+            w.u8(12);
+            w.str(&function.name);
+        },
+    }
+}
+
+fn read_value(r: &mut Reader, module: &Module, depth: usize)
+-> Result<Value, SnapshotError> {
+    let value = match r.u8()? {
+        0 => Value::None,
+        1 => Value::Usize(r.usize()?),
+        2 => Value::F32(r.f32()?),
+        3 => Value::F64(r.f64()?),
+        4 => Value::U32(r.u32()?),
+        5 => Value::U64(r.u64()?),
+        6 => Value::I32(r.i32()?),
+        7 => Value::I64(r.i64()?),
+        8 => Value::Symbol(r.str()?.to_string()),
+        9 => Value::StringRef { index: r.usize()? },
+        10 => Value::Bool(r.bool()?),
+        11 => {
+            if depth >= MAX_STRUCT_DEPTH {
+                return Err(SnapshotError::InvalidTag(11));
+            }
+
+            let count = r.count()?;
+            let mut fields = Vec::with_capacity(count);
+            for _ in 0..count {
+                fields.push(read_value(r, module, depth + 1)?);
+            }
+            Value::Struct(fields)
+        },
+        12 => {
+            let name = r.str()?;
+            let Some(function) = module.function(name) else {
+                return Err(SnapshotError::UnknownFunction(name.to_string()));
+            };
+            Value::Function(function.clone())
+        },
+        tag => return Err(SnapshotError::InvalidTag(tag)),
+    };
+    Ok(value)
+}
@@ -12,17 +12,13 @@ fn struct_pop () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
     vm.run()?;
     dbg!(&vm.stack);
-    assert!(vm.stack.len() == 0);
+    assert!(vm.stack.is_empty());
 
     Ok(())
 }
@@ -39,11 +35,7 @@ fn struct_copy () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
@@ -61,11 +53,7 @@ fn value_copy () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
@@ -91,11 +79,7 @@ fn struct_swap1 () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
@@ -126,11 +110,7 @@ fn struct_swap2 () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
@@ -167,11 +147,7 @@ fn struct_swap3 () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
@@ -193,11 +169,7 @@ fn struct_swap3 () -> Result<(), VmError> {
 fn main_test () -> Result<(), VmError> {
     let code = vec![Op::U32(5), Op::U32(7), Op::Add, Op::Halt];
 
-    let module = Module {
-        start: 0,
-        code,
-        functions: BTreeMap::new(),
-    };
+    let module = Module::new(0, code, Vec::new());
 
     let mut vm = Vm::new(module);
 
@@ -229,8 +201,6 @@ fn main_call () -> Result<(), VmError> {
         Op::Halt,
         ];
 
-    let mut functions = BTreeMap::new();
-  
     let function = FunctionValue {
         name: "add".to_string(),
         offset: 0,
@@ -238,13 +208,9 @@ fn main_call () -> Result<(), VmError> {
         vars: Vec::new(),
     };
 
-    functions.insert("add".to_string(), function);
+    let functions = vec![function];
 
-    let module = Module {
-        start: 2,
-        code,
-        functions,
-    };
+    let module = Module::new(2, code, functions);
 
     let mut vm = Vm::new(module);
 
@@ -259,3 +225,255 @@ fn main_call () -> Result<(), VmError> {
     assert!(value == Some(12));
     Ok(())
 }
+
+
+#[test]
+fn return_drops_leftovers () -> Result<(), VmError> {
+    let run = |body: Vec<Op>| -> Result<Vec<Value>, VmError> {
+        // fn 0 takes one argument, main calls it with a value below.
+        let mut code = body;
+        code.push(Op::Return);
+        let start = code.len();
+        code.extend([
+            Op::I64(1),
+            Op::I64(2),
+            Op::Symbol("f".to_string()),
+            Op::GetFn,
+            Op::Call,
+            Op::Halt,
+        ]);
+
+        let function = FunctionValue {
+            name: "f".to_string(),
+            offset: 0,
+            args: 1,
+            vars: Vec::new(),
+        };
+        let mut vm = Vm::new(Module::new(start, code, vec![function]));
+        vm.run()?;
+        Ok(vm.stack)
+    };
+
+    // Everything the function leaves above the caller's values is dropped
+    // except the top value, its argument included.
+    let stack = run(vec![Op::U32(7), Op::F64(1.5), Op::I64(3)])?;
+    assert!(matches!(stack[..], [Value::I64(1), Value::I64(3)]));
+
+    // A function which took its argument and left nothing returns
+    // nothing. The compiler pushes None for such functions.
+    let stack = run(vec![Op::Pop])?;
+    assert!(matches!(stack[..], [Value::I64(1)]));
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct RecordingTracer {
+    ops: std::rc::Rc<std::cell::RefCell<Vec<(usize, usize, usize)>>>,
+}
+
+impl crate::trace::Tracer<Op, Value> for RecordingTracer {
+    fn before(&mut self, ip: usize, _op: &Op, stack: &[Value]) {
+        self.ops.borrow_mut().push((ip, stack.len(), usize::MAX));
+    }
+
+    fn after(&mut self, _ip: usize, _op: &Op, stack: &[Value]) {
+        if let Some(last) = self.ops.borrow_mut().last_mut() {
+            last.2 = stack.len();
+        }
+    }
+}
+
+#[test]
+fn trace_hooks () -> Result<(), VmError> {
+    let code = vec![Op::U32(5), Op::U32(7), Op::Add, Op::Halt];
+
+    let module = Module::new(0, code, Vec::new());
+
+    let tracer = RecordingTracer::default();
+    let ops = tracer.ops.clone();
+
+    let mut vm = Vm::new(module);
+    vm.set_tracer(Box::new(tracer));
+    vm.run()?;
+
+    assert!(*ops.borrow() == vec![(0, 0, 1), (1, 1, 2), (2, 2, 1), (3, 1, 1)]);
+
+    // A failing op is traced after too.
+    let module = Module::new(0, vec![Op::U32(5), Op::Pop, Op::Pop], Vec::new());
+    let tracer = RecordingTracer::default();
+    let ops = tracer.ops.clone();
+
+    let mut vm = Vm::new(module);
+    vm.set_tracer(Box::new(tracer));
+    assert!(vm.run().is_err());
+    assert!(*ops.borrow() == vec![(0, 0, 1), (1, 1, 0), (2, 0, 0)]);
+
+    let mut out = Vec::new();
+    {
+        let mut log = crate::trace::LogTracer::new(&mut out);
+        crate::trace::Tracer::<Op, Value>::before(&mut log, 2, &Op::Add, &[]);
+        crate::trace::Tracer::<Op, Value>::after(&mut log, 2, &Op::Add, &[Value::U32(12)]);
+    }
+    assert!(String::from_utf8(out).unwrap() == "     2: Add -> [U32(12)]\n");
+
+    Ok(())
+}
+
+
+#[test]
+fn fuel_resume () -> Result<(), VmError> {
+    let code = vec![Op::U32(5), Op::U32(7), Op::Add, Op::Halt];
+
+    let module = Module::new(0, code, Vec::new());
+
+    let limits = crate::limits::Limits::unlimited().with_fuel(2);
+    let mut vm = Vm::with_limits(module, limits);
+
+    assert!(matches!(vm.run(), Err(VmError::OutOfFuel)));
+    assert!(vm.stack.len() == 2);
+
+    vm.add_fuel(2);
+    vm.run()?;
+    assert!(vm.fuel() == Some(0));
+
+    let value = match vm.stack.last() {
+        Some(Value::U32(v)) => Some(*v),
+        _ => None,
+    };
+
+    assert!(value == Some(12));
+    Ok(())
+}
+
+#[test]
+fn stack_limit () -> Result<(), VmError> {
+    let code = vec![Op::U32(1), Op::Copy, Op::Copy, Op::Halt];
+
+    let module = Module::new(0, code, Vec::new());
+
+    let limits = crate::limits::Limits::unlimited().with_max_stack(2);
+    let mut vm = Vm::with_limits(module, limits);
+
+    // The copy which doesn't fit fails before the stack grows.
+    assert!(matches!(vm.run(), Err(VmError::StackOverflow)));
+    assert!(vm.stack.len() == 2);
+    assert!(vm.instruction_pointer == 2);
+
+    // The Vm doesn't carry on past the op which failed.
+    assert!(matches!(vm.run(), Err(VmError::Failed)));
+    assert!(matches!(vm.step(), Err(VmError::Failed)));
+    assert!(matches!(vm.resume(Value::None), Err(VmError::Failed)));
+    assert!(vm.stack.len() == 2);
+    Ok(())
+}
+
+#[test]
+fn call_depth_limit () -> Result<(), VmError> {
+    let code = vec![
+        Op::Halt,
+        // fn recurse
+        Op::Symbol("recurse".to_string()),
+        Op::GetFn,
+        Op::Call,
+        Op::Return,
+    ];
+
+    let function = FunctionValue {
+        name: "recurse".to_string(),
+        offset: 1,
+        args: 0,
+        vars: vec![],
+    };
+
+    let functions = vec![function];
+
+    let module = Module::new(1, code, functions);
+
+    let limits = crate::limits::Limits::unlimited().with_max_call_depth(8);
+    let mut vm = Vm::with_limits(module, limits);
+
+    assert!(matches!(vm.run(), Err(VmError::CallDepthExceeded)));
+    assert!(vm.call_stack.len() == 9);
+    Ok(())
+}
+
+#[test]
+fn frame_slots () -> Result<(), VmError> {
+    let var = |index| VarValue {
+        name: format!("v{}", index),
+        index,
+        var_type: Type::Unknown,
+    };
+
+    let code = vec![
+        Op::Halt,
+        // fn inner: stores to its own slot 0 and returns it
+        Op::U32(7),
+        Op::Usize(0),
+        Op::Store,
+        Op::Usize(0),
+        Op::Load,
+        Op::Return,
+        // fn main
+        Op::U32(5),
+        Op::Usize(1),
+        Op::Store,
+        Op::Symbol("inner".to_string()),
+        Op::GetFn,
+        Op::Call,
+        Op::Usize(1),
+        Op::Load,
+        Op::Add,
+        // main only has two slots
+        Op::Usize(2),
+        Op::Load,
+        Op::Halt,
+    ];
+
+    let functions = vec![FunctionValue {
+        name: "inner".to_string(),
+        offset: 1,
+        args: 0,
+        vars: vec![var(0)],
+    }, FunctionValue {
+        name: "main".to_string(),
+        offset: 7,
+        args: 0,
+        vars: vec![var(0), var(1)],
+    }];
+
+    let module = Module::new(7, code, functions);
+
+    let mut vm = Vm::new(module);
+
+    assert!(matches!(vm.run(), Err(VmError::UnknownVar(2))));
+    assert!(matches!(vm.stack[..], [Value::U32(12)]));
+    assert!(vm.slots.len() == 2);
+    Ok(())
+}
+
+#[test]
+fn countdown () -> Result<(), VmError> {
+    let code = vec![
+        Op::I64(5),
+        // 1: loop
+        Op::Copy,
+        Op::I64(1),
+        Op::Lt,
+        Op::JumpIfFalse(6),
+        Op::Jump(9),
+        Op::I64(1),
+        Op::Sub,
+        Op::Jump(1),
+        // 9: end
+        Op::Halt,
+    ];
+
+    let module = Module::new(0, code, Vec::new());
+
+    let mut vm = Vm::new(module);
+
+    vm.run()?;
+    assert!(matches!(vm.stack[..], [Value::I64(0)]));
+    Ok(())
+}
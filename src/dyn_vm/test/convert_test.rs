@@ -0,0 +1,108 @@
+use super::*;
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: i64,
+    y: f32,
+    label: Option<String>,
+}
+
+crate::value_struct!(Point { x, y, label });
+
+#[test]
+fn primitives () -> Result<(), VmError> {
+    assert!(matches!(5i64.to_value(), Value::I64(5)));
+    assert!(i64::from_value(Value::I64(5))? == 5);
+    assert!(f64::from_value(Value::F64(1.5))? == 1.5);
+    assert!(bool::from_value(Value::Bool(true))?);
+    assert!(matches!(i64::from_value(Value::I32(5)), Err(VmError::TypeCheck)));
+
+    assert!(String::from_value("name".to_value())? == "name");
+    assert!(matches!(().to_value(), Value::None));
+    Ok(())
+}
+
+#[test]
+fn options () -> Result<(), VmError> {
+    assert!(matches!(None::<i64>.to_value(), Value::None));
+    assert!(matches!(Some(3u32).to_value(), Value::U32(3)));
+    assert!(Option::<u32>::from_value(Value::None)?.is_none());
+    assert!(Option::<u32>::from_value(Value::U32(3))? == Some(3));
+    Ok(())
+}
+
+#[test]
+fn tuples () -> Result<(), VmError> {
+    let value = (1i64, (true, 2.5f64)).to_value();
+    let Value::Struct(fields) = &value else {
+        panic!("expected a struct");
+    };
+    assert!(matches!(fields[..], [Value::I64(1), Value::Struct(_)]));
+
+    let (a, (b, c)) = <(i64, (bool, f64))>::from_value(value)?;
+    assert!(a == 1 && b && c == 2.5);
+
+    // The number of fields must match.
+    let value = (1i64, 2i64).to_value();
+    assert!(matches!(<(i64,)>::from_value(value), Err(VmError::TypeCheck)));
+    Ok(())
+}
+
+#[test]
+fn structs () -> Result<(), VmError> {
+    let point = Point { x: 1, y: 2.5, label: Some("a".to_string()) };
+    let value = point.to_value();
+    assert!(matches!(&value, Value::Struct(fields) if fields.len() == 3));
+    assert!(Point::from_value(value)? == Point { x: 1, y: 2.5, label: Some("a".to_string()) });
+
+    let value = (1i64, 2.5f32).to_value();
+    assert!(matches!(Point::from_value(value), Err(VmError::TypeCheck)));
+    Ok(())
+}
+
+#[test]
+fn call () -> Result<(), Box<dyn std::error::Error>> {
+    let source = "
+        fn add(a, b) { return a + b; }
+        fn pair(a) { return a; }
+        fn main() { add(1, 2); }
+    ";
+    let mut vm = Vm::new(compile::parse_colang(source)?);
+
+    // Calls before main runs leave it ready to run.
+    assert!(vm.call::<(i64, i64), i64>("add", (5, 7))? == 12);
+    assert!(vm.call::<(f64, f64), f64>("add", (0.5, 0.25))? == 0.75);
+    assert!(vm.call::<((u32, bool),), (u32, bool)>("pair", ((4, true),))? == (4, true));
+
+    vm.run()?;
+    assert!(matches!(vm.stack()[..], [Value::I64(3)]));
+
+    // And after it halts.
+    assert!(vm.call::<(i64, i64), i64>("add", (1, 1))? == 2);
+    assert!(matches!(vm.stack()[..], [Value::I64(3)]));
+
+    assert!(matches!(vm.call::<(), i64>("missing", ()), Err(VmError::UnknownFunction(_))));
+    assert!(matches!(vm.call::<(i64,), i64>("add", (1,)), Err(VmError::WrongArgCount(_))));
+    assert!(matches!(vm.call::<(i64, i64), f64>("add", (1, 1)), Err(VmError::TypeCheck)));
+    Ok(())
+}
+
+#[test]
+fn call_yield () -> Result<(), Box<dyn std::error::Error>> {
+    let source = "fn ask(a) { let b = yield a; return b; } fn main() {}";
+    let mut vm = Vm::new(compile::parse_colang(source)?);
+
+    assert!(matches!(vm.call::<(i64,), i64>("ask", (1,)), Err(VmError::Suspended)));
+    assert!(matches!(vm.call::<(i64,), i64>("ask", (1,)), Err(VmError::Suspended)));
+    assert!(matches!(vm.resume(Value::I64(2))?, Status::Halted));
+    Ok(())
+}
+
+#[test]
+fn declared_args () -> Result<(), Box<dyn std::error::Error>> {
+    // The dyn Vm checks values as it runs, so declared types are ignored.
+    let source = "fn add(a: i64, b: i64) { return a + b; } fn main() {}";
+    let mut vm = Vm::new(compile::parse_colang(source)?);
+    assert!(vm.call::<(f64, f64), f64>("add", (0.5, 0.25))? == 0.75);
+    Ok(())
+}
@@ -1,27 +1,14 @@
 use super::*;
 
+use crate::limits::Limits;
+use crate::snapshot::SnapshotError;
 
-#[derive(Debug)]
-pub enum TestError {
-    LangError(LangError),
-    VmError(VmError),
-}
-
-impl From<VmError> for TestError {
-    fn from(value: VmError) -> Self {
-        TestError::VmError(value)
-    }
-}
 
-impl From<LangError> for TestError {
-    fn from(value: LangError) -> Self {
-        TestError::LangError(value)
-    }
-}
+type TestError = Box<dyn std::error::Error>;
 
 #[test]
 fn call_fn () -> Result<(), TestError> {
-    let file = "src/lang/example.co";
+    let file = "src/lang/corpus/example.co";
 
     let module = parse_colang_file(file)?;
     dbg!(&module);
@@ -35,7 +22,7 @@ fn call_fn () -> Result<(), TestError> {
 
 #[test]
 fn simple () -> Result<(), TestError> {
-    let file = "src/lang/simple.co";
+    let file = "src/lang/corpus/simple.co";
  
     let module = parse_colang_file(file)?;
     dbg!(&module);
@@ -58,7 +45,7 @@ fn simple () -> Result<(), TestError> {
 
 #[test]
 fn simple_vars () -> Result<(), TestError> {
-    let file = "src/lang/simple_vars.co";
+    let file = "src/lang/corpus/simple_vars.co";
  
     let module = parse_colang_file(file)?;
     dbg!(&module);
@@ -78,3 +65,231 @@ fn simple_vars () -> Result<(), TestError> {
     
     Ok(())
 }
+
+
+#[test]
+fn generator () -> Result<(), TestError> {
+    let file = "src/lang/corpus/generator.co";
+
+    let module = Arc::new(parse_colang_file(file)?);
+    let mut first = Vm::new(module.clone());
+    let mut second = Vm::new(module);
+
+    let Status::Yielded(Value::I64(1)) = first.run()? else {
+        panic!("expected the first yield");
+    };
+
+    // The second coroutine runs independently of the first.
+    let Status::Yielded(Value::I64(1)) = second.run()? else {
+        panic!("expected the first yield");
+    };
+
+    let Status::Yielded(Value::I64(11)) = first.resume(Value::I64(10))? else {
+        panic!("expected the second yield");
+    };
+
+    assert!(matches!(first.run(), Err(VmError::Suspended)));
+
+    let Status::Halted = first.resume(Value::I64(5))? else {
+        panic!("expected halt");
+    };
+
+    let value = match first.stack_get(0) {
+        Some(Value::I64(v)) => Some(*v),
+        _ => None,
+    };
+    assert!(value == Some(15));
+    assert!(matches!(first.resume(Value::None), Err(VmError::NotSuspended)));
+
+    let Status::Yielded(Value::I64(3)) = second.resume(Value::I64(2))? else {
+        panic!("expected the second yield");
+    };
+
+    Ok(())
+}
+
+
+#[test]
+fn snapshot_restore () -> Result<(), TestError> {
+    let module = Arc::new(parse_colang_file("src/lang/corpus/generator.co")?);
+    let mut vm = Vm::new(module.clone());
+
+    let Status::Yielded(Value::I64(1)) = vm.run()? else {
+        panic!("expected the first yield");
+    };
+    let Status::Yielded(Value::I64(11)) = vm.resume(Value::I64(10))? else {
+        panic!("expected the second yield");
+    };
+
+    let bytes = vm.snapshot();
+    let mut restored = Vm::restore(module, Limits::unlimited(), &bytes).unwrap();
+
+    let Status::Halted = restored.resume(Value::I64(5))? else {
+        panic!("expected halt");
+    };
+    assert!(matches!(restored.stack_get(0), Some(Value::I64(15))));
+
+    let other = parse_colang_file("src/lang/corpus/simple.co")?;
+    assert!(matches!(
+        Vm::restore(other, Limits::unlimited(), &bytes),
+        Err(SnapshotError::ModuleMismatch)
+    ));
+
+    let module = Arc::new(parse_colang_file("src/lang/corpus/generator.co")?);
+    assert!(matches!(
+        Vm::restore(module.clone(), Limits::unlimited(), &bytes[..bytes.len() - 1]),
+        Err(SnapshotError::Truncated)
+    ));
+
+    // The instruction pointer follows the header.
+    let mut corrupt = bytes.clone();
+    corrupt[14..22].copy_from_slice(&1000u64.to_le_bytes());
+    assert!(matches!(
+        Vm::restore(module, Limits::unlimited(), &corrupt),
+        Err(SnapshotError::InvalidInstruction(1000))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn direct_calls () -> Result<(), TestError> {
+    let module = parse_colang("fn add(a, b) { a + b; } fn main() { add(5u32, 7u32); }")?;
+
+    // Calls are linked to the function's index, no names are looked up.
+    let index = module.names["add"];
+    assert!(module.code.iter().any(|op| matches!(op, Op::CallDirect(i) if *i == index)));
+    assert!(!module.code.iter().any(|op| matches!(op, Op::Symbol(_) | Op::GetFn)));
+
+    let mut vm = Vm::new(module);
+    vm.run()?;
+    assert!(matches!(vm.stack[..], [Value::U32(12)]));
+
+    let result = parse_colang("fn main() { missing(1); }");
+    assert!(matches!(result, Err(LangError::UnknownFunction(name)) if name == "missing"));
+    Ok(())
+}
+
+#[test]
+fn argument_order () -> Result<(), TestError> {
+    // The caller pushes its arguments in order, so the function stores
+    // them last first.
+    let source = "
+        fn sub(a, b) { return a - b; }
+        fn middle(a, b, c) { return b; }
+        fn main() { let x = sub(10, 3); let y = middle(100, 20, 1); x + y; }
+    ";
+    let mut vm = Vm::new(parse_colang(source)?);
+    vm.run()?;
+    assert!(matches!(vm.stack().last(), Some(Value::I64(27))));
+    Ok(())
+}
+
+#[test]
+fn comments () -> Result<(), TestError> {
+    let source = "// A comment before the code.
+        fn main() { // after a brace
+            let a = 5; // after a statement
+            // on a line of its own, holding code: a = 1;
+            a + 2;
+        }
+        // at the end without a line break";
+    let mut vm = Vm::new(parse_colang(source)?);
+    vm.run()?;
+    assert!(matches!(vm.stack().last(), Some(Value::I64(7))));
+    Ok(())
+}
+
+#[test]
+fn unsupported_operators () {
+    // Operators without an op are refused rather than compiled to nothing.
+    for operator in ["*", "/", "^"] {
+        let result = parse_colang(&format!("fn main() {{ 6 {} 3; }}", operator));
+        assert!(matches!(&result, Err(LangError::UnsupportedOperator(op)) if op == operator));
+        assert!(result.unwrap_err().to_string() == format!("unsupported operator `{}`", operator));
+    }
+}
+
+#[test]
+fn source_map () -> Result<(), TestError> {
+    let mut sources = crate::source::SourceMap::new();
+    sources.add("lib.co", "fn add(a, b) { return a + b; }");
+    sources.add("main.co", "fn main() { add(7, 5); }");
+
+    let mut vm = Vm::new(compile::compile_sources(&sources)?);
+    vm.run()?;
+    assert!(matches!(vm.stack().last(), Some(Value::I64(12))));
+
+    // Parse errors name the source they were found in.
+    sources.add("lib.co", "fn add(a, b) {");
+    let error = compile::compile_sources(&sources).unwrap_err();
+    assert!(matches!(error, LangError::ParserError(_)));
+    assert!(error.to_string().contains("lib.co:1:"));
+
+    let error = compile::compile_str("generated", "fn main() { 1 +; }").unwrap_err();
+    assert!(error.to_string().contains("generated:1:"));
+
+    // Semantic errors are located too.
+    let error = compile::compile_str("generated", "fn main() {\n  x;\n}").unwrap_err();
+    assert!(matches!(&error, LangError::Located(at, inner)
+        if at.source == "generated" && matches!(**inner, LangError::UnknownVar(_))));
+    assert!(error.to_string() == "generated:2:3: unknown variable `x`", "{}", error);
+
+    // A function can only be defined once across all the sources.
+    let mut sources = crate::source::SourceMap::new();
+    sources.add("a.co", "fn f() { 1; }");
+    sources.add("b.co", "fn main() { f(); }\nfn f() { 2; }");
+    let error = compile::compile_sources(&sources).unwrap_err();
+    assert!(error.to_string() == "b.co:2:1: function `f` is already defined at a.co:1:1", "{}", error);
+
+    let error = parse_colang("fn f() { 1; } fn f() { 2; } fn main() { f(); }").unwrap_err();
+    assert!(matches!(&error, LangError::FunctionAlreadyDefined(name, None) if name == "f"));
+    Ok(())
+}
+
+#[test]
+fn missing_file () {
+    let error = parse_colang_file("src/lang/corpus/missing.co").unwrap_err();
+    assert!(matches!(&error, LangError::Io(io) if io.kind() == std::io::ErrorKind::NotFound));
+    assert!(std::error::Error::source(&error).is_some());
+}
+
+#[test]
+fn control_flow () -> Result<(), TestError> {
+    let run = |body: &str| -> Result<Vm, TestError> {
+        let mut vm = Vm::new(parse_colang(&format!("fn main() {{ {} }}", body))?);
+        vm.run()?;
+        Ok(vm)
+    };
+
+    assert!(matches!(run("10 - 3;")?.stack().last(), Some(Value::I64(7))));
+    assert!(matches!(run("2.5 - 4.0;")?.stack().last(), Some(Value::F64(v)) if *v == -1.5));
+    assert!(matches!(run("3u64 < 4u64;")?.stack().last(), Some(Value::Bool(true))));
+    assert!(matches!(run("2.0 < 1.5;")?.stack().last(), Some(Value::Bool(false))));
+
+    assert!(matches!(run("let a = 1; if 2 < 1 { a = 2; } a;")?.stack().last(), Some(Value::I64(1))));
+    assert!(matches!(run("let a = 1; if 1 < 2 { a = 2; } else { a = 3; } a;")?.stack().last(), Some(Value::I64(2))));
+    assert!(matches!(run("let a = 1; if 2 < 1 { a = 2; } else { a = 3; } a;")?.stack().last(), Some(Value::I64(3))));
+
+    // A var declared in a loop's body is declared again each time round.
+    let vm = run("
+        let i = 0;
+        let total = 0;
+        while i < 5 {
+            let twice = i + i;
+            total = total + twice;
+            i = i + 1;
+        }
+        total;
+    ")?;
+    assert!(matches!(vm.stack().last(), Some(Value::I64(20))));
+
+    let compile = |body: &str| parse_colang(&format!("fn main() {{ {} }}", body));
+    assert!(matches!(compile("b = 1;"), Err(LangError::UnknownVar(name)) if name == "b"));
+    assert!(matches!(compile("if 1 < 2 { let b = 1; } b;"), Err(LangError::UnknownVar(_))));
+
+    // Conditions are checked when they run.
+    let mut vm = Vm::new(compile("if 1 { 2; }")?);
+    assert!(vm.run().is_err());
+    Ok(())
+}
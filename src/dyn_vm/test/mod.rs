@@ -1,4 +1,6 @@
 use super::*;
 
 mod bytecode_test;
-mod lang_test;
\ No newline at end of file
+mod convert_test;
+mod lang_test;
+mod optimize_test;
\ No newline at end of file
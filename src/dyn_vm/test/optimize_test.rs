@@ -0,0 +1,83 @@
+use super::*;
+
+fn optimized(mut code: Vec<Op>) -> String {
+    optimize::optimize(&mut code);
+    format!("{:?}", code)
+}
+
+#[test]
+fn fold_constants () {
+    let code = vec![
+        Op::I64(10),
+        Op::I64(3),
+        Op::Sub,
+        Op::I64(4),
+        Op::Add,
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), format!("{:?}", [Op::I64(11), Op::Return]));
+
+    // Mixed types are left for the Vm to reject.
+    let code = vec![Op::I64(1), Op::I32(2), Op::Add, Op::Return];
+    assert_eq!(optimized(code), "[I64(1), I32(2), Add, Return]");
+}
+
+#[test]
+fn forward_store () {
+    let code = vec![
+        Op::Usize(0),
+        Op::Usize(0),
+        Op::Store,
+        Op::Usize(0),
+        Op::Load,
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), "[Usize(0), Copy, Usize(0), Store, Return]");
+}
+
+#[test]
+fn remove_dead_code () {
+    let code = vec![
+        Op::I64(1),
+        Op::Return,
+        Op::I64(2),
+        Op::Pop,
+        Op::Halt,
+    ];
+    assert_eq!(optimized(code), "[I64(1), Return]");
+}
+
+#[test]
+fn thread_jumps () {
+    let code = vec![
+        Op::Usize(0),
+        Op::Load,
+        Op::JumpIfFalse(5),
+        Op::I64(1),
+        Op::Jump(7),
+        Op::I64(2),
+        Op::Jump(7),
+        Op::Jump(8),
+        Op::Return,
+    ];
+    assert_eq!(
+        optimized(code),
+        "[Usize(0), Load, JumpIfFalse(5), I64(1), Jump(6), I64(2), Return]"
+    );
+}
+
+#[test]
+fn constant_branch () {
+    // while true { a = a - 1; }
+    let code = vec![
+        Op::Bool(true),
+        Op::JumpIfFalse(7),
+        Op::Usize(0),
+        Op::Load,
+        Op::I64(1),
+        Op::Sub,
+        Op::Jump(0),
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), "[Usize(0), Load, I64(1), Sub, Jump(0)]");
+}
@@ -2,3 +2,14 @@
 #[derive(Parser)]
 #[grammar = "lang/grammar.pest"]
 pub struct LangParser;
+
+/// Parses the text of a number literal dropping any type suffix, returning
+/// None if the number does not fit in `T`.
+pub fn parse_number<T: std::str::FromStr>(text: &str) -> Option<T> {
+    let digits = ["f32", "f64", "u32", "u64", "i32", "i64"]
+        .iter()
+        .find_map(|suffix| text.strip_suffix(suffix))
+        .unwrap_or(text);
+
+    digits.parse().ok()
+}
@@ -1,31 +1,144 @@
+//! Colang compiles a small language to bytecode and runs it on one of
+//! two Vms. `compile` and `Vm` run programs on the typed Vm, the faster
+//! of the two:
+//!
+//! ```
+//! let module = colang::compile("fn main() { 7 + 5; }")?;
+//! let mut vm = colang::Vm::new(module);
+//! vm.run()?;
+//! assert_eq!(vm.result::<i64>()?, 12);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Programs can `yield` values to the host which resumes them with a value
+//! of its own:
+//!
+//! ```
+//! use colang::{Status, Value};
+//!
+//! let source = "fn main() { let a = yield 1; a + 1; }";
+//! let mut vm = colang::Vm::new(colang::compile(source)?);
+//! let Status::Yielded(Value::I64(1)) = vm.run()? else { panic!() };
+//! vm.resume(Value::I64(41))?;
+//! assert_eq!(vm.result::<i64>()?, 42);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Hosts call functions by name with `Vm::call`, which converts Rust
+//! values to and from colang values with `ToValue` and `FromValue`. A
+//! function is compiled for the types it is called with, so one the host
+//! calls declares the type of each argument:
+//!
+//! ```
+//! let source = "fn shift(x: f64, by: f64) { x + by; } fn main() { 1; }";
+//! let mut vm = colang::Vm::new(colang::compile(source)?);
+//! assert_eq!(vm.call::<(f64, f64), f64>("shift", (1.5, 4.0))?, 5.5);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Hosts expose their own data to programs as tables by implementing
+//! `Table` and `Cursor` over `Value` and registering the table with
+//! `Vm::register_table`. `Vm::import` and `Vm::export` move records
+//! between the Vm's tables and CSV or JSON lines.
+//!
+//! The `dynamic` module holds the dynamic Vm, whose values carry their type.
+//! It has its own `Vm::call`, whose conversions also cover strings,
+//! tuples and, with `value_struct!`, structs.
+
 extern crate pest;
 #[macro_use]
 extern crate pest_derive;
 
 mod table;
-use crate::table::*;
+pub use crate::table::{Constraint, Cursor, Table};
 
-mod typed_vm;
+pub(crate) mod typed_vm;
 
-mod dyn_vm;
+pub(crate) mod dyn_vm;
 //mod sym_vm;
 
 mod lang;
+mod optimize;
+
+#[cfg(any(test, feature = "bench"))]
+pub mod bench;
+pub mod limits;
+pub mod snapshot;
+pub mod source;
+pub mod trace;
+
+#[cfg(test)]
+mod test;
+
+pub use typed_vm::{Access, Format, FormatError, Kind, Module, Slot, Status, Storage, TableType, Value, Vm, VmError};
+pub use typed_vm::{Args, FromValue, ToValue};
+pub use typed_vm::compile::LangError;
+pub use source::{Location, SourceMap};
+
+/// The dynamic Vm, whose values carry their type.
+pub mod dynamic {
+    pub use crate::dyn_vm::{Args, FromValue, Module, Status, ToValue, Value, Vm, VmError};
+    pub use crate::dyn_vm::compile::LangError;
+    #[doc(hidden)]
+    pub use crate::dyn_vm::convert::struct_fields;
+
+    /// Compiles colang source to a module for the dynamic Vm.
+    pub fn compile(source: &str) -> Result<Module, LangError> {
+        crate::dyn_vm::compile::parse_colang(source)
+    }
+
+    /// Compiles the file for the dynamic Vm.
+    pub fn compile_file(path: &str) -> Result<Module, LangError> {
+        crate::dyn_vm::compile::parse_colang_file(path)
+    }
+
+    /// Compiles the source for the dynamic Vm, naming it in any error.
+    pub fn compile_str(name: &str, source: &str) -> Result<Module, LangError> {
+        crate::dyn_vm::compile::compile_str(name, source)
+    }
+
+    /// Compiles the sources together for the dynamic Vm.
+    pub fn compile_sources(sources: &crate::SourceMap) -> Result<Module, LangError> {
+        crate::dyn_vm::compile::compile_sources(sources)
+    }
+}
+
+/// Compiles colang source to a module for the typed Vm.
+pub fn compile(source: &str) -> Result<Module, LangError> {
+    typed_vm::compile::parse_colang(source)
+}
+
+/// Compiles the file for the typed Vm.
+pub fn compile_file(path: &str) -> Result<Module, LangError> {
+    typed_vm::compile::parse_colang_file(path)
+}
+
+/// Compiles the source for the typed Vm, naming it in any parse error.
+pub fn compile_str(name: &str, source: &str) -> Result<Module, LangError> {
+    typed_vm::compile::compile_str(name, source)
+}
+
+/// Compiles the sources together for the typed Vm.
+pub fn compile_sources(sources: &SourceMap) -> Result<Module, LangError> {
+    typed_vm::compile::compile_sources(sources)
+}
 
-#[derive(Debug, Clone)]
-struct Var {
-    name: String,
-    var_type: Type,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Var {
+    pub name: String,
+    pub var_type: Type,
 }
 
-#[derive(Debug, Clone)]
+/// The signature of a function value.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Function {
-    name: String,
-    args: Vec<Type>,
-    vars: Vec<Var>,
+    pub name: String,
+    pub args: Vec<Type>,
+    pub vars: Vec<Var>,
 }
 
-#[derive(Debug, Clone)]
+/// The types of colang values.
+#[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     None,
     Unknown,
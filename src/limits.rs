@@ -0,0 +1,49 @@
+/// Resource limits for a Vm running untrusted code. Every limit is
+/// optional and `None` means unbounded, which is the default.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// The number of instructions the Vm may execute before it stops with
+    /// `VmError::OutOfFuel`. Deriving a table also burns a unit for each
+    /// record its rules match against, and a derivation which runs out
+    /// gives back what it burned so it starts over once the Vm has enough.
+    /// More fuel can be added with `Vm::add_fuel` after which `run`
+    /// continues from the instruction that ran out.
+    pub fuel: Option<u64>,
+
+    /// The maximum number of values on the operand stack. An op which
+    /// would push past it fails before it pushes anything.
+    pub max_stack: Option<usize>,
+
+    /// The maximum number of nested function calls.
+    pub max_call_depth: Option<usize>,
+
+    /// The maximum number of rows any one table may hold.
+    pub max_table_rows: Option<usize>,
+}
+
+impl Limits {
+    /// Limits with every resource unbounded.
+    pub fn unlimited() -> Self {
+        Limits::default()
+    }
+
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    pub fn with_max_stack(mut self, max_stack: usize) -> Self {
+        self.max_stack = Some(max_stack);
+        self
+    }
+
+    pub fn with_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.max_call_depth = Some(max_call_depth);
+        self
+    }
+
+    pub fn with_max_table_rows(mut self, max_table_rows: usize) -> Self {
+        self.max_table_rows = Some(max_table_rows);
+        self
+    }
+}
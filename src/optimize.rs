@@ -0,0 +1,162 @@
+//! Optimizes the ops of a single function. The passes here only need to
+//! know about control flow so they work for either Vm through
+//! `Instruction`, while each Vm supplies a peephole pass for rewrites that
+//! depend on what its ops do.
+//!
+//! Jump targets are indexes into the function's code, starting from 0.
+//! Passes replace the ops they remove with a no-op so that indexes stay
+//! valid, and the no-ops are dropped and jumps retargeted once a round of
+//! passes is done.
+
+/// The control flow of a Vm's ops.
+pub trait Instruction {
+    fn noop() -> Self;
+
+    fn is_noop(&self) -> bool;
+
+    /// The target of a jump, None for ops which don't jump.
+    fn target(&self) -> Option<usize>;
+
+    fn target_mut(&mut self) -> Option<&mut usize>;
+
+    /// True for an unconditional jump.
+    fn is_jump(&self) -> bool;
+
+    /// True for ops after which execution never continues with the next
+    /// op, such as returns and unconditional jumps.
+    fn ends_flow(&self) -> bool;
+}
+
+/// Bounds the rounds of passes, each round must shrink the code so this
+/// is only reached by very long chains of rewrites.
+const MAX_ROUNDS: usize = 32;
+
+/// Runs the passes until the code stops changing. `peephole` rewrites
+/// windows of ops in place and returns true if it changed anything. It is
+/// given the ops which are jump targets and must not rewrite a window
+/// which one jumps in to part way.
+pub fn optimize<O, F>(code: &mut Vec<O>, peephole: F)
+where
+    O: Instruction,
+    F: Fn(&mut [O], &[bool]) -> bool,
+{
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = thread_jumps(code);
+        changed |= remove_unreachable(code);
+
+        let targets = jump_targets(code);
+        changed |= peephole(code, &targets);
+
+        compact(code);
+        if !changed {
+            break;
+        }
+    }
+}
+
+/// Marks each index of the code, and the end of the code, with whether a
+/// jump targets it.
+pub fn jump_targets<O: Instruction>(code: &[O]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for op in code {
+        if let Some(target) = op.target() {
+            if target < targets.len() {
+                targets[target] = true;
+            }
+        }
+    }
+    targets
+}
+
+/// True if none of the ops after the first in the window starting at `at`
+/// are jumped to.
+pub fn is_block(targets: &[bool], at: usize, len: usize) -> bool {
+    (at + 1..at + len).all(|index| !targets.get(index).copied().unwrap_or(true))
+}
+
+/// Points jumps whose target is an unconditional jump at that jump's
+/// target, and removes jumps to the next op.
+fn thread_jumps<O: Instruction>(code: &mut [O]) -> bool {
+    let mut changed = false;
+
+    for index in 0..code.len() {
+        let Some(mut target) = code[index].target() else {
+            continue;
+        };
+
+        // A chain longer than the code must be a loop.
+        for _ in 0..code.len() {
+            match code.get(target) {
+                Some(op) if op.is_jump() && op.target() != Some(target) => {
+                    target = op.target().unwrap();
+                },
+                _ => break,
+            }
+        }
+
+        if code[index].target() != Some(target) {
+            *code[index].target_mut().unwrap() = target;
+            changed = true;
+        }
+
+        let skips_nothing = (index + 1..target).all(|i| code[i].is_noop());
+        if code[index].is_jump() && target > index && skips_nothing {
+            code[index] = O::noop();
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+/// Removes ops which can't be reached from the start of the code.
+fn remove_unreachable<O: Instruction>(code: &mut [O]) -> bool {
+    let mut reachable = vec![false; code.len()];
+    let mut pending = vec![0];
+
+    while let Some(index) = pending.pop() {
+        if index >= code.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+
+        let op = &code[index];
+        if let Some(target) = op.target() {
+            pending.push(target);
+        }
+        if !op.ends_flow() {
+            pending.push(index + 1);
+        }
+    }
+
+    let mut changed = false;
+    for (op, reachable) in code.iter_mut().zip(reachable) {
+        if !reachable && !op.is_noop() {
+            *op = O::noop();
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Drops the no-ops, moving jump targets to the op that followed them.
+fn compact<O: Instruction>(code: &mut Vec<O>) {
+    let mut moved = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for op in code.iter() {
+        moved.push(kept);
+        if !op.is_noop() {
+            kept += 1;
+        }
+    }
+    moved.push(kept);
+
+    code.retain(|op| !op.is_noop());
+    for op in code.iter_mut() {
+        if let Some(target) = op.target_mut() {
+            if let Some(to) = moved.get(*target) {
+                *target = *to;
+            }
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use crate::Type;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"COVM";
+const VERSION: u8 = 12;
+
+/// The kind of Vm a snapshot was taken from.
+pub(crate) const DYN_VM: u8 = 0;
+pub(crate) const TYPED_VM: u8 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The data does not start with a snapshot header.
+    InvalidHeader,
+    UnsupportedVersion(u8),
+    /// The snapshot was taken from the other kind of Vm.
+    WrongVm,
+    /// The snapshot was taken from a Vm running a different module.
+    ModuleMismatch,
+    /// An instruction pointer past the end of the module's code.
+    InvalidInstruction(usize),
+    /// A frame pointer past the top of the stack.
+    InvalidFrame(usize),
+    /// The data ended part way through the snapshot.
+    Truncated,
+    /// The data continued after the end of the snapshot.
+    TrailingData,
+    InvalidTag(u8),
+    /// The stack's tags or handle positions don't agree with its slots.
+    InvalidStack,
+    UnknownFunction(String),
+    UnknownType(u32),
+    InvalidString,
+    /// A free table handle which isn't empty.
+    InvalidHandle(usize),
+    /// A row whose length doesn't match the fields of its table type.
+    InvalidRow(u32),
+    /// The file a restored table is stored in couldn't be written.
+    Io(std::io::Error),
+    /// The snapshot holds a table of the type the host provided, which
+    /// can't be restored.
+    HostTable(u32),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::InvalidHeader => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            },
+            SnapshotError::WrongVm => write!(f, "the snapshot is from the other kind of vm"),
+            SnapshotError::ModuleMismatch => {
+                write!(f, "the snapshot is from a vm running a different module")
+            },
+            SnapshotError::InvalidInstruction(ptr) => {
+                write!(f, "instruction {} is outside the module's code", ptr)
+            },
+            SnapshotError::InvalidFrame(ptr) => {
+                write!(f, "frame pointer {} is outside the stack", ptr)
+            },
+            SnapshotError::Truncated => write!(f, "the snapshot is truncated"),
+            SnapshotError::TrailingData => write!(f, "data follows the snapshot"),
+            SnapshotError::InvalidTag(tag) => write!(f, "invalid tag {}", tag),
+            SnapshotError::InvalidStack => write!(f, "invalid stack"),
+            SnapshotError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            SnapshotError::UnknownType(index) => write!(f, "unknown table type {}", index),
+            SnapshotError::InvalidString => write!(f, "invalid string"),
+            SnapshotError::InvalidHandle(handle) => write!(f, "invalid handle {}", handle),
+            SnapshotError::InvalidRow(index) => {
+                write!(f, "a row doesn't match the fields of table type {}", index)
+            },
+            SnapshotError::Io(error) => write!(f, "{}", error),
+            SnapshotError::HostTable(index) => {
+                write!(f, "table {} is provided by the host and can't be restored", index)
+            },
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(error: std::io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+/// Hashes a module so a snapshot is only restored against the module it
+/// was taken from. `encode` writes the module's code, functions and types
+/// and the hash is FNV-1a over the bytes.
+pub(crate) fn module_hash(encode: impl FnOnce(&mut Writer)) -> u64 {
+    let mut w = Writer { bytes: Vec::new() };
+    encode(&mut w);
+    w.bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Writes a type for a module hash.
+pub(crate) fn write_type(w: &mut Writer, value_type: &Type) {
+    match value_type {
+        Type::None => w.u8(0),
+        Type::Unknown => w.u8(1),
+        Type::Usize => w.u8(2),
+        Type::F32 => w.u8(3),
+        Type::F64 => w.u8(4),
+        Type::U32 => w.u8(5),
+        Type::U64 => w.u8(6),
+        Type::I32 => w.u8(7),
+        Type::I64 => w.u8(8),
+        Type::Symbol => w.u8(9),
+        Type::StringRef => w.u8(10),
+        Type::Bool => w.u8(11),
+        Type::Struct(fields) => {
+            w.u8(12);
+            w.usize(fields.len());
+            for field in fields {
+                write_type(w, field);
+            }
+        },
+        Type::Table => w.u8(13),
+        Type::Cursor => w.u8(14),
+        Type::Function(function) => {
+            w.u8(15);
+            w.str(&function.name);
+            w.usize(function.args.len());
+            for arg in &function.args {
+                write_type(w, arg);
+            }
+            w.usize(function.vars.len());
+            for var in &function.vars {
+                w.str(&var.name);
+                write_type(w, &var.var_type);
+            }
+        },
+    }
+}
+
+/// Encodes snapshot fields as little endian bytes after a header naming
+/// the Vm kind and module hash.
+pub(crate) struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new(vm_kind: u8, module_hash: u64) -> Self {
+        let mut writer = Writer { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u8(VERSION);
+        writer.u8(vm_kind);
+        writer.u64(module_hash);
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    pub fn str(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn option_u64(&mut self, value: Option<u64>) {
+        match value {
+            None => self.u8(0),
+            Some(v) => {
+                self.u8(1);
+                self.u64(v);
+            },
+        }
+    }
+}
+
+/// Decodes the fields written by `Writer`.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the header matches the Vm kind and module, leaving the reader
+    /// at the first field.
+    pub fn new(bytes: &'a [u8], vm_kind: u8, module_hash: u64) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::InvalidHeader);
+        }
+
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        if reader.u8()? != vm_kind {
+            return Err(SnapshotError::WrongVm);
+        }
+
+        if reader.u64()? != module_hash {
+            return Err(SnapshotError::ModuleMismatch);
+        }
+
+        Ok(reader)
+    }
+
+    /// Checks that the whole snapshot has been read.
+    pub fn finish(self) -> Result<(), SnapshotError> {
+        if self.position != self.bytes.len() {
+            return Err(SnapshotError::TrailingData);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.position.checked_add(count)
+            .ok_or(SnapshotError::Truncated)?;
+        let Some(bytes) = self.bytes.get(self.position..end) else {
+            return Err(SnapshotError::Truncated);
+        };
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(SnapshotError::InvalidTag(tag)),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, SnapshotError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, SnapshotError> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Truncated)
+    }
+
+    pub fn f32(&mut self) -> Result<f32, SnapshotError> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, SnapshotError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, SnapshotError> {
+        let len = self.usize()?;
+        std::str::from_utf8(self.take(len)?)
+            .map_err(|_| SnapshotError::InvalidString)
+    }
+
+    pub fn option_u64(&mut self) -> Result<Option<u64>, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.u64()?)),
+            tag => Err(SnapshotError::InvalidTag(tag)),
+        }
+    }
+
+    /// Reads a count of items, rejecting counts that could not possibly
+    /// fit in the remaining data so corrupt input can't force a huge
+    /// allocation.
+    pub fn count(&mut self) -> Result<usize, SnapshotError> {
+        let count = self.usize()?;
+        if count > self.bytes.len() - self.position {
+            return Err(SnapshotError::Truncated);
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use pest::{Parser, Span};
+use pest::iterators::Pair;
+
+use crate::lang::{LangParser, Rule};
+
+/// Named colang sources which are compiled together as one program, so
+/// functions in one source can call functions defined in another.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    sources: Vec<(String, String)>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    /// Adds the source under the name, replacing any source already added
+    /// with that name.
+    pub fn add(&mut self, name: impl Into<String>, source: impl Into<String>) {
+        let name = name.into();
+        let source = source.into();
+
+        match self.sources.iter_mut().find(|(existing, _)| *existing == name) {
+            Some(entry) => entry.1 = source,
+            None => self.sources.push((name, source)),
+        }
+    }
+
+    /// Reads the file adding it under its path.
+    pub fn add_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        self.add(path.display().to_string(), source);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.sources.iter()
+            .find(|(existing, _)| existing == name)
+            .map(|(_, source)| source.as_str())
+    }
+
+    /// The names and sources in the order they were first added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.sources.iter().map(|(name, source)| (name.as_str(), source.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Parses every source, returning the functions defined in all of
+    /// them with the name of the source each came from. A parse error
+    /// names the source it was found in.
+    pub(crate) fn parse(&self) -> Result<Vec<(&str, Pair<'_, Rule>)>, pest::error::Error<Rule>> {
+        let mut pairs = Vec::new();
+        for (name, source) in self.iter() {
+            let program = LangParser::parse(Rule::program, source)
+                .map_err(|error| error.with_path(name))?;
+            pairs.extend(program.map(|pair| (name, pair)));
+        }
+        Ok(pairs)
+    }
+}
+
+/// Where in a named source something was found, lines and columns
+/// counting from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub source: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    /// The location of the start of the span in the source.
+    pub(crate) fn of(source: &str, span: Span) -> Self {
+        let (line, column) = span.start_pos().line_col();
+        Location { source: source.to_string(), line, column }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.source, self.line, self.column)
+    }
+}
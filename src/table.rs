@@ -1,13 +1,33 @@
 
-use std::collections::BTreeMap;
+use std::ops::Bound;
 
-use crate::dyn_vm::VmError;
-
-use super::*;
+/// A constraint a query places on one field of the records it matches.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint<T> {
+    /// Matches any value.
+    Any,
+    Eq(T),
+    /// Matches values between the bounds.
+    Range(Bound<T>, Bound<T>),
+    /// Matches values equal to one in the set.
+    In(Vec<T>),
+    /// Matches strings whose text starts with the prefix.
+    Prefix(String),
+    /// Matches strings whose text is between the bounds.
+    TextRange(Bound<String>, Bound<String>),
+}
 
 pub trait Table<T,E> {
     type Cursor: Cursor<T,E,Table=Self>;
-    fn find(self, query: &mut Vec<T>) -> Self::Cursor;
+    /// Opens a cursor at the first record matching the query, failing if
+    /// the query does not fit the table.
+    fn find(self, query: &mut Vec<T>) -> Result<Self::Cursor, E>;
+
+    /// Opens a cursor at the first record meeting the constraint on each
+    /// of its fields, failing if the constraints do not fit the table.
+    /// Tables with an index over the constrained fields use it rather than
+    /// checking every record.
+    fn select(self, query: Vec<Constraint<T>>) -> Result<Self::Cursor, E>;
 }
 
 pub trait Cursor<T,E> {
@@ -35,7 +55,9 @@ pub trait Cursor<T,E> {
     fn delete(&mut self) -> Result<(), E>;
 
     /// advances the cursor to the next matching record or the end of the 
-    /// table.
+    /// table. Records are visited in the order the table keeps them, the
+    /// Vm's tables keep them in the order they were inserted unless their
+    /// type is ordered.
     fn advance(&mut self) -> Result<bool, E>;
 
     /// Closes the cursor and returns the underlying table.
@@ -0,0 +1,26 @@
+//! Runs the benchmark suite at small sizes so its programs stay correct.
+
+use crate::bench::{report, run_suite, Sizes};
+
+#[test]
+fn small_suite() {
+    let measurements = run_suite(&Sizes::small()).unwrap();
+
+    let result = |benchmark, vm| measurements.iter()
+        .find(|m| m.benchmark == benchmark && m.vm == vm)
+        .map(|m| m.result.as_str())
+        .unwrap();
+
+    assert_eq!(result("fib", "dyn"), "Some(I64(55))");
+    assert_eq!(result("fib", "typed"), "Some(I64(55))");
+    assert_eq!(result("loop", "dyn"), "Some(U64(4950))");
+    assert_eq!(result("loop", "typed"), "Some(U64(4950))");
+    assert_eq!(result("frames", "dyn"), "Some(I64(34))");
+    assert_eq!(result("frames", "typed"), "Some(I64(34))");
+    assert_eq!(result("structs", "dyn"), "Some(U64(10))");
+    assert_eq!(result("structs", "typed"), "Some(U64(10))");
+    assert_eq!(result("table", "typed"), "Some(U64(3))");
+
+    assert!(measurements.iter().all(|m| m.instructions > 0));
+    assert_eq!(report(&measurements).lines().count(), measurements.len() + 1);
+}
@@ -0,0 +1,454 @@
+//! Runs every program in the corpus on both Vms and checks that they agree.
+//!
+//! Programs can state what they should produce with comments at the top:
+//!
+//! ```text
+//! // yields: I64(1), I64(11)
+//! // resumes: I64(10), I64(5)
+//! // expect: I64(15)
+//! ```
+//!
+//! `yields` lists the values the program yields in order and `resumes`
+//! the values the host resumes it with. `expect` is the value left on the
+//! top of the stack when the program halts. Values are written as their
+//! `Common` debug representation. Whatever the annotations say, both Vms
+//! must yield the same values and halt with the same stack.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::dyn_vm;
+use crate::typed_vm;
+use crate::limits::Limits;
+
+const CORPUS: &str = "src/lang/corpus";
+
+/// A value independent of the Vm which produced it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Common {
+    None,
+    Usize(usize),
+    F32(f32),
+    F64(f64),
+    U32(u32),
+    U64(u64),
+    I32(i32),
+    I64(i64),
+    Bool(bool),
+    Struct(Vec<Common>),
+    Function,
+    /// Values such as tables which have no common representation.
+    Opaque,
+}
+
+impl Common {
+    fn parse(text: &str) -> Option<Common> {
+        let text = text.trim();
+        if text == "None" {
+            return Some(Common::None);
+        }
+
+        let (name, rest) = text.split_once('(')?;
+        let arg = rest.strip_suffix(')')?;
+
+        let value = match name {
+            "Usize" => Common::Usize(arg.parse().ok()?),
+            "F32" => Common::F32(arg.parse().ok()?),
+            "F64" => Common::F64(arg.parse().ok()?),
+            "U32" => Common::U32(arg.parse().ok()?),
+            "U64" => Common::U64(arg.parse().ok()?),
+            "I32" => Common::I32(arg.parse().ok()?),
+            "I64" => Common::I64(arg.parse().ok()?),
+            "Bool" => Common::Bool(arg.parse().ok()?),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn parse_list(text: &str) -> Option<Vec<Common>> {
+        text.split(", ").map(Common::parse).collect()
+    }
+
+    pub fn from_dyn(value: &dyn_vm::Value) -> Common {
+        use dyn_vm::Value;
+        match value {
+            Value::None => Common::None,
+            Value::Usize(v) => Common::Usize(*v),
+            Value::F32(v) => Common::F32(*v),
+            Value::F64(v) => Common::F64(*v),
+            Value::U32(v) => Common::U32(*v),
+            Value::U64(v) => Common::U64(*v),
+            Value::I32(v) => Common::I32(*v),
+            Value::I64(v) => Common::I64(*v),
+            Value::Bool(v) => Common::Bool(*v),
+            Value::Struct(fields) => {
+                Common::Struct(fields.iter().map(Common::from_dyn).collect())
+            },
+            Value::Function(_) => Common::Function,
+            Value::Symbol(_) | Value::StringRef { .. } => Common::Opaque,
+        }
+    }
+
+    /// Converts a single typed Vm value. Struct markers and handles
+    /// only mean something on a stack, see `from_typed_stack`.
+    pub fn from_typed(value: &typed_vm::Value) -> Common {
+        use typed_vm::Value;
+        match value {
+            Value::None => Common::None,
+            Value::Usize(v) => Common::Usize(*v),
+            Value::F32(v) => Common::F32(*v),
+            Value::F64(v) => Common::F64(*v),
+            Value::U32(v) => Common::U32(*v),
+            Value::U64(v) => Common::U64(*v),
+            Value::I32(v) => Common::I32(*v),
+            Value::I64(v) => Common::I64(*v),
+            Value::Bool(v) => Common::Bool(*v),
+            Value::Function { .. } => Common::Function,
+            Value::StringRef { .. } | Value::Struct { .. } | Value::Table { .. }
+            | Value::Cursor { .. } | Value::Untagged(_) => Common::Opaque,
+        }
+    }
+
+    /// Converts a typed Vm stack to the values a dyn Vm stack would hold.
+    /// The slot at the bottom holding the Vm's function table is skipped
+    /// and each struct marker gathers the fields below it, or None if
+    /// there are too few.
+    pub fn from_typed_stack(stack: &[typed_vm::Value]) -> Option<Vec<Common>> {
+        let mut values = Vec::new();
+        for value in stack.get(1..)? {
+            let common = match value {
+                typed_vm::Value::Struct { field_count } => {
+                    let at = values.len().checked_sub(*field_count)?;
+                    Common::Struct(values.split_off(at))
+                },
+                value => Common::from_typed(value),
+            };
+            values.push(common);
+        }
+        Some(values)
+    }
+
+    /// The value to resume a dyn Vm with, or None if the value has no
+    /// dyn equivalent.
+    pub fn to_dyn(&self) -> Option<dyn_vm::Value> {
+        use dyn_vm::Value;
+        let value = match self {
+            Common::None => Value::None,
+            Common::Usize(v) => Value::Usize(*v),
+            Common::F32(v) => Value::F32(*v),
+            Common::F64(v) => Value::F64(*v),
+            Common::U32(v) => Value::U32(*v),
+            Common::U64(v) => Value::U64(*v),
+            Common::I32(v) => Value::I32(*v),
+            Common::I64(v) => Value::I64(*v),
+            Common::Bool(v) => Value::Bool(*v),
+            Common::Struct(fields) => {
+                Value::Struct(fields.iter().map(Common::to_dyn).collect::<Option<_>>()?)
+            },
+            Common::Function | Common::Opaque => return None,
+        };
+        Some(value)
+    }
+
+    /// The value to resume a typed Vm with, see `to_dyn`.
+    pub fn to_typed(&self) -> Option<typed_vm::Value> {
+        use typed_vm::Value;
+        let value = match self {
+            Common::None => Value::None,
+            Common::Usize(v) => Value::Usize(*v),
+            Common::F32(v) => Value::F32(*v),
+            Common::F64(v) => Value::F64(*v),
+            Common::U32(v) => Value::U32(*v),
+            Common::U64(v) => Value::U64(*v),
+            Common::I32(v) => Value::I32(*v),
+            Common::I64(v) => Value::I64(*v),
+            Common::Bool(v) => Value::Bool(*v),
+            // Resuming takes a single stack value.
+            Common::Struct(_) | Common::Function | Common::Opaque => return None,
+        };
+        Some(value)
+    }
+}
+
+/// What running a program produced, or the error that stopped it.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Halted {
+        yields: Vec<Common>,
+        stack: Vec<Common>,
+    },
+    CompileError,
+    VmError,
+}
+
+#[derive(Debug, Default)]
+struct Annotations {
+    expect: Option<Common>,
+    yields: Option<Vec<Common>>,
+    resumes: Vec<Common>,
+}
+
+impl Annotations {
+    fn parse(source: &str) -> Result<Self, String> {
+        let mut annotations = Annotations::default();
+
+        for line in source.lines() {
+            let Some(comment) = line.trim().strip_prefix("//") else {
+                continue;
+            };
+
+            let Some((key, value)) = comment.split_once(':') else {
+                continue;
+            };
+
+            let bad = || format!("invalid annotation: {}", line);
+            match key.trim() {
+                "expect" => {
+                    annotations.expect = Some(Common::parse(value).ok_or_else(bad)?);
+                },
+                "yields" => {
+                    annotations.yields = Some(Common::parse_list(value).ok_or_else(bad)?);
+                },
+                "resumes" => {
+                    annotations.resumes = Common::parse_list(value).ok_or_else(bad)?;
+                },
+                _ => {},
+            }
+        }
+
+        Ok(annotations)
+    }
+}
+
+/// Compiles and runs the source on the dyn Vm. Each time the program
+/// yields `resume` is called with the number of earlier yields and the
+/// yielded value and returns the value to resume with, or None to stop.
+pub fn run_dyn<F>(source: &str, limits: Limits, resume: F) -> Outcome
+where F: FnMut(usize, &Common) -> Option<Common> {
+    run_dyn_with(dyn_vm::compile::parse_colang, source, limits, resume)
+}
+
+/// Runs the source on the dyn Vm after compiling it with `compile`.
+pub fn run_dyn_with<C, E, F>(compile: C, source: &str, limits: Limits, mut resume: F) -> Outcome
+where
+    C: Fn(&str) -> Result<dyn_vm::Module, E>,
+    F: FnMut(usize, &Common) -> Option<Common>,
+{
+    let Ok(module) = compile(source) else {
+        return Outcome::CompileError;
+    };
+
+    let mut vm = dyn_vm::Vm::with_limits(module, limits);
+    let mut yields = Vec::new();
+    let mut status = vm.run();
+
+    loop {
+        match status {
+            Ok(dyn_vm::Status::Halted) => break,
+            Ok(dyn_vm::Status::Yielded(value)) => {
+                let value = Common::from_dyn(&value);
+                let Some(next) = resume(yields.len(), &value).and_then(|next| next.to_dyn()) else {
+                    return Outcome::VmError;
+                };
+                yields.push(value);
+                status = vm.resume(next);
+            },
+            Err(_) => return Outcome::VmError,
+        }
+    }
+
+    let stack = vm.stack().iter().map(Common::from_dyn).collect();
+    Outcome::Halted { yields, stack }
+}
+
+/// Compiles and runs the source on the typed Vm, see `run_dyn`.
+pub fn run_typed<F>(source: &str, limits: Limits, resume: F) -> Outcome
+where F: FnMut(usize, &Common) -> Option<Common> {
+    run_typed_with(typed_vm::compile::parse_colang, source, limits, resume)
+}
+
+/// Runs the source on the typed Vm after compiling it with `compile`.
+pub fn run_typed_with<C, E, F>(compile: C, source: &str, limits: Limits, mut resume: F) -> Outcome
+where
+    C: Fn(&str) -> Result<typed_vm::Module, E>,
+    F: FnMut(usize, &Common) -> Option<Common>,
+{
+    let Ok(module) = compile(source) else {
+        return Outcome::CompileError;
+    };
+
+    // Tags are kept so the result can be read without knowing its type.
+    let mut vm = typed_vm::Vm::checked_with_limits(module, limits);
+    let mut yields = Vec::new();
+    let mut status = vm.run();
+
+    loop {
+        match status {
+            Ok(typed_vm::Status::Halted) => break,
+            Ok(typed_vm::Status::Yielded(value)) => {
+                let value = Common::from_typed(&value);
+                let Some(next) = resume(yields.len(), &value).and_then(|next| next.to_typed()) else {
+                    return Outcome::VmError;
+                };
+                yields.push(value);
+                status = vm.resume(next);
+            },
+            Err(_) => return Outcome::VmError,
+        }
+    }
+
+    let Some(stack) = Common::from_typed_stack(&vm.stack()) else {
+        return Outcome::VmError;
+    };
+    Outcome::Halted { yields, stack }
+}
+
+fn corpus_files() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(CORPUS)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "co"))
+        .collect();
+    files.sort();
+    files
+}
+
+fn check_file(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let annotations = Annotations::parse(&source)?;
+    let resumes = |index: usize, _: &Common| annotations.resumes.get(index).cloned();
+
+    let dyn_outcome = run_dyn(&source, Limits::unlimited(), resumes);
+    let typed_outcome = run_typed(&source, Limits::unlimited(), resumes);
+
+    if dyn_outcome != typed_outcome {
+        return Err(format!("dyn_vm: {:?} typed_vm: {:?}", dyn_outcome, typed_outcome));
+    }
+
+    let Outcome::Halted { yields, stack } = dyn_outcome else {
+        return Err(format!("{:?}", dyn_outcome));
+    };
+
+    if let Some(expected) = annotations.yields {
+        if yields != expected {
+            return Err(format!("yielded {:?} expected {:?}", yields, expected));
+        }
+    }
+
+    if let Some(expected) = annotations.expect {
+        if stack.last() != Some(&expected) {
+            return Err(format!("result {:?} expected {:?}", stack.last(), expected));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn corpus () {
+    let files = corpus_files();
+    assert!(!files.is_empty());
+
+    let failures: Vec<String> = files.iter()
+        .filter_map(|path| {
+            check_file(path).err()
+                .map(|error| format!("{}: {}", path.display(), error))
+        })
+        .collect();
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn annotations () {
+    let source = "// yields: I64(1), U32(2)\n// resumes: None\n// expect: F64(1.5)\nfn main() {}";
+    let annotations = Annotations::parse(source).unwrap();
+
+    assert!(annotations.yields == Some(vec![Common::I64(1), Common::U32(2)]));
+    assert!(annotations.resumes == vec![Common::None]);
+    assert!(annotations.expect == Some(Common::F64(1.5)));
+    assert!(Annotations::parse("// expect: I64(x)").is_err());
+}
+
+#[test]
+fn typed_stacks () {
+    use typed_vm::Value;
+
+    // Structs are gathered under their markers, nested ones included,
+    // and the function table at the bottom is left out.
+    let stack = [
+        Value::Table { handle: 0 },
+        Value::I64(1),
+        Value::U32(2), Value::Bool(true), Value::Struct { field_count: 1 }, Value::Struct { field_count: 2 },
+        Value::F64(3.0),
+    ];
+    let expected = vec![
+        Common::I64(1),
+        Common::Struct(vec![Common::U32(2), Common::Struct(vec![Common::Bool(true)])]),
+        Common::F64(3.0),
+    ];
+    assert!(Common::from_typed_stack(&stack) == Some(expected));
+    assert!(Common::from_typed_stack(&[Value::Table { handle: 0 }, Value::Struct { field_count: 1 }]).is_none());
+
+    // Values without an equivalent can't be passed to `resume`.
+    assert!(Common::Struct(vec![Common::I64(1)]).to_typed().is_none());
+    assert!(Common::Struct(vec![Common::Opaque]).to_dyn().is_none());
+    assert!(Common::Struct(vec![Common::I64(1)]).to_dyn().is_some());
+}
+
+/// Runs the source on the typed Vm returning the final stack slots.
+fn typed_slots(source: &str, checked: bool, resumes: &[Common]) -> Option<Vec<u64>> {
+    let module = typed_vm::compile::parse_colang(source).ok()?;
+    let mut vm = if checked {
+        typed_vm::Vm::checked(module)
+    } else {
+        typed_vm::Vm::new(module)
+    };
+
+    let mut resumes = resumes.iter();
+    let mut status = vm.run().ok()?;
+    while let typed_vm::Status::Yielded(_) = status {
+        status = vm.resume(resumes.next()?.to_typed()?).ok()?;
+    }
+
+    Some(vm.stack().iter().map(typed_vm::Value::bits).collect())
+}
+
+#[test]
+fn unchecked_corpus () {
+    // The compiler's code must run the same without the tags checking it.
+    for path in corpus_files() {
+        let source = fs::read_to_string(&path).unwrap();
+        let annotations = Annotations::parse(&source).unwrap();
+
+        let checked = typed_slots(&source, true, &annotations.resumes);
+        let unchecked = typed_slots(&source, false, &annotations.resumes);
+        assert!(checked == unchecked, "{}: {:?} {:?}", path.display(), checked, unchecked);
+    }
+}
+
+#[test]
+fn unoptimized_corpus () {
+    // The optimizer must not change what any program does.
+    for path in corpus_files() {
+        let source = fs::read_to_string(&path).unwrap();
+        let annotations = Annotations::parse(&source).unwrap();
+        let resumes = |index: usize, _: &Common| annotations.resumes.get(index).cloned();
+        let limits = Limits::unlimited;
+
+        let optimized = run_dyn(&source, limits(), resumes);
+        let unoptimized = run_dyn_with(
+            dyn_vm::compile::parse_colang_unoptimized, &source, limits(), resumes
+        );
+        assert!(optimized == unoptimized, "dyn_vm {}: {:?} {:?}",
+            path.display(), optimized, unoptimized);
+
+        let optimized = run_typed(&source, limits(), resumes);
+        let unoptimized = run_typed_with(
+            typed_vm::compile::parse_colang_unoptimized, &source, limits(), resumes
+        );
+        assert!(optimized == unoptimized, "typed_vm {}: {:?} {:?}",
+            path.display(), optimized, unoptimized);
+    }
+}
+
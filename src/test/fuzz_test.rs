@@ -0,0 +1,575 @@
+//! Fuzzes the compilers and both Vms with generated input.
+//!
+//! Programs are generated from the rules in `lang/grammar.pest` so they
+//! always parse and type check. Both Vms must run them to the same yields
+//! and result. Mutated copies of those programs and random op sequences
+//! only have to be rejected with a `LangError` or `VmError`, never a panic.
+//! Generated programs must also run the same with and without the
+//! optimizer.
+//!
+//! Each test runs a fixed number of cases from a fixed seed. Set
+//! `COLANG_FUZZ_SEED` and `COLANG_FUZZ_CASES` to explore further, a
+//! failure reports the seed of the case which failed.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::dyn_vm;
+use crate::typed_vm;
+use crate::limits::Limits;
+use crate::Type;
+
+use super::diff_test::{run_dyn, run_dyn_with, run_typed, run_typed_with, Common, Outcome};
+
+const DEFAULT_CASES: u64 = 200;
+
+/// A xorshift generator, good enough to pick grammar rules.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero.
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.parse().ok()
+}
+
+/// Runs `case` for each seed, reporting the seed of the first failure.
+fn for_each_case(default_seed: u64, case: impl Fn(u64) -> Result<(), String>) {
+    let seed = env_u64("COLANG_FUZZ_SEED").unwrap_or(default_seed);
+    let cases = env_u64("COLANG_FUZZ_CASES").unwrap_or(DEFAULT_CASES);
+
+    for seed in seed..seed + cases {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| case(seed)))
+            .unwrap_or_else(|_| Err("panicked".to_string()));
+
+        if let Err(error) = result {
+            panic!("seed {}: {}", seed, error);
+        }
+    }
+}
+
+/// The types a program can compute with and their literals.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    F32,
+    F64,
+    U32,
+    U64,
+    I32,
+    I64,
+}
+
+const TYPES: [Ty; 6] = [Ty::F32, Ty::F64, Ty::U32, Ty::U64, Ty::I32, Ty::I64];
+
+impl Ty {
+    fn literal(self, rng: &mut Rng) -> String {
+        // Large literals make additions wrap.
+        let big = rng.chance(10);
+        let int = if big { rng.next() } else { rng.next() % 100 };
+        let frac = rng.next() % 100;
+
+        match self {
+            Ty::F32 => format!("{}.{}f32", int % 1000, frac),
+            Ty::F64 if rng.chance(50) => format!("{}.{}f64", int % 1000, frac),
+            Ty::F64 => format!("{}.{}", int % 1000, frac),
+            Ty::U32 => format!("{}u32", int as u32),
+            Ty::U64 => format!("{}u64", int),
+            Ty::I32 => format!("{}i32", int as u32 >> 1),
+            Ty::I64 if rng.chance(50) => format!("{}i64", int >> 1),
+            Ty::I64 => format!("{}", int >> 1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Signature {
+    name: String,
+    args: Vec<Ty>,
+    ret: Ty,
+}
+
+/// Generates well typed programs. Functions only call functions defined
+/// before them so every program terminates.
+struct Generator {
+    rng: Rng,
+    functions: Vec<Signature>,
+    scope: Vec<(String, Ty)>,
+    /// Loop counters, which are kept out of scope so they can't be assigned.
+    loops: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Self {
+        Generator {
+            rng: Rng::new(seed),
+            functions: Vec::new(),
+            scope: Vec::new(),
+            loops: 0,
+        }
+    }
+
+    // program = _{ SOI ~ function* ~ EOI }
+    fn program(&mut self) -> String {
+        let mut source = String::new();
+
+        for index in 0..self.rng.below(5) {
+            let args = (0..self.rng.below(4))
+                .map(|_| *self.rng.pick(&TYPES))
+                .collect();
+            let ret = *self.rng.pick(&TYPES);
+            let signature = Signature { name: format!("f{}", index), args, ret };
+            source.push_str(&self.function(&signature));
+            self.functions.push(signature);
+        }
+
+        let ret = *self.rng.pick(&TYPES);
+        let main = Signature { name: "main".to_string(), args: Vec::new(), ret };
+        source.push_str(&self.function(&main));
+        source
+    }
+
+    // function = {"fn" ~ symbol ~ "(" ~ args ~ ")" ~ "{" ~ body ~ "}"}
+    fn function(&mut self, signature: &Signature) -> String {
+        self.scope = signature.args.iter()
+            .enumerate()
+            .map(|(index, arg)| (format!("a{}", index), *arg))
+            .collect();
+
+        let args: Vec<&str> = self.scope.iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let mut source = format!("fn {}({}) {{\n", signature.name, args.join(", "));
+
+        // body = { statment* }
+        for _ in 0..self.rng.below(6) {
+            let statement = self.statement(0);
+            source.push_str(&statement);
+        }
+
+        // The body ends in either a return or an expression whose value
+        // the function evaluates to.
+        let value = self.expression(signature.ret, 0);
+        if self.rng.chance(50) {
+            source.push_str(&format!("    return {};\n}}\n", value));
+        } else {
+            source.push_str(&format!("    {};\n}}\n", value));
+        }
+        source
+    }
+
+    // statment = _{ if_block | while_block |
+    //     (declaration | assignment | ret | expression) ~ ";"}
+    fn statement(&mut self, depth: usize) -> String {
+        let indent = "    ".repeat(depth + 1);
+        let ty = *self.rng.pick(&TYPES);
+
+        match self.rng.below(10) {
+            0 if depth < 2 => self.if_block(depth, ty),
+            1 if depth < 2 => self.while_block(depth),
+            2 => {
+                let vars: Vec<(String, Ty)> = self.scope.clone();
+                if vars.is_empty() {
+                    return format!("{}{};\n", indent, self.expression(ty, 0));
+                }
+                let (name, ty) = self.rng.pick(&vars).clone();
+                format!("{}{} = {};\n", indent, name, self.expression(ty, 0))
+            },
+            3..=8 => {
+                let value = self.expression(ty, 0);
+                let name = format!("v{}", self.scope.len());
+                self.scope.push((name.clone(), ty));
+                format!("{}let {} = {};\n", indent, name, value)
+            },
+            _ => format!("{}{};\n", indent, self.expression(ty, 0)),
+        }
+    }
+
+    // block = {"{" ~ statment* ~ "}"}
+    fn block(&mut self, depth: usize, suffix: &str) -> String {
+        let indent = "    ".repeat(depth + 1);
+        let scope = self.scope.len();
+
+        let mut source = "{\n".to_string();
+        for _ in 0..self.rng.below(4) {
+            let statement = self.statement(depth + 1);
+            source.push_str(&statement);
+        }
+        source.push_str(suffix);
+        source.push_str(&format!("{}}}", indent));
+
+        // Variables declared in the block aren't visible after it.
+        self.scope.truncate(scope);
+        source
+    }
+
+    // if_block = {"if" ~ expression ~ block ~ ("else" ~ block)?}
+    fn if_block(&mut self, depth: usize, ty: Ty) -> String {
+        let indent = "    ".repeat(depth + 1);
+        let condition = format!("{} < {}", self.value(ty), self.value(ty));
+
+        let mut source = format!("{}if {} {}", indent, condition, self.block(depth, ""));
+        if self.rng.chance(50) {
+            source.push_str(&format!(" else {}", self.block(depth, "")));
+        }
+        source.push('\n');
+        source
+    }
+
+    // while_block = {"while" ~ expression ~ block}
+    fn while_block(&mut self, depth: usize) -> String {
+        // Loops count up to a small bound so every program terminates.
+        let indent = "    ".repeat(depth + 1);
+        let counter = format!("c{}", self.loops);
+        self.loops += 1;
+        let bound = self.rng.below(4);
+        let step = format!("{}    {} = {} + 1u64;\n", indent, counter, counter);
+
+        let mut source = format!("{}let {} = 0u64;\n", indent, counter);
+        source.push_str(&format!("{}while {} < {}u64 {}\n",
+            indent, counter, bound, self.block(depth, &step)));
+        source
+    }
+
+    // expression = _{yield_expr | opperation | call | value }
+    fn expression(&mut self, ty: Ty, depth: usize) -> String {
+        let callable: Vec<usize> = self.functions.iter()
+            .enumerate()
+            .filter(|(_, f)| f.ret == ty)
+            .map(|(index, _)| index)
+            .collect();
+
+        match self.rng.below(10) {
+            0 if depth < 2 => format!("yield {}", self.expression(ty, depth + 1)),
+            1..=2 => format!("{} + {}", self.value(ty), self.value(ty)),
+            3 => format!("{} - {}", self.value(ty), self.value(ty)),
+            4..=6 if depth < 2 && !callable.is_empty() => {
+                let index = *self.rng.pick(&callable);
+                let args = self.functions[index].args.clone();
+                let params: Vec<String> = args.iter()
+                    .map(|arg| self.expression(*arg, depth + 1))
+                    .collect();
+                format!("{}({})", self.functions[index].name, params.join(", "))
+            },
+            _ => self.value(ty),
+        }
+    }
+
+    // value = _{ number | var }
+    fn value(&mut self, ty: Ty) -> String {
+        let vars: Vec<&String> = self.scope.iter()
+            .filter(|(_, var_ty)| *var_ty == ty)
+            .map(|(name, _)| name)
+            .collect();
+
+        if !vars.is_empty() && self.rng.chance(50) {
+            return self.rng.pick(&vars).to_string();
+        }
+        ty.literal(&mut self.rng)
+    }
+}
+
+/// Resumes every yield with the value yielded.
+fn echo(_: usize, value: &Common) -> Option<Common> {
+    match value {
+        Common::Struct(_) | Common::Function | Common::Opaque => None,
+        value => Some(value.clone()),
+    }
+}
+
+fn fuzz_limits() -> Limits {
+    Limits::unlimited()
+        .with_fuel(10_000)
+        .with_max_stack(10_000)
+        .with_max_call_depth(64)
+        .with_max_table_rows(100)
+}
+
+#[test]
+fn generated_programs () {
+    for_each_case(0, |seed| {
+        let source = Generator::new(seed).program();
+
+        let dyn_outcome = run_dyn(&source, Limits::unlimited(), echo);
+        let typed_outcome = run_typed(&source, Limits::unlimited(), echo);
+
+        if !matches!(dyn_outcome, Outcome::Halted { .. }) {
+            return Err(format!("dyn_vm: {:?}\n{}", dyn_outcome, source));
+        }
+
+        if dyn_outcome != typed_outcome {
+            return Err(format!(
+                "dyn_vm: {:?} typed_vm: {:?}\n{}",
+                dyn_outcome, typed_outcome, source
+            ));
+        }
+        Ok(())
+    });
+}
+
+#[test]
+fn optimized_programs () {
+    for_each_case(0, |seed| {
+        let source = Generator::new(seed).program();
+
+        let optimized = run_dyn(&source, Limits::unlimited(), echo);
+        let unoptimized = run_dyn_with(
+            dyn_vm::compile::parse_colang_unoptimized, &source, Limits::unlimited(), echo
+        );
+        if optimized != unoptimized {
+            return Err(format!(
+                "dyn_vm optimized: {:?} unoptimized: {:?}\n{}",
+                optimized, unoptimized, source
+            ));
+        }
+
+        let optimized = run_typed(&source, Limits::unlimited(), echo);
+        let unoptimized = run_typed_with(
+            typed_vm::compile::parse_colang_unoptimized, &source, Limits::unlimited(), echo
+        );
+        if optimized != unoptimized {
+            return Err(format!(
+                "typed_vm optimized: {:?} unoptimized: {:?}\n{}",
+                optimized, unoptimized, source
+            ));
+        }
+        Ok(())
+    });
+}
+
+/// Fragments spliced into programs, mostly tokens from the grammar.
+const FRAGMENTS: [&str; 18] = [
+    "fn", "let", "return", "yield", "(", ")", "{", "}", ",", ";", "=",
+    "+", "-", "main", "a0", "f0", "1.5", "99999999999999999999",
+];
+
+fn mutate(rng: &mut Rng, source: &str) -> String {
+    let mut chars: Vec<char> = source.chars().collect();
+
+    for _ in 0..1 + rng.below(3) {
+        let at = rng.below(chars.len() + 1);
+        match rng.below(3) {
+            0 => {
+                let end = (at + rng.below(8)).min(chars.len());
+                chars.drain(at..end);
+            },
+            1 => {
+                let fragment = format!(" {} ", rng.pick(&FRAGMENTS));
+                chars.splice(at..at, fragment.chars());
+            },
+            _ => {
+                let end = (at + rng.below(16)).min(chars.len());
+                let copy: Vec<char> = chars[at..end].to_vec();
+                chars.splice(at..at, copy);
+            },
+        }
+    }
+
+    chars.into_iter().collect()
+}
+
+#[test]
+fn mutated_programs () {
+    for_each_case(1 << 32, |seed| {
+        let source = Generator::new(seed).program();
+        let source = mutate(&mut Rng::new(seed), &source);
+
+        // Any outcome is fine so long as neither side panics.
+        run_dyn(&source, fuzz_limits(), echo);
+        run_typed(&source, fuzz_limits(), echo);
+        Ok(())
+    });
+}
+
+fn random_dyn_op(rng: &mut Rng, len: usize) -> dyn_vm::Op {
+    use dyn_vm::Op;
+    match rng.below(30) {
+        0 => Op::Noop,
+        1 => Op::Halt,
+        2 => Op::Pop,
+        3 => Op::Swap,
+        4 => Op::Copy,
+        5 => Op::CopyFrom,
+        6 => Op::Load,
+        7 => Op::Store,
+        8 => Op::GetFn,
+        9 => Op::Call,
+        10 => Op::Return,
+        11 => Op::None,
+        12 => Op::Symbol(rng.pick(&["f", "main", "g"]).to_string()),
+        13 => Op::F32(rng.next() as f32),
+        14 => Op::F64(rng.next() as f64),
+        15 => Op::I32(rng.next() as i32),
+        16 => Op::I64(rng.next() as i64),
+        17 => Op::U32(rng.next() as u32),
+        18 => Op::U64(rng.next()),
+        19 => Op::Usize(rng.below(len + 2)),
+        20 => Op::Bool(rng.chance(50)),
+        21 => Op::Struct,
+        22 => Op::StructRead,
+        23 => Op::Add,
+        24 => Op::CallDirect(rng.below(3)),
+        25 => Op::Jump(rng.below(len + 2)),
+        26 => Op::JumpIfFalse(rng.below(len + 2)),
+        27 => Op::Sub,
+        28 => Op::Lt,
+        _ => Op::Yield,
+    }
+}
+
+const KINDS: [typed_vm::Kind; 14] = {
+    use typed_vm::Kind::*;
+    [None, Usize, F32, F64, U32, U64, I32, I64, StringRef, Bool, Struct, Table, Cursor, Function]
+};
+
+fn random_typed_op(rng: &mut Rng, len: usize) -> typed_vm::Op {
+    use typed_vm::Op;
+    match rng.below(62) {
+        0 => Op::Noop,
+        1 => Op::Halt,
+        2 => Op::Pop,
+        3 => Op::PopN,
+        4 => Op::Swap,
+        5 => Op::SwapN,
+        6 => Op::Copy,
+        7 => Op::CopyMany,
+        8 => Op::CopyFrom,
+        9 => Op::CopyManyFrom,
+        10 => Op::Load,
+        11 => Op::LoadN,
+        12 => Op::Store,
+        13 => Op::StoreN,
+        14 => Op::Call,
+        15 => Op::Return,
+        16 => Op::Table,
+        17 => Op::Query,
+        18 => Op::Found,
+        19 => Op::Read,
+        20 => Op::Insert,
+        21 => Op::Update,
+        22 => Op::Delete,
+        23 => Op::Advance,
+        24 => Op::Close,
+        25 => Op::None,
+        26 => Op::Fn(rng.below(len + 2)),
+        27 => Op::F32(rng.next() as f32),
+        28 => Op::F64(rng.next() as f64),
+        29 => Op::I32(rng.next() as i32),
+        30 => Op::I64(rng.next() as i64),
+        31 => Op::U32(rng.below(3) as u32),
+        32 => Op::U64(rng.next()),
+        33 => Op::Bool(rng.chance(50)),
+        34 => Op::Struct,
+        35 => Op::AddF32,
+        36 => Op::AddF64,
+        37 => Op::AddU32,
+        38 => Op::AddU64,
+        39 => Op::AddI32,
+        40 => Op::AddI64,
+        41 => Op::Yield(*rng.pick(&KINDS)),
+        42 => Op::Jump(rng.below(len + 2)),
+        43 => Op::JumpIfFalse(rng.below(len + 2)),
+        44 => Op::SubF32,
+        45 => Op::SubF64,
+        46 => Op::SubU32,
+        47 => Op::SubU64,
+        48 => Op::SubI32,
+        49 => Op::SubI64,
+        50 => Op::LtF32,
+        51 => Op::LtF64,
+        52 => Op::LtU32,
+        53 => Op::LtU64,
+        54 => Op::LtI32,
+        55 => Op::LtI64,
+        56 => Op::LoadImm(rng.below(6)),
+        57 => Op::StoreImm(rng.below(6)),
+        58 => Op::AddImmI64(rng.next() as i64),
+        59 => Op::CallFn {
+            ptr: rng.below(len + 2),
+            args: rng.below(4),
+            rets: rng.below(3),
+        },
+        // Small counts and offsets are the interesting ones.
+        _ => Op::Usize(rng.below(6)),
+    }
+}
+
+#[test]
+fn random_dyn_ops () {
+    for_each_case(2 << 32, |seed| {
+        let mut rng = Rng::new(seed);
+        let mut module = dyn_vm::compile::parse_colang(
+            "fn f(a) { return a; } fn main() { f(1); }"
+        ).map_err(|e| format!("{:?}", e))?;
+
+        let len = 1 + rng.below(40);
+        module.code = (0..len).map(|_| random_dyn_op(&mut rng, len)).collect();
+        module.start = rng.below(len);
+
+        let mut vm = dyn_vm::Vm::with_limits(module, fuzz_limits());
+        let mut status = vm.run();
+        for _ in 0..4 {
+            let Ok(dyn_vm::Status::Yielded(value)) = status else {
+                break;
+            };
+            status = vm.resume(value);
+        }
+        Ok(())
+    });
+}
+
+#[test]
+fn random_typed_ops () {
+    for_each_case(3 << 32, |seed| {
+        let mut rng = Rng::new(seed);
+        let mut module = typed_vm::compile::parse_colang(
+            "fn f(a) { return a; } fn main() { f(1); }"
+        ).map_err(|e| format!("{:?}", e))?;
+
+        let len = 1 + rng.below(40);
+        module.code = (0..len).map(|_| random_typed_op(&mut rng, len)).collect();
+        module.start = rng.below(len);
+        module.types.insert(0, typed_vm::TableType::new("t", vec![Type::U32, Type::I64]));
+
+        // Unchecked Vms read whatever is in a slot so must stay safe with
+        // any code, checked Vms must reject it cleanly.
+        let module = std::sync::Arc::new(module);
+        for checked in [false, true] {
+            let mut vm = if checked {
+                typed_vm::Vm::checked_with_limits(module.clone(), fuzz_limits())
+            } else {
+                typed_vm::Vm::with_limits(module.clone(), fuzz_limits())
+            };
+
+            let mut status = vm.run();
+            for _ in 0..4 {
+                let Ok(typed_vm::Status::Yielded(value)) = status else {
+                    break;
+                };
+                status = vm.resume(value);
+            }
+        }
+        Ok(())
+    });
+}
@@ -0,0 +1,3 @@
+mod bench_test;
+mod diff_test;
+mod fuzz_test;
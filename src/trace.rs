@@ -0,0 +1,111 @@
+use std::fmt::{self, Debug};
+use std::io::Write;
+
+/// A hook that a Vm calls around every instruction it executes.
+///
+/// `O` and `V` are the `Op` and `Value` types of the Vm the tracer is
+/// attached to, so one tracer implementation can be used with either Vm.
+pub trait Tracer<O, V> {
+    /// Called before the op at `ip` is executed with the stack as the op
+    /// will see it.
+    fn before(&mut self, _ip: usize, _op: &O, _stack: &[V]) {}
+
+    /// Called after the op at `ip` has executed with the resulting stack,
+    /// also when the op failed, leaving the stack as it stopped.
+    fn after(&mut self, _ip: usize, _op: &O, _stack: &[V]) {}
+}
+
+impl<O, V> Debug for dyn Tracer<O, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Tracer")
+    }
+}
+
+/// Writes a human readable line per instruction, showing the op before it
+/// runs and the stack after.
+#[derive(Debug)]
+pub struct LogTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> LogTracer<W> {
+    pub fn new(out: W) -> Self {
+        LogTracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<O: Debug, V: Debug, W: Write> Tracer<O, V> for LogTracer<W> {
+    fn before(&mut self, ip: usize, op: &O, _stack: &[V]) {
+        // A failing trace sink should not stop the Vm.
+        let _ = write!(self.out, "{:>6}: {:?}", ip, op);
+    }
+
+    fn after(&mut self, _ip: usize, _op: &O, stack: &[V]) {
+        let _ = writeln!(self.out, " -> {:?}", stack);
+    }
+}
+
+/// Writes one JSON object per line for each instruction executed:
+///
+/// `{"ip":3,"op":"Add","stack":["I64(7)","I64(5)"]}`
+///
+/// `stack` is the stack before the op runs. Ops and values are rendered
+/// with their `Debug` representation.
+#[derive(Debug)]
+pub struct JsonTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> Self {
+        JsonTracer { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn write_record<O: Debug, V: Debug>(&mut self, ip: usize, op: &O, stack: &[V])
+    -> std::io::Result<()> {
+        let mut line = format!("{{\"ip\":{},\"op\":", ip);
+        push_json_string(&mut line, &format!("{:?}", op));
+        line.push_str(",\"stack\":[");
+        for (i, value) in stack.iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            push_json_string(&mut line, &format!("{:?}", value));
+        }
+        line.push_str("]}\n");
+        self.out.write_all(line.as_bytes())
+    }
+}
+
+impl<O: Debug, V: Debug, W: Write> Tracer<O, V> for JsonTracer<W> {
+    fn before(&mut self, ip: usize, op: &O, stack: &[V]) {
+        // A failing trace sink should not stop the Vm.
+        let _ = self.write_record(ip, op, stack);
+    }
+}
+
+fn push_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                out.push_str(&format!("\\u{:04x}", c as u32));
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -1,18 +1,48 @@
+//! The typed Vm runs code whose types were checked by the compiler, so
+//! values are held on the stack as untagged 64 bit slots.
+
 
 use super::*;
 
-//mod compile;
-//use crate::typed_vm::compile::*;
+pub mod compile;
+#[cfg(test)]
+use crate::typed_vm::compile::*;
 
 #[cfg(test)]
 mod test;
 
+mod aggregate;
+mod convert;
+mod datalog;
+mod format;
+mod host;
+mod optimize;
+mod order;
+mod storage;
 mod table;
-use self::table::{FnTable,TableTypes,CursorTypes};
+mod transaction;
+mod snapshot;
+mod stack;
+use self::table::{FnTable,MemTable,TableTypes,CursorTypes};
+pub use self::table::TableType;
+pub use self::storage::Storage;
+pub use self::host::Access;
+pub use self::aggregate::Aggregate;
+pub use self::order::Sort;
+pub use self::format::{Format, FormatError};
+pub use self::datalog::{Atom, TableRule, Term};
+pub use self::convert::{Args, FromValue, ToValue};
+use self::stack::{Handles,Resource,Stack,Strings};
+use self::transaction::Transaction;
+pub use self::stack::Slot;
 use crate::Type;
+use crate::limits::Limits;
+use crate::trace::Tracer;
 
 
-#[derive(Debug)]
+/// A value passed between the Vm and the host. On the stack values are
+/// held in untagged slots, see `Kind`.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     None,
     Usize(usize),
@@ -29,11 +59,63 @@ pub enum Value {
     Struct {
         field_count: usize,
     }, 
-    Table (TableTypes),
-    Cursor(CursorTypes),
+    Table {
+        handle: usize,
+    },
+    Cursor {
+        handle: usize,
+    },
     Function {
         ptr: usize,
     },
+    /// A slot read from a Vm which isn't checked, so its kind is unknown.
+    Untagged(u64),
+}
+
+/// The kind of value in a stack slot. Slots don't hold their kind, the
+/// ops reading them know it from the code, but a Vm created with
+/// `Vm::checked` keeps a tag with each slot to check every read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    None,
+    Usize,
+    F32,
+    F64,
+    U32,
+    U64,
+    I32,
+    I64,
+    StringRef,
+    Bool,
+    Struct,
+    Table,
+    Cursor,
+    Function,
+}
+
+impl Kind {
+    /// The kind of slot holding a value of the type, None for types which
+    /// aren't known until run time.
+    pub fn of(value_type: &Type) -> Option<Kind> {
+        let kind = match value_type {
+            Type::None => Kind::None,
+            Type::Usize => Kind::Usize,
+            Type::F32 => Kind::F32,
+            Type::F64 => Kind::F64,
+            Type::U32 => Kind::U32,
+            Type::U64 => Kind::U64,
+            Type::I32 => Kind::I32,
+            Type::I64 => Kind::I64,
+            Type::StringRef => Kind::StringRef,
+            Type::Bool => Kind::Bool,
+            Type::Struct(_) => Kind::Struct,
+            Type::Table => Kind::Table,
+            Type::Cursor => Kind::Cursor,
+            Type::Function(_) => Kind::Function,
+            Type::Symbol | Type::Unknown => return None,
+        };
+        Some(kind)
+    }
 }
 
 #[derive(Debug)]
@@ -88,23 +170,63 @@ pub enum Op {
     /// the second usize number off the top of the sack.
     StoreN,
 
+    /// ( -- Value ): `Load` with the frame offset in the op.
+    LoadImm(usize),
+
+    /// ( Value -- ): `Store` with the frame offset in the op.
+    StoreImm(usize),
+
     /// (Usize, Usize, Function -- ): Call the given function ref. The first 
     /// argument is the number of arguments which causes, and the second value is the number
     /// of returned values.
     Call,
 
-    /// ( -- )
+    /// ( Value(s) -- ): `Call` with the function, argument count and
+    /// return count in the op.
+    CallFn { ptr: usize, args: usize, rets: usize },
+
+    /// ( Value(s) -- Value(s) ): Return to the caller, dropping the
+    /// frame and leaving the top ret count values passed to `Call`.
     Return,
 
     /// (Usize -- Table) Construct a table with given type index.
     Table,
 
-    /// (Struct, Table -- Cursor) Querying a table using the Struct
-    /// that matches the table type to constrain the query. Any Struct
-    /// fields with a value of None will be considered to be free and 
-    /// not constrain the query.
+    /// ( -- Table): Take the Vm's table of the type index, created empty
+    /// when first taken. It must be put back before it is taken again.
+    TakeTable(u32),
+
+    /// (Table -- ): Put back the Vm's table of the type index.
+    PutTable(u32),
+
+    /// (Table -- ): Make the table, which must not be one the Vm holds,
+    /// the Vm's table of the type index in place of the one it holds.
+    SetTable(u32),
+
+    /// ( -- ): Begin a transaction over the Vm's tables.
+    Begin,
+
+    /// ( -- ): Keep the changes made to the Vm's tables since the
+    /// innermost transaction began, ending it.
+    Commit,
+
+    /// ( -- ): Undo the changes made to the Vm's tables since the
+    /// innermost transaction began, ending it. Every table it changed
+    /// must have been put back.
+    Rollback,
+
+    /// (Usize, Struct, Table -- Cursor) Querying a table using the Struct
+    /// that matches the table type to constrain the query. Fields whose
+    /// bit is set in the Usize mask are free and don't constrain the
+    /// query, their values are ignored.
     Query,
 
+    /// (Value(s), Table -- Cursor) Querying a table for records passing
+    /// the filters. Each filter takes its operands from the stack in order,
+    /// read as values of the type of the field it tests. Fields without a
+    /// filter don't constrain the query.
+    Select(Vec<Filter>),
+
     /// (Cursor -- Cursor, bool): Returns true when the cursor is
     /// at a record which matches the Query
     Found,
@@ -132,6 +254,33 @@ pub enum Op {
     /// (Cursor -- Table): Closes the cursor returning the table.
     Close,
 
+    /// (Cursor -- Value(s), Struct, Table): Computes the aggregates over
+    /// the records from the cursor to the end of its query, then closes
+    /// the cursor. Pushes a Struct of the values then the table.
+    Aggregate(Vec<Aggregate>),
+
+    /// (Cursor -- Table, Table): Groups the records from the cursor to the
+    /// end of its query by the key fields, then closes the cursor. Pushes
+    /// the table then a new table of the type index holding a record of
+    /// the columns computed over each group.
+    Group { keys: Vec<usize>, columns: Vec<Aggregate>, type_index: u32 },
+
+    /// (Cursor -- Table, Cursor): Reads the records from the cursor to the
+    /// end of its query in to a new table sorted by the order, then closes
+    /// the cursor. Skips `offset` records of the new table and keeps at
+    /// most `limit`. Pushes the table then a cursor over the new table.
+    Order { order: Vec<Sort>, offset: usize, limit: Option<usize> },
+
+    /// (Table -- ): Drop a table the Vm doesn't hold.
+    DropTable,
+
+    /// ( -- ): Continue from the instruction at the index.
+    Jump(usize),
+
+    /// ( Bool -- ): Continue from the instruction at the index if the Bool
+    /// is false.
+    JumpIfFalse(usize),
+
     /// ( -- None): Push None on to the stack.
     None,
 
@@ -159,6 +308,9 @@ pub enum Op {
     /// ( - usize): Push a usize on to the stack.
     Usize(usize),
 
+    /// ( -- StringRef): Intern the string and push a ref to it.
+    Str(String),
+
     /// ( -- Value::Bool): Push a Value::Bool on to the stack
     Bool(bool),
 
@@ -166,6 +318,8 @@ pub enum Op {
     /// consuming stack values as defined by the U32
     Struct,    
 
+    // Integer arithmetic wraps on overflow, as in the dyn Vm.
+
     /// (F32, F32 -- F32): Add two f32s.
     AddF32,
 
@@ -183,17 +337,110 @@ pub enum Op {
 
     /// (I64, I64 -- I64): Add two i64s
     AddI64,
+
+    /// (I64 -- I64): Add the i64 in the op.
+    AddImmI64(i64),
+
+    /// (F32, F32 -- F32): Subtract the top f32 from the one below it.
+    SubF32,
+
+    /// (F64, F64 -- F64): Subtract the top f64 from the one below it.
+    SubF64,
+
+    /// (U32, U32 -- U32): Subtract the top u32 from the one below it.
+    SubU32,
+
+    /// (U64, U64 -- U64): Subtract the top u64 from the one below it.
+    SubU64,
+
+    /// (I32, I32 -- I32): Subtract the top i32 from the one below it.
+    SubI32,
+
+    /// (I64, I64 -- I64): Subtract the top i64 from the one below it.
+    SubI64,
+
+    /// (F32, F32 -- Bool): Push true if the f32 below the top is less than
+    /// the top f32.
+    LtF32,
+
+    /// (F64, F64 -- Bool): Push true if the f64 below the top is less than
+    /// the top f64.
+    LtF64,
+
+    /// (U32, U32 -- Bool): Push true if the u32 below the top is less than
+    /// the top u32.
+    LtU32,
+
+    /// (U64, U64 -- Bool): Push true if the u64 below the top is less than
+    /// the top u64.
+    LtU64,
+
+    /// (I32, I32 -- Bool): Push true if the i32 below the top is less than
+    /// the top i32.
+    LtI32,
+
+    /// (I64, I64 -- Bool): Push true if the i64 below the top is less than
+    /// the top i64.
+    LtI64,
+
+    /// (Value, Value -- Bool): Push true if the two values of the kind are
+    /// equal.
+    Eq(Kind),
+
+    /// (Value -- Value): Suspend the Vm handing the Value of the kind to
+    /// the host. The value passed to `Vm::resume` is pushed when execution
+    /// continues.
+    Yield(Kind),
 }
 
 
 
+/// A test `Op::Select` applies to a field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Test {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Strings starting with the operand.
+    Prefix,
+    /// Values equal to one of the count operands.
+    In(usize),
+}
+
+/// A test of a field of the records `Op::Select` matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Filter {
+    pub field: usize,
+    pub test: Test,
+}
+
+impl Filter {
+    pub fn new(field: usize, test: Test) -> Self {
+        Filter { field, test }
+    }
+
+    /// The number of values the filter takes from the stack.
+    fn operand_count(&self) -> usize {
+        match self.test {
+            Test::In(count) => count,
+            _ => 1,
+        }
+    }
+}
+
 use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+/// Code compiled for the typed Vm, see `compile::parse_colang`.
 #[derive(Debug)]
 pub struct Module {
-    pub start: usize,
-    pub code: Vec<Op>,
-    pub functions: FnTable,
-    pub types: BTreeMap<u32,Vec<Type>>,
+    pub(crate) start: usize,
+    pub(crate) code: Vec<Op>,
+    pub(crate) functions: FnTable,
+    /// The table types `Op::Table` creates tables of, by type index.
+    pub(crate) types: BTreeMap<u32,TableType>,
 }
 
 #[derive(Debug)]
@@ -202,75 +449,438 @@ struct RetInfo {
     frame_ptr: usize,
     ret_count: usize,
 }
+/// Why `Vm::run` or `Vm::resume` returned control to the host.
+#[derive(Debug)]
+pub enum Status {
+    /// The Vm executed `Halt` and can not be resumed.
+    Halted,
+
+    /// The Vm executed `Yield` with the value. Call `Vm::resume` to
+    /// continue.
+    Yielded(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Ready,
+    Suspended,
+    Halted,
+    /// An op failed, leaving the Vm part way through it.
+    Failed,
+}
+
+/// Runs a `Module`. The Vm runs until the code halts or yields, handing
+/// control back to the host with a `Status`.
 pub struct Vm {
     frame_ptr: usize,
     instruction_pointer: usize,
-    stack: Vec<Value>,
-    types: BTreeMap<u32,Vec<Type>>,
+    stack: Stack,
+    handles: Handles,
+    strings: Strings,
+    /// The handles of the tables held for `TakeTable` by type index, None
+    /// while a table is taken.
+    tables: BTreeMap<u32, Option<usize>>,
+    /// The open transactions, innermost last.
+    transactions: Vec<Transaction>,
     call_stack: Vec<RetInfo>,
-    code: Vec<Op>,
+    module: Arc<Module>,
+    state: State,
+    yielded: Option<Value>,
+    tracer: Option<Box<dyn Tracer<Op, Value>>>,
+    limits: Limits,
+    fuel: Option<u64>,
 }
 
 #[derive(Debug)]
 pub enum VmError {
     InvalidOperation,
     TypeCheck,
+    UnknownType(u32),
+    OutOfFuel,
+    StackOverflow,
+    CallDepthExceeded,
+    TableFull,
+    /// `run` was called on a Vm waiting in `Yield`, use `resume` instead.
+    Suspended,
+    /// `resume` was called on a Vm which is not waiting in `Yield`.
+    NotSuspended,
+    /// The Vm was run after an op failed. Only running out of fuel leaves
+    /// a Vm which can be run again.
+    Failed,
+    /// An op needed more values than the stack holds.
+    StackUnderflow,
+    /// The instruction pointer is outside of the code.
+    InvalidAddress(usize),
+    /// A slot held a handle to a table or cursor which no longer exists.
+    InvalidHandle(usize),
+    /// A table type has an index over, or is ordered by, a field it
+    /// doesn't have, or is ordered by a string field.
+    InvalidIndex(u32),
+    /// The table of the type was taken and not yet put back.
+    TableInUse(u32),
+    /// `Commit` or `Rollback` outside of a transaction.
+    NoTransaction,
+    /// Reading or writing the file a table is stored in failed.
+    Storage(std::io::Error),
+    /// A change to a read-only host table of the type.
+    ReadOnly(u32),
+    /// A change to a read-write host table of the type inside a
+    /// transaction, which couldn't roll it back.
+    HostTableInTransaction(u32),
+    /// A host table registered for a type which rules match, which can
+    /// only derive from the Vm's own tables.
+    HostTableInRule(u32),
+    /// No table type in the module has the name.
+    UnknownTable(String),
+    /// `Vm::call` found no function of the name compiled for the types
+    /// of its arguments.
+    UnknownFunction(String),
+    /// A `min`, `max` or `avg` over no records.
+    NoRecords,
+}
+
+impl std::fmt::Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::InvalidOperation => write!(f, "invalid operation"),
+            VmError::TypeCheck => write!(f, "a value was not of the expected kind"),
+            VmError::UnknownType(index) => write!(f, "unknown table type {}", index),
+            VmError::OutOfFuel => write!(f, "out of fuel"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::CallDepthExceeded => write!(f, "call depth exceeded"),
+            VmError::TableFull => write!(f, "table full"),
+            VmError::Suspended => write!(f, "the vm is suspended, use resume"),
+            VmError::NotSuspended => write!(f, "the vm is not suspended"),
+            VmError::Failed => write!(f, "the vm failed and can't be run again"),
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::InvalidAddress(address) => write!(f, "invalid address {}", address),
+            VmError::InvalidHandle(handle) => write!(f, "invalid handle {}", handle),
+            VmError::TableInUse(index) => write!(f, "table {} is in use", index),
+            VmError::NoTransaction => write!(f, "no transaction is open"),
+            VmError::Storage(error) => write!(f, "table storage failed: {}", error),
+            VmError::ReadOnly(index) => write!(f, "table {} is read-only", index),
+            VmError::HostTableInTransaction(index) => {
+                write!(f, "host table {} can't be changed inside a transaction", index)
+            },
+            VmError::HostTableInRule(index) => {
+                write!(f, "table {} is matched by rules so can't be a host table", index)
+            },
+            VmError::UnknownTable(name) => write!(f, "unknown table `{}`", name),
+            VmError::UnknownFunction(name) => {
+                write!(f, "no function `{}` was compiled for the argument types", name)
+            },
+            VmError::NoRecords => write!(f, "aggregate over no records"),
+            VmError::InvalidIndex(index) => {
+                write!(
+                    f,
+                    "table type {} indexes or orders by a field it doesn't have, or orders by a string",
+                    index,
+                )
+            },
+        }
+    }
+}
+
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::Storage(error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl Vm {
-    pub fn new(module: Module) -> Self {
-        let table = TableTypes::Fn(module.functions);
-        let stack = 
-            vec![Value::Table(table)];
+    /// Creates a Vm for the module. Passing an `Arc<Module>` lets several
+    /// Vms run independently over the same code.
+    pub fn new(module: impl Into<Arc<Module>>) -> Self {
+        Vm::with_limits(module, Limits::unlimited())
+    }
+
+    pub fn with_limits(module: impl Into<Arc<Module>>, limits: Limits) -> Self {
+        Vm::build(module.into(), limits, false)
+    }
+
+    /// Creates a Vm which tags every slot with its kind and checks each
+    /// op reads slots of the kinds it expects. Slower, for debugging code
+    /// which didn't come from the compiler.
+    pub fn checked(module: impl Into<Arc<Module>>) -> Self {
+        Vm::checked_with_limits(module, Limits::unlimited())
+    }
+
+    pub fn checked_with_limits(module: impl Into<Arc<Module>>, limits: Limits) -> Self {
+        Vm::build(module.into(), limits, true)
+    }
+
+    fn build(module: Arc<Module>, limits: Limits, checked: bool) -> Self {
+        let mut handles = Handles::default();
+        let table = TableTypes::Fn(module.functions.clone());
+        let handle = handles.insert(Resource::Table(table));
 
+        // The function table sits below main's frame.
+        let tags = checked.then(|| vec![Kind::Table]);
+        let mut stack = Stack::from_parts(vec![handle as u64], tags, vec![0]);
+        stack.set_max(limits.max_stack);
+
+        // Returning from main leaves its result on the stack and halts.
         let bottom = RetInfo {
             instruction_pointer: 0,
             frame_ptr: 0,
-            ret_count: 0,
+            ret_count: 1,
         };
 
          Vm {
             frame_ptr: 1,
             instruction_pointer: module.start,
             stack,
-            types: module.types,
+            handles,
+            strings: Strings::default(),
+            tables: BTreeMap::new(),
+            transactions: Vec::new(),
             call_stack: vec![bottom],
-            code: module.code,
+            module,
+            state: State::Ready,
+            yielded: None,
+            tracer: None,
+            fuel: limits.fuel,
+            limits,
+        }
+    }
+
+    /// Adds fuel to a Vm created with a fuel limit. A Vm which stopped
+    /// with `VmError::OutOfFuel` can be `run` again once topped up.
+    pub fn add_fuel(&mut self, amount: u64) {
+        if let Some(fuel) = self.fuel.as_mut() {
+            *fuel = fuel.saturating_add(amount);
         }
     }
 
+    /// The remaining fuel, or None if execution is not metered.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Attaches a tracer which is called around every executed op,
+    /// replacing any previously attached tracer.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer<Op, Value>>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Detaches and returns the current tracer.
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer<Op, Value>>> {
+        self.tracer.take()
+    }
+
 
     pub fn stack_len(&self) -> usize {
         self.stack.len()
     }
 
-    pub fn stack<'a>(&'a self) -> &'a Vec<Value> {
-        &self.stack
+    /// The values on the stack, which are `Value::Untagged` unless the Vm
+    /// is checked.
+    pub fn stack(&self) -> Vec<Value> {
+        self.stack.values()
     }
 
-    pub fn code<'a>(&'a self) -> &'a Vec<Op> {
-        &self.code
+    pub fn is_checked(&self) -> bool {
+        self.stack.is_checked()
     }
 
-    pub fn stack_get<'a>(&'a self, index: usize) -> Option<&'a Value> {
-        self.stack.get(index)
+    /// Reads the top of the stack as a value of the kind.
+    pub fn top(&self, kind: Kind) -> Result<Value, VmError> {
+        let index = self.stack_index(1)?;
+        self.stack.decode(index, kind)
     }
 
-    pub fn run(&mut self) -> Result<(), VmError> {
+    /// Reads the top of the stack as a Rust value, such as the result
+    /// `main` leaves once the Vm has halted.
+    pub fn result<T: Slot>(&self) -> Result<T, VmError> {
+        self.stack.peek_slot(T::KIND).map(T::from_bits)
+    }
+
+    /// Interns the string, returning a ref to pass to the Vm.
+    pub fn intern(&mut self, text: &str) -> Value {
+        Value::StringRef { index: self.strings.intern(text) }
+    }
+
+    /// The text of a string ref from the Vm.
+    pub fn string(&self, value: &Value) -> Option<&str> {
+        match value {
+            Value::StringRef { index } => self.strings.get(*index),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &Vec<Op> {
+        &self.module.code
+    }
+
+    pub fn module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    pub fn stack_get(&self, index: usize) -> Option<Value> {
+        self.stack.value(index)
+    }
+
+    /// Runs until the Vm halts or yields.
+    pub fn run(&mut self) -> Result<Status, VmError> {
+        match self.state {
+            State::Ready => {},
+            State::Suspended => return Err(VmError::Suspended),
+            State::Halted => return Ok(Status::Halted),
+            State::Failed => return Err(VmError::Failed),
+        }
 
         let mut halt = false;
 
-        while halt == false {
+        while !halt {
             halt = self.step()?;
         }
 
-        Ok(())
+        match self.yielded.take() {
+            Some(value) => {
+                self.state = State::Suspended;
+                Ok(Status::Yielded(value))
+            },
+            None => {
+                self.state = State::Halted;
+                Ok(Status::Halted)
+            },
+        }
+    }
+
+    /// Continues a Vm suspended by `Yield`, pushing `value` as the result
+    /// of the yield. Tables and cursors are only made by the Vm, so their
+    /// handles can't be passed in.
+    pub fn resume(&mut self, value: Value) -> Result<Status, VmError> {
+        if self.state == State::Failed {
+            return Err(VmError::Failed);
+        }
+        if self.state != State::Suspended {
+            return Err(VmError::NotSuspended);
+        }
+        if let Value::Table { .. } | Value::Cursor { .. } = value {
+            return Err(VmError::InvalidOperation);
+        }
+
+        self.stack.push_value(value)?;
+        self.state = State::Ready;
+        self.run()
     }
 
+    /// Executes one op, returning true if the Vm halted or yielded. An
+    /// error other than running out of fuel stops the Vm for good, rolling
+    /// back every open transaction.
     pub fn step(&mut self) -> Result<bool, VmError> {
+        if self.state == State::Failed {
+            return Err(VmError::Failed);
+        }
+
+        let result = self.step_op();
+        if let Err(error) = &result {
+            if !matches!(error, VmError::OutOfFuel) {
+                self.abort();
+                self.state = State::Failed;
+            }
+        }
+        result
+    }
+
+    fn step_op(&mut self) -> Result<bool, VmError> {
         let ptr = self.instruction_pointer;
+        let start_fuel = self.fuel;
+
+        // Checked before the op runs so that the Vm can be resumed at
+        // this instruction once more fuel is added.
+        if let Some(fuel) = self.fuel.as_mut() {
+            if *fuel == 0 {
+                return Err(VmError::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+
+        if ptr >= self.module.code.len() {
+            return Err(VmError::InvalidAddress(ptr));
+        }
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.before(ptr, &self.module.code[ptr], &self.stack.values());
+        }
+
+        let result = self.execute(ptr);
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.after(ptr, &self.module.code[ptr], &self.stack.values());
+        }
+
+        match result {
+            // An op burning fuel as it goes, such as deriving a table,
+            // runs out before it changes anything, so it runs again once
+            // more fuel is added. The fuel it burned is given back, so
+            // topping up adds to what the next attempt has rather than
+            // paying for a restart.
+            Err(VmError::OutOfFuel) => {
+                self.instruction_pointer = ptr;
+                self.fuel = start_fuel;
+                Err(VmError::OutOfFuel)
+            },
+            result => result,
+        }
+    }
+
+    /// Takes the Vm's table of the type, opening it if the Vm doesn't
+    /// hold one yet, and returns its handle. The table is in use until it
+    /// is put back.
+    fn take_table(&mut self, type_index: u32) -> Result<usize, VmError> {
+        let module = self.module.clone();
+        let Some(table_type) = module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index));
+        };
+
+        let held = match self.tables.get(&type_index) {
+            Some(None) => return Err(VmError::TableInUse(type_index)),
+            Some(Some(handle)) => Some(*handle),
+            None => None,
+        };
+
+        let handle = match held {
+            Some(handle) if !table_type.is_derived() => handle,
+            _ => {
+                // Derived tables are derived again each time they are
+                // taken, replacing the last derivation.
+                let table = if table_type.is_derived() {
+                    self.derive(type_index)?
+                } else {
+                    MemTable::open(type_index, table_type, self.limits.max_table_rows)?
+                };
+                if let Some(handle) = held {
+                    self.handles.take_table(handle)?;
+                }
+                self.handles.insert(Resource::Table(TableTypes::Mem(table)))
+            },
+        };
+        self.tables.insert(type_index, None);
+        Ok(handle)
+    }
+
+    /// Puts back the Vm's table of the type taken with `take_table`.
+    fn put_table(&mut self, type_index: u32, handle: usize) -> Result<(), VmError> {
+        if let Some(Some(_)) = self.tables.get(&type_index) {
+            return Err(VmError::InvalidOperation);
+        }
+        match self.handles.table_mut(handle)? {
+            TableTypes::Mem(table) if table.type_index == type_index => table.held = true,
+            TableTypes::Host(table) if table.type_index == type_index => {},
+            _ => return Err(VmError::TypeCheck),
+        }
+        self.tables.insert(type_index, Some(handle));
+        Ok(())
+    }
+
+    fn execute(&mut self, ptr: usize) -> Result<bool, VmError> {
         self.instruction_pointer += 1;
-        match &self.code[ptr] {
+        match &self.module.code[ptr] {
             Op::Noop => {},
 
             Op::Halt => {
@@ -278,35 +888,33 @@ impl Vm {
             },
 
             Op::Pop => {
-                self.pop()?;
+                if let Some(handle) = self.stack.drop_top()? {
+                    self.release(handle)?;
+                }
             },
 
             Op::PopN => {
-                let Value::Usize(count) = self.pop()? else {
-                    unreachable!();
-                };
-
-                let len = self.stack.len() - count;
-                self.stack.truncate(len);
+                let count = self.stack.pop::<usize>()?;
+                let len = self.stack_index(count)?;
+                self.truncate(len)?;
             },
 
             Op::Swap => {
 
-                let last = self.stack.len() - 1;
+                let last = self.stack_index(1)?;
 
-                let second = last - 1;
+                let second = self.stack_index(2)?;
 
                 self.stack.swap(last, second);
             },
 
             Op::SwapN => {
-                let Value::Usize(top_size) = self.pop()? else {
-                    unreachable!();
-                };
+                let top_size = self.stack.pop::<usize>()?;
+                let bottom_size = self.stack.pop::<usize>()?;
 
-                let Value::Usize(bottom_size) = self.pop()? else {
-                    unreachable!();
-                };
+                let total = top_size.checked_add(bottom_size)
+                    .ok_or(VmError::StackUnderflow)?;
+                self.stack_index(total)?;
 
                 let at = self.stack.len() - top_size;
                 let mut top = self.stack.split_off(at);
@@ -314,340 +922,487 @@ impl Vm {
                 let at = self.stack.len() - bottom_size;
                 let mut bottom = self.stack.split_off(at);
 
-                self.stack.append(&mut top);
-                self.stack.append(&mut bottom);
+                self.stack.append(&mut top)?;
+                self.stack.append(&mut bottom)?;
             }
 
             Op::Copy => {
-                let value = self.stack.last().unwrap();
-                let copy = self.copy_value(value)?;
-                self.stack.push(copy);
+                let index = self.stack_index(1)?;
+                self.stack.copy(index)?;
             },
 
             Op::CopyMany => {
-                let Value::Usize(count) = self.pop()? else {
-                    unreachable!();
-                };
+                let count = self.stack.pop::<usize>()?;
                 
-                let start = self.stack.len() - count;
+                let start = self.stack_index(count)?;
                 let end = self.stack.len();
 
                 for index in start..end {
-                    let value = self.stack.get(index).unwrap();
-                    let copy = self.copy_value(value)?;
-                    self.stack.push(copy);
+                    self.stack.copy(index)?;
                 }
             },
 
             Op::CopyFrom => {
-                let Value::Usize(depth) = self.pop()? else {
-                    return Err(VmError::TypeCheck);
-                };
+                let depth = self.stack.pop::<usize>()?;
+
+                if depth == 0 {
+                    return Err(VmError::StackUnderflow);
+                }
 
-                let index = self.stack.len() - depth;
-                let value = self.stack.get(index).unwrap();
-                let copy = self.copy_value(value)?;
-                self.stack.push(copy);
+                let index = self.stack_index(depth)?;
+                self.stack.copy(index)?;
             },
 
 
             Op::CopyManyFrom => {
-                let Value::Usize(depth) = self.pop()? else {
-                    return Err(VmError::TypeCheck);
-                };
+                let depth = self.stack.pop::<usize>()?;
+                let count = self.stack.pop::<usize>()?;
 
-                let Value::Usize(count) = self.pop()? else {
-                    unreachable!();
-                };
+                if depth == 0 {
+                    return Err(VmError::StackUnderflow);
+                }
 
-                let end = self.stack.len() - depth;
-                let start = end - count;
+                let end = self.stack_index(depth)?;
+                let start = end.checked_sub(count)
+                    .ok_or(VmError::StackUnderflow)?;
                 for index in start..(end + 1) {
-                    let value = self.stack.get(index).unwrap();
-                    let copy = self.copy_value(value)?;
-                    self.stack.push(copy);
+                    self.stack.copy(index)?;
                 }
             },
 
             Op::Load => {
-                let Value::Usize(offset) = self.pop()? else {
-                    unreachable!()
-                };
+                let offset = self.stack.pop::<usize>()?;
+                self.load(offset)?;
+            },
 
-                let index = offset + self.frame_ptr;
-                let value = self.stack.get(index).unwrap();
-                let copy = self.copy_value(value)?;
-                self.stack.push(copy);
+            Op::LoadImm(offset) => {
+                self.load(*offset)?;
             },
 
             Op::LoadN => {
-                let Value::Usize(offset) = self.pop()? else {
-                    unreachable!()
-                };
+                let offset = self.stack.pop::<usize>()?;
+                let count = self.stack.pop::<usize>()?;
 
-                let Value::Usize(count) = self.pop()? else {
-                    unreachable!()
-                };
+                let index = self.frame_index(offset)?;
+                if count > index + 1 {
+                    return Err(VmError::StackUnderflow);
+                }
 
-                let index = offset + self.frame_ptr;
                 for i in 0..count {
-                    let value = self.stack.get(index - i).unwrap();
-                    let copy = self.copy_value(value)?;
-                    self.stack.push(copy);
+                    self.stack.copy(index - i)?;
                 }
             },
 
             
             Op::Store => {
-                let Value::Usize(offset) = self.pop()? else {
-                    return Err(VmError::TypeCheck);
-                };
+                let offset = self.stack.pop::<usize>()?;
+                self.store(offset)?;
+            },
 
-                let to = offset + self.frame_ptr;
-                let from = self.stack.len() - 1;
-                self.stack.swap(from, to);
-               
-                self.pop()?;
+            Op::StoreImm(offset) => {
+                self.store(*offset)?;
             },
             
             Op::StoreN => {
-                let Value::Usize(offset) = self.pop()? else {
-                    return Err(VmError::TypeCheck);
-                };
+                let offset = self.stack.pop::<usize>()?;
+                let count = self.stack.pop::<usize>()?;
 
-                let Value::Usize(count) = self.pop()? else {
-                    return Err(VmError::TypeCheck);
-                };
-
-                let to = offset + self.frame_ptr;
+                // The values stored must all sit above the slots they are
+                // stored to.
+                let to = self.frame_index(offset)?;
                 let from = self.stack.len() - 1;
+                if count == 0 || count > to + 1 || to + count > from {
+                    return Err(VmError::StackUnderflow);
+                }
+
                 for i in 0..count {
                     self.stack.swap(from - i, to - i);
                 }
                
-                self.stack.truncate(from - (count -1) );
+                self.truncate(from - (count -1) )?;
             },
 
             Op::Call => {
-                let Value::Function{ptr: index} = self.pop()? else {
-                    unimplemented!()
-                };
-                let Value::Usize(arg_count) = self.pop()? else {
-                    unimplemented!()
-                };
-                let Value::Usize(ret_count) = self.pop()? else {
-                    unimplemented!()
-                };
+                let index = self.stack.pop_slot(Kind::Function)? as usize;
+                let arg_count = self.stack.pop::<usize>()?;
+                let ret_count = self.stack.pop::<usize>()?;
+                self.enter(index, arg_count, ret_count)?;
+            },
 
-                let ret = RetInfo {
-                    instruction_pointer: self.instruction_pointer,
-                    frame_ptr: self.frame_ptr,
-                    ret_count: ret_count,
-                };
-        
-                
-                self.frame_ptr = self.stack.len() - arg_count ;
-                self.instruction_pointer = index;
-                self.call_stack.push(ret);
+            Op::CallFn { ptr, args, rets } => {
+                self.enter(*ptr, *args, *rets)?;
             },
 
             Op::Return => {
-                let ret = self.call_stack.pop().unwrap();
-                //assert!(self.frame_ptr + ret.ret_count == (self.stack.len() - 1));
+                let Some(ret) = self.call_stack.pop() else {
+                    return Err(VmError::InvalidOperation);
+                };
+
+                let at = match self.stack.len().checked_sub(ret.ret_count) {
+                    Some(at) if at >= self.frame_ptr => at,
+                    _ => return Err(VmError::InvalidOperation),
+                };
+
+                let mut values = self.stack.split_off(at);
+                self.truncate(self.frame_ptr)?;
+                self.stack.append(&mut values)?;
+
                 self.instruction_pointer = ret.instruction_pointer;
-                self.frame_ptr = ret.frame_ptr;            }
+                self.frame_ptr = ret.frame_ptr;
+            }
 
             Op::Table => {
-                todo!();
+                let index = self.stack.pop::<usize>()?;
+
+                // An index past u32::MAX names no type, rather than the
+                // type its low bits would.
+                let type_index = u32::try_from(index)
+                    .map_err(|_| VmError::UnknownType(u32::MAX))?;
+                let Some(table_type) = self.module.types.get(&type_index) else {
+                    return Err(VmError::UnknownType(type_index));
+                };
+
+                let table = MemTable::new(
+                    type_index, 
+                    table_type, 
+                    self.limits.max_table_rows
+                )?;
+                let handle = self.handles.insert(Resource::Table(TableTypes::Mem(table)));
+                self.stack.push_slot(Kind::Table, handle as u64)?;
+            },
+
+            Op::TakeTable(type_index) => {
+                let handle = self.take_table(*type_index)?;
+                self.stack.push_slot(Kind::Table, handle as u64)?;
             },
 
+            Op::PutTable(type_index) => {
+                let handle = self.stack.pop_slot(Kind::Table)? as usize;
+                self.put_table(*type_index, handle)?;
+            },
+
+            Op::SetTable(type_index) => {
+                let handle = self.stack.pop_slot(Kind::Table)? as usize;
+                self.set_table(*type_index, handle)?;
+            },
+
+            Op::Begin => self.begin()?,
+
+            Op::Commit => self.commit()?,
+
+            Op::Rollback => self.rollback()?,
+
             Op::Query => {
-                let Some(Value::Struct {field_count}) = self.stack.pop() else {
-                    unimplemented!()
-                };
+                let free = self.stack.pop::<usize>()?;
+                let field_count = self.stack.pop_slot(Kind::Struct)? as usize;
 
-                let at = self.stack.len() - field_count;
-                let mut fields = self.stack.split_off(at);
+                let at = self.stack_index(field_count)?;
+                let table_index = at.checked_sub(1).ok_or(VmError::StackUnderflow)?;
+                let handle = self.stack.get_slot(table_index, Kind::Table)? as usize;
 
-                let Some(Value::Table(table)) = self.stack.pop() else {
-                    unimplemented!()
-                };
+                let schema = self.handles.table(handle)?.schema();
+                let mut fields = Self::read_fields(&self.stack, at, field_count, schema, free)?;
+                self.truncate(table_index + 1)?;
+                self.stack.pop_slot(Kind::Table)?;
 
-                let cursor = table.find(&mut fields);
+                let table = self.handles.take_table(handle)?;
+                let cursor = table.find(&mut fields)?;
 
-                self.stack.push(Value::Cursor(cursor));                
+                let handle = self.handles.insert(Resource::Cursor(cursor));
+                self.stack.push_slot(Kind::Cursor, handle as u64)?;
             }
 
+            Op::Select(filters) => {
+                let operand_count = filters.iter().map(Filter::operand_count).sum();
+                let at = self.stack_index(operand_count)?;
+                let table_index = at.checked_sub(1).ok_or(VmError::StackUnderflow)?;
+                let handle = self.stack.get_slot(table_index, Kind::Table)? as usize;
+
+                let schema = self.handles.table(handle)?.schema();
+                let query = Self::read_filters(&self.stack, &self.strings, at, filters, schema)?;
+                self.truncate(table_index + 1)?;
+                self.stack.pop_slot(Kind::Table)?;
+
+                let table = self.handles.take_table(handle)?;
+                let cursor = self.select(table, query)?;
+
+                let handle = self.handles.insert(Resource::Cursor(cursor));
+                self.stack.push_slot(Kind::Cursor, handle as u64)?;
+            },
+
             Op::Found => {
-                todo!()
+                let handle = self.stack.peek_slot(Kind::Cursor)? as usize;
+                let found = self.handles.cursor(handle)?.found();
+                self.stack.push(found)?;
             }
 
             Op::Read => {
-                let Some(Value::Cursor(cursor)) = self.stack.pop() else {
-                    unimplemented!()
-                };
+                let handle = self.stack.peek_slot(Kind::Cursor)? as usize;
+
+                let mut record = Vec::new();
+                self.handles.cursor(handle)?.read(&mut record)?;
 
-                cursor.read(&mut self.stack)?;
-                self.stack.push(Value::Cursor(cursor));
+                // The record goes under the cursor, so nothing is pushed
+                // unless all of it fits.
+                self.stack.reserve(record.len())?;
+                self.stack.drop_top()?;
+                for value in record {
+                    self.stack.push_value(value)?;
+                }
+                self.stack.push_slot(Kind::Cursor, handle as u64)?;
             },
 
             Op::Insert => {
-                todo!();
+                let (cursor, mut record) = self.cursor_under_struct()?;
+                cursor.insert(&mut record)?;
             },
 
             Op::Update => {
-                todo!();
+                let (cursor, mut record) = self.cursor_under_struct()?;
+                cursor.update(&mut record)?;
             },
 
             Op::Delete => {
-                todo!()
+                let handle = self.stack.peek_slot(Kind::Cursor)? as usize;
+                self.check_host_write(handle)?;
+                self.track_cursor(handle)?;
+                self.handles.cursor_mut(handle)?.delete()?;
             },
 
             Op::Advance => {
-                todo!();
+                let handle = self.stack.peek_slot(Kind::Cursor)? as usize;
+                self.handles.cursor_mut(handle)?.advance()?;
             },
 
             Op::Close => {
-                todo!();
+                let handle = self.stack.pop_slot(Kind::Cursor)? as usize;
+                let table = self.handles.take_cursor(handle)?.close();
+
+                let handle = self.handles.insert(Resource::Table(table));
+                self.stack.push_slot(Kind::Table, handle as u64)?;
             }
 
+            Op::Aggregate(aggregates) => {
+                let aggregates = aggregates.clone();
+                self.aggregate(&aggregates)?;
+            },
+
+            Op::Group { keys, columns, type_index } => {
+                let (keys, columns, type_index) = (keys.clone(), columns.clone(), *type_index);
+                self.group(&keys, &columns, type_index)?;
+            },
+
+            Op::Order { order, offset, limit } => {
+                let (order, offset, limit) = (order.clone(), *offset, *limit);
+                self.order(&order, offset, limit)?;
+            },
+
+            Op::DropTable => {
+                let handle = self.stack.pop_slot(Kind::Table)? as usize;
+                self.drop_table(handle)?;
+            },
+
+            Op::Jump(target) => {
+                self.instruction_pointer = *target;
+            },
+
+            Op::JumpIfFalse(target) => {
+                let target = *target;
+                let condition = self.stack.pop::<bool>()?;
+
+                if !condition {
+                    self.instruction_pointer = target;
+                }
+            },
+
             Op::None => {
-                self.stack.push(Value::None);
+                self.stack.push_slot(Kind::None, 0)?;
             },
 
             Op::Fn(v) => {
-                self.stack.push(Value::Function { ptr: *v });
+                self.stack.push_slot(Kind::Function, *v as u64)?;
             },
 
             Op::F32(value) => {
-                self.stack.push(Value::F32(*value));
+                self.stack.push(*value)?;
             },
 
             Op::F64(value) => {
-                self.stack.push(Value::F64(*value));
+                self.stack.push(*value)?;
             },
             Op::I32(value) => {
-                self.stack.push(Value::I32(*value));
+                self.stack.push(*value)?;
             },
 
             Op::I64(value) => {
-                self.stack.push(Value::I64(*value));
+                self.stack.push(*value)?;
             },
             Op::U32(value) => {
-                self.stack.push(Value::U32(*value));
+                self.stack.push(*value)?;
             },
 
             Op::U64(value) => {
-                self.stack.push(Value::U64(*value));
+                self.stack.push(*value)?;
             },
 
             Op::Usize(value) => {
-                self.stack.push(Value::Usize(*value));
+                self.stack.push(*value)?;
+            },
+
+            Op::Str(text) => {
+                let index = self.strings.intern(text);
+                self.stack.push_slot(Kind::StringRef, index as u64)?;
             },
 
             Op::Bool(value) => {
-                self.stack.push(Value::Bool(*value));
+                self.stack.push(*value)?;
             },
 
             Op::Struct => {
-                let Value::Usize(field_count) = self.stack.pop().unwrap() else {
-                    unreachable!();
-                };
-
-                self.stack.push(Value::Struct{field_count});
+                let field_count = self.stack.pop::<usize>()?;
+                self.stack.push_slot(Kind::Struct, field_count as u64)?;
             },
 
             Op::AddF32 => {
-                let Value::F32(a) = self.pop()? else {
-                    unreachable!()
-                };
-                let Value::F32(b) = self.pop()? else {
-                    unreachable!()
-                };
-                self.stack.push(Value::F32(a+b));
+                let a = self.stack.pop::<f32>()?;
+                let b = self.stack.pop::<f32>()?;
+                self.stack.push(a + b)?;
             },
 
             Op::AddF64 => {
-                let Value::F64(a) = self.pop()? else {
-                    unreachable!()
-                };
-                let Value::F64(b) = self.pop()? else {
-                    unreachable!()
-                };
-                self.stack.push(Value::F64(a+b));
+                let a = self.stack.pop::<f64>()?;
+                let b = self.stack.pop::<f64>()?;
+                self.stack.push(a + b)?;
             },
 
             
             Op::AddU32 => {
-                let Value::U32(a) = self.pop()? else {
-                    unreachable!()
-                };
-                let Value::U32(b) = self.pop()? else {
-                    unreachable!()
-                };
-                self.stack.push(Value::U32(a+b));
+                let a = self.stack.pop::<u32>()?;
+                let b = self.stack.pop::<u32>()?;
+                self.stack.push(a.wrapping_add(b))?;
             },
 
             Op::AddU64 => {
-                let Value::U64(a) = self.pop()? else {
-                    unreachable!()
-                };
-                let Value::U64(b) = self.pop()? else {
-                    unreachable!()
-                };
-                self.stack.push(Value::U64(a+b));
+                let a = self.stack.pop::<u64>()?;
+                let b = self.stack.pop::<u64>()?;
+                self.stack.push(a.wrapping_add(b))?;
             },
             
             Op::AddI32 => {
-                let Value::I32(a) = self.pop()? else {
-                    unreachable!()
-                };
-                let Value::I32(b) = self.pop()? else {
-                    unreachable!()
-                };
-                self.stack.push(Value::I32(a+b));
+                let a = self.stack.pop::<i32>()?;
+                let b = self.stack.pop::<i32>()?;
+                self.stack.push(a.wrapping_add(b))?;
+            },
+
+            Op::AddImmI64(b) => {
+                let a = self.stack.pop::<i64>()?;
+                self.stack.push(a.wrapping_add(*b))?;
             },
 
             Op::AddI64 => {
-                let Value::I64(a) = self.pop()? else {
-                    unreachable!()
-                };
-                let Value::I64(b) = self.pop()? else {
-                    unreachable!()
-                };
-                self.stack.push(Value::I64(a+b));
+                let a = self.stack.pop::<i64>()?;
+                let b = self.stack.pop::<i64>()?;
+                self.stack.push(a.wrapping_add(b))?;
+            },
+
+            Op::SubF32 => {
+                let b = self.stack.pop::<f32>()?;
+                let a = self.stack.pop::<f32>()?;
+                self.stack.push(a - b)?;
+            },
+
+            Op::SubF64 => {
+                let b = self.stack.pop::<f64>()?;
+                let a = self.stack.pop::<f64>()?;
+                self.stack.push(a - b)?;
+            },
+
+            Op::SubU32 => {
+                let b = self.stack.pop::<u32>()?;
+                let a = self.stack.pop::<u32>()?;
+                self.stack.push(a.wrapping_sub(b))?;
+            },
+
+            Op::SubU64 => {
+                let b = self.stack.pop::<u64>()?;
+                let a = self.stack.pop::<u64>()?;
+                self.stack.push(a.wrapping_sub(b))?;
+            },
+
+            Op::SubI32 => {
+                let b = self.stack.pop::<i32>()?;
+                let a = self.stack.pop::<i32>()?;
+                self.stack.push(a.wrapping_sub(b))?;
+            },
+
+            Op::SubI64 => {
+                let b = self.stack.pop::<i64>()?;
+                let a = self.stack.pop::<i64>()?;
+                self.stack.push(a.wrapping_sub(b))?;
+            },
+
+            Op::LtF32 => {
+                let b = self.stack.pop::<f32>()?;
+                let a = self.stack.pop::<f32>()?;
+                self.stack.push(a < b)?;
+            },
+
+            Op::LtF64 => {
+                let b = self.stack.pop::<f64>()?;
+                let a = self.stack.pop::<f64>()?;
+                self.stack.push(a < b)?;
+            },
+
+            Op::LtU32 => {
+                let b = self.stack.pop::<u32>()?;
+                let a = self.stack.pop::<u32>()?;
+                self.stack.push(a < b)?;
+            },
+
+            Op::LtU64 => {
+                let b = self.stack.pop::<u64>()?;
+                let a = self.stack.pop::<u64>()?;
+                self.stack.push(a < b)?;
+            },
+
+            Op::LtI32 => {
+                let b = self.stack.pop::<i32>()?;
+                let a = self.stack.pop::<i32>()?;
+                self.stack.push(a < b)?;
+            },
+
+            Op::LtI64 => {
+                let b = self.stack.pop::<i64>()?;
+                let a = self.stack.pop::<i64>()?;
+                self.stack.push(a < b)?;
+            },
+
+            Op::Eq(kind) => {
+                let kind = *kind;
+                let b = Value::from_slot(kind, self.stack.pop_slot(kind)?);
+                let a = Value::from_slot(kind, self.stack.pop_slot(kind)?);
+                self.stack.push(Self::eq_value(&a, &b)?)?;
+            },
+
+            Op::Yield(kind) => {
+                let kind = *kind;
+                let bits = self.stack.pop_slot(kind)?;
+                self.yielded = Some(Value::from_slot(kind, bits));
+                return Ok(true);
             },
         }
         Ok(false)
     }
     
-    fn copy_value( &self, value: &Value) -> Result<Value, VmError> {
-        let result = match value {
-            Value::None => Value::None,
-            Value::F32(v) => Value::F32(*v),
-            Value::F64(v) => Value::F64(*v),
-            Value::I32(v) => Value::I32(*v),
-            Value::I64(v) => Value::I64(*v),
-            Value::U32(v) => Value::U32(*v),
-            Value::U64(v) => Value::U64(*v),
-            Value::Usize(v) => Value::Usize(*v),
-            Value::StringRef{index: _} => {
-                return Err(VmError::InvalidOperation)
-            },
-            Value::Bool(v) => Value::Bool(*v),
-            Value::Struct {field_count} => Value::Struct { field_count: *field_count }, 
-            Value::Table (_) => {
-                return Err(VmError::InvalidOperation)
-            },
-            Value::Cursor(_) => {
-                return Err(VmError::InvalidOperation)
-            },
-            Value::Function {ptr} => Value::Function { ptr:*ptr },
-        };
-        Ok(result)
+    pub(crate) fn copy_value(value: &Value) -> Result<Value, VmError> {
+        match value {
+            Value::Table { .. } => Err(VmError::InvalidOperation),
+            Value::Cursor { .. } => Err(VmError::InvalidOperation),
+            value => Ok(*value),
+        }
     }
 
-    fn eq_value(a: &Value, b: &Value) -> Result<bool, VmError> {
+    pub(crate) fn eq_value(a: &Value, b: &Value) -> Result<bool, VmError> {
         let result = match (a, b) {
             (Value::None, Value::None) => false,
             (Value::F32(x), Value::F32(y)) => *x == *y,
@@ -661,8 +1416,8 @@ impl Vm {
             (Value::Bool(x), Value::Bool(y)) => *x == *y,
             (Value::Struct {field_count: x}, Value::Struct {field_count: y}) => 
                 *x == *y, 
-            (Value::Table (_), Value::Table (_)) => return Err(VmError::InvalidOperation),
-            (Value::Cursor(_), Value::Cursor(_)) => return Err(VmError::InvalidOperation),
+            (Value::Table { .. }, Value::Table { .. }) => return Err(VmError::InvalidOperation),
+            (Value::Cursor { .. }, Value::Cursor { .. }) => return Err(VmError::InvalidOperation),
             (Value::Function {ptr: x}, Value::Function {ptr: y}) => 
                 *x == *y,
             _ => {
@@ -672,9 +1427,205 @@ impl Vm {
         Ok(result)
     }
 
-    fn pop(&mut self) -> Result<Value, VmError> {
-        Ok(self.stack.pop().unwrap())
+    /// Reads the `count` fields starting at `at` as values of the schema's
+    /// types. Fields with their bit set in `free` are read as None.
+    fn read_fields(stack: &Stack, at: usize, count: usize, schema: &[Type], free: usize)
+    -> Result<Vec<Value>, VmError> {
+        if count != schema.len() {
+            return Err(VmError::TypeCheck);
+        }
+
+        schema.iter().enumerate().map(|(i, field_type)| {
+            if i < usize::BITS as usize && free & (1 << i) != 0 {
+                return Ok(Value::None);
+            }
+
+            // Fields of an unknown type can only be read from a checked
+            // stack which knows what they hold.
+            let kind = Kind::of(field_type)
+                .or_else(|| stack.kind(at + i))
+                .ok_or(VmError::TypeCheck)?;
+            stack.decode(at + i, kind)
+        }).collect()
+    }
+
+    /// Reads the operands of the filters starting at `at` as constraints
+    /// on the schema's fields. Tests of strings other than equality are
+    /// made against their text.
+    fn read_filters(stack: &Stack, strings: &Strings, at: usize, filters: &[Filter], schema: &[Type])
+    -> Result<Vec<Constraint<Value>>, VmError> {
+        let mut query = vec![Constraint::Any; schema.len()];
+        let mut operand = at;
+
+        for filter in filters {
+            let field_type = schema.get(filter.field).ok_or(VmError::TypeCheck)?;
+            let kind = Kind::of(field_type)
+                .or_else(|| stack.kind(operand))
+                .ok_or(VmError::TypeCheck)?;
+
+            let mut operands = Vec::with_capacity(filter.operand_count());
+            for _ in 0..filter.operand_count() {
+                operands.push(stack.decode(operand, kind)?);
+                operand += 1;
+            }
+
+            let constraint = if kind == Kind::StringRef {
+                let text = |value: &Value| match value {
+                    Value::StringRef { index } => strings.get(*index)
+                        .map(str::to_string)
+                        .ok_or(VmError::InvalidOperation),
+                    _ => Err(VmError::TypeCheck),
+                };
+
+                match filter.test {
+                    Test::Eq => Constraint::Eq(operands[0]),
+                    Test::In(_) => Constraint::In(operands),
+                    Test::Prefix => Constraint::Prefix(text(&operands[0])?),
+                    Test::Lt => Constraint::TextRange(Bound::Unbounded, Bound::Excluded(text(&operands[0])?)),
+                    Test::Le => Constraint::TextRange(Bound::Unbounded, Bound::Included(text(&operands[0])?)),
+                    Test::Gt => Constraint::TextRange(Bound::Excluded(text(&operands[0])?), Bound::Unbounded),
+                    Test::Ge => Constraint::TextRange(Bound::Included(text(&operands[0])?), Bound::Unbounded),
+                }
+            } else {
+                let value = operands.first().copied();
+                match (filter.test, value) {
+                    (Test::In(_), _) => Constraint::In(operands),
+                    (Test::Eq, Some(value)) => Constraint::Eq(value),
+                    (Test::Lt, Some(value)) => Constraint::Range(Bound::Unbounded, Bound::Excluded(value)),
+                    (Test::Le, Some(value)) => Constraint::Range(Bound::Unbounded, Bound::Included(value)),
+                    (Test::Gt, Some(value)) => Constraint::Range(Bound::Excluded(value), Bound::Unbounded),
+                    (Test::Ge, Some(value)) => Constraint::Range(Bound::Included(value), Bound::Unbounded),
+                    // Only strings have prefixes.
+                    _ => return Err(VmError::TypeCheck),
+                }
+            };
+
+            let field = &mut query[filter.field];
+            *field = std::mem::replace(field, Constraint::Any).and_text(constraint, strings);
+        }
+
+        Ok(query)
+    }
+
+    /// Opens a cursor at the first record of the table meeting the query.
+    /// The Vm's own tables hold refs to its strings, so it gives them the
+    /// text for the query's constraints on text.
+    fn select(&self, table: TableTypes, mut query: Vec<Constraint<Value>>)
+    -> Result<CursorTypes, VmError> {
+        if let TableTypes::Mem(table) = &table {
+            table.resolve_text(&mut query, &self.strings);
+        }
+        table.select(query)
     }
 
+    /// Finds the cursor below the struct on the top of the stack and pops
+    /// the struct as a record of the cursor's table, leaving the cursor
+    /// in place. The record is to be written through the cursor.
+    fn cursor_under_struct(&mut self) -> Result<(&mut CursorTypes, Vec<Value>), VmError> {
+        let field_count = self.stack.peek_slot(Kind::Struct)? as usize;
+
+        let index = field_count.checked_add(2)
+            .and_then(|depth| self.stack.len().checked_sub(depth))
+            .ok_or(VmError::TypeCheck)?;
+        let handle = self.stack.get_slot(index, Kind::Cursor)? as usize;
+        self.check_host_write(handle)?;
+        self.track_cursor(handle)?;
+
+        let schema = self.handles.cursor(handle)?.schema();
+        let mut record = Self::read_fields(&self.stack, index + 1, field_count, schema, 0)?;
+        record.push(Value::Struct { field_count });
+        self.truncate(index + 1)?;
+
+        Ok((self.handles.cursor_mut(handle)?, record))
+    }
+
+    /// The index of the value `depth` down from the top of the stack where
+    /// a depth of 1 is the top value. A depth of 0 gives the stack length,
+    /// the start of an empty run of values.
+    fn stack_index(&self, depth: usize) -> Result<usize, VmError> {
+        self.stack.len().checked_sub(depth).ok_or(VmError::StackUnderflow)
+    }
+
+    /// The index of the frame slot at `offset`, which must be on the stack.
+    fn frame_index(&self, offset: usize) -> Result<usize, VmError> {
+        match self.frame_ptr.checked_add(offset) {
+            Some(index) if index < self.stack.len() => Ok(index),
+            _ => Err(VmError::StackUnderflow),
+        }
+    }
+
+    /// Copies the var at the frame offset to the top of the stack.
+    fn load(&mut self, offset: usize) -> Result<(), VmError> {
+        let index = self.frame_index(offset)?;
+        self.stack.copy(index)
+    }
+
+    /// Moves the top of the stack in to the var at the frame offset.
+    fn store(&mut self, offset: usize) -> Result<(), VmError> {
+        let to = self.frame_index(offset)?;
+        let from = self.stack.len() - 1;
+        if to == from {
+            return Err(VmError::StackUnderflow);
+        }
+        self.stack.swap(from, to);
+        if let Some(handle) = self.stack.drop_top()? {
+            self.release(handle)?;
+        }
+        Ok(())
+    }
 
+    /// Drops the slots from `len` up, releasing any handles they held.
+    fn truncate(&mut self, len: usize) -> Result<(), VmError> {
+        for handle in self.stack.truncate(len) {
+            self.release(handle)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the table or cursor of a handle whose slot was dropped. A
+    /// cursor is closed, and the Vm's own table of a type, taken with
+    /// `TakeTable`, is put back so it can be taken again.
+    fn release(&mut self, handle: usize) -> Result<(), VmError> {
+        let table = match self.handles.get(handle) {
+            Some(Resource::Cursor(_)) => self.handles.take_cursor(handle)?.close(),
+            Some(Resource::Table(_)) => self.handles.take_table(handle)?,
+            None => return Err(VmError::InvalidHandle(handle)),
+        };
+
+        let type_index = match &table {
+            TableTypes::Mem(table) if table.held => Some(table.type_index),
+            TableTypes::Host(table) => Some(table.type_index),
+            _ => None,
+        };
+        if let Some(type_index) = type_index {
+            if let Some(None) = self.tables.get(&type_index) {
+                let handle = self.handles.insert(Resource::Table(table));
+                self.tables.insert(type_index, Some(handle));
+            }
+        }
+        Ok(())
+    }
+
+    fn enter(&mut self, index: usize, arg_count: usize, ret_count: usize) -> Result<(), VmError> {
+        if let Some(max_depth) = self.limits.max_call_depth {
+            // The bottom entry returns from main.
+            if self.call_stack.len() > max_depth {
+                return Err(VmError::CallDepthExceeded);
+            }
+        }
+
+        let frame_ptr = self.stack.len().checked_sub(arg_count)
+            .ok_or(VmError::StackUnderflow)?;
+
+        let ret = RetInfo {
+            instruction_pointer: self.instruction_pointer,
+            frame_ptr: self.frame_ptr,
+            ret_count,
+        };
+
+        self.frame_ptr = frame_ptr;
+        self.instruction_pointer = index;
+        self.call_stack.push(ret);
+        Ok(())
+    }
 }
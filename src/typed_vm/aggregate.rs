@@ -0,0 +1,258 @@
+//! Aggregates over the records a query matches. `Op::Aggregate` reads the
+//! records from a cursor and computes a value from them for each
+//! aggregate, `Op::Group` computes them for each group of records with
+//! equal key fields and returns the results as a new table, one record
+//! per group.
+//!
+//! `Op::SetTable` makes a table, such as the one `Op::Group` returns, the
+//! Vm's table of its type so scripts can query it like any other.
+
+use super::*;
+use self::table::{compare, key, MemTable};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// A value computed from the records a query matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    /// The number of records, an I64.
+    Count,
+    /// The sum of the field, wrapping on overflow. Zero for no records.
+    Sum(usize),
+    /// The least value of the field, skipping NaNs.
+    Min(usize),
+    /// The greatest value of the field, skipping NaNs.
+    Max(usize),
+    /// The mean of the field, an F64.
+    Avg(usize),
+    /// The value of a field the records are grouped by.
+    Key(usize),
+}
+
+impl Aggregate {
+    /// The type of the aggregate's value over records of the schema, None
+    /// if it can't be computed over the field.
+    pub fn result_type(&self, schema: &[Type]) -> Option<Type> {
+        let numeric = |field_type: &Type| matches!(
+            field_type,
+            Type::Usize | Type::F32 | Type::F64 | Type::U32 | Type::U64 | Type::I32 | Type::I64
+        );
+
+        match *self {
+            Aggregate::Count => Some(Type::I64),
+            Aggregate::Sum(field) => schema.get(field).filter(|t| numeric(t)).cloned(),
+            Aggregate::Min(field) | Aggregate::Max(field) => schema.get(field)
+                .filter(|t| numeric(t) || **t == Type::Bool)
+                .cloned(),
+            Aggregate::Avg(field) => schema.get(field).filter(|t| numeric(t)).map(|_| Type::F64),
+            Aggregate::Key(field) => schema.get(field).cloned(),
+        }
+    }
+
+    /// Computes the aggregate over the rows, which have been checked to
+    /// have a field of a type it can be computed over.
+    fn compute(&self, rows: &[Vec<Value>], schema: &[Type]) -> Result<Value, VmError> {
+        match *self {
+            Aggregate::Count => Ok(Value::I64(rows.len() as i64)),
+            Aggregate::Sum(field) => {
+                let zero = Value::from_slot(Kind::of(&schema[field]).unwrap(), 0);
+                rows.iter().try_fold(zero, |sum, row| add(sum, row[field]))
+            },
+            Aggregate::Min(field) => extreme(rows, field, Ordering::Less),
+            Aggregate::Max(field) => extreme(rows, field, Ordering::Greater),
+            Aggregate::Avg(field) => {
+                if rows.is_empty() {
+                    return Err(VmError::NoRecords);
+                }
+                let sum: f64 = rows.iter().map(|row| as_f64(row[field])).sum();
+                Ok(Value::F64(sum / rows.len() as f64))
+            },
+            Aggregate::Key(field) => rows.first()
+                .map(|row| row[field])
+                .ok_or(VmError::NoRecords),
+        }
+    }
+}
+
+fn add(a: Value, b: Value) -> Result<Value, VmError> {
+    let sum = match (a, b) {
+        (Value::Usize(a), Value::Usize(b)) => Value::Usize(a.wrapping_add(b)),
+        (Value::F32(a), Value::F32(b)) => Value::F32(a + b),
+        (Value::F64(a), Value::F64(b)) => Value::F64(a + b),
+        (Value::U32(a), Value::U32(b)) => Value::U32(a.wrapping_add(b)),
+        (Value::U64(a), Value::U64(b)) => Value::U64(a.wrapping_add(b)),
+        (Value::I32(a), Value::I32(b)) => Value::I32(a.wrapping_add(b)),
+        (Value::I64(a), Value::I64(b)) => Value::I64(a.wrapping_add(b)),
+        _ => return Err(VmError::TypeCheck),
+    };
+    Ok(sum)
+}
+
+fn as_f64(value: Value) -> f64 {
+    match value {
+        Value::Usize(v) => v as f64,
+        Value::F32(v) => v as f64,
+        Value::F64(v) => v,
+        Value::U32(v) => v as f64,
+        Value::U64(v) => v as f64,
+        Value::I32(v) => v as f64,
+        Value::I64(v) => v as f64,
+        _ => f64::NAN,
+    }
+}
+
+/// The value of the field ordered first by `order`, Less for the least.
+fn extreme(rows: &[Vec<Value>], field: usize, order: Ordering) -> Result<Value, VmError> {
+    let mut best: Option<Value> = None;
+    for row in rows {
+        let value = row[field];
+        // NaNs aren't ordered, even against themselves.
+        if compare(&value, &value).is_none() {
+            continue;
+        }
+        if best.is_none_or(|best| compare(&value, &best) == Some(order)) {
+            best = Some(value);
+        }
+    }
+    best.ok_or(VmError::NoRecords)
+}
+
+/// Checks each aggregate can be computed over records of the schema.
+fn check(aggregates: &[Aggregate], schema: &[Type]) -> Result<Vec<Type>, VmError> {
+    aggregates.iter()
+        .map(|aggregate| aggregate.result_type(schema).ok_or(VmError::TypeCheck))
+        .collect()
+}
+
+impl Vm {
+    /// Reads the records from the cursor to the end of its query, then
+    /// closes it, pushing the table.
+    pub(super) fn drain(&mut self) -> Result<(Vec<Vec<Value>>, Vec<Type>), VmError> {
+        let handle = self.stack.pop_slot(Kind::Cursor)? as usize;
+        let mut cursor = self.handles.take_cursor(handle)?;
+        let schema = cursor.schema().to_vec();
+
+        let mut rows = Vec::new();
+        while cursor.found() {
+            let mut record = Vec::new();
+            cursor.read(&mut record)?;
+            record.pop();
+            rows.push(record);
+            cursor.advance()?;
+        }
+
+        let handle = self.handles.insert(Resource::Table(cursor.close()));
+        self.stack.push_slot(Kind::Table, handle as u64)?;
+        Ok((rows, schema))
+    }
+
+    pub(super) fn aggregate(&mut self, aggregates: &[Aggregate]) -> Result<(), VmError> {
+        let schema = self.handles.cursor(self.stack.peek_slot(Kind::Cursor)? as usize)?.schema();
+        check(aggregates, schema)?;
+        if aggregates.iter().any(|aggregate| matches!(aggregate, Aggregate::Key(_))) {
+            return Err(VmError::InvalidOperation);
+        }
+
+        let (rows, schema) = self.drain()?;
+        let table = self.stack.pop_slot(Kind::Table)?;
+        for aggregate in aggregates {
+            let value = aggregate.compute(&rows, &schema)?;
+            self.stack.push_value(value)?;
+        }
+        self.stack.push_value(Value::Struct { field_count: aggregates.len() })?;
+        self.stack.push_slot(Kind::Table, table)?;
+        Ok(())
+    }
+
+    pub(super) fn group(&mut self, keys: &[usize], columns: &[Aggregate], type_index: u32)
+    -> Result<(), VmError> {
+        let Some(table_type) = self.module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index));
+        };
+        let schema = self.handles.cursor(self.stack.peek_slot(Kind::Cursor)? as usize)?.schema();
+        let valid = keys.iter().all(|field| *field < schema.len())
+            && columns.iter().all(|column| match column {
+                Aggregate::Key(field) => keys.contains(field),
+                _ => true,
+            });
+        if !valid {
+            return Err(VmError::InvalidOperation);
+        }
+        if check(columns, schema)? != table_type.fields {
+            return Err(VmError::TypeCheck);
+        }
+
+        let mut table = MemTable::new(type_index, table_type, self.limits.max_table_rows)?;
+        let (rows, schema) = self.drain()?;
+
+        // Groups in the order their first record was read.
+        let mut groups: Vec<Vec<Vec<Value>>> = Vec::new();
+        let mut positions: HashMap<Vec<u64>, usize> = HashMap::new();
+        for row in rows {
+            let group_key = keys.iter().map(|field| key(&row[*field])).collect();
+            let position = *positions.entry(group_key).or_insert_with(|| {
+                groups.push(Vec::new());
+                groups.len() - 1
+            });
+            groups[position].push(row);
+        }
+
+        for rows in groups {
+            if self.limits.max_table_rows.is_some_and(|max_rows| table.rows.len() >= max_rows) {
+                return Err(VmError::TableFull);
+            }
+            let row = columns.iter()
+                .map(|column| column.compute(&rows, &schema))
+                .collect::<Result<_, _>>()?;
+            table.push_row(row);
+        }
+
+        let handle = self.handles.insert(Resource::Table(TableTypes::Mem(table)));
+        self.stack.push_slot(Kind::Table, handle as u64)?;
+        Ok(())
+    }
+
+    /// Makes the table the Vm's table of the type, dropping the one the Vm
+    /// held. A table stored in a file keeps its file, compacted to hold
+    /// the new table's rows.
+    pub(super) fn set_table(&mut self, type_index: u32, handle: usize) -> Result<(), VmError> {
+        let module = self.module.clone();
+        let Some(table_type) = module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index));
+        };
+        if table_type.is_derived() {
+            return Err(VmError::InvalidOperation);
+        }
+        match self.handles.table(handle)? {
+            TableTypes::Mem(table) if table.type_index == type_index && !table.held => {},
+            _ => return Err(VmError::TypeCheck),
+        }
+        if matches!(self.tables.get(&type_index), Some(None)) {
+            return Err(VmError::TableInUse(type_index));
+        }
+        self.track(type_index)?;
+
+        let log = match self.tables.get(&type_index) {
+            Some(None) => return Err(VmError::TableInUse(type_index)),
+            Some(Some(held)) => match self.handles.take_table(*held)? {
+                TableTypes::Mem(table) => table.log,
+                _ => None,
+            },
+            None if table_type.storage != Storage::Memory => {
+                MemTable::open(type_index, table_type, self.limits.max_table_rows)?.log
+            },
+            None => None,
+        };
+
+        let TableTypes::Mem(table) = self.handles.table_mut(handle)? else {
+            unreachable!()
+        };
+        table.held = true;
+        if let Some(mut log) = log {
+            log.compact(&table.rows).map_err(VmError::Storage)?;
+            table.log = Some(log);
+        }
+        self.tables.insert(type_index, Some(handle));
+        Ok(())
+    }
+}
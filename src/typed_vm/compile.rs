@@ -1,12 +1,15 @@
-
 use pest::Parser;
+use pest::Span;
 use pest::iterators::Pair;
 use pest::error::Error;
-use std::{fs, collections::BTreeMap};
-use crate::typed_vm::{Module};
-use super::table::FnTable;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::typed_vm::{Aggregate, Atom, Module, Sort, TableRule, TableType, Term, Value};
+use crate::Type;
+use crate::source::{Location, SourceMap};
+use super::table::{value_has_type, FnTable, Signature};
 
-use super::{Op};
+use super::{Filter, Kind, Op, Test};
 
 use crate::lang::*;
 
@@ -15,8 +18,77 @@ use crate::lang::*;
 pub enum LangError {
     NoMain,
     ParserError(Error<Rule>),
+    /// A source file could not be read.
+    Io(std::io::Error),
     UnknownVar(String),
     UnknownFunction(String),
+    VarAlreadyDeclared(String),
+    /// A second definition of the function, with where the first one is
+    /// when it's in a named source.
+    FunctionAlreadyDefined(String, Option<Location>),
+    InvalidNumber(String),
+    /// The function was called with the wrong number of arguments.
+    WrongArgCount(String),
+    /// The expected type followed by the type that was found.
+    TypeMismatch(Box<Type>, Box<Type>),
+    /// The operator can not be applied to values of the type.
+    UnsupportedType(Type),
+    /// An operator the grammar parses but the Vm has no op for.
+    UnsupportedOperator(String),
+    /// The function was called recursively before a `return` fixed its
+    /// return type.
+    UnknownReturnType(String),
+    TableAlreadyDeclared(String),
+    FieldAlreadyDeclared(String),
+    /// An index or pattern names a field its table doesn't have.
+    UnknownField(String),
+    UnknownTable(String),
+    /// An insert doesn't give a value for the field.
+    MissingField(String),
+    /// An `into` replacing the records of the table its pattern reads.
+    TableUsedTwice(String),
+    /// A use of a table inside a `for` loop over it, which has the table
+    /// taken until it ends.
+    TableInQuery(String),
+    /// A `return` inside a `for` loop, which would leave its tables taken.
+    ReturnInQuery,
+    /// An insert in to a table whose records are derived by rules.
+    InsertIntoDerived(String),
+    /// A `transaction` inside a `for` loop, whose tables it couldn't roll
+    /// back.
+    TransactionInQuery,
+    /// A `rollback` inside a `for` loop, which would leave its tables
+    /// taken.
+    RollbackInQuery,
+    RollbackOutsideTransaction,
+    /// A `return` inside a `transaction`, which would leave it open.
+    ReturnInTransaction,
+    /// An aggregate, `group by` or `where` condition on a var its
+    /// pattern doesn't bind.
+    NotBound(String),
+    /// A var given to two fields of the pattern an aggregate reads. Only
+    /// a `for` loop can check the fields are equal.
+    RepeatedVar(String),
+    /// A `starts_with` condition on a var which isn't a string.
+    NotAString(String),
+    /// A field of an `into` given a var which isn't grouped by.
+    NotGrouped(String),
+    /// An `order by`, `limit` or `offset` on a `for` loop over more than
+    /// one table.
+    OrderedJoin,
+    /// An error found in a named source, with where it was found.
+    Located(Location, Box<LangError>),
+}
+
+impl LangError {
+    /// Gives the error the location, unless it already has one.
+    fn at(self, location: Location) -> Self {
+        match self {
+            LangError::NoMain | LangError::ParserError(_) | LangError::Io(_)
+            | LangError::Located(..) => self,
+            error => LangError::Located(location, Box::new(error)),
+        }
+    }
 }
 
 impl From<pest::error::Error<Rule>> for LangError {
@@ -25,58 +97,1037 @@ impl From<pest::error::Error<Rule>> for LangError {
     }
 }
 
+impl From<std::io::Error> for LangError {
+    fn from(value: std::io::Error) -> Self {
+        LangError::Io(value)
+    }
+}
+
+impl std::fmt::Display for LangError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LangError::NoMain => write!(f, "no main function"),
+            LangError::ParserError(error) => write!(f, "{}", error),
+            LangError::Io(error) => write!(f, "{}", error),
+            LangError::UnknownVar(name) => write!(f, "unknown variable `{}`", name),
+            LangError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+            LangError::VarAlreadyDeclared(name) => {
+                write!(f, "variable `{}` is already declared", name)
+            },
+            LangError::FunctionAlreadyDefined(name, None) => {
+                write!(f, "function `{}` is already defined", name)
+            },
+            LangError::FunctionAlreadyDefined(name, Some(first)) => {
+                write!(f, "function `{}` is already defined at {}", name, first)
+            },
+            LangError::InvalidNumber(text) => write!(f, "invalid number `{}`", text),
+            LangError::WrongArgCount(name) => {
+                write!(f, "wrong number of arguments to `{}`", name)
+            },
+            LangError::TypeMismatch(expected, found) => {
+                write!(f, "expected {:?} found {:?}", expected, found)
+            },
+            LangError::UnsupportedType(value_type) => {
+                write!(f, "unsupported type {:?}", value_type)
+            },
+            LangError::UnsupportedOperator(op) => write!(f, "unsupported operator `{}`", op),
+            LangError::UnknownReturnType(name) => {
+                write!(f, "the return type of `{}` is unknown where it is called", name)
+            },
+            LangError::TableAlreadyDeclared(name) => {
+                write!(f, "table `{}` is already declared", name)
+            },
+            LangError::FieldAlreadyDeclared(name) => {
+                write!(f, "field `{}` is already declared", name)
+            },
+            LangError::UnknownField(name) => write!(f, "unknown field `{}`", name),
+            LangError::UnknownTable(name) => write!(f, "unknown table `{}`", name),
+            LangError::MissingField(name) => write!(f, "no value for field `{}`", name),
+            LangError::TableUsedTwice(name) => {
+                write!(f, "table `{}` is both read and replaced by an `into`", name)
+            },
+            LangError::TableInQuery(name) => {
+                write!(f, "table `{}` is used inside a `for` loop over it", name)
+            },
+            LangError::ReturnInQuery => write!(f, "`return` inside a `for` loop over tables"),
+            LangError::InsertIntoDerived(name) => {
+                write!(f, "can't insert in to table `{}`, its records are derived", name)
+            },
+            LangError::TransactionInQuery => {
+                write!(f, "`transaction` inside a `for` loop over tables")
+            },
+            LangError::RollbackInQuery => write!(f, "`rollback` inside a `for` loop over tables"),
+            LangError::RollbackOutsideTransaction => write!(f, "`rollback` outside a `transaction`"),
+            LangError::ReturnInTransaction => write!(f, "`return` inside a `transaction`"),
+            LangError::NotBound(name) => write!(f, "`{}` isn't bound by the pattern", name),
+            LangError::RepeatedVar(name) => {
+                write!(f, "`{}` is given to two fields of a pattern outside a `for` loop", name)
+            },
+            LangError::NotAString(name) => write!(f, "`{}` isn't a string", name),
+            LangError::NotGrouped(name) => write!(f, "`{}` isn't grouped by", name),
+            LangError::OrderedJoin => {
+                write!(f, "`order by`, `limit` and `offset` can't be used on a join")
+            },
+            LangError::Located(location, error) => write!(f, "{}: {}", location, error),
+        }
+    }
+}
+
+impl std::error::Error for LangError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LangError::Io(error) => Some(error),
+            LangError::Located(_, error) => error.source(),
+            _ => None,
+        }
+    }
+}
+
+/// A function compiled for one set of argument types. Functions are
+/// compiled separately for each distinct set of argument types they are
+/// called with, starting from `main`.
 #[derive(Debug)]
  struct FnType {
-    index: usize,
-    arg_count: usize,
-    frame_size: usize,
+    name: String,
+    args: Vec<Type>,
+    ret: Option<Type>,
+    code: Vec<Op>,
  }
 
-#[derive(Debug)]
-pub struct ModuleBuilder<'a> {
-    code: Vec<Op> ,
-    functions: BTreeMap<&'a str,FnType>,
-    scope: BTreeMap<&'a str, usize>,
+/// The state of the function currently being compiled.
+#[derive(Debug, Default)]
+struct Frame<'a> {
+    code: Vec<Op>,
+    scope: BTreeMap<&'a str, (usize, Type)>,
     frame_size: usize,
+    /// The types of the values the code pushes above the frame.
+    types: Vec<Type>,
+    /// Set once a `return` has been compiled, the rest of the body is
+    /// unreachable.
+    returned: bool,
+    /// The number of blocks the code being compiled is nested in.
+    depth: usize,
+    /// Vars in the order they were declared so blocks can drop theirs.
+    declared: Vec<&'a str>,
+    /// The number of `for` loops the code being compiled is nested in.
+    queries: usize,
+    /// The tables those loops have taken, which the code can't use.
+    taken: Vec<u32>,
+    /// The `rollback` jumps to the end of each `transaction` the code
+    /// being compiled is nested in, innermost last.
+    transactions: Vec<Vec<usize>>,
+}
+
+/// A table pattern, `name{field: value, ...}`, matching records whose
+/// fields equal the values. Fields given a var which isn't in scope bind
+/// the var to the field instead, a var given to more than one field
+/// matches records whose fields are equal.
+#[derive(Debug)]
+struct Pattern<'a> {
+    type_index: u32,
+    /// The position of each field given a value, and the value.
+    fields: Vec<(usize, Pair<'a, Rule>)>,
+    /// The tests of the `where` conditions on the vars the pattern binds,
+    /// with the field tested and the operands.
+    conditions: Vec<(usize, Test, Vec<Pair<'a, Rule>>)>,
+}
+
+/// The order a `for` loop over one table reads its records in, and the
+/// window of them it reads.
+#[derive(Debug, Default)]
+struct Window<'a> {
+    /// The vars sorted by and whether each is sorted descending.
+    order: Vec<(&'a str, bool)>,
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ModuleBuilder<'a> {
+    /// Function definitions by name, with the name of the source each is
+    /// in.
+    definitions: BTreeMap<&'a str, (Option<&'a str>, Pair<'a, Rule>)>,
+    functions: Vec<FnType>,
+    /// Declared tables, their type index is their position.
+    tables: Vec<TableType>,
+    /// Rule declarations, compiled once every table is declared.
+    rules: Vec<(Option<&'a str>, Pair<'a, Rule>)>,
+    /// The name of the source being compiled, None for a lone unnamed
+    /// source whose errors aren't located.
+    source: Option<&'a str>,
+    frame: Frame<'a>,
+    current: usize,
+    /// Whether each function's code is optimized once compiled.
+    optimize: bool,
 }
 
 impl<'a> ModuleBuilder<'a> {
     pub fn new() -> Self {
         ModuleBuilder {
-            code: vec![Op::Halt],
-            functions: BTreeMap::new(),
-            scope: BTreeMap::new(),
-            frame_size: 0,
+            definitions: BTreeMap::new(),
+            functions: Vec::new(),
+            tables: Vec::new(),
+            rules: Vec::new(),
+            source: None,
+            frame: Frame::default(),
+            current: 0,
+            optimize: true,
         }
-    } 
+    }
 
-    pub fn new_frame(&mut self) {
-        self.scope = BTreeMap::new();
-        self.frame_size = 0;
+    fn new_frame(&mut self) -> Frame<'a> {
+        std::mem::take(&mut self.frame)
     }
 
-    pub fn into_module(self) -> Result<Module, LangError> {
-        let Some(fn_type) = self.functions.get("main") else {
+    pub fn into_module(mut self) -> Result<Module, LangError> {
+        if !self.definitions.contains_key("main") {
             return Err(LangError::NoMain);
-        };
+        }
+
+        for (source, pair) in std::mem::take(&mut self.rules) {
+            self.source = source;
+            let span = pair.as_span();
+            self.rule(pair).map_err(|error| self.locate(error, span))?;
+        }
+
+        let main = self.function("main", Vec::new())?;
+
+        // Functions declaring the type of every argument are compiled for
+        // those types so the host can call them, even if nothing else does.
+        let declared: Vec<_> = self.definitions.iter()
+            .filter_map(|(name, (_, definition))| {
+                let args = declared_args(definition.clone()).into_iter()
+                    .map(|(_, arg_type)| arg_type)
+                    .collect::<Option<Vec<_>>>()?;
+                Some((*name, args))
+            })
+            .collect();
+        for (name, args) in declared {
+            self.function(name, args)?;
+        }
+
+        // Lay the functions out after the Halt that returning from main
+        // jumps to, then point function references at their offsets.
+        let mut code = vec![Op::Halt];
+        let mut offsets = Vec::new();
+        let mut functions = FnTable::new();
+        for (index, function) in self.functions.iter_mut().enumerate() {
+            offsets.push(code.len());
+            functions.add_fn(index as u32, code.len());
+            functions.add_signature(Signature {
+                name: function.name.clone(),
+                args: function.args.clone(),
+                ret: function.ret.clone().unwrap_or(Type::Unknown),
+                ptr: code.len(),
+            });
+            relocate(&mut function.code, code.len());
+            code.append(&mut function.code);
+        }
+
+        for op in code.iter_mut() {
+            if let Op::Fn(index) | Op::CallFn { ptr: index, .. } = op {
+                *index = offsets[*index];
+            }
+        }
 
         let resulst = Module {
-            start: fn_type.index,
-            code: self.code,
-            functions: FnTable::new(), //BOOG
-            types: BTreeMap::new(),
+            start: offsets[main],
+            code,
+            functions,
+            types: self.tables.into_iter()
+                .enumerate()
+                .map(|(index, table)| (index as u32, table))
+                .collect(),
         };
 
         Ok(resulst)
     }
+
+    /// Returns the index of `name` compiled for the argument types,
+    /// compiling it if this is the first call with those types.
+    fn function(&mut self, name: &str, args: Vec<Type>) -> Result<usize, LangError> {
+        let existing = self.functions.iter()
+            .position(|f| f.name == name && f.args == args);
+        if let Some(index) = existing {
+            return Ok(index);
+        }
+
+        let Some((source, definition)) = self.definitions.get(name) else {
+            return Err(LangError::UnknownFunction(name.to_string()));
+        };
+        let source = *source;
+        let span = definition.as_span();
+
+        let declared = declared_args(definition.clone());
+        let fn_body = definition.clone().into_inner().nth(2).unwrap();
+
+        if declared.len() != args.len() {
+            return Err(LangError::WrongArgCount(name.to_string()));
+        }
+        // Args declared with a type only take values of that type, the
+        // error is located at the definition.
+        for ((_, declared), found) in declared.iter().zip(&args) {
+            match declared {
+                Some(declared) if declared != found => {
+                    let error = LangError::TypeMismatch(
+                        Box::new(declared.clone()),
+                        Box::new(found.clone()),
+                    );
+                    return Err(match source {
+                        Some(source) => error.at(Location::of(source, span)),
+                        None => error,
+                    });
+                },
+                _ => {},
+            }
+        }
+        let arg_names: Vec<&'a str> = declared.into_iter().map(|(name, _)| name).collect();
+
+        let index = self.functions.len();
+        self.functions.push(FnType {
+            name: name.to_string(),
+            args: args.clone(),
+            ret: None,
+            code: Vec::new(),
+        });
+
+        let outer = self.new_frame();
+        let outer_index = std::mem::replace(&mut self.current, index);
+        let outer_source = std::mem::replace(&mut self.source, source);
+
+        let result = self.function_body(&arg_names, args, fn_body)
+            .map_err(|error| self.locate(error, span));
+
+        let frame = std::mem::replace(&mut self.frame, outer);
+        self.current = outer_index;
+        self.source = outer_source;
+        result?;
+
+        // allocate space on the stack for vars.
+        let var_count = frame.frame_size - arg_names.len();
+        let mut code = Vec::with_capacity(var_count + frame.code.len());
+        for _ in 0..var_count {
+            code.push(Op::None);
+        }
+        code.extend(frame.code);
+        relocate(&mut code[var_count..], var_count);
+
+        if self.optimize {
+            super::optimize::optimize(&mut code);
+        }
+
+        self.functions[index].code = code;
+        Ok(index)
+    }
+
+    fn function_body(&mut self, arg_names: &[&'a str], args: Vec<Type>, body: Pair<'a, Rule>)
+    -> Result<(), LangError> {
+        // Process fn args
+        for (name, arg_type) in arg_names.iter().zip(args) {
+            self.new_var(name, arg_type)?;
+        }
+
+        // Process statements
+        for statement_n in body.into_inner() {
+            if self.frame.returned {
+                break;
+            }
+            parse_pair(self, statement_n)?;
+        }
+
+        if !self.frame.returned {
+            // Without a return the function evaluates to the last value it
+            // left on the stack.
+            let ret = match self.frame.types.last() {
+                Some(ret) => ret.clone(),
+                None => {
+                    self.push(Op::None, Type::None);
+                    Type::None
+                },
+            };
+            self.set_return(ret)?;
+            self.frame.code.push(Op::Return);
+        }
+
+        Ok(())
+    }
+
+    /// Locates an error found in the span if the source being compiled
+    /// is named.
+    fn locate(&self, error: LangError, span: Span) -> LangError {
+        match self.source {
+            Some(source) => error.at(Location::of(source, span)),
+            None => error,
+        }
+    }
+
+    fn set_return(&mut self, ret: Type) -> Result<(), LangError> {
+        let function = &mut self.functions[self.current];
+        match &function.ret {
+            None => function.ret = Some(ret),
+            Some(existing) if *existing == ret => {},
+            Some(existing) => {
+                return Err(LangError::TypeMismatch(Box::new(existing.clone()), Box::new(ret)));
+            },
+        }
+        Ok(())
+    }
+
+    fn new_var(&mut self, name: &'a str, var_type: Type) -> Result<usize, LangError> {
+        if self.frame.scope.contains_key(name) {
+            return Err(LangError::VarAlreadyDeclared(name.to_string()));
+        }
+
+        let offset = self.frame.frame_size;
+        self.frame.frame_size += 1;
+        self.frame.scope.insert(name, (offset, var_type));
+        self.frame.declared.push(name);
+        Ok(offset)
+    }
+
+    /// Drops the vars declared since `declared` vars were in scope. Their
+    /// slots stay part of the frame.
+    fn end_block(&mut self, declared: usize) {
+        for name in self.frame.declared.split_off(declared) {
+            self.frame.scope.remove(name);
+        }
+    }
+
+    /// Emits a jump to be pointed at its target with `patch`.
+    fn jump(&mut self, op: Op) -> usize {
+        self.frame.code.push(op);
+        self.frame.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.frame.code.len();
+        match &mut self.frame.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Compiles a condition which must be a Bool.
+    fn condition(&mut self, pair: Pair<'a, Rule>) -> Result<(), LangError> {
+        parse_pair(self, pair)?;
+        match self.pop_type() {
+            Type::Bool => Ok(()),
+            other => Err(LangError::TypeMismatch(Box::new(Type::Bool), Box::new(other))),
+        }
+    }
+
+    /// Adds a table type from its declaration.
+    fn table(&mut self, pair: Pair<'a, Rule>) -> Result<(), LangError> {
+        let mut parts = pair.into_inner();
+        let name = parts.next().unwrap().as_str();
+        if self.tables.iter().any(|table| table.name == name) {
+            return Err(LangError::TableAlreadyDeclared(name.to_string()));
+        }
+
+        let mut table = TableType::new(name, Vec::new());
+        for part in parts {
+            match part.as_rule() {
+                Rule::field_decl => {
+                    let mut field = part.into_inner();
+                    let field_name = field.next().unwrap().as_str();
+                    let field_type = parse_type(field.next().unwrap().as_str());
+                    if table.field(field_name).is_some() {
+                        return Err(LangError::FieldAlreadyDeclared(field_name.to_string()));
+                    }
+                    table.names.push(field_name.to_string());
+                    table.fields.push(field_type);
+                },
+                Rule::file_decl => {
+                    let path = part.into_inner().next().unwrap().as_str();
+                    table = table.with_file(path.trim_matches('"'));
+                },
+                Rule::order_decl => {
+                    let order = sort_keys(part).into_iter()
+                        .map(|(name, descending)| match table.field(name) {
+                            Some(field) => Ok(Sort { field, descending }),
+                            None => Err(LangError::UnknownField(name.to_string())),
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    table = table.with_order(&order);
+                },
+                _ => {
+                    let fields = part.into_inner()
+                        .map(|field| {
+                            table.field(field.as_str())
+                                .ok_or_else(|| LangError::UnknownField(field.as_str().to_string()))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    table.indexes.push(fields);
+                },
+            }
+        }
+
+        self.tables.push(table);
+        Ok(())
+    }
+
+    /// Adds a rule to the table named by its head. Each var takes the type
+    /// of the first field it is given for and must be given for a field
+    /// of the body to be used in the head.
+    fn rule(&mut self, pair: Pair<'a, Rule>) -> Result<(), LangError> {
+        let mut vars: Vec<(&'a str, Type)> = Vec::new();
+        let mut atoms = pair.into_inner();
+        let head = atoms.next().unwrap();
+
+        let mut body = Vec::new();
+        for body_atom in atoms {
+            body.push(self.atom(body_atom, &mut vars, true)?);
+        }
+        let head = self.atom(head, &mut vars, false)?;
+
+        self.tables[head.type_index as usize].rules.push(TableRule {
+            head: head.terms,
+            body,
+        });
+        Ok(())
+    }
+
+    /// Compiles an atom's terms, numbering vars by their position in
+    /// `vars`. Only atoms of a rule's body can add vars.
+    fn atom(&self, pair: Pair<'a, Rule>, vars: &mut Vec<(&'a str, Type)>, binds: bool)
+    -> Result<Atom, LangError> {
+        let mut parts = pair.into_inner();
+        let name = parts.next().unwrap().as_str();
+        let Some(type_index) = self.tables.iter().position(|table| table.name == name) else {
+            return Err(LangError::UnknownTable(name.to_string()));
+        };
+        let fields = &self.tables[type_index].fields;
+
+        let mut terms = Vec::new();
+        for (position, term) in parts.enumerate() {
+            let Some(field_type) = fields.get(position) else {
+                return Err(LangError::WrongArgCount(name.to_string()));
+            };
+
+            if term.as_rule() != Rule::var {
+                let value = parse_value(&term)?;
+                if !value_has_type(&value, field_type) {
+                    let value_type = literal_type(term.as_rule());
+                    return Err(LangError::TypeMismatch(Box::new(field_type.clone()), Box::new(value_type)));
+                }
+                terms.push(Term::Value(value));
+                continue;
+            }
+
+            let var = match vars.iter().position(|(var, _)| *var == term.as_str()) {
+                Some(var) => var,
+                None if binds => {
+                    vars.push((term.as_str(), field_type.clone()));
+                    vars.len() - 1
+                },
+                None => return Err(LangError::UnknownVar(term.as_str().to_string())),
+            };
+            if vars[var].1 != *field_type {
+                return Err(LangError::TypeMismatch(Box::new(field_type.clone()), Box::new(vars[var].1.clone())));
+            }
+            terms.push(Term::Var(var));
+        }
+
+        if terms.len() != fields.len() {
+            return Err(LangError::WrongArgCount(name.to_string()));
+        }
+
+        Ok(Atom {
+            type_index: type_index as u32,
+            terms,
+        })
+    }
+
+    fn pattern(&self, pair: Pair<'a, Rule>) -> Result<Pattern<'a>, LangError> {
+        let mut parts = pair.into_inner();
+        let name = parts.next().unwrap().as_str();
+        let Some(type_index) = self.tables.iter().position(|table| table.name == name) else {
+            return Err(LangError::UnknownTable(name.to_string()));
+        };
+
+        let mut fields = Vec::new();
+        for field_value in parts {
+            let mut parts = field_value.into_inner();
+            let field_name = parts.next().unwrap().as_str();
+            let value = parts.next().unwrap();
+
+            let Some(field) = self.tables[type_index].field(field_name) else {
+                return Err(LangError::UnknownField(field_name.to_string()));
+            };
+            if fields.iter().any(|(f, _)| *f == field) {
+                return Err(LangError::FieldAlreadyDeclared(field_name.to_string()));
+            }
+            fields.push((field, value));
+        }
+
+        Ok(Pattern {
+            type_index: type_index as u32,
+            fields,
+            conditions: Vec::new(),
+        })
+    }
+
+    /// Adds the conditions of a `where` clause to the patterns binding
+    /// their vars. Operands must be literals or vars in scope before the
+    /// patterns are queried.
+    fn conditions(&self, clause: Pair<'a, Rule>, patterns: &mut [Pattern<'a>]) -> Result<(), LangError> {
+        for condition in clause.into_inner() {
+            let rule = condition.as_rule();
+            let mut parts = condition.into_inner();
+            let var = parts.next().unwrap().as_str();
+
+            let test = match rule {
+                Rule::comparison => match parts.next().unwrap().as_str() {
+                    "<" => Test::Lt,
+                    "<=" => Test::Le,
+                    ">" => Test::Gt,
+                    ">=" => Test::Ge,
+                    _ => Test::Eq,
+                },
+                Rule::membership => Test::In(parts.len()),
+                _ => Test::Prefix,
+            };
+            let operands: Vec<_> = parts.collect();
+            for operand in &operands {
+                if operand.as_rule() == Rule::var && !self.frame.scope.contains_key(operand.as_str()) {
+                    return Err(LangError::UnknownVar(operand.as_str().to_string()));
+                }
+            }
+
+            // Vars in scope are matched by the patterns rather than bound.
+            let mut bound = false;
+            if !self.frame.scope.contains_key(var) {
+                for pattern in patterns.iter_mut() {
+                    let Some((field, _)) = pattern.fields.iter()
+                        .find(|(_, value)| value.as_rule() == Rule::var && value.as_str() == var) else {
+                        continue;
+                    };
+                    let field_type = &self.tables[pattern.type_index as usize].fields[*field];
+                    if test == Test::Prefix && *field_type != Type::StringRef {
+                        return Err(LangError::NotAString(var.to_string()));
+                    }
+                    pattern.conditions.push((*field, test, operands.clone()));
+                    bound = true;
+                }
+            }
+            if !bound {
+                return Err(LangError::NotBound(var.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The fields of the pattern given a literal or a var which is in
+    /// scope or in `bound`.
+    fn bound_fields(&self, pattern: &Pattern<'a>, bound: &BTreeSet<&'a str>) -> Vec<usize> {
+        pattern.fields.iter()
+            .filter(|(_, value)| {
+                value.as_rule() != Rule::var
+                    || self.frame.scope.contains_key(value.as_str())
+                    || bound.contains(value.as_str())
+            })
+            .map(|(field, _)| *field)
+            .collect()
+    }
+
+    /// Orders the patterns of a join so that each is queried with as much
+    /// of it bound as possible. The next pattern is one an index covers
+    /// the bound fields of if there is one, then the one with the most
+    /// bound fields, then the first written.
+    fn plan(&self, mut patterns: Vec<Pattern<'a>>) -> Vec<Pattern<'a>> {
+        let mut bound = BTreeSet::new();
+        let mut order = Vec::with_capacity(patterns.len());
+
+        while !patterns.is_empty() {
+            let next = (0..patterns.len())
+                .max_by_key(|i| {
+                    let pattern = &patterns[*i];
+                    let fields = self.bound_fields(pattern, &bound);
+                    let indexed = self.tables[pattern.type_index as usize].indexes.iter()
+                        .any(|index| index.iter().all(|field| fields.contains(field)));
+                    (indexed, fields.len(), Reverse(*i))
+                })
+                .unwrap();
+
+            let pattern = patterns.remove(next);
+            for (_, value) in &pattern.fields {
+                if value.as_rule() == Rule::var {
+                    bound.insert(value.as_str());
+                }
+            }
+            order.push(pattern);
+        }
+
+        order
+    }
+
+    /// Compiles a value given to a field, which must have the field's type.
+    fn field_value(&mut self, value: Pair<'a, Rule>, field_type: &Type) -> Result<(), LangError> {
+        parse_pair(self, value)?;
+        let value_type = self.pop_type();
+        if value_type != *field_type {
+            return Err(LangError::TypeMismatch(Box::new(field_type.clone()), Box::new(value_type)));
+        }
+        Ok(())
+    }
+
+    /// Compiles taking the pattern's table and querying it with the bound
+    /// fields and its conditions, leaving the cursor on the stack. Returns
+    /// the fields which bind a var and the var's name, a var given to more
+    /// than one field is returned for each.
+    fn query(&mut self, pattern: &Pattern<'a>) -> Result<Vec<(usize, &'a str)>, LangError> {
+        let fields = self.tables[pattern.type_index as usize].fields.clone();
+
+        self.take(pattern.type_index)?;
+        let mut filters = Vec::new();
+        let mut binds: Vec<(usize, &'a str)> = Vec::new();
+        for (field, value) in &pattern.fields {
+            let name = value.as_str();
+            if value.as_rule() == Rule::var && !self.frame.scope.contains_key(name) {
+                binds.push((*field, name));
+                continue;
+            }
+
+            self.field_value(value.clone(), &fields[*field])?;
+            filters.push(Filter::new(*field, Test::Eq));
+        }
+        for (field, test, operands) in &pattern.conditions {
+            for operand in operands {
+                self.field_value(operand.clone(), &fields[*field])?;
+            }
+            filters.push(Filter::new(*field, *test));
+        }
+        self.frame.code.push(Op::Select(filters));
+        Ok(binds)
+    }
+
+    /// Fails if a var is bound by more than one field, which only a `for`
+    /// loop can check the records for.
+    fn single_binds(binds: &[(usize, &'a str)]) -> Result<(), LangError> {
+        for (i, (_, name)) in binds.iter().enumerate() {
+            if binds[..i].iter().any(|(_, bind)| bind == name) {
+                return Err(LangError::RepeatedVar(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles taking the table, which an enclosing loop mustn't have.
+    fn take(&mut self, type_index: u32) -> Result<(), LangError> {
+        if self.frame.taken.contains(&type_index) {
+            let name = &self.tables[type_index as usize].name;
+            return Err(LangError::TableInQuery(name.clone()));
+        }
+        self.frame.code.push(Op::TakeTable(type_index));
+        Ok(())
+    }
+
+    /// Compiles a loop over the records matching the first pattern, binding
+    /// its vars, around the loops for the rest of the patterns. The body is
+    /// compiled inside the innermost loop.
+    fn join(&mut self, patterns: &[Pattern<'a>], window: Option<&Window<'a>>, body: Pair<'a, Rule>)
+    -> Result<(), LangError> {
+        let Some((pattern, rest)) = patterns.split_first() else {
+            return parse_pair(self, body);
+        };
+        let fields = self.tables[pattern.type_index as usize].fields.clone();
+
+        let binds = self.query(pattern)?;
+
+        // An ordered loop reads a sorted copy of the records, as does one
+        // over a table a later pattern matches again, so the table is put
+        // back before it starts and its body can use it.
+        let copied = window.is_some() || rest.iter().any(|later| later.type_index == pattern.type_index);
+        if copied {
+            let order = window.map_or(&[][..], |window| &window.order).iter()
+                .map(|(var, descending)| match binds.iter().find(|(_, bind)| bind == var) {
+                    Some((field, _)) => Ok(Sort { field: *field, descending: *descending }),
+                    None => Err(LangError::NotBound(var.to_string())),
+                })
+                .collect::<Result<_, _>>()?;
+            let (offset, limit) = window.map_or((0, None), |window| (window.offset, window.limit));
+            self.frame.code.extend([
+                Op::Order { order, offset, limit },
+                Op::Swap,
+                Op::PutTable(pattern.type_index),
+            ]);
+        } else {
+            self.frame.taken.push(pattern.type_index);
+        }
+        self.frame.types.push(Type::Cursor);
+
+        // Read each record moving its fields in to their vars from under
+        // the cursor.
+        let start = self.frame.code.len();
+        self.frame.code.push(Op::Found);
+        let to_end = self.jump(Op::JumpIfFalse(0));
+        self.frame.code.extend([Op::Read, Op::Swap, Op::Pop]);
+        let mut repeats = Vec::new();
+        for (field, field_type) in fields.iter().enumerate().rev() {
+            self.frame.code.push(Op::Swap);
+            match binds.iter().find(|(bind, _)| *bind == field) {
+                // The var is bound by its first field, the others are kept
+                // in slots of their own to check against it.
+                Some((_, name)) if binds.iter().any(|(bind, other)| other == name && *bind < field) => {
+                    let offset = self.frame.frame_size;
+                    self.frame.frame_size += 1;
+                    self.frame.code.push(Op::StoreImm(offset));
+                    repeats.push((offset, *name, field_type));
+                },
+                Some((_, name)) => {
+                    let offset = self.new_var(name, field_type.clone())?;
+                    self.frame.code.push(Op::StoreImm(offset));
+                },
+                None => self.frame.code.push(Op::Pop),
+            }
+        }
+
+        // Records whose fields given the same var differ are skipped.
+        let mut to_next = Vec::new();
+        for (offset, name, field_type) in repeats {
+            let kind = Kind::of(field_type).ok_or_else(|| LangError::UnsupportedType(field_type.clone()))?;
+            let var = self.frame.scope[name].0;
+            self.frame.code.extend([Op::LoadImm(var), Op::LoadImm(offset), Op::Eq(kind)]);
+            to_next.push(self.jump(Op::JumpIfFalse(0)));
+        }
+
+        self.frame.queries += 1;
+        let result = self.join(rest, None, body);
+        self.frame.queries -= 1;
+        if !copied {
+            self.frame.taken.pop();
+        }
+        result?;
+
+        for at in to_next {
+            self.patch(at);
+        }
+        self.frame.code.extend([Op::Advance, Op::Jump(start)]);
+        self.patch(to_end);
+        if copied {
+            self.frame.code.extend([Op::Close, Op::DropTable]);
+        } else {
+            self.frame.code.extend([Op::Close, Op::PutTable(pattern.type_index)]);
+        }
+        self.frame.types.pop();
+        Ok(())
+    }
+
+    /// Compiles an aggregate over the records matching the pattern, of a
+    /// var the pattern binds. Only `count` takes no var.
+    fn fold(&self, pair: Pair<'a, Rule>, pattern: &Pattern<'a>, binds: &[(usize, &'a str)])
+    -> Result<(Aggregate, Type), LangError> {
+        let mut parts = pair.into_inner();
+        let function = parts.next().unwrap().as_str();
+        let var = parts.next().map(|var| var.as_str());
+
+        let field = match (function, var) {
+            ("count", None) => 0,
+            ("count", Some(_)) | (_, None) => {
+                return Err(LangError::WrongArgCount(function.to_string()));
+            },
+            (_, Some(var)) => match binds.iter().find(|(_, bind)| *bind == var) {
+                Some((field, _)) => *field,
+                None => return Err(LangError::NotBound(var.to_string())),
+            },
+        };
+        let aggregate = match function {
+            "count" => Aggregate::Count,
+            "sum" => Aggregate::Sum(field),
+            "min" => Aggregate::Min(field),
+            "max" => Aggregate::Max(field),
+            _ => Aggregate::Avg(field),
+        };
+
+        let fields = &self.tables[pattern.type_index as usize].fields;
+        match aggregate.result_type(fields) {
+            Some(result_type) => Ok((aggregate, result_type)),
+            None => Err(LangError::UnsupportedType(fields[field].clone())),
+        }
+    }
+
+    /// Compiles an aggregate over the records matching the pattern,
+    /// leaving its value on the stack.
+    fn aggregate(&mut self, fold: Pair<'a, Rule>, pattern: Pattern<'a>) -> Result<(), LangError> {
+        let binds = self.query(&pattern)?;
+        Self::single_binds(&binds)?;
+        let (aggregate, result_type) = self.fold(fold, &pattern, &binds)?;
+
+        self.frame.code.extend([
+            Op::Aggregate(vec![aggregate]),
+            Op::Swap,
+            Op::Pop,
+            Op::PutTable(pattern.type_index),
+        ]);
+        self.frame.types.push(result_type);
+        Ok(())
+    }
+
+    /// Compiles replacing the records of the table with one for each group
+    /// of the records matching the pattern. Each field is given an
+    /// aggregate over the group or a var it is grouped by.
+    fn group(
+        &mut self,
+        name: &str,
+        columns: Vec<Pair<'a, Rule>>,
+        pattern: Pattern<'a>,
+        group_by: Vec<&'a str>,
+    ) -> Result<(), LangError> {
+        let Some(type_index) = self.tables.iter().position(|table| table.name == name) else {
+            return Err(LangError::UnknownTable(name.to_string()));
+        };
+        let table = self.tables[type_index].clone();
+        if table.is_derived() {
+            return Err(LangError::InsertIntoDerived(table.name));
+        }
+        if type_index as u32 == pattern.type_index {
+            return Err(LangError::TableUsedTwice(table.name));
+        }
+        if self.frame.taken.contains(&(type_index as u32)) {
+            return Err(LangError::TableInQuery(table.name));
+        }
+
+        let mut given = Vec::new();
+        for column in columns {
+            let mut parts = column.into_inner();
+            let field_name = parts.next().unwrap().as_str();
+            let value = parts.next().unwrap();
+            let Some(field) = table.field(field_name) else {
+                return Err(LangError::UnknownField(field_name.to_string()));
+            };
+            if given.iter().any(|(f, _)| *f == field) {
+                return Err(LangError::FieldAlreadyDeclared(field_name.to_string()));
+            }
+            given.push((field, value));
+        }
+
+        let binds = self.query(&pattern)?;
+        Self::single_binds(&binds)?;
+        let bound = |var: &str| binds.iter()
+            .find(|(_, bind)| *bind == var)
+            .map(|(field, _)| *field)
+            .ok_or_else(|| LangError::NotBound(var.to_string()));
+        let keys = group_by.iter().map(|var| bound(var)).collect::<Result<Vec<_>, _>>()?;
+
+        let source = &self.tables[pattern.type_index as usize].fields;
+        let mut aggregates = Vec::new();
+        for (field, field_type) in table.fields.iter().enumerate() {
+            let Some((_, value)) = given.iter().find(|(f, _)| *f == field) else {
+                return Err(LangError::MissingField(table.names[field].clone()));
+            };
+
+            let (aggregate, value_type) = match value.as_rule() {
+                Rule::fold => self.fold(value.clone(), &pattern, &binds)?,
+                _ => {
+                    let var = value.as_str();
+                    if !group_by.contains(&var) {
+                        return Err(LangError::NotGrouped(var.to_string()));
+                    }
+                    let key = bound(var)?;
+                    (Aggregate::Key(key), source[key].clone())
+                },
+            };
+            if value_type != *field_type {
+                return Err(LangError::TypeMismatch(Box::new(field_type.clone()), Box::new(value_type)));
+            }
+            aggregates.push(aggregate);
+        }
+
+        self.frame.code.extend([
+            Op::Group { keys, columns: aggregates, type_index: type_index as u32 },
+            Op::SetTable(type_index as u32),
+            Op::PutTable(pattern.type_index),
+        ]);
+        Ok(())
+    }
+
+    /// Compiles appending a record with the pattern's values, which must
+    /// give one for every field.
+    fn insert(&mut self, pattern: Pattern<'a>) -> Result<(), LangError> {
+        let table = self.tables[pattern.type_index as usize].clone();
+        if table.is_derived() {
+            return Err(LangError::InsertIntoDerived(table.name));
+        }
+
+        // A query matching nothing leaves the cursor at the end of the
+        // table, so the record is appended.
+        self.take(pattern.type_index)?;
+        self.frame.code.push(Op::Select(vec![Filter::new(0, Test::In(0))]));
+
+        for (field, field_type) in table.fields.iter().enumerate() {
+            let Some((_, value)) = pattern.fields.iter().find(|(f, _)| *f == field) else {
+                return Err(LangError::MissingField(table.names[field].clone()));
+            };
+            self.field_value(value.clone(), field_type)?;
+        }
+
+        self.frame.code.extend([
+            Op::Usize(table.fields.len()),
+            Op::Struct,
+            Op::Insert,
+            Op::Close,
+            Op::PutTable(pattern.type_index),
+        ]);
+        Ok(())
+    }
+
+    fn push(&mut self, op: Op, value_type: Type) {
+        self.frame.code.push(op);
+        self.frame.types.push(value_type);
+    }
+
+    fn pop_type(&mut self) -> Type {
+        // The grammar only lets statements consume values pushed by their
+        // own expressions so there is always a type to pop.
+        self.frame.types.pop().unwrap()
+    }
 }
 
-pub fn parse_colang_file<'a>(file: &'a str) -> Result<Module, LangError> {
-    let data = fs::read_to_string(file).expect("Unable to read file");
-    let pairs = LangParser::parse(Rule::program, &data)?;
+pub fn parse_colang_file(file: &str) -> Result<Module, LangError> {
+    let mut sources = SourceMap::new();
+    sources.add_file(file)?;
+    compile_sources(&sources)
+}
+
+/// Compiles colang source to a module for the typed Vm.
+pub fn parse_colang(source: &str) -> Result<Module, LangError> {
+    let pairs = LangParser::parse(Rule::program, source)?;
+    compile(pairs.map(|pair| (None, pair)), true)
+}
+
+/// Compiles without optimizing, for checking what the optimizer does.
+#[cfg(test)]
+pub fn parse_colang_unoptimized(source: &str) -> Result<Module, LangError> {
+    let pairs = LangParser::parse(Rule::program, source)?;
+    compile(pairs.map(|pair| (None, pair)), false)
+}
+
+/// Compiles the source naming it in any parse error, for source which
+/// doesn't come from a file.
+pub fn compile_str(name: &str, source: &str) -> Result<Module, LangError> {
+    let mut sources = SourceMap::new();
+    sources.add(name, source);
+    compile_sources(&sources)
+}
+
+/// Compiles the sources together, as if their functions were defined in
+/// one source. Errors are located in the source they were found in.
+pub fn compile_sources(sources: &SourceMap) -> Result<Module, LangError> {
+    let pairs = sources.parse()?;
+    compile(pairs.into_iter().map(|(name, pair)| (Some(name), pair)), true)
+}
+
+/// Compiles the pairs, each with the name of its source if it has one.
+fn compile<'a>(
+    pairs: impl IntoIterator<Item = (Option<&'a str>, Pair<'a, Rule>)>,
+    optimize: bool,
+) -> Result<Module, LangError> {
     let mut builder = ModuleBuilder::new();
+    builder.optimize = optimize;
 
-    for pair in pairs {
+    for (source, pair) in pairs {
+        builder.source = source;
         parse_pair(&mut builder, pair)?;
     }
 
@@ -84,47 +1135,181 @@ pub fn parse_colang_file<'a>(file: &'a str) -> Result<Module, LangError> {
     Ok(result)
 }
 
+/// Jump targets are compiled relative to the start of their function's
+/// code and moved as the code is laid out.
+fn relocate(code: &mut [Op], by: usize) {
+    for op in code.iter_mut() {
+        if let Op::Jump(to) | Op::JumpIfFalse(to) = op {
+            *to += by;
+        }
+    }
+}
+
+/// The names of a function definition's args, with the type each was
+/// declared with if it was.
+fn declared_args<'a>(definition: Pair<'a, Rule>) -> Vec<(&'a str, Option<Type>)> {
+    let fn_args = definition.into_inner().nth(1).unwrap();
+    fn_args.into_inner()
+        .map(|arg| {
+            let mut parts = arg.into_inner();
+            let name = parts.next().unwrap().as_str();
+            (name, parts.next().map(|arg_type| parse_type(arg_type.as_str())))
+        })
+        .collect()
+}
+
+/// The type named by a `type_name`, which the grammar limits to these.
+fn parse_type(name: &str) -> Type {
+    match name {
+        "f32" => Type::F32,
+        "f64" => Type::F64,
+        "u32" => Type::U32,
+        "u64" => Type::U64,
+        "i32" => Type::I32,
+        "i64" => Type::I64,
+        "usize" => Type::Usize,
+        "bool" => Type::Bool,
+        "string" => Type::StringRef,
+        _ => unreachable!(),
+    }
+}
+
+/// The op applying the operator to two values of the type.
+fn binary_op(operator: Rule, operand: &Type) -> Option<Op> {
+    let op = match (operator, operand) {
+        (Rule::add, Type::F32) => Op::AddF32,
+        (Rule::add, Type::F64) => Op::AddF64,
+        (Rule::add, Type::U32) => Op::AddU32,
+        (Rule::add, Type::U64) => Op::AddU64,
+        (Rule::add, Type::I32) => Op::AddI32,
+        (Rule::add, Type::I64) => Op::AddI64,
+
+        (Rule::sub, Type::F32) => Op::SubF32,
+        (Rule::sub, Type::F64) => Op::SubF64,
+        (Rule::sub, Type::U32) => Op::SubU32,
+        (Rule::sub, Type::U64) => Op::SubU64,
+        (Rule::sub, Type::I32) => Op::SubI32,
+        (Rule::sub, Type::I64) => Op::SubI64,
+
+        (Rule::lt, Type::F32) => Op::LtF32,
+        (Rule::lt, Type::F64) => Op::LtF64,
+        (Rule::lt, Type::U32) => Op::LtU32,
+        (Rule::lt, Type::U64) => Op::LtU64,
+        (Rule::lt, Type::I32) => Op::LtI32,
+        (Rule::lt, Type::I64) => Op::LtI64,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// The value of a number literal.
+fn parse_value(pair: &Pair<Rule>) -> Result<Value, LangError> {
+    let value = match pair.as_rule() {
+        Rule::F32 => Value::F32(parse_literal(pair)?),
+        Rule::F64 => Value::F64(parse_literal(pair)?),
+        Rule::U32 => Value::U32(parse_literal(pair)?),
+        Rule::U64 => Value::U64(parse_literal(pair)?),
+        Rule::I32 => Value::I32(parse_literal(pair)?),
+        Rule::I64 => Value::I64(parse_literal(pair)?),
+        _ => unreachable!(),
+    };
+    Ok(value)
+}
+
+/// The type of a number literal.
+fn literal_type(literal: Rule) -> Type {
+    match literal {
+        Rule::F32 => Type::F32,
+        Rule::F64 => Type::F64,
+        Rule::U32 => Type::U32,
+        Rule::U64 => Type::U64,
+        Rule::I32 => Type::I32,
+        _ => Type::I64,
+    }
+}
+
+/// The names of the sort keys of an `order` or `order by`, and whether
+/// each is descending.
+fn sort_keys<'a>(pair: Pair<'a, Rule>) -> Vec<(&'a str, bool)> {
+    pair.into_inner()
+        .map(|key| {
+            let mut parts = key.into_inner();
+            let name = parts.next().unwrap().as_str();
+            (name, parts.next().is_some())
+        })
+        .collect()
+}
+
+/// The count of a `limit` or `offset`.
+fn parse_row_count(pair: Pair<Rule>) -> Result<usize, LangError> {
+    let count = pair.into_inner().next().unwrap();
+    count.as_str().parse().map_err(|_| LangError::InvalidNumber(count.as_str().to_string()))
+}
+
+fn parse_literal<T: std::str::FromStr>(pair: &Pair<Rule>) -> Result<T, LangError> {
+    match parse_number(pair.as_str()) {
+        Some(v) => Ok(v),
+        None => Err(LangError::InvalidNumber(pair.as_str().to_string())),
+    }
+}
+
+/// Compiles the pair, locating errors in a named source at the innermost
+/// pair they were found in.
 fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Result<(), LangError> {
+    let span = pair.as_span();
+    compile_pair(builder, pair).map_err(|error| builder.locate(error, span))
+}
+
+fn compile_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Result<(), LangError> {
     use Rule::*;
 
     match pair.as_rule() {
         WHITESPACE
+        | COMMENT
         | number
         | value
         | op
         | expression
         | statment
+        | term
+        | group_value
+        | operand
+        | where_test
         | program
         // These rules are silent
         => unreachable!(),
-        EOI => { 
+        EOI => {
             // Noop
         },
 
         F32 => {
             // (Rule::F32(s:str)) => Op::F32(parse(str))
-            let v = pair.as_str().parse().unwrap();
-            builder.code.push(Op::F32(v));
+            let v = parse_literal(&pair)?;
+            builder.push(Op::F32(v), Type::F32);
         },
         F64 => {
-            let v = pair.as_str().parse().unwrap();
-            builder.code.push(Op::F64(v));
+            let v = parse_literal(&pair)?;
+            builder.push(Op::F64(v), Type::F64);
         },
         I32 => {
-            let v = pair.as_str().parse().unwrap();
-            builder.code.push(Op::I32(v));
+            let v = parse_literal(&pair)?;
+            builder.push(Op::I32(v), Type::I32);
         },
         I64 => {
-            let v = pair.as_str().parse().unwrap();
-            builder.code.push(Op::I64(v));
+            let v = parse_literal(&pair)?;
+            builder.push(Op::I64(v), Type::I64);
+        },
+        string => {
+            let text = pair.as_str().trim_matches('"');
+            builder.push(Op::Str(text.to_string()), Type::StringRef);
         },
         U32 => {
-            let v = pair.as_str().parse().unwrap();
-            builder.code.push(Op::U32(v));
+            let v = parse_literal(&pair)?;
+            builder.push(Op::U32(v), Type::U32);
         },
         U64 => {
-            let v = pair.as_str().parse().unwrap();
-            builder.code.push(Op::U64(v));
+            let v = parse_literal(&pair)?;
+            builder.push(Op::U64(v), Type::U64);
         },
 
         symbol => {
@@ -132,28 +1317,34 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
         },
         var => {
             let name = pair.as_str();
-            let Some(offset) = builder.scope.get(name) else {
+            let Some((offset, var_type)) = builder.frame.scope.get(name) else {
                 return Err(LangError::UnknownVar(name.to_string()));
             };
 
-            builder.code.push(Op::Usize(*offset));
-            builder.code.push(Op::Load);
-
+            let offset = *offset;
+            let var_type = var_type.clone();
+            builder.push(Op::LoadImm(offset), var_type);
         },
-        add => {
-            builder.code.push(Op::Add);
-        },
-        sub => {
-            //builder.code.push(Op::Sub);
-        },
-        mul => {
-            //builder.code.push(Op::Mul);
-        },
-        div => {
-            //builder.code.push(Op::Div);
+        add | sub | lt => {
+            let second = builder.pop_type();
+            let first = builder.pop_type();
+
+            if first != second {
+                return Err(LangError::TypeMismatch(Box::new(first), Box::new(second)));
+            }
+
+            let Some(op_code) = binary_op(pair.as_rule(), &first) else {
+                return Err(LangError::UnsupportedType(first));
+            };
+
+            let result = match pair.as_rule() {
+                lt => Type::Bool,
+                _ => first,
+            };
+            builder.push(op_code, result);
         },
-        exp => {
-            //builder.code.push(Op::Exp);
+        mul | div | exp => {
+            return Err(LangError::UnsupportedOperator(pair.as_str().to_string()));
         },
         opperation => {
             let mut parts = pair.into_inner();
@@ -167,6 +1358,20 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             parse_pair(builder, operator)?;
         },
 
+        yield_expr => {
+            for pair in pair.into_inner() {
+                parse_pair(builder, pair)?;
+            }
+
+            // The host resumes the Vm with a value of the same type as the
+            // one yielded.
+            let yield_type = builder.pop_type();
+            let Some(kind) = Kind::of(&yield_type) else {
+                return Err(LangError::UnsupportedType(yield_type));
+            };
+            builder.push(Op::Yield(kind), yield_type);
+        },
+
         params => {
             let parts = pair.into_inner();
             for p in parts {
@@ -180,22 +1385,24 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             let sym = parts.next().unwrap();
             let arguments = parts.next().unwrap();
 
+            let mut arg_count = 0;
             for a in arguments.into_inner() {
                 parse_pair(builder, a)?;
+                arg_count += 1;
             }
 
+            let at = builder.frame.types.len() - arg_count;
+            let arg_types = builder.frame.types.split_off(at);
+
             let name = sym.as_str();
+            let index = builder.function(name, arg_types)?;
 
-            let Some(fn_info) = builder.functions.get(name) else {
-                return Err(LangError::UnknownFunction(name.to_string()));
+            let Some(ret_type) = builder.functions[index].ret.clone() else {
+                return Err(LangError::UnknownReturnType(name.to_string()));
             };
 
-            builder.code.push(Op::Usize(1)); // BOOG
-            builder.code.push(Op::Usize(fn_info.arg_count));
-            builder.code.push(Op::Fn(fn_info.index));
-            builder.code.push(Op::Call);
-
-  
+            let call_fn = Op::CallFn { ptr: index, args: arg_count, rets: 1 };
+            builder.push(call_fn, ret_type);
         },
 
         declaration => {
@@ -207,71 +1414,247 @@ fn parse_pair<'a>(builder: &mut ModuleBuilder<'a>, pair: Pair<'a, Rule>) -> Resu
             parse_pair(builder, r_value)?;
 
             let name = l_value.as_str();
+            let var_type = builder.pop_type();
+            let offset = builder.new_var(name, var_type)?;
 
-            let offset = builder.frame_size;
-            builder.frame_size += 1;
-
-            builder.scope.insert(name, offset);
-
-            builder.code.push(Op::Usize(offset));
-            builder.code.push(Op::Store);
+            builder.frame.code.push(Op::StoreImm(offset));
         },
 
         ret => {
+            if builder.frame.queries > 0 {
+                return Err(LangError::ReturnInQuery);
+            }
+            if !builder.frame.transactions.is_empty() {
+                return Err(LangError::ReturnInTransaction);
+            }
+
             for pair in pair.into_inner() {
                 parse_pair(builder, pair)?;
             }
-            builder.code.push(Op::Return);
+
+            let ret_type = builder.pop_type();
+            builder.set_return(ret_type)?;
+            builder.frame.code.push(Op::Return);
+            // A return in a block may not be taken.
+            builder.frame.returned = builder.frame.depth == 0;
         },
-        args => {todo!()},
-        body => {todo!()},
-        function => {
-            builder.new_frame();
 
+        assignment => {
             let mut parts = pair.into_inner();
 
-            let fn_name = parts.next().unwrap();
-            let fn_args = parts.next().unwrap();
-            let fn_body = parts.next().unwrap();
+            let l_value = parts.next().unwrap();
+            let r_value = parts.next().unwrap();
 
-            let index = builder.code.len();
+            parse_pair(builder, r_value)?;
+            let value_type = builder.pop_type();
 
-            // Process fn args
-            let mut arg_count = 0;
-            for arg_n in fn_args.into_inner() {
-                let name = arg_n.as_str();
-                let offset = builder.frame_size;
-                arg_count += 1;
-                builder.frame_size += 1;
-                builder.scope.insert(name, offset);
+            let name = l_value.as_str();
+            let Some((offset, var_type)) = builder.frame.scope.get(name) else {
+                return Err(LangError::UnknownVar(name.to_string()));
+            };
+
+            if *var_type != value_type {
+                return Err(LangError::TypeMismatch(Box::new(var_type.clone()), Box::new(value_type)));
             }
 
-            // Process statements
-            for statement_n in fn_body.into_inner() {
-                parse_pair(builder, statement_n)?;
+            let offset = *offset;
+            builder.frame.code.push(Op::StoreImm(offset));
+        },
+
+        block => {
+            // Values left by expression statements are dropped so a block
+            // leaves the stack as it found it.
+            let declared = builder.frame.declared.len();
+            let stack_depth = builder.frame.types.len();
+            builder.frame.depth += 1;
+
+            for statement in pair.into_inner() {
+                parse_pair(builder, statement)?;
+                while builder.frame.types.len() > stack_depth {
+                    builder.pop_type();
+                    builder.frame.code.push(Op::Pop);
+                }
             }
-            builder.code.push(Op::Return);
 
+            builder.frame.depth -= 1;
+            builder.end_block(declared);
+        },
+
+        if_block => {
+            let mut parts = pair.into_inner();
+
+            let condition = parts.next().unwrap();
+            let then_block = parts.next().unwrap();
+            let else_block = parts.next();
 
-            // allocate space on the stack for vars.
-            let var_count = builder.frame_size - arg_count;
+            builder.condition(condition)?;
+            let to_else = builder.jump(Op::JumpIfFalse(0));
+            parse_pair(builder, then_block)?;
 
-            for _  in 0..var_count {
-                builder.code.insert(index, Op::None);
+            match else_block {
+                Some(else_block) => {
+                    let to_end = builder.jump(Op::Jump(0));
+                    builder.patch(to_else);
+                    parse_pair(builder, else_block)?;
+                    builder.patch(to_end);
+                },
+                None => builder.patch(to_else),
             }
+        },
 
-            // Process fn name
+        while_block => {
+            let mut parts = pair.into_inner();
 
-            let name = fn_name.as_str();
-            let fn_type = FnType { 
-                index,
-                arg_count,
-                frame_size: builder.frame_size,
-            };
-            builder.functions.insert(name, fn_type);
+            let condition = parts.next().unwrap();
+            let loop_body = parts.next().unwrap();
+
+            let start = builder.frame.code.len();
+            builder.condition(condition)?;
+            let to_end = builder.jump(Op::JumpIfFalse(0));
+            parse_pair(builder, loop_body)?;
+            builder.frame.code.push(Op::Jump(start));
+            builder.patch(to_end);
+        },
+
+        for_block => {
+            let mut parts: Vec<_> = pair.into_inner().collect();
+            let loop_body = parts.pop().unwrap();
+            let tables = parts.remove(0);
 
-        }, 
+            let mut patterns = tables.into_inner()
+                .map(|table_pattern| builder.pattern(table_pattern))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // The clauses between the patterns and the body.
+            let mut window = None;
+            for clause in parts {
+                if clause.as_rule() == where_clause {
+                    builder.conditions(clause, &mut patterns)?;
+                    continue;
+                }
+                let ordered = window.get_or_insert_with(Window::default);
+                match clause.as_rule() {
+                    order_by => ordered.order = sort_keys(clause),
+                    limit_clause => ordered.limit = Some(parse_row_count(clause)?),
+                    _ => ordered.offset = parse_row_count(clause)?,
+                }
+            }
+
+            if window.is_some() && patterns.len() > 1 {
+                return Err(LangError::OrderedJoin);
+            }
+
+            // Vars bound by the patterns are only in scope in the loop.
+            let declared = builder.frame.declared.len();
+            let patterns = builder.plan(patterns);
+            builder.join(&patterns, window.as_ref(), loop_body)?;
+            builder.end_block(declared);
+        },
+
+        insert => {
+            let record = builder.pattern(pair.into_inner().next().unwrap())?;
+            builder.insert(record)?;
+        },
+
+        aggregate => {
+            let mut parts = pair.into_inner();
+            let folded = parts.next().unwrap();
+            let mut records = [builder.pattern(parts.next().unwrap())?];
+            if let Some(clause) = parts.next() {
+                builder.conditions(clause, &mut records)?;
+            }
+            let [records] = records;
+            builder.aggregate(folded, records)?;
+        },
+
+        aggregate_into => {
+            let mut parts = pair.into_inner();
+            let name = parts.next().unwrap().as_str();
+
+            let mut columns = Vec::new();
+            let mut records = None;
+            let mut keys = Vec::new();
+            for part in parts {
+                match part.as_rule() {
+                    group_field => columns.push(part),
+                    pattern => records = Some(builder.pattern(part)?),
+                    where_clause => builder.conditions(part, records.as_mut_slice())?,
+                    _ => keys.extend(part.into_inner().map(|key| key.as_str())),
+                }
+            }
+            builder.group(name, columns, records.unwrap(), keys)?;
+        },
+
+        transaction_block => {
+            // Transactions can't begin inside a `for` loop so a `rollback`
+            // is only ever nested in loops it would leave.
+            if builder.frame.queries > 0 {
+                return Err(LangError::TransactionInQuery);
+            }
+
+            builder.frame.code.push(Op::Begin);
+            builder.frame.transactions.push(Vec::new());
+            let result = parse_pair(builder, pair.into_inner().next().unwrap());
+            let rollbacks = builder.frame.transactions.pop().unwrap();
+            result?;
+
+            builder.frame.code.push(Op::Commit);
+            for rollback_jump in rollbacks {
+                builder.patch(rollback_jump);
+            }
+        },
+
+        rollback => {
+            if builder.frame.transactions.is_empty() {
+                return Err(LangError::RollbackOutsideTransaction);
+            }
+            if builder.frame.queries > 0 {
+                return Err(LangError::RollbackInQuery);
+            }
+
+            builder.frame.code.push(Op::Rollback);
+            let to_end = builder.jump(Op::Jump(0));
+            builder.frame.transactions.last_mut().unwrap().push(to_end);
+        },
+
+        join | pattern | field_value | fold | fold_fn | group_field | group_by | order_by
+        | sort_key | descending | limit_clause | offset_clause | row_count | where_clause
+        | comparison | compare_op | membership | prefix_test => {
+            // Handled with their statement
+            unreachable!()
+        },
+
+        args | arg_decl | body => {
+            // Handled when the function is compiled
+            unreachable!()
+        },
+
+        table_decl => builder.table(pair)?,
+
+        // Rules are compiled once every table is declared.
+        rule_decl => builder.rules.push((builder.source, pair)),
+
+        atom => {
+            // Handled with their rule
+            unreachable!()
+        },
+
+        field_decl | index_decl | file_decl | order_decl | file_path | type_name => {
+            // Handled with their table
+            unreachable!()
+        },
+
+        function => {
+            // Functions are compiled when they are first called, once the
+            // types of their arguments are known.
+            let mut parts = pair.clone().into_inner();
+            let name = parts.next().unwrap().as_str();
+            let definition = (builder.source, pair);
+            if let Some((source, first)) = builder.definitions.insert(name, definition) {
+                let first = source.map(|source| Location::of(source, first.as_span()));
+                return Err(LangError::FunctionAlreadyDefined(name.to_string(), first));
+            }
+        },
     };
     Ok(())
 }
-
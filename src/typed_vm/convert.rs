@@ -0,0 +1,171 @@
+//! Calling colang functions from the host. `Vm::call` finds a function
+//! by its name and the types of the arguments it is given, so it calls
+//! the function compiled for those types: one declaring the type of each
+//! argument, such as `fn add(a: i64, b: i64)`, or one `main` calls with
+//! them.
+
+use super::*;
+
+/// A Rust value which can be passed to colang code.
+pub trait ToValue {
+    fn to_value(self) -> Value;
+}
+
+/// A Rust value which can be read from a value colang code produced.
+/// Values of another kind give `VmError::TypeCheck`.
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, VmError>;
+}
+
+/// The arguments of `Vm::call`, a tuple with one value per argument.
+pub trait Args {
+    fn into_values(self) -> Vec<Value>;
+}
+
+impl<T: Slot> ToValue for T {
+    fn to_value(self) -> Value {
+        Value::from_slot(T::KIND, self.into_bits())
+    }
+}
+
+impl<T: Slot> FromValue for T {
+    fn from_value(value: Value) -> Result<Self, VmError> {
+        match value.kind() {
+            Some(kind) if kind == T::KIND => Ok(T::from_bits(value.bits())),
+            _ => Err(VmError::TypeCheck),
+        }
+    }
+}
+
+impl ToValue for Value {
+    fn to_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, VmError> {
+        Ok(value)
+    }
+}
+
+impl ToValue for () {
+    fn to_value(self) -> Value {
+        Value::None
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, VmError> {
+        match value {
+            Value::None => Ok(()),
+            _ => Err(VmError::TypeCheck),
+        }
+    }
+}
+
+impl Args for () {
+    fn into_values(self) -> Vec<Value> {
+        Vec::new()
+    }
+}
+
+macro_rules! args {
+    ($($name:ident),+) => {
+        impl<$($name: ToValue),+> Args for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Vec<Value> {
+                let ($($name,)+) = self;
+                vec![$($name.to_value()),+]
+            }
+        }
+    };
+}
+
+args!(A);
+args!(A, B);
+args!(A, B, C);
+args!(A, B, C, D);
+args!(A, B, C, D, E);
+args!(A, B, C, D, E, F);
+
+/// The type of an argument the host passes, None for values which are
+/// only meaningful inside the Vm.
+fn arg_type(value: &Value) -> Option<Type> {
+    let arg_type = match value {
+        Value::None => Type::None,
+        Value::Usize(_) => Type::Usize,
+        Value::F32(_) => Type::F32,
+        Value::F64(_) => Type::F64,
+        Value::U32(_) => Type::U32,
+        Value::U64(_) => Type::U64,
+        Value::I32(_) => Type::I32,
+        Value::I64(_) => Type::I64,
+        Value::StringRef { .. } => Type::StringRef,
+        Value::Bool(_) => Type::Bool,
+        _ => return None,
+    };
+    Some(arg_type)
+}
+
+impl Vm {
+    /// Calls the function compiled for the types of the arguments,
+    /// converting the value it returns.
+    ///
+    /// ```
+    /// let source = "fn add(a: i64, b: i64) { a + b; } fn main() { 1; }";
+    /// let mut vm = colang::Vm::new(colang::compile(source)?);
+    ///
+    /// assert_eq!(vm.call::<(i64, i64), i64>("add", (5, 7))?, 12);
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// The Vm can be called before or after `run`, but not while it is
+    /// suspended or once it has failed. If the function yields or fails
+    /// the Vm is left where it stopped and the call returns
+    /// `VmError::Suspended` or the error.
+    pub fn call<A: Args, R: FromValue>(&mut self, name: &str, args: A) -> Result<R, VmError> {
+        match self.state {
+            State::Suspended => return Err(VmError::Suspended),
+            State::Failed => return Err(VmError::Failed),
+            State::Ready | State::Halted => {},
+        }
+
+        let args = args.into_values();
+        let arg_types = args.iter()
+            .map(arg_type)
+            .collect::<Option<Vec<_>>>()
+            .ok_or(VmError::TypeCheck)?;
+        let Some(signature) = self.module.functions.signature(name, &arg_types) else {
+            return Err(VmError::UnknownFunction(name.to_string()));
+        };
+        // Structs take more than one slot and handles stay in the Vm.
+        let ret = match Kind::of(&signature.ret) {
+            Some(Kind::Struct | Kind::Table | Kind::Cursor) | None => {
+                return Err(VmError::TypeCheck);
+            },
+            Some(kind) => kind,
+        };
+        let ptr = signature.ptr;
+
+        let (instruction_pointer, state) = (self.instruction_pointer, self.state);
+        let arg_count = args.len();
+        for value in args {
+            self.stack.push_value(value)?;
+        }
+
+        // Returning lands on the Halt at the start of the code.
+        self.instruction_pointer = 0;
+        self.enter(ptr, arg_count, 1)?;
+        self.state = State::Ready;
+
+        if let Status::Yielded(_) = self.run()? {
+            return Err(VmError::Suspended);
+        }
+
+        let result = Value::from_slot(ret, self.stack.pop_slot(ret)?);
+        self.instruction_pointer = instruction_pointer;
+        self.state = state;
+        R::from_value(result)
+    }
+}
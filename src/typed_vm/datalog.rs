@@ -0,0 +1,308 @@
+//! Derived tables, whose records are derived by rules from the records of
+//! other tables. A rule `ancestor(a, c) :- parent(a, b), ancestor(b, c)`
+//! derives a record of its table for each match of the atoms of its body
+//! which binds each var to one value.
+//!
+//! A derived table is derived again each time `Op::TakeTable` takes it,
+//! from the records the Vm's tables hold at that time. Derived tables
+//! whose rules match each other are derived together by semi-naive
+//! iteration: after the first round each round only matches rules with
+//! at least one atom against the records derived by the round before, and
+//! stops once a round derives nothing new.
+//!
+//! Deriving runs inside the `Op::TakeTable` that asks for it, so it is
+//! metered as it goes: each record an atom is matched against burns a
+//! unit of fuel, and every table derived along the way, not just the one
+//! taken, is held to the row limit.
+
+use super::*;
+use self::table::{key, MemTable};
+use std::collections::{BTreeSet, HashSet};
+
+/// A term of an atom, either a var numbered within its rule or a value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Term {
+    Var(usize),
+    Value(Value),
+}
+
+/// A table and a term for each of its fields, matching records whose
+/// fields equal the terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atom {
+    pub type_index: u32,
+    pub terms: Vec<Term>,
+}
+
+/// A rule of a derived table. Each match of every atom of the body
+/// derives a record holding the head's terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRule {
+    pub head: Vec<Term>,
+    pub body: Vec<Atom>,
+}
+
+impl TableRule {
+    /// The number of vars the rule numbers.
+    fn var_count(&self) -> usize {
+        self.body.iter()
+            .flat_map(|atom| &atom.terms)
+            .chain(&self.head)
+            .filter_map(|term| match term {
+                Term::Var(var) => Some(var + 1),
+                Term::Value(_) => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// The records of a table, each held once.
+#[derive(Debug, Default)]
+struct Relation {
+    rows: Vec<Vec<Value>>,
+    keys: HashSet<Vec<u64>>,
+}
+
+impl Relation {
+    /// Adds the row, returning false if the relation already holds it.
+    fn insert(&mut self, row: &[Value]) -> bool {
+        let inserted = self.keys.insert(row.iter().map(key).collect());
+        if inserted {
+            self.rows.push(row.to_vec());
+        }
+        inserted
+    }
+}
+
+/// Takes a unit of fuel, failing once a metered Vm has none left.
+fn burn(fuel: &mut Option<u64>) -> Result<(), VmError> {
+    match fuel {
+        Some(0) => Err(VmError::OutOfFuel),
+        Some(fuel) => {
+            *fuel -= 1;
+            Ok(())
+        },
+        None => Ok(()),
+    }
+}
+
+/// The derived tables the derived table's rules match, including itself,
+/// and the other tables they match.
+pub(super) fn dependencies(type_index: u32, types: &BTreeMap<u32, TableType>)
+-> (BTreeSet<u32>, BTreeSet<u32>) {
+    let mut derived = BTreeSet::new();
+    let mut base = BTreeSet::new();
+    let mut pending = vec![type_index];
+
+    while let Some(type_index) = pending.pop() {
+        if !derived.insert(type_index) {
+            continue;
+        }
+        let rules = types.get(&type_index).map_or(&[][..], |t| &t.rules);
+        for atom in rules.iter().flat_map(|rule| &rule.body) {
+            match types.get(&atom.type_index) {
+                Some(table_type) if !table_type.rules.is_empty() => pending.push(atom.type_index),
+                _ => {
+                    base.insert(atom.type_index);
+                },
+            }
+        }
+    }
+
+    (derived, base)
+}
+
+/// Derives the records of the derived tables from the records of the base
+/// tables they match, burning `fuel` as it matches and failing if any of
+/// them would hold more than `max_rows` records.
+pub(super) fn evaluate(
+    derived: &BTreeSet<u32>,
+    types: &BTreeMap<u32, TableType>,
+    base: &BTreeMap<u32, &[Vec<Value>]>,
+    fuel: &mut Option<u64>,
+    max_rows: Option<usize>,
+) -> Result<BTreeMap<u32, Vec<Vec<Value>>>, VmError> {
+    let mut full: BTreeMap<u32, Relation> = derived.iter()
+        .map(|type_index| (*type_index, Relation::default()))
+        .collect();
+    let mut delta: BTreeMap<u32, Vec<Vec<Value>>> = BTreeMap::new();
+    let mut first = true;
+
+    loop {
+        let mut derivations = Vec::new();
+        for type_index in derived {
+            for rule in &types[type_index].rules {
+                let records = |atom: &Atom, from_delta: bool| -> &[Vec<Value>] {
+                    if from_delta {
+                        return delta.get(&atom.type_index).map_or(&[], Vec::as_slice);
+                    }
+                    match full.get(&atom.type_index) {
+                        Some(relation) => &relation.rows,
+                        None => base.get(&atom.type_index).copied().unwrap_or_default(),
+                    }
+                };
+
+                if first {
+                    let sources: Vec<_> = rule.body.iter().map(|atom| records(atom, false)).collect();
+                    derive(rule, &sources, fuel, &mut |row| derivations.push((*type_index, row)))?;
+                    continue;
+                }
+
+                // A new record must match at least one record derived by the
+                // last round.
+                for (i, atom) in rule.body.iter().enumerate() {
+                    if !derived.contains(&atom.type_index) {
+                        continue;
+                    }
+                    let sources: Vec<_> = rule.body.iter()
+                        .enumerate()
+                        .map(|(j, atom)| records(atom, i == j))
+                        .collect();
+                    derive(rule, &sources, fuel, &mut |row| derivations.push((*type_index, row)))?;
+                }
+            }
+        }
+
+        delta.clear();
+        for (type_index, row) in derivations {
+            let relation = full.get_mut(&type_index).unwrap();
+            if relation.insert(&row) {
+                if max_rows.is_some_and(|max_rows| relation.rows.len() > max_rows) {
+                    return Err(VmError::TableFull);
+                }
+                delta.entry(type_index).or_default().push(row);
+            }
+        }
+
+        if delta.is_empty() {
+            break;
+        }
+        first = false;
+    }
+
+    Ok(full.into_iter()
+        .map(|(type_index, relation)| (type_index, relation.rows))
+        .collect())
+}
+
+/// Calls `found` with the head of the rule for each match of its body,
+/// matching each atom against the records of the source at its position.
+fn derive(
+    rule: &TableRule,
+    sources: &[&[Vec<Value>]],
+    fuel: &mut Option<u64>,
+    found: &mut dyn FnMut(Vec<Value>),
+) -> Result<(), VmError> {
+    let mut vars = vec![None; rule.var_count()];
+    match_atoms(rule, sources, 0, &mut vars, fuel, found)
+}
+
+fn match_atoms(
+    rule: &TableRule,
+    sources: &[&[Vec<Value>]],
+    at: usize,
+    vars: &mut Vec<Option<Value>>,
+    fuel: &mut Option<u64>,
+    found: &mut dyn FnMut(Vec<Value>),
+) -> Result<(), VmError> {
+    let Some(atom) = rule.body.get(at) else {
+        let head = rule.head.iter()
+            .map(|term| match term {
+                Term::Value(value) => *value,
+                // The compiler only allows vars in the head that the body
+                // binds.
+                Term::Var(var) => vars[*var].unwrap_or(Value::None),
+            })
+            .collect();
+        found(head);
+        return Ok(());
+    };
+
+    let eq = |a: &Value, b: &Value| matches!(Vm::eq_value(a, b), Ok(true));
+
+    for row in sources[at] {
+        burn(fuel)?;
+        let mut bound = Vec::new();
+        let mut matched = true;
+
+        for (term, value) in atom.terms.iter().zip(row) {
+            match term {
+                Term::Value(term) => matched = eq(term, value),
+                Term::Var(var) => match &vars[*var] {
+                    Some(bound_value) => matched = eq(bound_value, value),
+                    None => {
+                        vars[*var] = Some(*value);
+                        bound.push(*var);
+                    },
+                },
+            }
+            if !matched {
+                break;
+            }
+        }
+
+        if matched {
+            match_atoms(rule, sources, at + 1, vars, fuel, found)?;
+        }
+        for var in bound {
+            vars[var] = None;
+        }
+    }
+    Ok(())
+}
+
+impl Vm {
+    /// Derives the records of the derived table, opening the tables stored
+    /// in files it is derived from if the Vm hasn't yet.
+    pub(super) fn derive(&mut self, type_index: u32) -> Result<MemTable, VmError> {
+        let module = self.module.clone();
+        let types = &module.types;
+        let (derived, base) = dependencies(type_index, types);
+
+        for type_index in &base {
+            let Some(table_type) = types.get(type_index) else {
+                continue;
+            };
+            if table_type.storage != Storage::Memory && !self.tables.contains_key(type_index) {
+                let table = MemTable::open(*type_index, table_type, self.limits.max_table_rows)?;
+                let handle = self.handles.insert(Resource::Table(TableTypes::Mem(table)));
+                self.tables.insert(*type_index, Some(handle));
+            }
+        }
+
+        let base = base.into_iter()
+            .map(|type_index| (type_index, self.held_rows(type_index)))
+            .collect();
+        // The fuel is copied out while the base records borrow the Vm, and
+        // what's left written back even if deriving ran out.
+        let mut fuel = self.fuel;
+        let records = evaluate(&derived, types, &base, &mut fuel, self.limits.max_table_rows);
+        self.fuel = fuel;
+
+        let mut table = MemTable::new(type_index, &types[&type_index], self.limits.max_table_rows)?;
+        table.held = true;
+        for row in records?.remove(&type_index).unwrap_or_default() {
+            table.push_row(row);
+        }
+        Ok(table)
+    }
+
+    /// The records of the Vm's table of the type, whether it is held for
+    /// `TakeTable` or has been taken in to a cursor. Empty if the Vm
+    /// hasn't opened the table yet, which for a table stored in a file
+    /// `derive` does first.
+    pub(super) fn held_rows(&self, type_index: u32) -> &[Vec<Value>] {
+        self.handles.entries.iter()
+            .flatten()
+            .find_map(|resource| {
+                let table = match resource {
+                    Resource::Table(TableTypes::Mem(table)) => table,
+                    Resource::Cursor(CursorTypes::Mem(cursor)) => &cursor.table,
+                    _ => return None,
+                };
+                (table.held && table.type_index == type_index).then_some(&table.rows[..])
+            })
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,662 @@
+//! Moving records between the Vm's tables and text: CSV with a header
+//! row naming the fields, or JSON lines with an object per record. The
+//! host seeds a table with `Vm::import`, and reads a table back out with
+//! `Vm::export` or the records a query matches with `Vm::export_query`.
+//!
+//! Values are converted by the types of the table's fields in
+//! `Module.types`. Numbers and bools are written as Rust writes them,
+//! string refs as their text, which is interned on import, and None as an
+//! empty CSV field or a JSON null. The fields of tables declared without
+//! names are named by their position, from 0.
+//!
+//! Errors number the records of a file from 1, the CSV header being
+//! record 0.
+
+use super::*;
+use self::table::query_fits;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+/// The text formats tables are imported from and exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Comma separated values with a header row naming the fields.
+    /// Fields holding commas, quotes or line breaks are quoted.
+    Csv,
+    /// A JSON object on each line with a member for each field.
+    JsonLines,
+}
+
+impl Format {
+    /// The format of files with the path's extension: `.csv`, or `.jsonl`
+    /// or `.ndjson` for JSON lines.
+    pub fn of_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(Format::Csv),
+            "jsonl" | "ndjson" => Some(Format::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    Vm(VmError),
+    /// The path's extension isn't one `Format::of_path` knows.
+    UnknownFormat(PathBuf),
+    /// The record isn't valid CSV or JSON, or has the wrong number of
+    /// fields for the CSV header.
+    Syntax { row: usize, message: String },
+    /// The record's value in the column doesn't convert to the type of
+    /// the field, or the column isn't one of the table's fields, or the
+    /// record lacks it.
+    Field { row: usize, column: String, message: String },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(error) => write!(f, "{}", error),
+            FormatError::Vm(error) => write!(f, "{}", error),
+            FormatError::UnknownFormat(path) => {
+                write!(f, "unknown format for `{}`", path.display())
+            },
+            FormatError::Syntax { row, message } => write!(f, "row {}: {}", row, message),
+            FormatError::Field { row, column, message } => {
+                write!(f, "row {}, column `{}`: {}", row, column, message)
+            },
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(error) => Some(error),
+            FormatError::Vm(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(error: io::Error) -> Self {
+        FormatError::Io(error)
+    }
+}
+
+impl From<VmError> for FormatError {
+    fn from(error: VmError) -> Self {
+        FormatError::Vm(error)
+    }
+}
+
+/// A value read from a file, before it is converted to the type of its
+/// field.
+#[derive(Debug)]
+enum Cell {
+    /// A CSV field, which is converted from its text whatever the type.
+    Raw(String),
+    Null,
+    Bool(bool),
+    Number(String),
+    Text(String),
+}
+
+impl fmt::Display for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cell::Raw(text) | Cell::Number(text) => write!(f, "`{}`", text),
+            Cell::Null => write!(f, "null"),
+            Cell::Bool(value) => write!(f, "`{}`", value),
+            Cell::Text(text) => write!(f, "the string {:?}", text),
+        }
+    }
+}
+
+fn type_name(field_type: &Type) -> String {
+    match field_type {
+        Type::None => "an empty value".to_string(),
+        Type::Usize => "usize".to_string(),
+        Type::F32 => "f32".to_string(),
+        Type::F64 => "f64".to_string(),
+        Type::U32 => "u32".to_string(),
+        Type::U64 => "u64".to_string(),
+        Type::I32 => "i32".to_string(),
+        Type::I64 => "i64".to_string(),
+        Type::Bool => "a bool".to_string(),
+        Type::StringRef => "a string".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+fn parse_number(text: &str, field_type: &Type) -> Option<Value> {
+    match field_type {
+        Type::Usize => text.parse().ok().map(Value::Usize),
+        Type::F32 => text.parse().ok().map(Value::F32),
+        Type::F64 => text.parse().ok().map(Value::F64),
+        Type::U32 => text.parse().ok().map(Value::U32),
+        Type::U64 => text.parse().ok().map(Value::U64),
+        Type::I32 => text.parse().ok().map(Value::I32),
+        Type::I64 => text.parse().ok().map(Value::I64),
+        _ => None,
+    }
+}
+
+/// The names of the table's fields, their positions if it has none.
+fn column_names(table_type: &TableType) -> Vec<String> {
+    if table_type.names.is_empty() {
+        (0..table_type.fields.len()).map(|field| field.to_string()).collect()
+    } else {
+        table_type.names.clone()
+    }
+}
+
+/// The position of each field's column among the names of the columns
+/// of a record.
+fn column_positions(names: &[&str], columns: &[String], row: usize)
+-> Result<Vec<usize>, FormatError> {
+    for (at, name) in names.iter().enumerate() {
+        let message = if !columns.iter().any(|column| column == name) {
+            "the table has no such field"
+        } else if names[..at].contains(name) {
+            "the column is repeated"
+        } else {
+            continue;
+        };
+        return Err(FormatError::Field { row, column: name.to_string(), message: message.to_string() });
+    }
+
+    columns.iter()
+        .map(|column| names.iter().position(|name| name == column).ok_or_else(|| {
+            FormatError::Field { row, column: column.clone(), message: "missing".to_string() }
+        }))
+        .collect()
+}
+
+/// Splits CSV text in to records of fields. Quoted fields may hold
+/// commas, line breaks and quotes, which are doubled. Blank lines are
+/// skipped.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, FormatError> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    // The record has a field, even if an empty one.
+    let mut started = false;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.next_if_eq(&'"').is_some() => field.push('"'),
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                quoted = true;
+                started = true;
+            },
+            ',' => {
+                record.push(std::mem::take(&mut field));
+                started = true;
+            },
+            '\r' if chars.peek() == Some(&'\n') => {},
+            '\n' | '\r' => {
+                if started || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                started = false;
+            },
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        let message = "unterminated quoted field".to_string();
+        return Err(FormatError::Syntax { row: records.len(), message });
+    }
+    if started || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Reads the CSV records after the header in to a cell for each field,
+/// by the columns the header names.
+fn csv_records(text: &str, columns: &[String]) -> Result<Vec<Vec<Cell>>, FormatError> {
+    let mut records = parse_csv(text)?.into_iter();
+    let Some(header) = records.next() else {
+        return Ok(Vec::new());
+    };
+    let names: Vec<&str> = header.iter().map(String::as_str).collect();
+    let positions = column_positions(&names, columns, 0)?;
+
+    records.enumerate()
+        .map(|(at, record)| {
+            if record.len() != header.len() {
+                let message = format!("expected {} fields, found {}", header.len(), record.len());
+                return Err(FormatError::Syntax { row: at + 1, message });
+            }
+            Ok(positions.iter().map(|position| Cell::Raw(record[*position].clone())).collect())
+        })
+        .collect()
+}
+
+/// Reads each non-blank line in to a cell for each field, by the names
+/// of the members of its object.
+fn json_records(text: &str, columns: &[String]) -> Result<Vec<Vec<Cell>>, FormatError> {
+    let mut records = Vec::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let row = records.len() + 1;
+        let members = parse_object(line).map_err(|message| FormatError::Syntax { row, message })?;
+        let names: Vec<&str> = members.iter().map(|(name, _)| name.as_str()).collect();
+        let positions = column_positions(&names, columns, row)?;
+
+        let mut cells: Vec<Option<Cell>> = members.into_iter().map(|(_, cell)| Some(cell)).collect();
+        records.push(positions.iter().filter_map(|position| cells[*position].take()).collect());
+    }
+    Ok(records)
+}
+
+/// Parses a line holding a JSON object whose members are all strings,
+/// numbers, bools or nulls.
+fn parse_object(line: &str) -> Result<Vec<(String, Cell)>, String> {
+    let mut json = Json { chars: line.chars().peekable() };
+    let mut members = Vec::new();
+    json.expect('{')?;
+    if !json.eat('}') {
+        loop {
+            let name = json.string()?;
+            json.expect(':')?;
+            members.push((name, json.value()?));
+            if json.eat(',') {
+                continue;
+            }
+            json.expect('}')?;
+            break;
+        }
+    }
+    json.space();
+    match json.chars.peek() {
+        Some(_) => Err("data follows the object".to_string()),
+        None => Ok(members),
+    }
+}
+
+struct Json<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Json<'_> {
+    fn space(&mut self) {
+        while self.chars.next_if(|c| matches!(c, ' ' | '\t' | '\r' | '\n')).is_some() {}
+    }
+
+    /// Skips space and the char if it comes next, returning true if it
+    /// did.
+    fn eat(&mut self, c: char) -> bool {
+        self.space();
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", c)))
+        }
+    }
+
+    fn unexpected(&mut self, expected: &str) -> String {
+        match self.chars.peek() {
+            Some(c) => format!("expected {}, found `{}`", expected, c),
+            None => format!("expected {}, found the end of the line", expected),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err("unterminated string".to_string()),
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let c = match self.chars.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode()?,
+                        _ => return Err("invalid escape in string".to_string()),
+                    };
+                    text.push(c);
+                },
+                Some(c) if c < ' ' => return Err("control character in string".to_string()),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    /// Reads the code unit of a `\u` escape, and the low surrogate
+    /// following a high one.
+    fn unicode(&mut self) -> Result<char, String> {
+        let unit = self.hex()?;
+        let code = if (0xd800..0xdc00).contains(&unit) {
+            let escaped = self.chars.next_if_eq(&'\\').is_some()
+                && self.chars.next_if_eq(&'u').is_some();
+            let low = if escaped { self.hex()? } else { 0 };
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err("unpaired surrogate in string".to_string());
+            }
+            0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            unit
+        };
+        char::from_u32(code).ok_or_else(|| "unpaired surrogate in string".to_string())
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let mut unit = 0;
+        for _ in 0..4 {
+            let digit = self.chars.next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| "invalid unicode escape in string".to_string())?;
+            unit = unit * 16 + digit;
+        }
+        Ok(unit)
+    }
+
+    fn value(&mut self) -> Result<Cell, String> {
+        self.space();
+        match self.chars.peek().copied() {
+            Some('"') => self.string().map(Cell::Text),
+            Some('{') | Some('[') => Err("nested values aren't supported".to_string()),
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.next_if(|c| matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E')) {
+                    number.push(c);
+                }
+                Ok(Cell::Number(number))
+            },
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "true" => Ok(Cell::Bool(true)),
+                    "false" => Ok(Cell::Bool(false)),
+                    "null" => Ok(Cell::Null),
+                    _ => Err(format!("expected a value, found `{}`", word)),
+                }
+            },
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+}
+
+/// Quotes the text if it holds a comma, quote or line break.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The text of a number or bool.
+fn scalar(value: &Value) -> String {
+    match *value {
+        Value::Usize(v) => v.to_string(),
+        Value::F32(v) => v.to_string(),
+        Value::F64(v) => v.to_string(),
+        Value::U32(v) => v.to_string(),
+        Value::U64(v) => v.to_string(),
+        Value::I32(v) => v.to_string(),
+        Value::I64(v) => v.to_string(),
+        Value::Bool(v) => v.to_string(),
+        _ => String::new(),
+    }
+}
+
+impl Vm {
+    /// Inserts the records read from the reader in to the Vm's table of
+    /// the type, returning how many there were. Every record is read and
+    /// converted before any is inserted, so a file with an error leaves
+    /// the table as it was.
+    pub fn import<R: Read>(&mut self, type_index: u32, format: Format, mut reader: R)
+    -> Result<usize, FormatError> {
+        let Some(table_type) = self.module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index).into());
+        };
+        if table_type.is_derived() {
+            return Err(VmError::InvalidOperation.into());
+        }
+        let schema = table_type.fields.clone();
+        let columns = column_names(table_type);
+
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let records = match format {
+            Format::Csv => csv_records(&text, &columns)?,
+            Format::JsonLines => json_records(&text, &columns)?,
+        };
+
+        let mut rows = Vec::with_capacity(records.len());
+        for (at, record) in records.into_iter().enumerate() {
+            let mut row = Vec::with_capacity(schema.len() + 1);
+            for ((cell, field_type), column) in record.into_iter().zip(&schema).zip(&columns) {
+                let value = self.convert(cell, field_type).map_err(|message| {
+                    FormatError::Field { row: at + 1, column: column.clone(), message }
+                })?;
+                row.push(value);
+            }
+            rows.push(row);
+        }
+
+        let count = rows.len();
+        self.track(type_index)?;
+        let handle = self.take_table(type_index)?;
+        let full = match self.handles.table(handle)? {
+            TableTypes::Mem(table) => self.limits.max_table_rows
+                .is_some_and(|max_rows| table.rows.len() + count > max_rows),
+            _ => false,
+        };
+        if full {
+            self.put_table(type_index, handle)?;
+            return Err(VmError::TableFull.into());
+        }
+
+        // A query matching nothing leaves the cursor at the end of the
+        // table, so the records are appended in the order they were read.
+        let table = self.handles.take_table(handle)?;
+        let mut cursor = table.select(vec![Constraint::In(Vec::new()); schema.len()])?;
+        let inserted = rows.into_iter().try_for_each(|mut row| {
+            row.push(Value::Struct { field_count: schema.len() });
+            cursor.insert(&mut row)
+        });
+
+        let handle = self.handles.insert(Resource::Table(cursor.close()));
+        self.put_table(type_index, handle)?;
+        inserted?;
+        Ok(count)
+    }
+
+    /// Imports the file at the path in to the Vm's table of the type, in
+    /// the format its extension names, see `import`.
+    pub fn import_file<P: AsRef<Path>>(&mut self, type_index: u32, path: P)
+    -> Result<usize, FormatError> {
+        let path = path.as_ref();
+        let format = Format::of_path(path)
+            .ok_or_else(|| FormatError::UnknownFormat(path.to_path_buf()))?;
+        self.import(type_index, format, File::open(path)?)
+    }
+
+    /// Writes the records of the Vm's table of the type to the writer,
+    /// returning how many there were.
+    pub fn export<W: Write>(&mut self, type_index: u32, format: Format, writer: W)
+    -> Result<usize, FormatError> {
+        let Some(table_type) = self.module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index).into());
+        };
+        let query = vec![Constraint::Any; table_type.fields.len()];
+        self.export_query(type_index, query, format, writer)
+    }
+
+    /// Writes the records of the Vm's table of the type which match the
+    /// query to the writer, returning how many there were. The query has
+    /// a constraint for each field, as `Table::select` takes it.
+    pub fn export_query<W: Write>(
+        &mut self,
+        type_index: u32,
+        query: Vec<Constraint<Value>>,
+        format: Format,
+        mut writer: W,
+    ) -> Result<usize, FormatError> {
+        let Some(table_type) = self.module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index).into());
+        };
+        if !query_fits(&query, &table_type.fields) {
+            return Err(VmError::TypeCheck.into());
+        }
+        let columns = column_names(table_type);
+
+        let handle = self.take_table(type_index)?;
+        let table = self.handles.take_table(handle)?;
+        let mut cursor = self.select(table, query)?;
+        let mut rows = Vec::new();
+        let read = (|| {
+            while cursor.found() {
+                let mut record = Vec::new();
+                cursor.read(&mut record)?;
+                record.pop();
+                rows.push(record);
+                cursor.advance()?;
+            }
+            Ok::<_, VmError>(())
+        })();
+
+        let handle = self.handles.insert(Resource::Table(cursor.close()));
+        self.put_table(type_index, handle)?;
+        read?;
+
+        let text = match format {
+            Format::Csv => self.csv_text(&columns, &rows),
+            Format::JsonLines => self.json_lines_text(&columns, &rows)?,
+        };
+        writer.write_all(text.as_bytes())?;
+        writer.flush()?;
+        Ok(rows.len())
+    }
+
+    /// Exports the Vm's table of the type to the file at the path, in the
+    /// format its extension names, replacing the file. See `export`.
+    pub fn export_file<P: AsRef<Path>>(&mut self, type_index: u32, path: P)
+    -> Result<usize, FormatError> {
+        let path = path.as_ref();
+        let format = Format::of_path(path)
+            .ok_or_else(|| FormatError::UnknownFormat(path.to_path_buf()))?;
+        let mut text = Vec::new();
+        let count = self.export(type_index, format, &mut text)?;
+        fs::write(path, text)?;
+        Ok(count)
+    }
+
+    /// Converts the cell to a value of the field's type, interning
+    /// strings.
+    fn convert(&mut self, cell: Cell, field_type: &Type) -> Result<Value, String> {
+        let value = match &cell {
+            Cell::Raw(text) => match field_type {
+                Type::None if text.is_empty() => Some(Value::None),
+                Type::Bool => text.parse().ok().map(Value::Bool),
+                Type::StringRef => Some(self.intern(text)),
+                _ => parse_number(text, field_type),
+            },
+            Cell::Null if *field_type == Type::None => Some(Value::None),
+            Cell::Bool(value) if *field_type == Type::Bool => Some(Value::Bool(*value)),
+            Cell::Number(text) => parse_number(text, field_type),
+            Cell::Text(text) if *field_type == Type::StringRef => Some(self.intern(text)),
+            _ => None,
+        };
+        value.ok_or_else(|| format!("expected {}, found {}", type_name(field_type), cell))
+    }
+
+    fn csv_text(&self, columns: &[String], rows: &[Vec<Value>]) -> String {
+        let header: Vec<String> = columns.iter().map(|column| csv_field(column)).collect();
+        let mut text = header.join(",") + "\n";
+        for row in rows {
+            let fields: Vec<String> = row.iter()
+                .map(|value| match value {
+                    Value::None => String::new(),
+                    Value::StringRef { .. } => csv_field(self.string(value).unwrap_or_default()),
+                    value => scalar(value),
+                })
+                .collect();
+            let line = fields.join(",");
+            // A blank line would be skipped, so a lone empty field is
+            // quoted.
+            text += if line.is_empty() { "\"\"" } else { &line };
+            text += "\n";
+        }
+        text
+    }
+
+    fn json_lines_text(&self, columns: &[String], rows: &[Vec<Value>])
+    -> Result<String, FormatError> {
+        let mut text = String::new();
+        for (at, row) in rows.iter().enumerate() {
+            let mut members = Vec::with_capacity(row.len());
+            for (value, column) in row.iter().zip(columns) {
+                let value = match value {
+                    Value::None => "null".to_string(),
+                    Value::StringRef { .. } => json_string(self.string(value).unwrap_or_default()),
+                    Value::F32(v) if !v.is_finite() => return Err(not_finite(at, column)),
+                    Value::F64(v) if !v.is_finite() => return Err(not_finite(at, column)),
+                    value => scalar(value),
+                };
+                members.push(format!("{}:{}", json_string(column), value));
+            }
+            text += &format!("{{{}}}\n", members.join(","));
+        }
+        Ok(text)
+    }
+}
+
+fn not_finite(at: usize, column: &str) -> FormatError {
+    let message = "JSON can't hold infinities or NaNs".to_string();
+    FormatError::Field { row: at + 1, column: column.to_string(), message }
+}
@@ -0,0 +1,302 @@
+//! Tables the host provides, so scripts can query data the host keeps in
+//! its own structures with the same cursor ops as the Vm's tables. The
+//! host implements `Table` and `Cursor` over `Value` and registers the
+//! table as the Vm's table of a type from `Module.types`, which gives the
+//! types of its fields. Queries, reads and changes then call in to the
+//! host's implementation.
+//!
+//! Records read from a host table are checked against the type's fields,
+//! as are the records written to it. A read-only table fails any insert,
+//! update or delete with `VmError::ReadOnly` before calling the host.
+//!
+//! Host tables aren't copied by transactions, so a read-write host table
+//! can't be changed while one is open. Types matched by rules can't have
+//! a host table, rules only derive from the Vm's own tables. Host tables
+//! can't be saved in a snapshot.
+
+use super::*;
+use self::table::{check_record, value_has_type, TableTypes};
+
+/// Whether scripts can change a host table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    /// Scripts can change the table outside of transactions. Rolling
+    /// back couldn't undo the host's changes, so inside a transaction
+    /// changes fail with `VmError::HostTableInTransaction`.
+    ReadWrite,
+}
+
+/// `Table` made object safe so tables of any type can be held together.
+trait DynTable {
+    fn find(self: Box<Self>, query: &mut Vec<Value>) -> Result<Box<dyn DynCursor>, VmError>;
+
+    fn select(self: Box<Self>, query: Vec<Constraint<Value>>)
+    -> Result<Box<dyn DynCursor>, VmError>;
+}
+
+impl<T> DynTable for T
+where
+    T: Table<Value, VmError> + 'static,
+{
+    fn find(self: Box<Self>, query: &mut Vec<Value>) -> Result<Box<dyn DynCursor>, VmError> {
+        Ok(Box::new((*self).find(query)?))
+    }
+
+    fn select(self: Box<Self>, query: Vec<Constraint<Value>>)
+    -> Result<Box<dyn DynCursor>, VmError> {
+        Ok(Box::new((*self).select(query)?))
+    }
+}
+
+/// `Cursor` made object safe, see `DynTable`.
+trait DynCursor {
+    fn found(&self) -> bool;
+    fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError>;
+    fn insert(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError>;
+    fn update(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError>;
+    fn delete(&mut self) -> Result<(), VmError>;
+    fn advance(&mut self) -> Result<bool, VmError>;
+    fn close(self: Box<Self>) -> Box<dyn DynTable>;
+}
+
+impl<C> DynCursor for C
+where
+    C: Cursor<Value, VmError> + 'static,
+    C::Table: 'static,
+{
+    fn found(&self) -> bool {
+        Cursor::found(self)
+    }
+
+    fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        Cursor::read(self, stack)
+    }
+
+    fn insert(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        Cursor::insert(self, stack)
+    }
+
+    fn update(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        Cursor::update(self, stack)
+    }
+
+    fn delete(&mut self) -> Result<(), VmError> {
+        Cursor::delete(self)
+    }
+
+    fn advance(&mut self) -> Result<bool, VmError> {
+        Cursor::advance(self)
+    }
+
+    fn close(self: Box<Self>) -> Box<dyn DynTable> {
+        Box::new((*self).close())
+    }
+}
+
+/// A table the host registered with `Vm::register_table`.
+pub struct HostTable {
+    pub(super) type_index: u32,
+    pub(super) schema: Vec<Type>,
+    pub(super) access: Access,
+    table: Box<dyn DynTable>,
+}
+
+impl std::fmt::Debug for HostTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostTable")
+            .field("type_index", &self.type_index)
+            .field("access", &self.access)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Table<Value, VmError> for HostTable {
+    type Cursor = HostCursor;
+
+    fn find(self, query: &mut Vec<Value>) -> Result<Self::Cursor, VmError> {
+        if query.len() != self.schema.len() {
+            return Err(VmError::TypeCheck);
+        }
+        let cursor = self.table.find(query)?;
+        Ok(HostCursor {
+            type_index: self.type_index,
+            schema: self.schema,
+            access: self.access,
+            cursor,
+        })
+    }
+
+    fn select(self, query: Vec<Constraint<Value>>) -> Result<Self::Cursor, VmError> {
+        if query.len() != self.schema.len() {
+            return Err(VmError::TypeCheck);
+        }
+        let cursor = self.table.select(query)?;
+        Ok(HostCursor {
+            type_index: self.type_index,
+            schema: self.schema,
+            access: self.access,
+            cursor,
+        })
+    }
+}
+
+/// A cursor over a `HostTable`.
+pub struct HostCursor {
+    pub(super) type_index: u32,
+    pub(super) schema: Vec<Type>,
+    pub(super) access: Access,
+    cursor: Box<dyn DynCursor>,
+}
+
+impl std::fmt::Debug for HostCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostCursor")
+            .field("type_index", &self.type_index)
+            .field("access", &self.access)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HostCursor {
+    fn writable(&self) -> Result<(), VmError> {
+        match self.access {
+            Access::ReadWrite => Ok(()),
+            Access::ReadOnly => Err(VmError::ReadOnly(self.type_index)),
+        }
+    }
+}
+
+impl Cursor<Value, VmError> for HostCursor {
+    type Table = HostTable;
+
+    fn found(&self) -> bool {
+        self.cursor.found()
+    }
+
+    fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        let at = stack.len();
+        self.cursor.read(stack)?;
+
+        // At the end of the table the host pushes None rather than a
+        // record.
+        if stack[at..] == [Value::None] {
+            return Ok(());
+        }
+        let record = &stack[at..];
+        let fits = record.len() == self.schema.len() + 1
+            && record.last() == Some(&Value::Struct { field_count: self.schema.len() })
+            && record.iter().zip(&self.schema).all(|(value, field_type)| value_has_type(value, field_type));
+        if !fits {
+            stack.truncate(at);
+            return Err(VmError::TypeCheck);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        self.writable()?;
+        check_record(stack, &self.schema)?;
+        self.cursor.insert(stack)
+    }
+
+    fn update(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        self.writable()?;
+        check_record(stack, &self.schema)?;
+        self.cursor.update(stack)
+    }
+
+    fn delete(&mut self) -> Result<(), VmError> {
+        self.writable()?;
+        self.cursor.delete()
+    }
+
+    fn advance(&mut self) -> Result<bool, VmError> {
+        self.cursor.advance()
+    }
+
+    fn close(self) -> Self::Table {
+        HostTable {
+            type_index: self.type_index,
+            schema: self.schema,
+            access: self.access,
+            table: self.cursor.close(),
+        }
+    }
+}
+
+impl Vm {
+    /// Registers the host's table as the Vm's table of the type, which
+    /// `TakeTable` then takes, replacing any table the Vm had. The type
+    /// must be in `Module.types` and give the types of the table's fields,
+    /// and can't be derived or matched by rules.
+    pub fn register_table<T>(&mut self, type_index: u32, table: T, access: Access)
+    -> Result<(), VmError>
+    where
+        T: Table<Value, VmError> + 'static,
+    {
+        let Some(table_type) = self.module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index));
+        };
+        if table_type.is_derived() {
+            return Err(VmError::InvalidOperation);
+        }
+        let matched = self.module.types.values()
+            .flat_map(|table_type| &table_type.rules)
+            .flat_map(|rule| &rule.body)
+            .any(|atom| atom.type_index == type_index);
+        if matched {
+            return Err(VmError::HostTableInRule(type_index));
+        }
+
+        let table = HostTable {
+            type_index,
+            schema: table_type.fields.clone(),
+            access,
+            table: Box::new(table),
+        };
+
+        match self.tables.get(&type_index) {
+            Some(None) => return Err(VmError::TableInUse(type_index)),
+            Some(Some(handle)) => {
+                self.handles.take_table(*handle)?;
+            },
+            None => {},
+        }
+        let handle = self.handles.insert(Resource::Table(TableTypes::Host(table)));
+        self.tables.insert(type_index, Some(handle));
+        Ok(())
+    }
+
+    /// Refuses changes through the cursor while a transaction is open if
+    /// it's over a read-write host table.
+    pub(super) fn check_host_write(&self, handle: usize) -> Result<(), VmError> {
+        match self.handles.cursor(handle)? {
+            CursorTypes::Host(cursor) if cursor.access == Access::ReadWrite
+                && !self.transactions.is_empty() =>
+            {
+                Err(VmError::HostTableInTransaction(cursor.type_index))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The index of the table type with the name.
+    pub fn type_index(&self, name: &str) -> Option<u32> {
+        self.module.types.iter()
+            .find(|(_, table_type)| table_type.name == name)
+            .map(|(type_index, _)| *type_index)
+    }
+
+    /// Registers the host's table as the Vm's table of the type with the
+    /// name, see `register_table`.
+    pub fn register_table_named<T>(&mut self, name: &str, table: T, access: Access)
+    -> Result<(), VmError>
+    where
+        T: Table<Value, VmError> + 'static,
+    {
+        let type_index = self.type_index(name)
+            .ok_or_else(|| VmError::UnknownTable(name.to_string()))?;
+        self.register_table(type_index, table, access)
+    }
+}
@@ -0,0 +1,160 @@
+use super::*;
+
+use crate::optimize::{self, is_block, Instruction};
+
+impl Instruction for Op {
+    fn noop() -> Self {
+        Op::Noop
+    }
+
+    fn is_noop(&self) -> bool {
+        matches!(self, Op::Noop)
+    }
+
+    fn target(&self) -> Option<usize> {
+        match self {
+            Op::Jump(target) | Op::JumpIfFalse(target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    fn target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Op::Jump(target) | Op::JumpIfFalse(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    fn is_jump(&self) -> bool {
+        matches!(self, Op::Jump(_))
+    }
+
+    fn ends_flow(&self) -> bool {
+        matches!(self, Op::Jump(_) | Op::Return | Op::Halt)
+    }
+}
+
+/// Optimizes the code of one function, see `crate::optimize`.
+pub fn optimize(code: &mut Vec<Op>) {
+    optimize::optimize(code, peephole);
+}
+
+fn peephole(code: &mut [Op], targets: &[bool]) -> bool {
+    let mut changed = false;
+
+    for index in 0..code.len() {
+        let window = &mut code[index..];
+
+        match window {
+            // Constant folding.
+            [a, b, operator, ..] if is_block(targets, index, 3) => {
+                if let Some(folded) = fold(a, b, operator) {
+                    window[0] = folded;
+                    window[1] = Op::Noop;
+                    window[2] = Op::Noop;
+                    changed = true;
+                    continue;
+                }
+            },
+            _ => {},
+        }
+
+        match window {
+            // A value stored and then loaded straight back is kept on the
+            // stack instead.
+            [Op::StoreImm(a), Op::LoadImm(b), ..]
+            if a == b && is_block(targets, index, 2) => {
+                let offset = *a;
+                window[0] = Op::Copy;
+                window[1] = Op::StoreImm(offset);
+                changed = true;
+            },
+
+            // Adding a constant takes it from the op.
+            [Op::I64(b), Op::AddI64, ..] if is_block(targets, index, 2) => {
+                window[0] = Op::AddImmI64(*b);
+                window[1] = Op::Noop;
+                changed = true;
+            },
+
+            [Op::AddImmI64(a), Op::AddImmI64(b), ..] if is_block(targets, index, 2) => {
+                window[0] = Op::AddImmI64(a.wrapping_add(*b));
+                window[1] = Op::Noop;
+                changed = true;
+            },
+
+            [Op::I64(a), Op::AddImmI64(b), ..] if is_block(targets, index, 2) => {
+                window[0] = Op::I64(a.wrapping_add(*b));
+                window[1] = Op::Noop;
+                changed = true;
+            },
+
+            // A constant condition either always or never jumps.
+            [Op::Bool(condition), Op::JumpIfFalse(target), ..]
+            if is_block(targets, index, 2) => {
+                window[1] = match condition {
+                    true => Op::Noop,
+                    false => Op::Jump(*target),
+                };
+                window[0] = Op::Noop;
+                changed = true;
+            },
+
+            // A constant which is dropped straight away.
+            [constant, Op::Pop, ..]
+            if is_constant(constant) && is_block(targets, index, 2) => {
+                window[0] = Op::Noop;
+                window[1] = Op::Noop;
+                changed = true;
+            },
+
+            _ => {},
+        }
+    }
+
+    changed
+}
+
+fn is_constant(op: &Op) -> bool {
+    matches!(op,
+        Op::None
+        | Op::Fn(_)
+        | Op::F32(_)
+        | Op::F64(_)
+        | Op::I32(_)
+        | Op::I64(_)
+        | Op::U32(_)
+        | Op::U64(_)
+        | Op::Usize(_)
+        | Op::Bool(_)
+    )
+}
+
+/// The constant the operator gives for two constants, matching what the
+/// Vm computes. Operands the Vm would reject aren't folded.
+fn fold(a: &Op, b: &Op, operator: &Op) -> Option<Op> {
+    let op = match (a, b, operator) {
+        (Op::F32(a), Op::F32(b), Op::AddF32) => Op::F32(a + b),
+        (Op::F64(a), Op::F64(b), Op::AddF64) => Op::F64(a + b),
+        (Op::I32(a), Op::I32(b), Op::AddI32) => Op::I32(a.wrapping_add(*b)),
+        (Op::I64(a), Op::I64(b), Op::AddI64) => Op::I64(a.wrapping_add(*b)),
+        (Op::U32(a), Op::U32(b), Op::AddU32) => Op::U32(a.wrapping_add(*b)),
+        (Op::U64(a), Op::U64(b), Op::AddU64) => Op::U64(a.wrapping_add(*b)),
+
+        (Op::F32(a), Op::F32(b), Op::SubF32) => Op::F32(a - b),
+        (Op::F64(a), Op::F64(b), Op::SubF64) => Op::F64(a - b),
+        (Op::I32(a), Op::I32(b), Op::SubI32) => Op::I32(a.wrapping_sub(*b)),
+        (Op::I64(a), Op::I64(b), Op::SubI64) => Op::I64(a.wrapping_sub(*b)),
+        (Op::U32(a), Op::U32(b), Op::SubU32) => Op::U32(a.wrapping_sub(*b)),
+        (Op::U64(a), Op::U64(b), Op::SubU64) => Op::U64(a.wrapping_sub(*b)),
+
+        (Op::F32(a), Op::F32(b), Op::LtF32) => Op::Bool(a < b),
+        (Op::F64(a), Op::F64(b), Op::LtF64) => Op::Bool(a < b),
+        (Op::I32(a), Op::I32(b), Op::LtI32) => Op::Bool(a < b),
+        (Op::I64(a), Op::I64(b), Op::LtI64) => Op::Bool(a < b),
+        (Op::U32(a), Op::U32(b), Op::LtU32) => Op::Bool(a < b),
+        (Op::U64(a), Op::U64(b), Op::LtU64) => Op::Bool(a < b),
+        _ => return None,
+    };
+    Some(op)
+}
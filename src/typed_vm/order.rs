@@ -0,0 +1,108 @@
+//! Ordered iteration over records. A table type with an order keeps the
+//! rows of its tables sorted by it: inserts go to the record's place in
+//! the order rather than after the cursor, and an update changing the
+//! fields it sorts by moves the record, and the cursor with it. Tables
+//! can't be ordered by string fields, as they compare strings by their
+//! intern position, which changes between runs.
+//!
+//! `Op::Order` reads the records a query matches in to a new table sorted
+//! by the order it is given, keeping a window of them, and returns a
+//! cursor over it. Strings are sorted by their text. Records which sort
+//! equal keep the order they were read in, so iteration is always
+//! deterministic.
+
+use super::*;
+use self::table::{key, MemTable};
+use std::cmp::Ordering;
+
+/// A field records are sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sort {
+    pub field: usize,
+    pub descending: bool,
+}
+
+impl Sort {
+    pub fn asc(field: usize) -> Self {
+        Sort { field, descending: false }
+    }
+
+    pub fn desc(field: usize) -> Self {
+        Sort { field, descending: true }
+    }
+}
+
+/// Compares rows by each sort in turn. Values of a field are ordered as
+/// an index orders them, except strings which are ordered by their text
+/// when `strings` is given.
+pub(super) fn compare_rows(order: &[Sort], a: &[Value], b: &[Value], strings: Option<&Strings>)
+-> Ordering {
+    for sort in order {
+        let ordering = match (&a[sort.field], &b[sort.field], strings) {
+            (Value::StringRef { index: x }, Value::StringRef { index: y }, Some(strings)) => {
+                strings.get(*x).cmp(&strings.get(*y))
+            },
+            (x, y, _) => key(x).cmp(&key(y)),
+        };
+        let ordering = if sort.descending { ordering.reverse() } else { ordering };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+impl Vm {
+    /// Replaces the cursor on the stack with the table it was over and a
+    /// cursor over a new table of its records from the cursor on, sorted,
+    /// skipping `offset` and keeping at most `limit`.
+    pub(super) fn order(&mut self, order: &[Sort], offset: usize, limit: Option<usize>)
+    -> Result<(), VmError> {
+        let handle = self.stack.peek_slot(Kind::Cursor)? as usize;
+        let (type_index, field_count) = match self.handles.cursor(handle)? {
+            CursorTypes::Mem(cursor) => (cursor.table.type_index, cursor.table.schema.len()),
+            CursorTypes::Host(cursor) => (cursor.type_index, cursor.schema.len()),
+            CursorTypes::Fn(_) => return Err(VmError::InvalidOperation),
+        };
+        if order.iter().any(|sort| sort.field >= field_count) {
+            return Err(VmError::InvalidOperation);
+        }
+        let Some(table_type) = self.module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index));
+        };
+        let mut table = MemTable::new(type_index, table_type, self.limits.max_table_rows)?;
+        // A copy sorted by text can't keep records inserted through its
+        // cursor in order, so it isn't an ordered table.
+        let by_text = order.iter().any(|sort| table.schema[sort.field] == Type::StringRef);
+        table.order = if by_text { Vec::new() } else { order.to_vec() };
+
+        let (mut rows, _) = self.drain()?;
+        let strings = &self.strings;
+        rows.sort_by(|a, b| compare_rows(order, a, b, Some(strings)));
+        let window = rows.into_iter().skip(offset).take(limit.unwrap_or(usize::MAX));
+        for row in window {
+            if self.limits.max_table_rows.is_some_and(|max_rows| table.rows.len() >= max_rows) {
+                return Err(VmError::TableFull);
+            }
+            table.push_row(row);
+        }
+
+        let query = vec![Constraint::Any; field_count];
+        let cursor = TableTypes::Mem(table).select(query)?;
+        let handle = self.handles.insert(Resource::Cursor(cursor));
+        self.stack.push_slot(Kind::Cursor, handle as u64)?;
+        Ok(())
+    }
+
+    /// Drops a table the Vm doesn't hold, such as the one `Op::Order`
+    /// returns a cursor over once it is closed.
+    pub(super) fn drop_table(&mut self, handle: usize) -> Result<(), VmError> {
+        match self.handles.table(handle)? {
+            TableTypes::Mem(table) if !table.held => {
+                self.handles.take_table(handle)?;
+                Ok(())
+            },
+            _ => Err(VmError::InvalidOperation),
+        }
+    }
+}
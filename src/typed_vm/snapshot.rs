@@ -0,0 +1,885 @@
+use super::*;
+
+use self::storage::Log;
+use self::table::{FnCursor, MemCursor};
+use crate::snapshot::{module_hash, write_type, Reader, SnapshotError, Writer, TYPED_VM};
+use std::ops::Bound;
+
+impl Vm {
+    /// Serializes the complete execution state of the Vm including the
+    /// contents of any tables on the stack. The module is not included,
+    /// only its hash, so the snapshot must be restored with `Vm::restore`
+    /// against the same module. Tracers are not saved.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = Writer::new(TYPED_VM, module_hash(|w| write_module(w, &self.module)));
+
+        w.usize(self.instruction_pointer);
+        w.usize(self.frame_ptr);
+        w.u8(match self.state {
+            State::Ready => 0,
+            State::Suspended => 1,
+            State::Halted => 2,
+            State::Failed => 3,
+        });
+        w.option_u64(self.fuel);
+
+        w.usize(self.stack.len());
+        for bits in self.stack.slots() {
+            w.u64(*bits);
+        }
+
+        // Only a checked Vm has tags to write.
+        w.bool(self.stack.is_checked());
+        for kind in self.stack.tags().unwrap_or_default() {
+            w.u8(*kind as u8);
+        }
+
+        w.usize(self.stack.handles().len());
+        for index in self.stack.handles() {
+            w.usize(*index);
+        }
+
+        w.usize(self.handles.entries.len());
+        for entry in &self.handles.entries {
+            match entry {
+                None => w.u8(0),
+                Some(Resource::Table(table)) => {
+                    w.u8(1);
+                    write_table(&mut w, table);
+                },
+                Some(Resource::Cursor(cursor)) => {
+                    w.u8(2);
+                    write_cursor(&mut w, cursor);
+                },
+            }
+        }
+
+        w.usize(self.handles.free.len());
+        for handle in &self.handles.free {
+            w.usize(*handle);
+        }
+
+        w.usize(self.tables.len());
+        for (type_index, handle) in &self.tables {
+            w.u32(*type_index);
+            match handle {
+                None => w.u8(0),
+                Some(handle) => {
+                    w.u8(1);
+                    w.usize(*handle);
+                },
+            }
+        }
+
+        w.usize(self.transactions.len());
+        for transaction in &self.transactions {
+            w.usize(transaction.tables.len());
+            for (type_index, table) in &transaction.tables {
+                w.u32(*type_index);
+                write_mem_table(&mut w, table);
+            }
+        }
+
+        w.usize(self.strings.strings.len());
+        for text in &self.strings.strings {
+            w.str(text);
+        }
+
+        w.usize(self.call_stack.len());
+        for ret in &self.call_stack {
+            w.usize(ret.instruction_pointer);
+            w.usize(ret.frame_ptr);
+            w.usize(ret.ret_count);
+        }
+
+        w.into_bytes()
+    }
+
+    /// Recreates a Vm from a snapshot taken while running `module`. The
+    /// remaining fuel is restored from the snapshot while the other limits,
+    /// including the row limit of restored tables, are taken from `limits`.
+    pub fn restore(module: impl Into<Arc<Module>>, limits: Limits, bytes: &[u8])
+    -> Result<Self, SnapshotError> {
+        let module = module.into();
+        let mut r = Reader::new(bytes, TYPED_VM, module_hash(|w| write_module(w, &module)))?;
+
+        let instruction_pointer = r.usize()?;
+        let frame_ptr = r.usize()?;
+        let state = match r.u8()? {
+            0 => State::Ready,
+            1 => State::Suspended,
+            2 => State::Halted,
+            3 => State::Failed,
+            tag => return Err(SnapshotError::InvalidTag(tag)),
+        };
+        let fuel = r.option_u64()?;
+
+        let count = r.count()?;
+        let mut slots = Vec::with_capacity(count);
+        for _ in 0..count {
+            slots.push(r.u64()?);
+        }
+
+        let tags = if r.bool()? {
+            let mut tags = Vec::with_capacity(count);
+            for _ in 0..count {
+                tags.push(read_kind(&mut r)?);
+            }
+            Some(tags)
+        } else {
+            None
+        };
+
+        let mut stack_handles = Vec::new();
+        for _ in 0..r.count()? {
+            stack_handles.push(r.usize()?);
+        }
+        let mut stack = Stack::from_parts(slots, tags, stack_handles);
+        if !stack.is_consistent() {
+            return Err(SnapshotError::InvalidStack);
+        }
+        stack.set_max(limits.max_stack);
+
+        let mut handles = Handles::default();
+        for _ in 0..r.count()? {
+            let entry = match r.u8()? {
+                0 => None,
+                1 => Some(Resource::Table(read_table(&mut r, &module, &limits)?)),
+                2 => Some(Resource::Cursor(read_cursor(&mut r, &module, &limits)?)),
+                tag => return Err(SnapshotError::InvalidTag(tag)),
+            };
+            handles.entries.push(entry);
+        }
+
+        for _ in 0..r.count()? {
+            let handle = r.usize()?;
+            if !matches!(handles.entries.get(handle), Some(None)) {
+                return Err(SnapshotError::InvalidHandle(handle));
+            }
+            handles.free.push(handle);
+        }
+
+        let mut tables = BTreeMap::new();
+        for _ in 0..r.count()? {
+            let type_index = r.u32()?;
+            let handle = match r.u8()? {
+                0 => None,
+                1 => {
+                    let handle = r.usize()?;
+                    if !matches!(handles.get(handle), Some(Resource::Table(_))) {
+                        return Err(SnapshotError::InvalidHandle(handle));
+                    }
+                    Some(handle)
+                },
+                tag => return Err(SnapshotError::InvalidTag(tag)),
+            };
+            tables.insert(type_index, handle);
+        }
+
+        let mut transactions = Vec::new();
+        for _ in 0..r.count()? {
+            let mut transaction = Transaction::default();
+            for _ in 0..r.count()? {
+                let type_index = r.u32()?;
+                let table = read_mem_table(&mut r, &module, &limits)?;
+                transaction.tables.insert(type_index, table);
+            }
+            transactions.push(transaction);
+        }
+
+        let mut strings = Strings::default();
+        for _ in 0..r.count()? {
+            strings.intern(r.str()?);
+        }
+
+        let count = r.count()?;
+        let mut call_stack = Vec::with_capacity(count);
+        for _ in 0..count {
+            call_stack.push(RetInfo {
+                instruction_pointer: r.usize()?,
+                frame_ptr: r.usize()?,
+                ret_count: r.usize()?,
+            });
+        }
+
+        r.finish()?;
+
+        // Execution continues from the pointers, so they must be inside
+        // the code and the stack. The instruction pointer is past the op
+        // that last ran.
+        let frames = call_stack.iter()
+            .map(|ret| (ret.instruction_pointer, ret.frame_ptr))
+            .chain([(instruction_pointer, frame_ptr)]);
+        for (instruction_pointer, frame_ptr) in frames {
+            if instruction_pointer > module.code.len() {
+                return Err(SnapshotError::InvalidInstruction(instruction_pointer));
+            }
+            if frame_ptr > stack.len() {
+                return Err(SnapshotError::InvalidFrame(frame_ptr));
+            }
+        }
+
+        let mut vm = Vm::with_limits(module, limits);
+        vm.instruction_pointer = instruction_pointer;
+        vm.frame_ptr = frame_ptr;
+        vm.state = state;
+        vm.fuel = fuel;
+        vm.stack = stack;
+        vm.handles = handles;
+        vm.strings = strings;
+        vm.tables = tables;
+        vm.transactions = transactions;
+        vm.call_stack = call_stack;
+
+        // Restoring a table put its file back to the table's rows. Inside
+        // a transaction the file only holds the rows the outermost began
+        // with, and the changes since are held back as they were.
+        if !vm.transactions.is_empty() {
+            let module = vm.module.clone();
+            for type_index in module.types.keys() {
+                let begun: Vec<Vec<Vec<Value>>> = vm.transactions.iter()
+                    .filter_map(|transaction| transaction.tables.get(type_index))
+                    .map(|table| table.rows.clone())
+                    .collect();
+                let Some(table) = vm.held_table_mut(*type_index) else {
+                    continue;
+                };
+                let rows = table.rows.clone();
+                let (Some(log), Some(outermost)) = (&mut table.log, begun.first()) else {
+                    continue;
+                };
+                log.compact(outermost)?;
+                for depth in 1..=begun.len() {
+                    log.begin();
+                    log.compact(begun.get(depth).unwrap_or(&rows))?;
+                }
+            }
+        }
+        Ok(vm)
+    }
+}
+
+/// Writes the parts of the module a snapshot depends on for its hash.
+fn write_module(w: &mut Writer, module: &Module) {
+    w.usize(module.start);
+    w.usize(module.code.len());
+    for op in &module.code {
+        write_op(w, op);
+    }
+
+    w.usize(module.functions.functions.len());
+    for (index, ptr) in &module.functions.functions {
+        w.u32(*index);
+        w.usize(*ptr);
+    }
+    w.usize(module.functions.signatures.len());
+    for signature in &module.functions.signatures {
+        w.str(&signature.name);
+        write_types(w, &signature.args);
+        write_type(w, &signature.ret);
+        w.usize(signature.ptr);
+    }
+
+    w.usize(module.types.len());
+    for (type_index, table_type) in &module.types {
+        w.u32(*type_index);
+        w.str(&table_type.name);
+        write_types(w, &table_type.fields);
+        w.usize(table_type.names.len());
+        for name in &table_type.names {
+            w.str(name);
+        }
+        w.usize(table_type.indexes.len());
+        for index in &table_type.indexes {
+            write_fields(w, index);
+        }
+        w.usize(table_type.rules.len());
+        for rule in &table_type.rules {
+            write_terms(w, &rule.head);
+            w.usize(rule.body.len());
+            for atom in &rule.body {
+                w.u32(atom.type_index);
+                write_terms(w, &atom.terms);
+            }
+        }
+        match &table_type.storage {
+            Storage::Memory => w.u8(0),
+            Storage::File(path) => {
+                w.u8(1);
+                w.str(&path.to_string_lossy());
+            },
+        }
+        write_order(w, &table_type.order);
+    }
+}
+
+fn write_op(w: &mut Writer, op: &Op) {
+    match op {
+        Op::Noop => w.u8(0),
+        Op::Halt => w.u8(1),
+        Op::Pop => w.u8(2),
+        Op::PopN => w.u8(3),
+        Op::Swap => w.u8(4),
+        Op::SwapN => w.u8(5),
+        Op::Copy => w.u8(6),
+        Op::CopyMany => w.u8(7),
+        Op::CopyFrom => w.u8(8),
+        Op::CopyManyFrom => w.u8(9),
+        Op::Load => w.u8(10),
+        Op::LoadN => w.u8(11),
+        Op::Store => w.u8(12),
+        Op::StoreN => w.u8(13),
+        Op::LoadImm(offset) => {
+            w.u8(14);
+            w.usize(*offset);
+        },
+        Op::StoreImm(offset) => {
+            w.u8(15);
+            w.usize(*offset);
+        },
+        Op::Call => w.u8(16),
+        Op::CallFn { ptr, args, rets } => {
+            w.u8(17);
+            w.usize(*ptr);
+            w.usize(*args);
+            w.usize(*rets);
+        },
+        Op::Return => w.u8(18),
+        Op::Table => w.u8(19),
+        Op::TakeTable(type_index) => {
+            w.u8(20);
+            w.u32(*type_index);
+        },
+        Op::PutTable(type_index) => {
+            w.u8(21);
+            w.u32(*type_index);
+        },
+        Op::SetTable(type_index) => {
+            w.u8(22);
+            w.u32(*type_index);
+        },
+        Op::Begin => w.u8(23),
+        Op::Commit => w.u8(24),
+        Op::Rollback => w.u8(25),
+        Op::Query => w.u8(26),
+        Op::Select(filters) => {
+            w.u8(27);
+            w.usize(filters.len());
+            for filter in filters {
+                w.usize(filter.field);
+                match filter.test {
+                    Test::Eq => w.u8(0),
+                    Test::Lt => w.u8(1),
+                    Test::Le => w.u8(2),
+                    Test::Gt => w.u8(3),
+                    Test::Ge => w.u8(4),
+                    Test::Prefix => w.u8(5),
+                    Test::In(count) => {
+                        w.u8(6);
+                        w.usize(count);
+                    },
+                }
+            }
+        },
+        Op::Found => w.u8(28),
+        Op::Read => w.u8(29),
+        Op::Insert => w.u8(30),
+        Op::Update => w.u8(31),
+        Op::Delete => w.u8(32),
+        Op::Advance => w.u8(33),
+        Op::Close => w.u8(34),
+        Op::Aggregate(columns) => {
+            w.u8(35);
+            write_aggregates(w, columns);
+        },
+        Op::Group { keys, columns, type_index } => {
+            w.u8(36);
+            write_fields(w, keys);
+            write_aggregates(w, columns);
+            w.u32(*type_index);
+        },
+        Op::Order { order, offset, limit } => {
+            w.u8(37);
+            write_order(w, order);
+            w.usize(*offset);
+            w.option_u64(limit.map(|limit| limit as u64));
+        },
+        Op::DropTable => w.u8(38),
+        Op::Jump(ptr) => {
+            w.u8(39);
+            w.usize(*ptr);
+        },
+        Op::JumpIfFalse(ptr) => {
+            w.u8(40);
+            w.usize(*ptr);
+        },
+        Op::None => w.u8(41),
+        Op::Fn(ptr) => {
+            w.u8(42);
+            w.usize(*ptr);
+        },
+        Op::F32(v) => {
+            w.u8(43);
+            w.f32(*v);
+        },
+        Op::F64(v) => {
+            w.u8(44);
+            w.f64(*v);
+        },
+        Op::I32(v) => {
+            w.u8(45);
+            w.i32(*v);
+        },
+        Op::I64(v) => {
+            w.u8(46);
+            w.i64(*v);
+        },
+        Op::U32(v) => {
+            w.u8(47);
+            w.u32(*v);
+        },
+        Op::U64(v) => {
+            w.u8(48);
+            w.u64(*v);
+        },
+        Op::Usize(v) => {
+            w.u8(49);
+            w.usize(*v);
+        },
+        Op::Str(text) => {
+            w.u8(50);
+            w.str(text);
+        },
+        Op::Bool(v) => {
+            w.u8(51);
+            w.bool(*v);
+        },
+        Op::Struct => w.u8(52),
+        Op::AddF32 => w.u8(53),
+        Op::AddF64 => w.u8(54),
+        Op::AddU32 => w.u8(55),
+        Op::AddU64 => w.u8(56),
+        Op::AddI32 => w.u8(57),
+        Op::AddI64 => w.u8(58),
+        Op::AddImmI64(v) => {
+            w.u8(59);
+            w.i64(*v);
+        },
+        Op::SubF32 => w.u8(60),
+        Op::SubF64 => w.u8(61),
+        Op::SubU32 => w.u8(62),
+        Op::SubU64 => w.u8(63),
+        Op::SubI32 => w.u8(64),
+        Op::SubI64 => w.u8(65),
+        Op::LtF32 => w.u8(66),
+        Op::LtF64 => w.u8(67),
+        Op::LtU32 => w.u8(68),
+        Op::LtU64 => w.u8(69),
+        Op::LtI32 => w.u8(70),
+        Op::LtI64 => w.u8(71),
+        Op::Yield(kind) => {
+            w.u8(72);
+            w.u8(*kind as u8);
+        },
+        Op::Eq(kind) => {
+            w.u8(73);
+            w.u8(*kind as u8);
+        },
+    }
+}
+
+fn write_types(w: &mut Writer, types: &[Type]) {
+    w.usize(types.len());
+    for value_type in types {
+        write_type(w, value_type);
+    }
+}
+
+fn write_fields(w: &mut Writer, fields: &[usize]) {
+    w.usize(fields.len());
+    for field in fields {
+        w.usize(*field);
+    }
+}
+
+fn write_order(w: &mut Writer, order: &[Sort]) {
+    w.usize(order.len());
+    for sort in order {
+        w.usize(sort.field);
+        w.bool(sort.descending);
+    }
+}
+
+fn write_aggregates(w: &mut Writer, columns: &[Aggregate]) {
+    w.usize(columns.len());
+    for column in columns {
+        let (tag, field) = match column {
+            Aggregate::Count => (0, 0),
+            Aggregate::Sum(field) => (1, *field),
+            Aggregate::Min(field) => (2, *field),
+            Aggregate::Max(field) => (3, *field),
+            Aggregate::Avg(field) => (4, *field),
+            Aggregate::Key(field) => (5, *field),
+        };
+        w.u8(tag);
+        w.usize(field);
+    }
+}
+
+fn write_terms(w: &mut Writer, terms: &[Term]) {
+    w.usize(terms.len());
+    for term in terms {
+        match term {
+            Term::Var(var) => {
+                w.u8(0);
+                w.usize(*var);
+            },
+            Term::Value(value) => {
+                w.u8(1);
+                write_value(w, value);
+            },
+        }
+    }
+}
+
+fn write_value(w: &mut Writer, value: &Value) {
+    match value {
+        Value::None => w.u8(0),
+        Value::Usize(v) => {
+            w.u8(1);
+            w.usize(*v);
+        },
+        Value::F32(v) => {
+            w.u8(2);
+            w.f32(*v);
+        },
+        Value::F64(v) => {
+            w.u8(3);
+            w.f64(*v);
+        },
+        Value::U32(v) => {
+            w.u8(4);
+            w.u32(*v);
+        },
+        Value::U64(v) => {
+            w.u8(5);
+            w.u64(*v);
+        },
+        Value::I32(v) => {
+            w.u8(6);
+            w.i32(*v);
+        },
+        Value::I64(v) => {
+            w.u8(7);
+            w.i64(*v);
+        },
+        Value::StringRef { index } => {
+            w.u8(8);
+            w.usize(*index);
+        },
+        Value::Bool(v) => {
+            w.u8(9);
+            w.bool(*v);
+        },
+        Value::Struct { field_count } => {
+            w.u8(10);
+            w.usize(*field_count);
+        },
+        Value::Table { handle } => {
+            w.u8(11);
+            w.usize(*handle);
+        },
+        Value::Cursor { handle } => {
+            w.u8(12);
+            w.usize(*handle);
+        },
+        Value::Function { ptr } => {
+            w.u8(13);
+            w.usize(*ptr);
+        },
+        Value::Untagged(bits) => {
+            w.u8(14);
+            w.u64(*bits);
+        },
+    }
+}
+
+fn read_value(r: &mut Reader)
+-> Result<Value, SnapshotError> {
+    let value = match r.u8()? {
+        0 => Value::None,
+        1 => Value::Usize(r.usize()?),
+        2 => Value::F32(r.f32()?),
+        3 => Value::F64(r.f64()?),
+        4 => Value::U32(r.u32()?),
+        5 => Value::U64(r.u64()?),
+        6 => Value::I32(r.i32()?),
+        7 => Value::I64(r.i64()?),
+        8 => Value::StringRef { index: r.usize()? },
+        9 => Value::Bool(r.bool()?),
+        10 => Value::Struct { field_count: r.usize()? },
+        11 => Value::Table { handle: r.usize()? },
+        12 => Value::Cursor { handle: r.usize()? },
+        13 => Value::Function { ptr: r.usize()? },
+        14 => Value::Untagged(r.u64()?),
+        tag => return Err(SnapshotError::InvalidTag(tag)),
+    };
+    Ok(value)
+}
+
+fn read_kind(r: &mut Reader) -> Result<Kind, SnapshotError> {
+    let kind = match r.u8()? {
+        0 => Kind::None,
+        1 => Kind::Usize,
+        2 => Kind::F32,
+        3 => Kind::F64,
+        4 => Kind::U32,
+        5 => Kind::U64,
+        6 => Kind::I32,
+        7 => Kind::I64,
+        8 => Kind::StringRef,
+        9 => Kind::Bool,
+        10 => Kind::Struct,
+        11 => Kind::Table,
+        12 => Kind::Cursor,
+        13 => Kind::Function,
+        tag => return Err(SnapshotError::InvalidTag(tag)),
+    };
+    Ok(kind)
+}
+
+fn write_table(w: &mut Writer, table: &TableTypes) {
+    match table {
+        TableTypes::Fn(table) => {
+            w.u8(0);
+            write_fn_table(w, table);
+        },
+        TableTypes::Mem(table) => {
+            w.u8(1);
+            write_mem_table(w, table);
+        },
+        TableTypes::Host(table) => {
+            w.u8(2);
+            w.u32(table.type_index);
+        },
+    }
+}
+
+fn read_table(r: &mut Reader, module: &Module, limits: &Limits)
+-> Result<TableTypes, SnapshotError> {
+    match r.u8()? {
+        0 => Ok(TableTypes::Fn(read_fn_table(r)?)),
+        1 => Ok(TableTypes::Mem(read_mem_table(r, module, limits)?)),
+        2 => Err(SnapshotError::HostTable(r.u32()?)),
+        tag => Err(SnapshotError::InvalidTag(tag)),
+    }
+}
+
+fn write_cursor(w: &mut Writer, cursor: &CursorTypes) {
+    match cursor {
+        CursorTypes::Fn(cursor) => {
+            w.u8(0);
+            write_fn_table(w, &cursor.table);
+            w.usize(cursor.index);
+        },
+        CursorTypes::Mem(cursor) => {
+            w.u8(1);
+            write_mem_table(w, &cursor.table);
+            w.usize(cursor.query.len());
+            for constraint in &cursor.query {
+                write_constraint(w, constraint);
+            }
+            w.usize(cursor.position);
+        },
+        CursorTypes::Host(cursor) => {
+            w.u8(2);
+            w.u32(cursor.type_index);
+        },
+    }
+}
+
+fn read_cursor(r: &mut Reader, module: &Module, limits: &Limits)
+-> Result<CursorTypes, SnapshotError> {
+    match r.u8()? {
+        0 => {
+            let table = read_fn_table(r)?;
+            let index = r.usize()?;
+            Ok(CursorTypes::Fn(FnCursor { table, index }))
+        },
+        1 => {
+            let table = read_mem_table(r, module, limits)?;
+            let count = r.count()?;
+            let mut query = Vec::with_capacity(count);
+            for _ in 0..count {
+                query.push(read_constraint(r)?);
+            }
+            let position = r.usize()?;
+            if query.len() != table.schema.len() {
+                return Err(SnapshotError::InvalidRow(table.type_index));
+            }
+            Ok(CursorTypes::Mem(MemCursor::new(table, query, position)))
+        },
+        2 => Err(SnapshotError::HostTable(r.u32()?)),
+        tag => Err(SnapshotError::InvalidTag(tag)),
+    }
+}
+
+fn write_fn_table(w: &mut Writer, table: &FnTable) {
+    w.usize(table.functions.len());
+    for (index, ptr) in &table.functions {
+        w.u32(*index);
+        w.usize(*ptr);
+    }
+}
+
+fn read_fn_table(r: &mut Reader) -> Result<FnTable, SnapshotError> {
+    let mut table = FnTable::new();
+    for _ in 0..r.count()? {
+        let index = r.u32()?;
+        let ptr = r.usize()?;
+        table.add_fn(index, ptr);
+    }
+    Ok(table)
+}
+
+/// Mem tables are written as their type index and rows. The schema is
+/// taken from the module's types when restoring, as is the file of a
+/// table stored in one.
+fn write_mem_table(w: &mut Writer, table: &MemTable) {
+    w.u32(table.type_index);
+    w.bool(table.held);
+    w.bool(table.log.is_some());
+    write_order(w, &table.order);
+    w.usize(table.rows.len());
+    for row in &table.rows {
+        write_row(w, row);
+    }
+}
+
+fn read_mem_table(r: &mut Reader, module: &Module, limits: &Limits)
+-> Result<MemTable, SnapshotError> {
+    let type_index = r.u32()?;
+    let Some(table_type) = module.types.get(&type_index) else {
+        return Err(SnapshotError::UnknownType(type_index));
+    };
+
+    let mut table = MemTable::new(type_index, table_type, limits.max_table_rows)
+        .map_err(|_| SnapshotError::UnknownType(type_index))?;
+    table.held = r.bool()?;
+    let logged = r.bool()?;
+
+    // The rows are in the table's order, which for the tables `Op::Order`
+    // makes isn't the type's.
+    let mut order = Vec::new();
+    for _ in 0..r.count()? {
+        let field = r.usize()?;
+        if field >= table_type.fields.len() {
+            return Err(SnapshotError::InvalidRow(type_index));
+        }
+        order.push(Sort { field, descending: r.bool()? });
+    }
+    table.order = order;
+
+    for _ in 0..r.count()? {
+        let row = read_row(r)?;
+        if row.len() != table_type.fields.len() {
+            return Err(SnapshotError::InvalidRow(type_index));
+        }
+        table.push_row(row);
+    }
+
+    // The file is put back to the rows the table had when the snapshot
+    // was taken, as if the Vm had never carried on past it.
+    if let (true, Storage::File(path)) = (logged, &table_type.storage) {
+        let (mut log, _) = Log::open(path, &table_type.fields)?;
+        log.compact(&table.rows)?;
+        table.log = Some(Box::new(log));
+    }
+    Ok(table)
+}
+
+fn write_constraint(w: &mut Writer, constraint: &Constraint<Value>) {
+    match constraint {
+        Constraint::Any => w.u8(0),
+        Constraint::Eq(value) => {
+            w.u8(1);
+            write_value(w, value);
+        },
+        Constraint::Range(start, end) => {
+            w.u8(2);
+            write_bound(w, start, write_value);
+            write_bound(w, end, write_value);
+        },
+        Constraint::In(values) => {
+            w.u8(3);
+            write_row(w, values);
+        },
+        Constraint::Prefix(prefix) => {
+            w.u8(4);
+            w.str(prefix);
+        },
+        Constraint::TextRange(start, end) => {
+            w.u8(5);
+            write_bound(w, start, |w, text| w.str(text));
+            write_bound(w, end, |w, text| w.str(text));
+        },
+    }
+}
+
+fn read_constraint(r: &mut Reader) -> Result<Constraint<Value>, SnapshotError> {
+    match r.u8()? {
+        0 => Ok(Constraint::Any),
+        1 => Ok(Constraint::Eq(read_value(r)?)),
+        2 => Ok(Constraint::Range(read_bound(r, read_value)?, read_bound(r, read_value)?)),
+        3 => Ok(Constraint::In(read_row(r)?)),
+        4 => Ok(Constraint::Prefix(r.str()?.to_string())),
+        5 => {
+            let read_text = |r: &mut Reader| r.str().map(str::to_string);
+            Ok(Constraint::TextRange(read_bound(r, read_text)?, read_bound(r, read_text)?))
+        },
+        tag => Err(SnapshotError::InvalidTag(tag)),
+    }
+}
+
+fn write_bound<T>(w: &mut Writer, bound: &Bound<T>, write: impl Fn(&mut Writer, &T)) {
+    match bound {
+        Bound::Unbounded => w.u8(0),
+        Bound::Included(value) => {
+            w.u8(1);
+            write(w, value);
+        },
+        Bound::Excluded(value) => {
+            w.u8(2);
+            write(w, value);
+        },
+    }
+}
+
+fn read_bound<'a, T>(
+    r: &mut Reader<'a>,
+    read: impl Fn(&mut Reader<'a>) -> Result<T, SnapshotError>,
+) -> Result<Bound<T>, SnapshotError> {
+    match r.u8()? {
+        0 => Ok(Bound::Unbounded),
+        1 => Ok(Bound::Included(read(r)?)),
+        2 => Ok(Bound::Excluded(read(r)?)),
+        tag => Err(SnapshotError::InvalidTag(tag)),
+    }
+}
+
+fn write_row(w: &mut Writer, row: &[Value]) {
+    w.usize(row.len());
+    for value in row {
+        write_value(w, value);
+    }
+}
+
+fn read_row(r: &mut Reader)
+-> Result<Vec<Value>, SnapshotError> {
+    let count = r.count()?;
+    let mut row = Vec::with_capacity(count);
+    for _ in 0..count {
+        row.push(read_value(r)?);
+    }
+    Ok(row)
+}
@@ -0,0 +1,513 @@
+use super::*;
+use std::collections::HashMap;
+
+/// A Rust type held in a single stack slot.
+pub trait Slot: Sized {
+    const KIND: Kind;
+    fn from_bits(bits: u64) -> Self;
+    fn into_bits(self) -> u64;
+}
+
+impl Slot for usize {
+    const KIND: Kind = Kind::Usize;
+    fn from_bits(bits: u64) -> Self { bits as usize }
+    fn into_bits(self) -> u64 { self as u64 }
+}
+
+impl Slot for f32 {
+    const KIND: Kind = Kind::F32;
+    fn from_bits(bits: u64) -> Self { f32::from_bits(bits as u32) }
+    fn into_bits(self) -> u64 { self.to_bits() as u64 }
+}
+
+impl Slot for f64 {
+    const KIND: Kind = Kind::F64;
+    fn from_bits(bits: u64) -> Self { f64::from_bits(bits) }
+    fn into_bits(self) -> u64 { self.to_bits() }
+}
+
+impl Slot for u32 {
+    const KIND: Kind = Kind::U32;
+    fn from_bits(bits: u64) -> Self { bits as u32 }
+    fn into_bits(self) -> u64 { self as u64 }
+}
+
+impl Slot for u64 {
+    const KIND: Kind = Kind::U64;
+    fn from_bits(bits: u64) -> Self { bits }
+    fn into_bits(self) -> u64 { self }
+}
+
+impl Slot for i32 {
+    const KIND: Kind = Kind::I32;
+    fn from_bits(bits: u64) -> Self { bits as u32 as i32 }
+    fn into_bits(self) -> u64 { self as u32 as u64 }
+}
+
+impl Slot for i64 {
+    const KIND: Kind = Kind::I64;
+    fn from_bits(bits: u64) -> Self { bits as i64 }
+    fn into_bits(self) -> u64 { self as u64 }
+}
+
+impl Slot for bool {
+    const KIND: Kind = Kind::Bool;
+    fn from_bits(bits: u64) -> Self { bits != 0 }
+    fn into_bits(self) -> u64 { self as u64 }
+}
+
+impl Value {
+    /// The kind of slot holding the value, None for `Untagged`.
+    pub fn kind(&self) -> Option<Kind> {
+        let kind = match self {
+            Value::None => Kind::None,
+            Value::Usize(_) => Kind::Usize,
+            Value::F32(_) => Kind::F32,
+            Value::F64(_) => Kind::F64,
+            Value::U32(_) => Kind::U32,
+            Value::U64(_) => Kind::U64,
+            Value::I32(_) => Kind::I32,
+            Value::I64(_) => Kind::I64,
+            Value::StringRef { .. } => Kind::StringRef,
+            Value::Bool(_) => Kind::Bool,
+            Value::Struct { .. } => Kind::Struct,
+            Value::Table { .. } => Kind::Table,
+            Value::Cursor { .. } => Kind::Cursor,
+            Value::Function { .. } => Kind::Function,
+            Value::Untagged(_) => return None,
+        };
+        Some(kind)
+    }
+
+    /// The contents of the slot holding the value.
+    pub fn bits(&self) -> u64 {
+        match *self {
+            Value::None => 0,
+            Value::Usize(v) => v.into_bits(),
+            Value::F32(v) => v.into_bits(),
+            Value::F64(v) => v.into_bits(),
+            Value::U32(v) => v.into_bits(),
+            Value::U64(v) => v,
+            Value::I32(v) => v.into_bits(),
+            Value::I64(v) => v.into_bits(),
+            Value::StringRef { index } => index as u64,
+            Value::Bool(v) => v.into_bits(),
+            Value::Struct { field_count } => field_count as u64,
+            Value::Table { handle } => handle as u64,
+            Value::Cursor { handle } => handle as u64,
+            Value::Function { ptr } => ptr as u64,
+            Value::Untagged(bits) => bits,
+        }
+    }
+
+    /// Reads a slot as a value of the kind.
+    pub fn from_slot(kind: Kind, bits: u64) -> Value {
+        match kind {
+            Kind::None => Value::None,
+            Kind::Usize => Value::Usize(Slot::from_bits(bits)),
+            Kind::F32 => Value::F32(Slot::from_bits(bits)),
+            Kind::F64 => Value::F64(Slot::from_bits(bits)),
+            Kind::U32 => Value::U32(Slot::from_bits(bits)),
+            Kind::U64 => Value::U64(bits),
+            Kind::I32 => Value::I32(Slot::from_bits(bits)),
+            Kind::I64 => Value::I64(Slot::from_bits(bits)),
+            Kind::StringRef => Value::StringRef { index: bits as usize },
+            Kind::Bool => Value::Bool(Slot::from_bits(bits)),
+            Kind::Struct => Value::Struct { field_count: bits as usize },
+            Kind::Table => Value::Table { handle: bits as usize },
+            Kind::Cursor => Value::Cursor { handle: bits as usize },
+            Kind::Function => Value::Function { ptr: bits as usize },
+        }
+    }
+}
+
+/// Whether slots of the kind hold handles.
+fn is_handle(kind: Kind) -> bool {
+    matches!(kind, Kind::Table | Kind::Cursor)
+}
+
+/// The operand stack. Each value takes one untagged 64 bit slot, the ops
+/// reading a slot know its kind. A checked stack also keeps the kind of
+/// every slot and fails with `VmError::TypeCheck` when a slot is read as
+/// the wrong kind.
+///
+/// Every stack knows which of its slots hold table and cursor handles,
+/// so that dropping one hands the handle back to be released and a
+/// handle is never copied.
+#[derive(Debug)]
+pub struct Stack {
+    slots: Vec<u64>,
+    tags: Option<Vec<Kind>>,
+    /// The positions of the slots holding handles, lowest first.
+    handles: Vec<usize>,
+    /// The most slots the stack may hold, pushing another fails with
+    /// `VmError::StackOverflow`.
+    max: Option<usize>,
+}
+
+impl Stack {
+    pub fn set_max(&mut self, max: Option<usize>) {
+        self.max = max;
+    }
+
+    /// Fails unless `count` more slots fit.
+    pub fn reserve(&self, count: usize) -> Result<(), VmError> {
+        match self.max {
+            Some(max) if self.slots.len() + count > max => Err(VmError::StackOverflow),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_checked(&self) -> bool {
+        self.tags.is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn slots(&self) -> &[u64] {
+        &self.slots
+    }
+
+    pub fn tags(&self) -> Option<&[Kind]> {
+        self.tags.as_deref()
+    }
+
+    /// The positions of the slots holding handles, lowest first.
+    pub fn handles(&self) -> &[usize] {
+        &self.handles
+    }
+
+    /// Rebuilds a stack from its slots, the positions of its handles and,
+    /// for a checked stack, tags.
+    pub fn from_parts(slots: Vec<u64>, tags: Option<Vec<Kind>>, handles: Vec<usize>) -> Self {
+        Stack { slots, tags, handles, max: None }
+    }
+
+    /// Whether the parts the stack was rebuilt from agree: the handles
+    /// are in order and on the stack, and a checked stack has a tag for
+    /// every slot, of a handle kind exactly where the handles are.
+    pub fn is_consistent(&self) -> bool {
+        let handles = &self.handles;
+        let ordered = handles.windows(2).all(|pair| pair[0] < pair[1])
+            && handles.last().is_none_or(|last| *last < self.slots.len());
+        let tagged = match self.tags.as_ref() {
+            Some(tags) => {
+                tags.len() == self.slots.len()
+                    && tags.iter().enumerate().all(|(index, kind)| {
+                        is_handle(*kind) == handles.binary_search(&index).is_ok()
+                    })
+            },
+            None => true,
+        };
+        ordered && tagged
+    }
+
+    /// Whether the slot at `index` holds a handle.
+    fn is_handle(&self, index: usize) -> bool {
+        // Handles are rare and most reads are near the top.
+        !self.handles.is_empty() && self.handles.binary_search(&index).is_ok()
+    }
+
+    pub fn push_slot(&mut self, kind: Kind, bits: u64) -> Result<(), VmError> {
+        self.reserve(1)?;
+        if is_handle(kind) {
+            self.handles.push(self.slots.len());
+        }
+        self.slots.push(bits);
+        if let Some(tags) = self.tags.as_mut() {
+            tags.push(kind);
+        }
+        Ok(())
+    }
+
+    pub fn push<T: Slot>(&mut self, value: T) -> Result<(), VmError> {
+        self.push_slot(T::KIND, value.into_bits())
+    }
+
+    /// Pushes a value from the host. An untagged value can only be pushed
+    /// on to an unchecked stack.
+    pub fn push_value(&mut self, value: Value) -> Result<(), VmError> {
+        match value.kind() {
+            Some(kind) => self.push_slot(kind, value.bits()),
+            None if self.tags.is_none() => {
+                self.reserve(1)?;
+                self.slots.push(value.bits());
+                Ok(())
+            },
+            None => Err(VmError::TypeCheck),
+        }
+    }
+
+    pub fn pop_slot(&mut self, kind: Kind) -> Result<u64, VmError> {
+        let top = self.len().checked_sub(1).ok_or(VmError::StackUnderflow)?;
+        if is_handle(kind) != self.is_handle(top) {
+            return Err(VmError::TypeCheck);
+        }
+        if is_handle(kind) {
+            self.handles.pop();
+        }
+        let bits = self.slots.pop().ok_or(VmError::StackUnderflow)?;
+        if let Some(tags) = self.tags.as_mut() {
+            if tags.pop() != Some(kind) {
+                return Err(VmError::TypeCheck);
+            }
+        }
+        Ok(bits)
+    }
+
+    pub fn pop<T: Slot>(&mut self) -> Result<T, VmError> {
+        self.pop_slot(T::KIND).map(T::from_bits)
+    }
+
+    /// Removes the top slot whatever its kind, returning the handle it
+    /// held if it held one.
+    #[must_use = "a dropped handle must be released"]
+    pub fn drop_top(&mut self) -> Result<Option<usize>, VmError> {
+        let top = self.len().checked_sub(1).ok_or(VmError::StackUnderflow)?;
+        let handle = self.is_handle(top).then(|| {
+            self.handles.pop();
+            self.slots[top] as usize
+        });
+        self.slots.pop();
+        if let Some(tags) = self.tags.as_mut() {
+            tags.pop();
+        }
+        Ok(handle)
+    }
+
+    /// Reads the slot at `index` as the kind.
+    pub fn get_slot(&self, index: usize, kind: Kind) -> Result<u64, VmError> {
+        let bits = *self.slots.get(index).ok_or(VmError::StackUnderflow)?;
+        if is_handle(kind) != self.is_handle(index) {
+            return Err(VmError::TypeCheck);
+        }
+        if let Some(tags) = self.tags.as_ref() {
+            if tags[index] != kind {
+                return Err(VmError::TypeCheck);
+            }
+        }
+        Ok(bits)
+    }
+
+    /// Reads the top slot as the kind without removing it.
+    pub fn peek_slot(&self, kind: Kind) -> Result<u64, VmError> {
+        let index = self.len().checked_sub(1).ok_or(VmError::StackUnderflow)?;
+        self.get_slot(index, kind)
+    }
+
+    /// The kind of the slot at `index`, if the stack is checked.
+    pub fn kind(&self, index: usize) -> Option<Kind> {
+        self.tags.as_ref().and_then(|tags| tags.get(index).copied())
+    }
+
+    /// The value at `index`, untagged unless the stack is checked.
+    pub fn value(&self, index: usize) -> Option<Value> {
+        let bits = *self.slots.get(index)?;
+        match self.kind(index) {
+            Some(kind) => Some(Value::from_slot(kind, bits)),
+            None => Some(Value::Untagged(bits)),
+        }
+    }
+
+    pub fn values(&self) -> Vec<Value> {
+        (0..self.len()).filter_map(|index| self.value(index)).collect()
+    }
+
+    /// Reads the slot at `index` as a value of the kind.
+    pub fn decode(&self, index: usize, kind: Kind) -> Result<Value, VmError> {
+        self.get_slot(index, kind).map(|bits| Value::from_slot(kind, bits))
+    }
+
+    /// Drops the slots from `len` up, returning the handles they held.
+    #[must_use = "dropped handles must be released"]
+    pub fn truncate(&mut self, len: usize) -> Vec<usize> {
+        let from = self.handles.partition_point(|index| *index < len);
+        let dropped = self.handles.split_off(from).into_iter()
+            .map(|index| self.slots[index] as usize)
+            .collect();
+        self.slots.truncate(len);
+        if let Some(tags) = self.tags.as_mut() {
+            tags.truncate(len);
+        }
+        dropped
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        match (self.is_handle(a), self.is_handle(b)) {
+            (true, false) | (false, true) => {
+                let (from, to) = if self.is_handle(a) { (a, b) } else { (b, a) };
+                let at = self.handles.binary_search(&from).unwrap_or_else(|at| at);
+                self.handles.remove(at);
+                let at = self.handles.binary_search(&to).unwrap_or_else(|at| at);
+                self.handles.insert(at, to);
+            },
+            _ => {},
+        }
+        self.slots.swap(a, b);
+        if let Some(tags) = self.tags.as_mut() {
+            tags.swap(a, b);
+        }
+    }
+
+    /// Pushes a copy of the slot at `index`. Tables and cursors can't be
+    /// copied.
+    pub fn copy(&mut self, index: usize) -> Result<(), VmError> {
+        let bits = *self.slots.get(index).ok_or(VmError::StackUnderflow)?;
+        if self.is_handle(index) {
+            return Err(VmError::InvalidOperation);
+        }
+        self.reserve(1)?;
+        if let Some(tags) = self.tags.as_mut() {
+            let kind = tags[index];
+            tags.push(kind);
+        }
+        self.slots.push(bits);
+        Ok(())
+    }
+
+    pub fn split_off(&mut self, at: usize) -> Stack {
+        let from = self.handles.partition_point(|index| *index < at);
+        Stack {
+            slots: self.slots.split_off(at),
+            tags: self.tags.as_mut().map(|tags| tags.split_off(at)),
+            handles: self.handles.split_off(from).into_iter().map(|index| index - at).collect(),
+            max: None,
+        }
+    }
+
+    pub fn append(&mut self, other: &mut Stack) -> Result<(), VmError> {
+        self.reserve(other.len())?;
+        let len = self.len();
+        self.handles.extend(other.handles.drain(..).map(|index| index + len));
+        self.slots.append(&mut other.slots);
+        if let (Some(tags), Some(other)) = (self.tags.as_mut(), other.tags.as_mut()) {
+            tags.append(other);
+        }
+        Ok(())
+    }
+}
+
+/// The strings the Vm has interned. A `StringRef` holds the position of
+/// its string, so equal strings have equal refs.
+#[derive(Debug, Default)]
+pub struct Strings {
+    pub(super) strings: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl Strings {
+    pub fn intern(&mut self, text: &str) -> usize {
+        if let Some(position) = self.positions.get(text) {
+            return *position;
+        }
+
+        self.strings.push(text.to_string());
+        self.positions.insert(text.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.strings.get(index).map(String::as_str)
+    }
+}
+
+/// A table or cursor referenced from the stack by its handle.
+#[derive(Debug)]
+pub enum Resource {
+    Table(TableTypes),
+    Cursor(CursorTypes),
+}
+
+/// Holds the tables and cursors the stack refers to so that they don't
+/// need to fit in a slot. An op consuming a table or cursor takes it out
+/// of its entry and the handle is reused for whatever it produces.
+#[derive(Debug, Default)]
+pub struct Handles {
+    pub(super) entries: Vec<Option<Resource>>,
+    pub(super) free: Vec<usize>,
+}
+
+impl Handles {
+    pub fn insert(&mut self, resource: Resource) -> usize {
+        match self.free.pop() {
+            Some(handle) => {
+                self.entries[handle] = Some(resource);
+                handle
+            },
+            None => {
+                self.entries.push(Some(resource));
+                self.entries.len() - 1
+            },
+        }
+    }
+
+    pub fn get(&self, handle: usize) -> Option<&Resource> {
+        self.entries.get(handle).and_then(Option::as_ref)
+    }
+
+    fn take(&mut self, handle: usize) -> Result<Resource, VmError> {
+        let resource = self.entries.get_mut(handle)
+            .and_then(Option::take)
+            .ok_or(VmError::InvalidHandle(handle))?;
+        self.free.push(handle);
+        Ok(resource)
+    }
+
+    pub fn take_table(&mut self, handle: usize) -> Result<TableTypes, VmError> {
+        match self.get(handle) {
+            Some(Resource::Table(_)) => {},
+            Some(_) => return Err(VmError::TypeCheck),
+            None => return Err(VmError::InvalidHandle(handle)),
+        }
+        let Resource::Table(table) = self.take(handle)? else {
+            unreachable!()
+        };
+        Ok(table)
+    }
+
+    pub fn take_cursor(&mut self, handle: usize) -> Result<CursorTypes, VmError> {
+        match self.get(handle) {
+            Some(Resource::Cursor(_)) => {},
+            Some(_) => return Err(VmError::TypeCheck),
+            None => return Err(VmError::InvalidHandle(handle)),
+        }
+        let Resource::Cursor(cursor) = self.take(handle)? else {
+            unreachable!()
+        };
+        Ok(cursor)
+    }
+
+    pub fn table(&self, handle: usize) -> Result<&TableTypes, VmError> {
+        match self.get(handle) {
+            Some(Resource::Table(table)) => Ok(table),
+            Some(_) => Err(VmError::TypeCheck),
+            None => Err(VmError::InvalidHandle(handle)),
+        }
+    }
+
+    pub fn table_mut(&mut self, handle: usize) -> Result<&mut TableTypes, VmError> {
+        match self.entries.get_mut(handle).and_then(Option::as_mut) {
+            Some(Resource::Table(table)) => Ok(table),
+            Some(_) => Err(VmError::TypeCheck),
+            None => Err(VmError::InvalidHandle(handle)),
+        }
+    }
+
+    pub fn cursor(&self, handle: usize) -> Result<&CursorTypes, VmError> {
+        match self.get(handle) {
+            Some(Resource::Cursor(cursor)) => Ok(cursor),
+            Some(_) => Err(VmError::TypeCheck),
+            None => Err(VmError::InvalidHandle(handle)),
+        }
+    }
+
+    pub fn cursor_mut(&mut self, handle: usize) -> Result<&mut CursorTypes, VmError> {
+        match self.entries.get_mut(handle).and_then(Option::as_mut) {
+            Some(Resource::Cursor(cursor)) => Ok(cursor),
+            Some(_) => Err(VmError::TypeCheck),
+            None => Err(VmError::InvalidHandle(handle)),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+//! File backed storage for the Vm's tables. A table stored in a file is
+//! held in memory like any other, and each change made through a cursor
+//! is appended to the file as a record before it is made. Opening the
+//! file replays the records, so the table is as it was when the last Vm
+//! to use it stopped.
+//!
+//! A file starts with a header holding the type of each of the table's
+//! fields as a tag. Each record after it is its length, a checksum and
+//! the change: an insert or update with the position and row, or a
+//! delete with the position. A record cut short by a crash part way
+//! through writing it fails its checksum and is dropped, along with
+//! anything after it, when the file is next opened. Once the file holds many more records than the table has
+//! rows it is compacted, written again as one insert per row in to a new
+//! file which then replaces it.
+//!
+//! Changes made inside a transaction are held back until the outermost
+//! transaction commits, then written at once between a begin and a commit
+//! record. Opening the file drops the records of a transaction whose
+//! commit record is missing, so a Vm stopping part way through a
+//! transaction, or part way through writing one, leaves none of it.
+//!
+//! Only fields holding numbers and bools can be stored, string refs are
+//! only meaningful to the Vm that interned them.
+
+use super::*;
+use self::order::compare_rows;
+use self::table::MemTable;
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"COTB";
+const VERSION: u8 = 2;
+
+const INSERT: u8 = 0;
+const UPDATE: u8 = 1;
+const DELETE: u8 = 2;
+/// Removes every row, the first record of a compaction made inside a
+/// transaction.
+const CLEAR: u8 = 3;
+const BEGIN: u8 = 4;
+const COMMIT: u8 = 5;
+
+/// The number of records beyond one per row a file can hold before it is
+/// compacted.
+const COMPACT_AFTER: usize = 1024;
+
+/// Where the Vm keeps the records of its table of a type.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Storage {
+    #[default]
+    Memory,
+    /// In the file at the path, created if it doesn't exist.
+    File(PathBuf),
+}
+
+/// The file a table's changes are appended to.
+#[derive(Debug)]
+pub struct Log {
+    path: PathBuf,
+    file: File,
+    schema: Vec<Type>,
+    /// The number of records in the file.
+    records: usize,
+    /// The records written inside the open transactions.
+    pending: Vec<u8>,
+    /// The length `pending` had when each open transaction began.
+    marks: Vec<usize>,
+}
+
+impl Log {
+    /// Opens the file at the path, creating it if it doesn't exist, and
+    /// returns the rows its records leave the table with. A torn record at
+    /// the end of the file is removed.
+    pub fn open(path: &Path, schema: &[Type]) -> io::Result<(Log, Vec<Vec<Value>>)> {
+        if let Some(field_type) = schema.iter().find(|field_type| !storable(field_type)) {
+            let message = format!("fields of type {:?} can't be stored in a file", field_type);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut log = Log {
+            path: path.to_path_buf(),
+            file,
+            schema: schema.to_vec(),
+            records: 0,
+            pending: Vec::new(),
+            marks: Vec::new(),
+        };
+
+        // A file shorter than its header was being created when it was
+        // cut short.
+        let header = log.header();
+        if bytes.len() < header.len() && header.starts_with(&bytes) {
+            log.file.set_len(0)?;
+            log.file.write_all(&header)?;
+            log.file.sync_all()?;
+            return Ok((log, Vec::new()));
+        }
+
+        if bytes.get(..header.len()) != Some(&header[..]) {
+            let message = match bytes.get(..MAGIC.len() + 1) {
+                Some([magic @ .., version]) if magic == MAGIC && *version == VERSION => {
+                    "the file holds a table with different fields"
+                },
+                Some([magic @ .., _]) if magic == MAGIC => "the file is from another version",
+                _ => "the file doesn't hold a table",
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid record");
+        let mut rows = Vec::new();
+        let mut at = header.len();
+        // The end of the last record applied, and the records read since.
+        let mut end = at;
+        let mut records = 0;
+        // The records of a transaction, applied once its commit is read.
+        let mut transaction: Option<Vec<&[u8]>> = None;
+        while let Some((payload, next)) = read_record(&bytes, at) {
+            records += 1;
+            match (payload.first(), &mut transaction) {
+                (Some(&BEGIN), None) => transaction = Some(Vec::new()),
+                (Some(&COMMIT), Some(payloads)) => {
+                    for payload in payloads.drain(..) {
+                        log.replay(payload, &mut rows)?;
+                    }
+                    transaction = None;
+                },
+                (Some(&BEGIN | &COMMIT), _) => return Err(invalid()),
+                (_, Some(payloads)) => payloads.push(payload),
+                (_, None) => log.replay(payload, &mut rows)?,
+            }
+            at = next;
+            if transaction.is_none() {
+                end = at;
+                log.records += records;
+                records = 0;
+            }
+        }
+
+        if end < bytes.len() {
+            log.file.set_len(end as u64)?;
+            log.file.sync_all()?;
+        }
+
+        if log.records > rows.len() + COMPACT_AFTER {
+            log.compact(&rows)?;
+        }
+
+        Ok((log, rows))
+    }
+
+    pub fn insert(&mut self, at: usize, row: &[Value]) -> io::Result<()> {
+        self.append(INSERT, at, row)
+    }
+
+    pub fn update(&mut self, at: usize, row: &[Value]) -> io::Result<()> {
+        self.append(UPDATE, at, row)
+    }
+
+    pub fn delete(&mut self, at: usize) -> io::Result<()> {
+        self.append(DELETE, at, &[])
+    }
+
+    /// Holds back the records written from now on until the transaction
+    /// commits.
+    pub fn begin(&mut self) {
+        self.marks.push(self.pending.len());
+    }
+
+    /// Commits the innermost transaction. Once the outermost commits its
+    /// records are written to the file.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.marks.pop();
+        if !self.marks.is_empty() || self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut bytes = record(BEGIN, 0, &[]);
+        bytes.append(&mut self.pending);
+        bytes.extend(record(COMMIT, 0, &[]));
+        let mut records = 0;
+        let mut at = 0;
+        while let Some((_, next)) = read_record(&bytes, at) {
+            records += 1;
+            at = next;
+        }
+
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.records += records;
+        Ok(())
+    }
+
+    /// Drops the records written since the transaction at the depth began,
+    /// and the transactions inside it, leaving those outside it open.
+    pub fn rollback(&mut self, depth: usize) {
+        if let Some(&mark) = self.marks.get(depth) {
+            self.pending.truncate(mark);
+            self.marks.truncate(depth);
+        }
+    }
+
+    /// True once the file holds enough more records than the table's rows
+    /// to be worth compacting. A file isn't compacted inside a
+    /// transaction.
+    pub fn needs_compaction(&self, rows: usize) -> bool {
+        self.marks.is_empty() && self.records > rows + COMPACT_AFTER
+    }
+
+    /// Replaces the file with one holding an insert of each row. Inside a
+    /// transaction the file is instead cleared and the rows inserted once
+    /// it commits.
+    ///
+    /// The new file is synced before it replaces the old one but the
+    /// directory isn't synced after the rename, so a crash just after it
+    /// can leave the old file in place and lose the changes written since.
+    pub fn compact(&mut self, rows: &[Vec<Value>]) -> io::Result<()> {
+        if !self.marks.is_empty() {
+            self.pending.extend(record(CLEAR, 0, &[]));
+            for (at, row) in rows.iter().enumerate() {
+                self.pending.extend(record(INSERT, at, row));
+            }
+            return Ok(());
+        }
+
+        let mut bytes = self.header();
+        for (at, row) in rows.iter().enumerate() {
+            bytes.extend(record(INSERT, at, row));
+        }
+
+        let mut compacted: OsString = self.path.clone().into();
+        compacted.push(".compact");
+        let compacted = PathBuf::from(compacted);
+
+        let mut file = File::create(&compacted)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&compacted, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = rows.len();
+        Ok(())
+    }
+
+    /// The magic, the version, then the number of fields and a tag for
+    /// the type of each.
+    fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend((self.schema.len() as u32).to_le_bytes());
+        header.extend(self.schema.iter().map(field_tag));
+        header
+    }
+
+    /// Writes the record in one write so a crash can only tear the last
+    /// record, or holds it back inside a transaction.
+    fn append(&mut self, op: u8, at: usize, row: &[Value]) -> io::Result<()> {
+        if !self.marks.is_empty() {
+            self.pending.extend(record(op, at, row));
+            return Ok(());
+        }
+        self.file.write_all(&record(op, at, row))?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Applies a record's change to the rows.
+    fn replay(&self, payload: &[u8], rows: &mut Vec<Vec<Value>>) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid record");
+
+        let (&op, rest) = payload.split_first().ok_or_else(invalid)?;
+        let (at, values) = rest.split_first_chunk::<8>().ok_or_else(invalid)?;
+        let at = usize::try_from(u64::from_le_bytes(*at)).map_err(|_| invalid())?;
+
+        let row = || -> io::Result<Vec<Value>> {
+            if values.len() != self.schema.len() * 8 {
+                return Err(invalid());
+            }
+            let row = values.chunks_exact(8)
+                .zip(&self.schema)
+                .map(|(bits, field_type)| {
+                    let bits = u64::from_le_bytes(bits.try_into().unwrap());
+                    Value::from_slot(Kind::of(field_type).unwrap(), bits)
+                })
+                .collect();
+            Ok(row)
+        };
+
+        match op {
+            INSERT if at <= rows.len() => rows.insert(at, row()?),
+            UPDATE if at < rows.len() => rows[at] = row()?,
+            DELETE if at < rows.len() && values.is_empty() => {
+                rows.remove(at);
+            },
+            CLEAR if values.is_empty() => rows.clear(),
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+}
+
+/// True for field types whose values mean the same to any Vm.
+fn storable(field_type: &Type) -> bool {
+    matches!(
+        field_type,
+        Type::None | Type::Usize | Type::F32 | Type::F64 | Type::U32 | Type::U64
+        | Type::I32 | Type::I64 | Type::Bool
+    )
+}
+
+/// The tag a field of the type is written as in the header. These are
+/// part of the file format and never change.
+fn field_tag(field_type: &Type) -> u8 {
+    match field_type {
+        Type::None => 0,
+        Type::Usize => 1,
+        Type::F32 => 2,
+        Type::F64 => 3,
+        Type::U32 => 4,
+        Type::U64 => 5,
+        Type::I32 => 6,
+        Type::I64 => 7,
+        Type::Bool => 8,
+        // Only storable types are written.
+        _ => u8::MAX,
+    }
+}
+
+fn record(op: u8, at: usize, row: &[Value]) -> Vec<u8> {
+    let mut payload = vec![op];
+    payload.extend((at as u64).to_le_bytes());
+    for value in row {
+        payload.extend(value.bits().to_le_bytes());
+    }
+
+    let mut bytes = Vec::with_capacity(payload.len() + 8);
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(checksum(&payload).to_le_bytes());
+    bytes.extend(payload);
+    bytes
+}
+
+/// The payload of the record at `at` and the offset of the next record,
+/// None at the end of the file or a torn record.
+fn read_record(bytes: &[u8], at: usize) -> Option<(&[u8], usize)> {
+    let header = bytes.get(at..at + 8)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let sum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let end = (at + 8).checked_add(len)?;
+    let payload = bytes.get(at + 8..end)?;
+    (checksum(payload) == sum).then_some((payload, end))
+}
+
+/// FNV-1a, enough to tell a torn record from a whole one.
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+impl MemTable {
+    /// Creates the Vm's table of the type, loading its records if it is
+    /// stored in a file.
+    pub(super) fn open(type_index: u32, table_type: &TableType, max_rows: Option<usize>)
+    -> Result<Self, VmError> {
+        let mut table = MemTable::new(type_index, table_type, max_rows)?;
+        table.held = true;
+
+        let Storage::File(path) = &table_type.storage else {
+            return Ok(table);
+        };
+
+        let (mut log, rows) = Log::open(path, &table_type.fields).map_err(VmError::Storage)?;
+        if max_rows.is_some_and(|max_rows| rows.len() > max_rows) {
+            return Err(VmError::TableFull);
+        }
+        // Rows written before the type was ordered are sorted, and the
+        // file rewritten to hold them in order.
+        let sorted = rows.windows(2)
+            .all(|pair| {
+                compare_rows(&table.order, &pair[0], &pair[1], None) != Ordering::Greater
+            });
+        for row in rows {
+            table.push_row(row);
+        }
+        if !sorted {
+            log.compact(&table.rows).map_err(VmError::Storage)?;
+        }
+        table.log = Some(Box::new(log));
+        Ok(table)
+    }
+
+    /// Appends a change to the table's file, if it is stored in one.
+    pub(super) fn write_log(&mut self, write: impl FnOnce(&mut Log) -> io::Result<()>)
+    -> Result<(), VmError> {
+        match &mut self.log {
+            Some(log) => write(log).map_err(VmError::Storage),
+            None => Ok(()),
+        }
+    }
+
+    /// Compacts the table's file if it has grown enough since it was last
+    /// compacted.
+    pub(super) fn compact_log(&mut self) -> Result<(), VmError> {
+        match &mut self.log {
+            Some(log) if log.needs_compaction(self.rows.len()) => {
+                log.compact(&self.rows).map_err(VmError::Storage)
+            },
+            _ => Ok(()),
+        }
+    }
+}
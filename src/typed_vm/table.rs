@@ -1,39 +1,68 @@
+//! The tables a program's cursor ops work on. `TableTypes` holds the
+//! function table every Vm starts with, the Vm's own tables held in
+//! memory, and tables the host registers. `CursorTypes` holds a cursor
+//! over any of them. Opening a cursor takes its table, and closing the
+//! cursor gives the table back.
+//!
+//! A `MemTable` keeps its rows in a `Vec` in the order they were inserted
+//! unless its type gives an order. Its type can also give indexes over
+//! fields, which `select` uses to find rows without checking each one.
+//! Inserts past `Limits::max_table_rows` fail with `VmError::TableFull`.
+
 use super::*;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use std::path::PathBuf;
+use super::host::{HostCursor, HostTable};
+use super::order::{compare_rows, Sort};
+use super::storage::{Log, Storage};
 
 #[derive(Debug)]
 pub enum TableTypes {
     Fn(FnTable),
+    Mem(MemTable),
+    Host(HostTable),
+}
+
+/// The records of a function table, queried by function index.
+const FN_SCHEMA: &[Type] = &[Type::None, Type::U32];
+
+impl TableTypes {
+    /// The types of the table's fields, used to read queries and records
+    /// off of the stack.
+    pub fn schema(&self) -> &[Type] {
+        match self {
+            TableTypes::Fn(_) => FN_SCHEMA,
+            TableTypes::Mem(table) => &table.schema,
+            TableTypes::Host(table) => &table.schema,
+        }
+    }
 }
 
 impl Table<Value,VmError> for TableTypes {
     type Cursor = CursorTypes;
 
-    fn find(self, query: &mut Vec<Value>) -> Self::Cursor {
+    fn find(self, query: &mut Vec<Value>) -> Result<Self::Cursor, VmError> {
         match self {
             TableTypes::Fn(table) => {
                 // BUG: this type checking should be based on Vm.types
-                if query.len() != 2 {
-                    unimplemented!()
+                if !matches!(query[..], [Value::None, Value::U32(_)]) {
+                    return Err(VmError::TypeCheck);
                 }
 
-                let Some(key) = query.get(1) else {
-                    unreachable!()
-                };
-
-                if !matches!(key, Value::U32(_)) {
-                    unimplemented!()
-                }
-                
-                let Some(value) = query.get(0) else {
-                    unreachable!()
-                };
-
-                if !matches!(value, Value::None) {
-                    unimplemented!()
-                }
-
-                CursorTypes::Fn(table.find(query))
+                Ok(CursorTypes::Fn(table.find(query)?))
             },
+            TableTypes::Mem(table) => Ok(CursorTypes::Mem(table.find(query)?)),
+            TableTypes::Host(table) => Ok(CursorTypes::Host(table.find(query)?)),
+        }
+    }
+
+    fn select(self, query: Vec<Constraint<Value>>) -> Result<Self::Cursor, VmError> {
+        match self {
+            TableTypes::Fn(table) => Ok(CursorTypes::Fn(table.select(query)?)),
+            TableTypes::Mem(table) => Ok(CursorTypes::Mem(table.select(query)?)),
+            TableTypes::Host(table) => Ok(CursorTypes::Host(table.select(query)?)),
         }
     }
 }
@@ -41,12 +70,29 @@ impl Table<Value,VmError> for TableTypes {
 #[derive(Debug)]
 pub enum CursorTypes {
     Fn(FnCursor),
+    Mem(MemCursor),
+    Host(HostCursor),
+}
+
+impl CursorTypes {
+    /// The types of the fields of the cursor's table.
+    pub fn schema(&self) -> &[Type] {
+        match self {
+            CursorTypes::Fn(_) => FN_SCHEMA,
+            CursorTypes::Mem(cursor) => &cursor.table.schema,
+            CursorTypes::Host(cursor) => &cursor.schema,
+        }
+    }
 }
 
 impl Cursor<Value,VmError> for CursorTypes {
     type Table = TableTypes;
     fn found(&self) -> bool {
-        todo!()
+        match self {
+            CursorTypes::Fn(table) => table.found(),
+            CursorTypes::Mem(table) => table.found(),
+            CursorTypes::Host(table) => table.found(),
+        }
     }
 
     /// Pushes a struct on to the stack matching the record at the cursor.
@@ -54,6 +100,8 @@ impl Cursor<Value,VmError> for CursorTypes {
     fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError> {
         match self {
             CursorTypes::Fn(table) => table.read(stack),
+            CursorTypes::Mem(table) => table.read(stack),
+            CursorTypes::Host(table) => table.read(stack),
         }
     }
 
@@ -63,6 +111,8 @@ impl Cursor<Value,VmError> for CursorTypes {
     fn insert(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError>{
         match self {
             CursorTypes::Fn(table) => table.insert(stack),
+            CursorTypes::Mem(table) => table.insert(stack),
+            CursorTypes::Host(table) => table.insert(stack),
         }
     }
 
@@ -71,6 +121,8 @@ impl Cursor<Value,VmError> for CursorTypes {
     fn update(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
         match self {
             CursorTypes::Fn(table) => table.update(stack),
+            CursorTypes::Mem(table) => table.update(stack),
+            CursorTypes::Host(table) => table.update(stack),
         }
     }
 
@@ -79,6 +131,8 @@ impl Cursor<Value,VmError> for CursorTypes {
     fn delete(&mut self) -> Result<(), VmError> {
         match self {
             CursorTypes::Fn(table) => table.delete(),
+            CursorTypes::Mem(table) => table.delete(),
+            CursorTypes::Host(table) => table.delete(),
         }
      }
 
@@ -87,6 +141,8 @@ impl Cursor<Value,VmError> for CursorTypes {
     fn advance(&mut self) -> Result<bool, VmError> {
         match self {
             CursorTypes::Fn(table) => table.advance(),
+            CursorTypes::Mem(table) => table.advance(),
+            CursorTypes::Host(table) => table.advance(),
         }
     }
 
@@ -94,6 +150,8 @@ impl Cursor<Value,VmError> for CursorTypes {
     fn close(self) -> Self::Table {
         match self {
             CursorTypes::Fn(table) => TableTypes::Fn(table.close()),
+            CursorTypes::Mem(table) => TableTypes::Mem(table.close()),
+            CursorTypes::Host(table) => TableTypes::Host(table.close()),
         }
     }
 }
@@ -101,14 +159,15 @@ impl Cursor<Value,VmError> for CursorTypes {
 
 #[derive(Debug)]
 pub struct FnCursor {
-    table: FnTable,
-    index: usize,
+    pub(super) table: FnTable,
+    pub(super) index: usize,
 }
 
 impl Cursor<Value,VmError> for FnCursor {
     type Table = FnTable;
     fn found(&self) -> bool {
-        todo!()
+        // find only constructs cursors for functions that exist.
+        true
     }
 
     /// Pushes a struct on to the stack matching the record at the cursor.
@@ -145,42 +204,902 @@ impl Cursor<Value,VmError> for FnCursor {
 
     /// Closes the cursor and returns the underlying table.
     fn close(self) -> Self::Table {
-        return self.table;
+        self.table
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FnTable {
-    functions: BTreeMap<u32,usize>,
+    pub(super) functions: BTreeMap<u32,usize>,
+    /// The functions compiled from source, which `Vm::call` finds by
+    /// name. Not part of the table colang code reads.
+    pub(super) signatures: Vec<Signature>,
+}
+
+/// A function compiled for the types of its arguments. Functions are
+/// compiled once for each list of argument types they are called with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub args: Vec<Type>,
+    pub ret: Type,
+    pub ptr: usize,
 }
 
 impl FnTable {
     pub fn new() -> Self {
         FnTable {
             functions: BTreeMap::new(),
+            signatures: Vec::new(),
         }
     }
 
     pub fn add_fn(&mut self, index: u32, ptr: usize) {
         self.functions.insert(index, ptr);
     }
+
+    pub fn add_signature(&mut self, signature: Signature) {
+        self.signatures.push(signature);
+    }
+
+    /// The function of the name compiled for the argument types.
+    pub fn signature(&self, name: &str, args: &[Type]) -> Option<&Signature> {
+        self.signatures.iter().find(|signature| signature.name == name && signature.args == args)
+    }
 }
 
 impl Table<Value,VmError> for FnTable {
     type Cursor = FnCursor;
-    fn find(self, query: &mut Vec<Value>) -> Self::Cursor {
+    fn find(self, query: &mut Vec<Value>) -> Result<Self::Cursor, VmError> {
         let Some(Value::U32(fn_index)) = query.pop() else {
-            unreachable!()
+            return Err(VmError::TypeCheck);
         };
 
         let Some(ptr) = self.functions.get(&fn_index) else {
-            unreachable!()
+            return Err(VmError::InvalidOperation);
         };
 
         let ptr = *ptr;
-        FnCursor {
+        Ok(FnCursor {
             table: self,
             index: ptr, // Bug: How do I know this won't overflow?
+        })
+    }
+
+    /// Functions can only be looked up by index.
+    fn select(self, query: Vec<Constraint<Value>>) -> Result<Self::Cursor, VmError> {
+        match query[..] {
+            [Constraint::Any, Constraint::Eq(index @ Value::U32(_))] => {
+                self.find(&mut vec![Value::None, index])
+            },
+            _ => Err(VmError::TypeCheck),
+        }
+    }
+}
+
+/// A table type from `Module.types`: the type of each field of its
+/// records and the indexes kept over them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableType {
+    pub name: String,
+    pub fields: Vec<Type>,
+    /// The name of each field, empty for tables declared without names.
+    pub names: Vec<String>,
+    /// The positions of the fields each index covers.
+    pub indexes: Vec<Vec<usize>>,
+    /// The rules deriving the table's records, empty for tables whose
+    /// records are inserted.
+    pub(crate) rules: Vec<TableRule>,
+    /// Where the Vm keeps its table of the type.
+    pub storage: Storage,
+    /// The fields the rows of tables of the type are sorted by, empty
+    /// for tables whose rows are in the order they were inserted.
+    pub(crate) order: Vec<Sort>,
+}
+
+impl TableType {
+    pub fn new(name: &str, fields: Vec<Type>) -> Self {
+        TableType {
+            name: name.to_string(),
+            fields,
+            ..TableType::default()
+        }
+    }
+
+    pub fn with_names(mut self, names: &[&str]) -> Self {
+        self.names = names.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    /// Adds an index over the fields at the positions.
+    pub fn with_index(mut self, fields: &[usize]) -> Self {
+        self.indexes.push(fields.to_vec());
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_rule(mut self, rule: TableRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Stores the Vm's table of the type in the file at the path.
+    pub fn with_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.storage = Storage::File(path.into());
+        self
+    }
+
+    /// Keeps the rows of tables of the type sorted by the fields.
+    pub(crate) fn with_order(mut self, order: &[Sort]) -> Self {
+        self.order = order.to_vec();
+        self
+    }
+
+    pub fn is_derived(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// The position of the named field.
+    pub fn field(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|field| field == name)
+    }
+}
+
+const SIGN: u64 = 1 << 63;
+
+/// The key of a field value in an index. Keys of values of the same kind
+/// are ordered as the values are, so a range of values is a range of keys,
+/// and zeros of either sign are keyed alike as they compare equal.
+pub(super) fn key(value: &Value) -> u64 {
+    match *value {
+        Value::I32(v) => v as i64 as u64 ^ SIGN,
+        Value::I64(v) => v as u64 ^ SIGN,
+        Value::F32(v) => float_key(v as f64),
+        Value::F64(v) => float_key(v),
+        value => value.bits(),
+    }
+}
+
+fn float_key(v: f64) -> u64 {
+    if v == 0.0 {
+        return SIGN;
+    }
+
+    let bits = v.to_bits();
+    if bits & SIGN != 0 {
+        !bits
+    } else {
+        bits | SIGN
+    }
+}
+
+/// Orders values of the same kind, None for values of different kinds or
+/// NaN which aren't ordered.
+pub(super) fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    let is_nan = |value: &Value| match value {
+        Value::F32(v) => v.is_nan(),
+        Value::F64(v) => v.is_nan(),
+        _ => false,
+    };
+
+    if a.kind() != b.kind() || a.kind().is_none() || is_nan(a) || is_nan(b) {
+        return None;
+    }
+    Some(key(a).cmp(&key(b)))
+}
+
+/// The value of a bound, None if unbounded.
+fn bound_value<T>(bound: &Bound<T>) -> Option<&T> {
+    match bound {
+        Bound::Included(value) | Bound::Excluded(value) => Some(value),
+        Bound::Unbounded => None,
+    }
+}
+
+/// True if the value is between the bounds.
+fn within<T: ?Sized, U: std::borrow::Borrow<T>>(
+    value: &T,
+    (start, end): (&Bound<U>, &Bound<U>),
+    compare: impl Fn(&T, &T) -> Option<Ordering>,
+) -> bool {
+    let above = match start {
+        Bound::Included(start) => compare(value, start.borrow()).is_some_and(Ordering::is_ge),
+        Bound::Excluded(start) => compare(value, start.borrow()).is_some_and(Ordering::is_gt),
+        Bound::Unbounded => true,
+    };
+    let below = match end {
+        Bound::Included(end) => compare(value, end.borrow()).is_some_and(Ordering::is_le),
+        Bound::Excluded(end) => compare(value, end.borrow()).is_some_and(Ordering::is_lt),
+        Bound::Unbounded => true,
+    };
+    above && below
+}
+
+/// The range of values in both ranges: the later start and the earlier
+/// end, excluding the value when the bounds are at the same value.
+fn intersect<T>(
+    (start_a, end_a): (Bound<T>, Bound<T>),
+    (start_b, end_b): (Bound<T>, Bound<T>),
+    compare: impl Fn(&T, &T) -> Option<Ordering>,
+) -> (Bound<T>, Bound<T>) {
+    let start = match (bound_value(&start_a), bound_value(&start_b)) {
+        (None, _) => start_b,
+        (_, None) => start_a,
+        (Some(a), Some(b)) => match compare(a, b) {
+            Some(Ordering::Less) => start_b,
+            Some(Ordering::Equal) if matches!(start_b, Bound::Excluded(_)) => start_b,
+            _ => start_a,
+        },
+    };
+    let end = match (bound_value(&end_a), bound_value(&end_b)) {
+        (None, _) => end_b,
+        (_, None) => end_a,
+        (Some(a), Some(b)) => match compare(a, b) {
+            Some(Ordering::Greater) => end_b,
+            Some(Ordering::Equal) if matches!(end_b, Bound::Excluded(_)) => end_b,
+            _ => end_a,
+        },
+    };
+    (start, end)
+}
+
+/// The range of the text of the strings starting with the prefix, up to
+/// the first string after the prefix which doesn't.
+fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // The next char, skipping the surrogates which aren't chars.
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return (Bound::Included(prefix.to_string()), Bound::Excluded(chars.into_iter().collect()));
+        }
+    }
+    (Bound::Included(prefix.to_string()), Bound::Unbounded)
+}
+
+impl Constraint<Value> {
+    /// True if the value meets the constraint. A string only holds a ref
+    /// to its text, so it never meets a constraint on text, which
+    /// `accepts_text` checks the text against instead.
+    pub fn accepts(&self, value: &Value) -> bool {
+        let eq = |other: &Value| matches!(Vm::eq_value(other, value), Ok(true));
+        match self {
+            Constraint::Any => true,
+            Constraint::Eq(other) => eq(other),
+            Constraint::In(values) => values.iter().any(eq),
+            Constraint::Range(start, end) => within(value, (start, end), compare),
+            Constraint::Prefix(_) | Constraint::TextRange(..) => false,
+        }
+    }
+
+    /// True if the text of a string meets the constraint on text.
+    pub fn accepts_text(&self, text: &str) -> bool {
+        match self {
+            Constraint::Any => true,
+            Constraint::Prefix(prefix) => text.starts_with(prefix.as_str()),
+            Constraint::TextRange(start, end) => {
+                within(text, (start, end), |a: &str, b: &str| Some(a.cmp(b)))
+            },
+            Constraint::Eq(_) | Constraint::In(_) | Constraint::Range(..) => false,
+        }
+    }
+
+    /// True if the value meets the constraint, finding the text of
+    /// strings in `strings` for the constraints on text.
+    pub(super) fn accepts_string(&self, value: &Value, strings: &Strings) -> bool {
+        match (self, value) {
+            (Constraint::Prefix(_) | Constraint::TextRange(..), Value::StringRef { index }) => {
+                strings.get(*index).is_some_and(|text| self.accepts_text(text))
+            },
+            _ => self.accepts(value),
+        }
+    }
+
+    /// The constraint met by values meeting both constraints. Strings in
+    /// a set only meet a constraint on text through `and_text`.
+    pub fn and(self, other: Constraint<Value>) -> Constraint<Value> {
+        self.and_with(other, &|constraint, value| constraint.accepts(value))
+    }
+
+    /// `and`, finding the text of strings in `strings`.
+    pub(super) fn and_text(self, other: Constraint<Value>, strings: &Strings) -> Constraint<Value> {
+        self.and_with(other, &|constraint, value| constraint.accepts_string(value, strings))
+    }
+
+    fn and_with(
+        self,
+        other: Constraint<Value>,
+        accepts: &dyn Fn(&Constraint<Value>, &Value) -> bool,
+    ) -> Constraint<Value> {
+        match (self, other) {
+            (Constraint::Any, other) | (other, Constraint::Any) => other,
+            (Constraint::Eq(value), other) | (other, Constraint::Eq(value)) => {
+                Constraint::In(vec![value]).and_with(other, accepts)
+            },
+            (Constraint::In(values), other) | (other, Constraint::In(values)) => {
+                Constraint::In(values.into_iter().filter(|v| accepts(&other, v)).collect())
+            },
+            (Constraint::Range(start_a, end_a), Constraint::Range(start_b, end_b)) => {
+                let (start, end) = intersect((start_a, end_a), (start_b, end_b), compare);
+                Constraint::Range(start, end)
+            },
+            (Constraint::Prefix(a), Constraint::Prefix(b)) => {
+                if a.starts_with(b.as_str()) {
+                    Constraint::Prefix(a)
+                } else if b.starts_with(a.as_str()) {
+                    Constraint::Prefix(b)
+                } else {
+                    Constraint::In(Vec::new())
+                }
+            },
+            (a @ (Constraint::Prefix(_) | Constraint::TextRange(..)),
+                b @ (Constraint::Prefix(_) | Constraint::TextRange(..))) => {
+                let text_range = |constraint| match constraint {
+                    Constraint::Prefix(prefix) => prefix_range(&prefix),
+                    Constraint::TextRange(start, end) => (start, end),
+                    _ => unreachable!(),
+                };
+                let (start, end) = intersect(text_range(a), text_range(b), |a, b| Some(a.cmp(b)));
+                Constraint::TextRange(start, end)
+            },
+            // Only strings have text, so no value meets both.
+            (Constraint::Range(..), _) | (_, Constraint::Range(..)) => Constraint::In(Vec::new()),
+        }
+    }
+
+    /// The values in the constraint, which must fit the field they
+    /// constrain.
+    fn values(&self) -> Vec<&Value> {
+        match self {
+            Constraint::Any | Constraint::Prefix(_) | Constraint::TextRange(..) => Vec::new(),
+            Constraint::Eq(value) => vec![value],
+            Constraint::In(values) => values.iter().collect(),
+            Constraint::Range(start, end) => {
+                bound_value(start).into_iter().chain(bound_value(end)).collect()
+            },
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        matches!(self, Constraint::Prefix(_) | Constraint::TextRange(..))
+    }
+}
+
+/// A range of whole keys of an index.
+pub type KeyRange = (Bound<Vec<u64>>, Bound<Vec<u64>>);
+
+/// True if no key is in the range. Ranges built by `Index::ranges` are
+/// always bounded.
+fn is_empty(range: &KeyRange) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// The positions of the rows holding each combination of values of the
+/// index's fields.
+#[derive(Debug, Clone)]
+pub struct Index {
+    pub(super) fields: Vec<usize>,
+    rows: BTreeMap<Vec<u64>, Vec<usize>>,
+}
+
+impl Index {
+    fn new(fields: Vec<usize>) -> Self {
+        Index {
+            fields,
+            rows: BTreeMap::new(),
+        }
+    }
+
+    fn key(&self, row: &[Value]) -> Vec<u64> {
+        self.fields.iter().map(|field| key(&row[*field])).collect()
+    }
+
+    /// True if the query constrains each field of the index to a set of
+    /// values, except the last which may be constrained to a range.
+    fn covers(&self, query: &[Constraint<Value>]) -> bool {
+        self.fields.iter().enumerate().all(|(i, field)| match &query[*field] {
+            Constraint::Eq(_) | Constraint::In(_) => true,
+            Constraint::Range(..) => i == self.fields.len() - 1,
+            Constraint::Any | Constraint::Prefix(_) | Constraint::TextRange(..) => false,
+        })
+    }
+
+    /// The ranges of keys holding the rows meeting the query's constraints
+    /// on the index's fields, which the index must cover.
+    fn ranges(&self, query: &[Constraint<Value>]) -> Vec<KeyRange> {
+        let mut prefixes = vec![Vec::new()];
+        for field in &self.fields {
+            match &query[*field] {
+                Constraint::Eq(value) => {
+                    for prefix in &mut prefixes {
+                        prefix.push(key(value));
+                    }
+                },
+                Constraint::In(values) => {
+                    prefixes = prefixes.iter()
+                        .flat_map(|prefix| values.iter().map(|value| {
+                            let mut prefix = prefix.clone();
+                            prefix.push(key(value));
+                            prefix
+                        }))
+                        .collect();
+                },
+                Constraint::Range(start, end) => {
+                    // Only the last field is a range, so the range holds
+                    // whole keys.
+                    let bound = |prefix: &Vec<u64>, bound: &Bound<Value>, unbounded: u64| {
+                        let mut prefix = prefix.clone();
+                        match bound {
+                            Bound::Included(value) => {
+                                prefix.push(key(value));
+                                Bound::Included(prefix)
+                            },
+                            Bound::Excluded(value) => {
+                                prefix.push(key(value));
+                                Bound::Excluded(prefix)
+                            },
+                            Bound::Unbounded => {
+                                prefix.push(unbounded);
+                                Bound::Included(prefix)
+                            },
+                        }
+                    };
+
+                    return prefixes.iter()
+                        .map(|prefix| (bound(prefix, start, 0), bound(prefix, end, u64::MAX)))
+                        .filter(|range| !is_empty(range))
+                        .collect();
+                },
+                Constraint::Any | Constraint::Prefix(_) | Constraint::TextRange(..) => unreachable!(),
+            }
+        }
+
+        prefixes.into_iter()
+            .map(|key| (Bound::Included(key.clone()), Bound::Included(key)))
+            .collect()
+    }
+
+    fn add(&mut self, row: &[Value], position: usize) {
+        let positions = self.rows.entry(self.key(row)).or_default();
+        let at = positions.partition_point(|p| *p < position);
+        positions.insert(at, position);
+    }
+
+    fn remove(&mut self, row: &[Value], position: usize) {
+        let key = self.key(row);
+        if let Some(positions) = self.rows.get_mut(&key) {
+            positions.retain(|p| *p != position);
+            if positions.is_empty() {
+                self.rows.remove(&key);
+            }
+        }
+    }
+
+    /// Moves the positions from `from` on by one, up for an insert before
+    /// them or down for a removal.
+    fn shift(&mut self, from: usize, up: bool) {
+        for positions in self.rows.values_mut() {
+            let start = positions.partition_point(|p| *p < from);
+            for position in &mut positions[start..] {
+                if up {
+                    *position += 1;
+                } else {
+                    *position -= 1;
+                }
+            }
+        }
+    }
+}
+
+/// A table of records held in memory. Each record is a row of values
+/// matching the table's schema from `Module.types`.
+#[derive(Debug)]
+pub struct MemTable {
+    pub(super) type_index: u32,
+    pub(super) schema: Vec<Type>,
+    pub(super) rows: Vec<Vec<Value>>,
+    pub(super) indexes: Vec<Index>,
+    pub(super) max_rows: Option<usize>,
+    /// True for the Vm's table of the type, see `Op::TakeTable`.
+    pub(super) held: bool,
+    /// The file changes are written to, for tables stored in one.
+    pub(super) log: Option<Box<Log>>,
+    /// The fields the rows are sorted by, see `TableType.order`.
+    pub(super) order: Vec<Sort>,
+}
+
+impl MemTable {
+    /// Creates an empty table of the type, failing if an index covers or
+    /// the order sorts by a field the type doesn't have, or the order
+    /// sorts by a string field.
+    pub fn new(type_index: u32, table_type: &TableType, max_rows: Option<usize>)
+    -> Result<Self, VmError> {
+        let field_count = table_type.fields.len();
+        let valid = table_type.indexes.iter()
+            .all(|fields| !fields.is_empty() && fields.iter().all(|f| *f < field_count))
+            && table_type.order.iter().all(|sort| {
+                table_type.fields.get(sort.field).is_some_and(|field| *field != Type::StringRef)
+            });
+        if !valid {
+            return Err(VmError::InvalidIndex(type_index));
+        }
+
+        Ok(MemTable {
+            type_index,
+            schema: table_type.fields.clone(),
+            rows: Vec::new(),
+            indexes: table_type.indexes.iter().cloned().map(Index::new).collect(),
+            max_rows,
+            held: false,
+            log: None,
+            order: table_type.order.clone(),
+        })
+    }
+
+    /// A copy of the table's rows and indexes, not written to its file.
+    pub(super) fn copy(&self) -> Self {
+        MemTable {
+            type_index: self.type_index,
+            schema: self.schema.clone(),
+            rows: self.rows.clone(),
+            indexes: self.indexes.clone(),
+            max_rows: self.max_rows,
+            held: self.held,
+            log: None,
+            order: self.order.clone(),
+        }
+    }
+
+    /// Adds a row at the end of the table, or its place in the order of
+    /// an ordered table.
+    pub(super) fn push_row(&mut self, row: Vec<Value>) {
+        let position = self.place(&row);
+        self.insert_row(position, row);
+    }
+
+    /// The position a row is inserted at following the cursor at
+    /// `position`, after the rows sorting equal to it in an ordered
+    /// table.
+    fn place_after(&self, position: usize, row: &[Value]) -> usize {
+        if !self.order.is_empty() {
+            return self.place(row);
         }
+        if position < self.rows.len() {
+            position + 1
+        } else {
+            self.rows.len()
+        }
+    }
+
+    /// The position of a row appended to the table.
+    fn place(&self, row: &[Value]) -> usize {
+        self.rows.partition_point(|other| {
+            compare_rows(&self.order, other, row, None) != Ordering::Greater
+        })
+    }
+
+    /// True if the row can replace the one at the position without
+    /// breaking the table's order.
+    fn fits(&self, at: usize, row: &[Value]) -> bool {
+        let in_order = |a: &[Value], b: &[Value]| {
+            compare_rows(&self.order, a, b, None) != Ordering::Greater
+        };
+        let before = at.checked_sub(1).and_then(|before| self.rows.get(before));
+        before.is_none_or(|before| in_order(before, row))
+            && self.rows.get(at + 1).is_none_or(|after| in_order(row, after))
+    }
+
+    fn insert_row(&mut self, at: usize, row: Vec<Value>) {
+        let append = at == self.rows.len();
+        for index in &mut self.indexes {
+            if !append {
+                index.shift(at, true);
+            }
+            index.add(&row, at);
+        }
+        self.rows.insert(at, row);
+    }
+
+    fn remove_row(&mut self, at: usize) {
+        let row = self.rows.remove(at);
+        for index in &mut self.indexes {
+            index.remove(&row, at);
+            index.shift(at, false);
+        }
+    }
+
+    fn replace_row(&mut self, at: usize, row: Vec<Value>) {
+        for index in &mut self.indexes {
+            index.remove(&self.rows[at], at);
+            index.add(&row, at);
+        }
+        self.rows[at] = row;
+    }
+
+    /// The index covering the most fields of the query.
+    fn choose_index(&self, query: &[Constraint<Value>]) -> Option<usize> {
+        self.indexes.iter()
+            .enumerate()
+            .filter(|(_, index)| index.covers(query))
+            .max_by_key(|(_, index)| index.fields.len())
+            .map(|(position, _)| position)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Answers the query's constraints on text from the strings in the
+    /// table's rows, which only hold refs to the Vm's strings, so that
+    /// `select` can use its indexes for them. Each becomes the set of
+    /// the strings the field holds which meet it, so the cursor won't
+    /// find records of other strings inserted later.
+    pub(super) fn resolve_text(&self, query: &mut [Constraint<Value>], strings: &Strings) {
+        for (field, constraint) in query.iter_mut().enumerate() {
+            if !constraint.is_text() {
+                continue;
+            }
+
+            let mut seen = HashSet::new();
+            let values = self.rows.iter()
+                .map(|row| row[field])
+                .filter(|value| seen.insert(value.bits()))
+                .filter(|value| constraint.accepts_string(value, strings))
+                .collect();
+            *constraint = Constraint::In(values);
+        }
+    }
+
+    /// Pops a struct matching the schema off of the stack.
+    fn pop_record(&self, stack: &mut Vec<Value>) -> Result<Vec<Value>, VmError> {
+        check_record(stack, &self.schema)?;
+        stack.pop();
+        let at = stack.len() - self.schema.len();
+        Ok(stack.split_off(at))
+    }
+}
+
+impl Table<Value,VmError> for MemTable {
+    type Cursor = MemCursor;
+    fn find(self, query: &mut Vec<Value>) -> Result<Self::Cursor, VmError> {
+        let query = query.drain(..)
+            .map(|value| match value {
+                Value::None => Constraint::Any,
+                value => Constraint::Eq(value),
+            })
+            .collect();
+        self.select(query)
+    }
+
+    fn select(self, query: Vec<Constraint<Value>>) -> Result<Self::Cursor, VmError> {
+        if !query_fits(&query, &self.schema) {
+            return Err(VmError::TypeCheck);
+        }
+
+        let mut cursor = MemCursor::new(self, query, 0);
+        cursor.seek();
+        Ok(cursor)
+    }
+}
+
+#[derive(Debug)]
+pub struct MemCursor {
+    pub(super) table: MemTable,
+    pub(super) query: Vec<Constraint<Value>>,
+    /// The index the query is answered from and the ranges of its keys
+    /// meeting the query, None if every row is scanned.
+    pub(super) index: Option<(usize, Vec<KeyRange>)>,
+    pub(super) position: usize,
+}
+
+impl MemCursor {
+    /// Creates a cursor at the position, answering the query from an
+    /// index if one covers it. The query must have a constraint for each
+    /// field.
+    pub(super) fn new(table: MemTable, query: Vec<Constraint<Value>>, position: usize) -> Self {
+        let index = table.choose_index(&query);
+        let ranges = index.map(|index| table.indexes[index].ranges(&query));
+        MemCursor {
+            table,
+            query,
+            index: index.zip(ranges),
+            position,
+        }
+    }
+
+    fn matches(&self, row: &[Value]) -> bool {
+        self.query.iter()
+            .zip(row.iter())
+            .all(|(constraint, field)| constraint.accepts(field))
     }
-}
\ No newline at end of file
+
+    /// Moves the cursor forward from its current position to the first
+    /// matching record, or the end of the table.
+    fn seek(&mut self) {
+        let Some((index, ranges)) = &self.index else {
+            while self.position < self.table.rows.len()
+                && !self.matches(&self.table.rows[self.position]) {
+                self.position += 1;
+            }
+            return;
+        };
+
+        // The index gives the rows matching its fields, the rest of the
+        // query is checked against each. The next match is the earliest
+        // under any of the keys.
+        let mut next = self.table.rows.len();
+        for range in ranges {
+            for positions in self.table.indexes[*index].rows.range(range.clone()).map(|(_, p)| p) {
+                let start = positions.partition_point(|p| *p < self.position);
+                let found = positions[start..].iter()
+                    .copied()
+                    .take_while(|p| *p < next)
+                    .find(|p| self.matches(&self.table.rows[*p]));
+                if let Some(position) = found {
+                    next = position;
+                }
+            }
+        }
+        self.position = next;
+    }
+}
+
+impl Cursor<Value,VmError> for MemCursor {
+    type Table = MemTable;
+    fn found(&self) -> bool {
+        match self.table.rows.get(self.position) {
+            Some(row) => self.matches(row),
+            None => false,
+        }
+    }
+
+    /// Pushes a struct on to the stack matching the record at the cursor.
+    /// If no record exists at the cursor, None is pushed on to the stack.
+    fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        let Some(row) = self.table.rows.get(self.position) else {
+            stack.push(Value::None);
+            return Ok(());
+        };
+
+        for value in row {
+            stack.push(Vm::copy_value(value)?);
+        }
+        stack.push(Value::Struct { field_count: row.len() });
+        Ok(())
+    }
+
+    /// Consumes a struct from the stack which matches the record type
+    /// and adds it to the table in the position fallowing the cursor.
+    /// The resulting cursor is advanced to point at the inserted record.
+    fn insert(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        if let Some(max_rows) = self.table.max_rows {
+            if self.table.rows.len() >= max_rows {
+                return Err(VmError::TableFull);
+            }
+        }
+
+        let record = self.table.pop_record(stack)?;
+        let at = self.table.place_after(self.position, &record);
+
+        self.table.write_log(|log| log.insert(at, &record))?;
+        self.table.insert_row(at, record);
+        self.position = at;
+        self.table.compact_log()
+    }
+
+    /// Consumes a struct from the stack which matches the record type
+    /// and replaces the record in the table in the position of the cursor.
+    /// On an ordered table a record whose sort fields change moves to its
+    /// place in the order and the cursor moves with it, so advancing from
+    /// there skips the records it moved past, or reads again those it
+    /// moved in front of.
+    fn update(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        if self.position >= self.table.rows.len() {
+            return Err(VmError::InvalidOperation);
+        }
+
+        let record = self.table.pop_record(stack)?;
+        let at = self.position;
+        if self.table.fits(at, &record) {
+            self.table.write_log(|log| log.update(at, &record))?;
+            self.table.replace_row(at, record);
+            return self.table.compact_log();
+        }
+
+        // The record moves to its place in the table's order.
+        self.table.write_log(|log| log.delete(at))?;
+        self.table.remove_row(at);
+        let to = self.table.place(&record);
+        self.table.write_log(|log| log.insert(to, &record))?;
+        self.table.insert_row(to, record);
+        self.position = to;
+        self.table.compact_log()
+    }
+
+    /// Deletes the record at the cursor and advances the cursor to the
+    /// next matching record or the end of the table.
+    fn delete(&mut self) -> Result<(), VmError> {
+        if self.position >= self.table.rows.len() {
+            return Err(VmError::InvalidOperation);
+        }
+
+        let at = self.position;
+        self.table.write_log(|log| log.delete(at))?;
+        self.table.remove_row(at);
+        self.seek();
+        self.table.compact_log()
+    }
+
+    /// advances the cursor to the next matching record or the end of the 
+    /// table.
+    fn advance(&mut self) -> Result<bool, VmError> {
+        if self.position < self.table.rows.len() {
+            self.position += 1;
+        }
+        self.seek();
+        Ok(self.found())
+    }
+
+    /// Closes the cursor and returns the underlying table.
+    fn close(self) -> Self::Table {
+        self.table
+    }
+}
+
+/// Returns true if the query has a constraint for each field of the
+/// schema, with values fitting the field.
+pub(super) fn query_fits(query: &[Constraint<Value>], schema: &[Type]) -> bool {
+    query.len() == schema.len()
+        && query.iter()
+            .zip(schema.iter())
+            .all(|(constraint, field_type)| {
+                constraint.values().iter().all(|value| value_has_type(value, field_type))
+                    && (!constraint.is_text() || *field_type == Type::StringRef)
+            })
+}
+
+/// Checks the top of the stack is a struct matching the schema.
+pub(super) fn check_record(stack: &[Value], schema: &[Type]) -> Result<(), VmError> {
+    let Some((Value::Struct { field_count }, rest)) = stack.split_last() else {
+        return Err(VmError::TypeCheck);
+    };
+
+    if *field_count != schema.len() || *field_count > rest.len() {
+        return Err(VmError::TypeCheck);
+    }
+
+    let types_match = rest[rest.len() - field_count..].iter()
+        .zip(schema.iter())
+        .all(|(value, field_type)| value_has_type(value, field_type));
+
+    if !types_match {
+        return Err(VmError::TypeCheck);
+    }
+    Ok(())
+}
+
+/// Returns true if the value can be stored in a field of the given type.
+pub fn value_has_type(value: &Value, field_type: &Type) -> bool {
+    matches!(
+        (value, field_type),
+        (Value::None, Type::None)
+        | (Value::Usize(_), Type::Usize)
+        | (Value::F32(_), Type::F32)
+        | (Value::F64(_), Type::F64)
+        | (Value::U32(_), Type::U32)
+        | (Value::U64(_), Type::U64)
+        | (Value::I32(_), Type::I32)
+        | (Value::I64(_), Type::I64)
+        | (Value::StringRef { .. }, Type::StringRef)
+        | (Value::Bool(_), Type::Bool)
+        | (Value::Function { .. }, Type::Function(_))
+        | (_, Type::Unknown)
+    )
+}
@@ -20,10 +20,10 @@ fn struct_pop () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
-    dbg!(&vm.stack);
+    dbg!(vm.stack());
     assert!(vm.stack.len() == 1);
 
     Ok(())
@@ -49,15 +49,15 @@ fn struct_copy () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
-    dbg!(&vm.stack);
+    dbg!(vm.stack());
     assert!(vm.stack.len() == 9);
-    assert!(Vm::eq_value(&vm.stack[1], &vm.stack[5])?);
-    assert!(Vm::eq_value(&vm.stack[2], &vm.stack[6])?);
-    assert!(Vm::eq_value(&vm.stack[3], &vm.stack[7])?);
-    assert!(Vm::eq_value(&vm.stack[4], &vm.stack[8])?);
+    assert!(Vm::eq_value(&vm.stack()[1], &vm.stack()[5])?);
+    assert!(Vm::eq_value(&vm.stack()[2], &vm.stack()[6])?);
+    assert!(Vm::eq_value(&vm.stack()[3], &vm.stack()[7])?);
+    assert!(Vm::eq_value(&vm.stack()[4], &vm.stack()[8])?);
 
     Ok(())
 }
@@ -77,12 +77,12 @@ fn value_copy () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
-    dbg!(&vm.stack);
+    dbg!(vm.stack());
     assert!(vm.stack.len() == 3);
-    assert!(Vm::eq_value(&vm.stack[1], &vm.stack[2])?);
+    assert!(Vm::eq_value(&vm.stack()[1], &vm.stack()[2])?);
 
     Ok(())
 }
@@ -111,13 +111,13 @@ fn struct_swap1 () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
-    dbg!(&vm.stack);
+    dbg!(vm.stack());
     assert!(vm.stack.len() == 2);
 
-    let value = match &vm.stack[1] {
+    let value = match &vm.stack()[1] {
         Value::U32(v) => Some(*v),
         _ => None,
     };
@@ -149,13 +149,13 @@ fn struct_swap2 () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
-    dbg!(&vm.stack);
+    dbg!(vm.stack());
     assert!(vm.stack.len() == 5);
 
-    let value = match vm.stack.last() {
+    let value = match vm.stack().last() {
         Some(Value::U32(v)) => Some(*v),
         _ => None,
     };
@@ -192,13 +192,13 @@ fn struct_swap3 () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
-    dbg!(&vm.stack);
+    dbg!(vm.stack());
     assert!(vm.stack.len() == 4);
 
-    let value = match &vm.stack[1] {
+    let value = match &vm.stack()[1] {
         Value::U32(v) => Some(*v),
         _ => None,
     };
@@ -219,12 +219,12 @@ fn main_test () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
     assert!(vm.stack.len() == 2);
 
-    let value = match &vm.stack[1] {
+    let value = match &vm.stack()[1] {
         Value::U32(v) => Some(*v),
         _ => None,
     };
@@ -245,6 +245,7 @@ fn main_call () -> Result<(), VmError> {
         Op::U32(0), // Query Key
         Op::Usize(2), // Struct Size
         Op::Struct, // Build query struct
+        Op::Usize(0b01), // The value is free
         Op::Query,  // Run Query
         Op::Read,   // Read fn value
         Op::U32(5), // First Fn arg
@@ -267,12 +268,84 @@ fn main_call () -> Result<(), VmError> {
         types: BTreeMap::new(),
     };
 
-    let mut vm = Vm::new(module);
+    let mut vm = Vm::checked(module);
 
     vm.run()?;
     assert!(vm.stack.len() == 3);
 
-    let value = match &vm.stack[2] {
+    let value = match &vm.stack()[2] {
+        Value::U32(v) => Some(*v),
+        _ => None,
+    };
+
+    assert!(value == Some(12));
+    Ok(())
+}
+
+
+#[derive(Clone, Default)]
+struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+impl std::io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn json_trace () -> Result<(), VmError> {
+    let code = vec![Op::U32(5), Op::U32(7), Op::AddU32, Op::Halt];
+
+    let module = Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    };
+
+    let out = SharedBuffer::default();
+    let mut vm = Vm::checked(module);
+    vm.set_tracer(Box::new(crate::trace::JsonTracer::new(out.clone())));
+
+    vm.run()?;
+
+    let trace = String::from_utf8(out.0.borrow().clone()).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+    assert!(lines.len() == 4);
+    assert!(lines[2].starts_with("{\"ip\":2,\"op\":\"AddU32\",\"stack\":["));
+    assert!(lines[2].ends_with("\"U32(5)\",\"U32(7)\"]}"));
+    assert!(lines[3] == "{\"ip\":3,\"op\":\"Halt\",\"stack\":[\"Table { handle: 0 }\",\"U32(12)\"]}");
+
+    Ok(())
+}
+
+
+#[test]
+fn fuel_resume () -> Result<(), VmError> {
+    let code = vec![Op::U32(5), Op::U32(7), Op::AddU32, Op::Halt];
+
+    let module = Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    };
+
+    let limits = crate::limits::Limits::unlimited().with_fuel(1);
+    let mut vm = Vm::checked_with_limits(module, limits);
+
+    assert!(matches!(vm.run(), Err(VmError::OutOfFuel)));
+    assert!(vm.stack.len() == 2);
+
+    vm.add_fuel(10);
+    vm.run()?;
+    assert!(vm.fuel() == Some(7));
+
+    let value = match &vm.stack()[1] {
         Value::U32(v) => Some(*v),
         _ => None,
     };
@@ -280,3 +353,264 @@ fn main_call () -> Result<(), VmError> {
     assert!(value == Some(12));
     Ok(())
 }
+
+#[test]
+fn stack_limit () -> Result<(), VmError> {
+    let code = vec![Op::U32(1), Op::U32(2), Op::U32(3), Op::Halt];
+
+    let module = Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    };
+
+    // The function table below main's frame takes one of the slots. The
+    // push which doesn't fit fails before the stack grows.
+    let limits = crate::limits::Limits::unlimited().with_max_stack(3);
+    let mut vm = Vm::checked_with_limits(module, limits);
+    assert!(matches!(vm.run(), Err(VmError::StackOverflow)));
+    assert!(vm.stack_len() == 3);
+    Ok(())
+}
+
+#[test]
+fn call_depth_limit () -> Result<(), VmError> {
+    let code = vec![
+        Op::Halt,
+        // fn 1 calls itself
+        Op::Usize(0),
+        Op::Usize(0),
+        Op::Fn(1),
+        Op::Call,
+        Op::Return,
+    ];
+
+    let module = Module {
+        start: 1,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    };
+
+    let limits = crate::limits::Limits::unlimited()
+        .with_max_call_depth(16)
+        .with_max_stack(64);
+    let mut vm = Vm::checked_with_limits(module, limits);
+
+    assert!(matches!(vm.run(), Err(VmError::CallDepthExceeded)));
+    assert!(vm.call_stack.len() == 17);
+    Ok(())
+}
+
+
+#[test]
+fn yield_resume () -> Result<(), VmError> {
+    let code = vec![
+        Op::U32(5),
+        Op::Yield(Kind::U32),
+        Op::U32(7),
+        Op::AddU32,
+        Op::Halt,
+    ];
+
+    let module = Arc::new(Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    });
+
+    let mut first = Vm::checked(module.clone());
+    let mut second = Vm::checked(module);
+
+    let Status::Yielded(Value::U32(5)) = first.run()? else {
+        panic!("expected a yield");
+    };
+    let Status::Yielded(Value::U32(5)) = second.run()? else {
+        panic!("expected a yield");
+    };
+
+    let Status::Halted = first.resume(Value::U32(1))? else {
+        panic!("expected halt");
+    };
+    // Handles can't be forged, such as handle 0 of the function table.
+    assert!(matches!(second.resume(Value::Table { handle: 0 }), Err(VmError::InvalidOperation)));
+    assert!(matches!(second.resume(Value::Cursor { handle: 0 }), Err(VmError::InvalidOperation)));
+
+    let Status::Halted = second.resume(Value::U32(2))? else {
+        panic!("expected halt");
+    };
+
+    assert!(matches!(first.stack().last(), Some(Value::U32(8))));
+    assert!(matches!(second.stack().last(), Some(Value::U32(9))));
+    assert!(matches!(first.run(), Ok(Status::Halted)));
+
+    Ok(())
+}
+
+#[test]
+fn countdown () -> Result<(), VmError> {
+    let code = vec![
+        Op::I64(5),
+        // 1: loop
+        Op::Copy,
+        Op::I64(1),
+        Op::LtI64,
+        Op::JumpIfFalse(6),
+        Op::Jump(9),
+        Op::I64(1),
+        Op::SubI64,
+        Op::Jump(1),
+        // 9: end
+        Op::Halt,
+    ];
+
+    let module = Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    };
+
+    let mut vm = Vm::checked(module);
+
+    vm.run()?;
+    assert!(matches!(vm.stack().last(), Some(Value::I64(0))));
+    Ok(())
+}
+
+#[test]
+fn checked_tags () -> Result<(), VmError> {
+    let code = vec![
+        Op::U32(5),
+        Op::U32(7),
+        Op::AddI64,
+        Op::Halt,
+    ];
+
+    let module = Arc::new(Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    });
+
+    // Unchecked slots are read as whatever the op expects.
+    let mut vm = Vm::new(module.clone());
+    vm.run()?;
+    assert!(matches!(vm.stack()[..], [Value::Untagged(0), Value::Untagged(12)]));
+    assert!(matches!(vm.top(Kind::I64), Ok(Value::I64(12))));
+
+    let mut vm = Vm::checked(module);
+    assert!(matches!(vm.run(), Err(VmError::TypeCheck)));
+    Ok(())
+}
+
+#[test]
+fn immediates () -> Result<(), VmError> {
+    let code = vec![
+        Op::Halt,
+        // 1: fn(a) { a = a + 2; return a + -5; }
+        Op::LoadImm(0),
+        Op::AddImmI64(2),
+        Op::StoreImm(0),
+        Op::LoadImm(0),
+        Op::AddImmI64(-5),
+        Op::Return,
+        // 7: main
+        Op::I64(10),
+        Op::CallFn { ptr: 1, args: 1, rets: 1 },
+        Op::Return,
+    ];
+
+    let module = Arc::new(Module {
+        start: 7,
+        code,
+        functions: FnTable::new(),
+        types: BTreeMap::new(),
+    });
+
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    assert!(matches!(vm.stack().last(), Some(Value::I64(7))));
+
+    let mut vm = Vm::new(module);
+    vm.run()?;
+    assert!(matches!(vm.top(Kind::I64), Ok(Value::I64(7))));
+    Ok(())
+}
+
+#[test]
+fn return_drops_frame () -> Result<(), VmError> {
+    let run = |start: usize| -> Result<Vec<Value>, VmError> {
+        let code = vec![
+            Op::Halt,
+            // 1: fn(a) leaving two values above its argument
+            Op::I64(2),
+            Op::I64(3),
+            Op::LoadImm(0),
+            Op::Return,
+            // 5: main calling it for two values
+            Op::I64(1),
+            Op::I64(10),
+            Op::CallFn { ptr: 1, args: 1, rets: 2 },
+            Op::Halt,
+            // 9: main returning
+            Op::I64(1),
+            Op::I64(10),
+            Op::CallFn { ptr: 1, args: 1, rets: 2 },
+            Op::Return,
+            // 13: fn returning more values than its frame holds
+            Op::Return,
+            // 14: main calling it
+            Op::CallFn { ptr: 13, args: 0, rets: 1 },
+            Op::Halt,
+        ];
+        let module = Module {
+            start,
+            code,
+            functions: FnTable::new(),
+            types: BTreeMap::new(),
+        };
+        let mut vm = Vm::checked(module);
+        vm.run()?;
+        Ok(vm.stack())
+    };
+
+    // Return drops the callee's frame, its argument and anything left
+    // below the results, and keeps the top `rets` values for the caller.
+    let stack = run(5)?;
+    assert!(stack[1..] == [Value::I64(1), Value::I64(3), Value::I64(10)]);
+
+    // Main is called for one value, so returning from it leaves only its
+    // result above the function table.
+    let stack = run(9)?;
+    assert!(stack[1..] == [Value::I64(10)]);
+
+    // The results must come from the callee's own frame.
+    assert!(matches!(run(14), Err(VmError::InvalidOperation)));
+    Ok(())
+}
+
+#[test]
+fn immediate_checks () {
+    let run = |code: Vec<Op>| {
+        let module = Module {
+            start: 0,
+            code,
+            functions: FnTable::new(),
+            types: BTreeMap::new(),
+        };
+        Vm::checked(module).run()
+    };
+
+    // The immediate forms check their operands like the stack forms.
+    assert!(matches!(run(vec![Op::U32(1), Op::AddImmI64(1), Op::Halt]), Err(VmError::TypeCheck)));
+    assert!(matches!(run(vec![Op::LoadImm(3), Op::Halt]), Err(VmError::StackUnderflow)));
+    assert!(matches!(run(vec![Op::StoreImm(0), Op::Halt]), Err(VmError::StackUnderflow)));
+    assert!(matches!(
+        run(vec![Op::CallFn { ptr: 0, args: 4, rets: 1 }, Op::Halt]),
+        Err(VmError::StackUnderflow)
+    ));
+}
@@ -0,0 +1,65 @@
+use super::*;
+
+#[test]
+fn primitives () -> Result<(), VmError> {
+    assert!(matches!(5i64.to_value(), Value::I64(5)));
+    assert!(i64::from_value(Value::I64(5))? == 5);
+    assert!(matches!(u32::from_value(Value::I64(5)), Err(VmError::TypeCheck)));
+    assert!(<()>::from_value(Value::None).is_ok());
+    Ok(())
+}
+
+#[test]
+fn call () -> Result<(), Box<dyn std::error::Error>> {
+    let source = "
+        fn add(a: i64, b: i64) { return a + b; }
+        fn less(a) { return a - 0.5; }
+        fn main() { less(2.0); }
+    ";
+    let module = Arc::new(parse_colang(source)?);
+
+    for mut vm in [Vm::new(module.clone()), Vm::checked(module.clone())] {
+        // Calls before main runs leave it ready to run.
+        assert!(vm.call::<(i64, i64), i64>("add", (5, 7))? == 12);
+        assert!(vm.call::<(f64,), f64>("less", (5.0,))? == 4.5);
+
+        vm.run()?;
+        assert!(vm.result::<f64>()? == 1.5);
+
+        // And after it halts.
+        assert!(vm.call::<(i64, i64), i64>("add", (1, 1))? == 2);
+        assert!(vm.stack_len() == 2 && vm.result::<f64>()? == 1.5);
+
+        // Functions are found by the types of their arguments too, `less`
+        // was only compiled for an f64.
+        assert!(matches!(vm.call::<(), i64>("missing", ()), Err(VmError::UnknownFunction(_))));
+        assert!(matches!(vm.call::<(i64,), i64>("less", (1,)), Err(VmError::UnknownFunction(_))));
+        assert!(matches!(vm.call::<(i64,), i64>("add", (1,)), Err(VmError::UnknownFunction(_))));
+        assert!(matches!(vm.call::<(i64, i64), f64>("add", (1, 1)), Err(VmError::TypeCheck)));
+    }
+    Ok(())
+}
+
+#[test]
+fn call_yield () -> Result<(), Box<dyn std::error::Error>> {
+    let source = "fn ask(a: i64) { let b = yield a; return b; } fn main() { 1; }";
+    let mut vm = Vm::new(parse_colang(source)?);
+
+    assert!(matches!(vm.call::<(i64,), i64>("ask", (1,)), Err(VmError::Suspended)));
+    assert!(matches!(vm.call::<(i64,), i64>("ask", (1,)), Err(VmError::Suspended)));
+    assert!(matches!(vm.resume(Value::I64(2))?, Status::Halted));
+    Ok(())
+}
+
+#[test]
+fn declared_args () {
+    // A declared type must match the type the function is called with.
+    let source = "fn add(a: i64, b) { a + b; } fn main() { add(1i32, 2i32); }";
+    let error = crate::compile_str("lib.co", source).unwrap_err();
+    assert!(error.to_string() == "lib.co:1:1: expected I64 found I32", "{}", error);
+
+    // Functions declaring every arg's type are compiled even if nothing
+    // calls them, so their errors are found.
+    let source = "fn bad(a: bool) { a + 1; } fn main() { 1; }";
+    assert!(matches!(parse_colang(source), Err(LangError::TypeMismatch(..))));
+}
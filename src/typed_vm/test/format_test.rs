@@ -0,0 +1,164 @@
+use super::*;
+
+fn people() -> TableType {
+    TableType::new("people", vec![Type::U32, Type::StringRef, Type::F64, Type::Bool])
+        .with_names(&["id", "name", "score", "active"])
+}
+
+fn module(types: impl IntoIterator<Item = TableType>) -> Module {
+    Module {
+        start: 0,
+        code: vec![Op::Halt],
+        functions: FnTable::new(),
+        types: types.into_iter().enumerate().map(|(at, table_type)| (at as u32, table_type)).collect(),
+    }
+}
+
+fn exported(vm: &mut Vm, format: Format) -> Result<String, FormatError> {
+    let mut text = Vec::new();
+    vm.export(0, format, &mut text)?;
+    Ok(String::from_utf8(text).unwrap())
+}
+
+#[test]
+fn csv_and_json_lines () -> Result<(), FormatError> {
+    // Columns can come in any order, quoted fields can hold commas,
+    // quotes and line breaks, and blank lines are skipped.
+    let csv = "\u{feff}name,id,active,score\r\n\
+        bob,1,true,2.5\r\n\
+        \r\n\
+        \"smith, \"\"jo\"\"\",2,false,-1\n\
+        \"two\nlines\",3,true,1e3";
+
+    let mut vm = Vm::checked(module([people()]));
+    assert!(vm.import(0, Format::Csv, csv.as_bytes())? == 3);
+
+    let json = exported(&mut vm, Format::JsonLines)?;
+    assert!(json == "\
+        {\"id\":1,\"name\":\"bob\",\"score\":2.5,\"active\":true}\n\
+        {\"id\":2,\"name\":\"smith, \\\"jo\\\"\",\"score\":-1,\"active\":false}\n\
+        {\"id\":3,\"name\":\"two\\nlines\",\"score\":1000,\"active\":true}\n");
+
+    let csv = exported(&mut vm, Format::Csv)?;
+    assert!(csv == "id,name,score,active\n\
+        1,bob,2.5,true\n\
+        2,\"smith, \"\"jo\"\"\",-1,false\n\
+        3,\"two\nlines\",1000,true\n");
+
+    // Each format reads back what it wrote.
+    for (format, text) in [(Format::Csv, &csv), (Format::JsonLines, &json)] {
+        let mut copy = Vm::checked(module([people()]));
+        assert!(copy.import(0, format, text.as_bytes())? == 3);
+        assert!(exported(&mut copy, Format::JsonLines)? == json);
+    }
+
+    // Imports append to the records already in the table.
+    vm.import(0, Format::JsonLines, "{ \"active\": false, \"score\": 0, \"id\": 4, \"name\": \"\\u00e9\\ud83d\\ude00\" }".as_bytes())?;
+    let json = exported(&mut vm, Format::JsonLines)?;
+    assert!(json.ends_with("{\"id\":4,\"name\":\"é😀\",\"score\":0,\"active\":false}\n"));
+    Ok(())
+}
+
+#[test]
+fn import_errors () {
+    let cases = [
+        (Format::Csv, "id,name,score,active\n1,a,1,true\n2,b,x,true", "row 2, column `score`: expected f64, found `x`"),
+        (Format::Csv, "id,name,score,active\n1,a,1,yes", "row 1, column `active`: expected a bool, found `yes`"),
+        (Format::Csv, "id,name,score,active\n-1,a,1,true", "row 1, column `id`: expected u32, found `-1`"),
+        (Format::Csv, "id,name,score\n1,a,1", "row 0, column `active`: missing"),
+        (Format::Csv, "id,name,score,active,age\n", "row 0, column `age`: the table has no such field"),
+        (Format::Csv, "id,name,id,score,active\n", "row 0, column `id`: the column is repeated"),
+        (Format::Csv, "id,name,score,active\n1,a,1", "row 1: expected 4 fields, found 3"),
+        (Format::Csv, "id,name,score,active\n1,\"a,1,true\n", "row 1: unterminated quoted field"),
+        (
+            Format::JsonLines,
+            "{\"id\":1,\"name\":\"a\",\"score\":1,\"active\":true}\n{\"id\":\"2\",\"name\":\"b\",\"score\":1,\"active\":true}",
+            "row 2, column `id`: expected u32, found the string \"2\"",
+        ),
+        (Format::JsonLines, "{\"id\":1,\"name\":2,\"score\":1,\"active\":true}", "row 1, column `name`: expected a string, found `2`"),
+        (Format::JsonLines, "{\"id\":1,\"name\":\"a\",\"score\":1}", "row 1, column `active`: missing"),
+        (Format::JsonLines, "{\"id\":1,\"name\":\"a\",\"score\":1,\"active\":true", "row 1: expected `}`, found the end of the line"),
+        (Format::JsonLines, "{\"id\":[1]}", "row 1: nested values aren't supported"),
+        (Format::JsonLines, "{\"id\":1} 2", "row 1: data follows the object"),
+        (Format::JsonLines, "{\"id\":nil}", "row 1: expected a value, found `nil`"),
+        (Format::JsonLines, "{\"name\":\"\\ud83d\"}", "row 1: unpaired surrogate in string"),
+    ];
+
+    for (format, text, message) in cases {
+        let mut vm = Vm::checked(module([people()]));
+        vm.import(0, Format::Csv, "id,name,score,active\n7,seed,0,true".as_bytes()).unwrap();
+        let error = vm.import(0, format, text.as_bytes()).unwrap_err();
+        assert!(error.to_string() == message, "{}", error);
+
+        // A failed import leaves the table as it was.
+        let csv = exported(&mut vm, Format::Csv).unwrap();
+        assert!(csv == "id,name,score,active\n7,seed,0,true\n");
+    }
+}
+
+#[test]
+fn import_limits () -> Result<(), FormatError> {
+    let limits = crate::limits::Limits::unlimited().with_max_table_rows(2);
+    let mut vm = Vm::checked_with_limits(module([people()]), limits);
+    vm.import(0, Format::Csv, "id,name,score,active\n1,a,1,true".as_bytes())?;
+    let error = vm.import(0, Format::Csv, "id,name,score,active\n2,b,1,true\n3,c,1,true".as_bytes());
+    assert!(matches!(error, Err(FormatError::Vm(VmError::TableFull))));
+    assert!(exported(&mut vm, Format::Csv)? == "id,name,score,active\n1,a,1,true\n");
+
+    let error = vm.import(1, Format::Csv, "".as_bytes());
+    assert!(matches!(error, Err(FormatError::Vm(VmError::UnknownType(1)))));
+    Ok(())
+}
+
+#[test]
+fn unnamed_and_ordered () -> Result<(), FormatError> {
+    // Fields without names are named by their position, and ordered
+    // tables keep their order whatever order the records come in.
+    let scores = TableType::new("scores", vec![Type::U32, Type::I64]).with_order(&[Sort::desc(1)]);
+    let mut vm = Vm::checked(module([scores]));
+    vm.import(0, Format::Csv, "0,1\n5,10\n6,30\n7,20\n".as_bytes())?;
+    assert!(exported(&mut vm, Format::Csv)? == "0,1\n6,30\n7,20\n5,10\n");
+
+    // A query picks the records to export.
+    let query = vec![Constraint::Any, Constraint::Range(Bound::Included(Value::I64(15)), Bound::Unbounded)];
+    let mut text = Vec::new();
+    assert!(vm.export_query(0, query, Format::JsonLines, &mut text)? == 2);
+    assert!(text == b"{\"0\":6,\"1\":30}\n{\"0\":7,\"1\":20}\n");
+
+    let query = vec![Constraint::Any, Constraint::Eq(Value::U32(1))];
+    let error = vm.export_query(0, query, Format::Csv, Vec::new());
+    assert!(matches!(error, Err(FormatError::Vm(VmError::TypeCheck))));
+
+    // JSON has no NaNs, CSV does.
+    let floats = TableType::new("floats", vec![Type::F32]).with_names(&["f"]);
+    let mut vm = Vm::checked(module([floats]));
+    vm.import(0, Format::Csv, "f\nNaN\n".as_bytes())?;
+    assert!(exported(&mut vm, Format::Csv)? == "f\nNaN\n");
+    let error = exported(&mut vm, Format::JsonLines).unwrap_err();
+    assert!(error.to_string() == "row 1, column `f`: JSON can't hold infinities or NaNs");
+    Ok(())
+}
+
+#[test]
+fn import_and_export_files () -> Result<(), FormatError> {
+    let dir = std::env::temp_dir();
+    let csv = dir.join(format!("colang-people-{}.csv", std::process::id()));
+    let json = dir.join(format!("colang-people-{}.jsonl", std::process::id()));
+    std::fs::write(&csv, "id,name,score,active\n1,bob,2.5,true\n")?;
+
+    let mut vm = Vm::checked(module([people()]));
+    assert!(vm.import_file(0, &csv)? == 1);
+    assert!(vm.export_file(0, &json)? == 1);
+    assert!(std::fs::read_to_string(&json)? == "{\"id\":1,\"name\":\"bob\",\"score\":2.5,\"active\":true}\n");
+
+    let mut vm = Vm::checked(module([people()]));
+    assert!(vm.import_file(0, &json)? == 1);
+    assert!(exported(&mut vm, Format::Csv)? == "id,name,score,active\n1,bob,2.5,true\n");
+
+    let error = vm.export_file(0, dir.join("people.txt"));
+    assert!(matches!(error, Err(FormatError::UnknownFormat(_))));
+
+    std::fs::remove_file(&csv)?;
+    std::fs::remove_file(&json)?;
+    Ok(())
+}
@@ -1,27 +1,11 @@
 use super::*;
 
 
-#[derive(Debug)]
-pub enum TestError {
-    LangError(LangError),
-    VmError(VmError),
-}
-
-impl From<VmError> for TestError {
-    fn from(value: VmError) -> Self {
-        TestError::VmError(value)
-    }
-}
-
-impl From<LangError> for TestError {
-    fn from(value: LangError) -> Self {
-        TestError::LangError(value)
-    }
-}
+type TestError = Box<dyn std::error::Error>;
 
 #[test]
 fn call_fn () -> Result<(), TestError> {
-    let file = "src/lang/example.co";
+    let file = "src/lang/corpus/example.co";
     let module = parse_colang_file(file)?;
     let mut vm = Vm::new(module);
     dbg!(vm.code());
@@ -33,7 +17,7 @@ fn call_fn () -> Result<(), TestError> {
 
 #[test]
 fn simple () -> Result<(), TestError> {
-    let file = "src/lang/simple.co";
+    let file = "src/lang/corpus/simple.co";
  
     let module = parse_colang_file(file)?;
     dbg!(&module);
@@ -43,9 +27,8 @@ fn simple () -> Result<(), TestError> {
     dbg!(vm.stack());
     assert!(vm.stack_len() == 2);
 
-    let computed = &vm.stack_get(1);
-    let value = match computed {
-        Some(Value::I64(v)) => Some(*v),
+    let value = match vm.top(Kind::I64) {
+        Ok(Value::I64(v)) => Some(v),
         _ => None,
     };
     assert!(value == Some(12));
@@ -56,7 +39,7 @@ fn simple () -> Result<(), TestError> {
 
 #[test]
 fn simple_vars () -> Result<(), TestError> {
-    let file = "src/lang/simple_vars.co";
+    let file = "src/lang/corpus/simple_vars.co";
  
     let module = parse_colang_file(file)?;
     dbg!(&module);
@@ -65,14 +48,785 @@ fn simple_vars () -> Result<(), TestError> {
     vm.run()?;
     dbg!(vm.code());
     dbg!(vm.stack());
-    assert!(vm.stack_len() == 4);
+    // Returning from main drops `a` and `b` with its frame, leaving the
+    // function table and the result.
+    assert!(vm.stack_len() == 2);
 
-    let computed = &vm.stack_get(3);
-    let value = match computed {
-        Some(Value::I64(v)) => Some(*v),
+    let value = match vm.top(Kind::I64) {
+        Ok(Value::I64(v)) => Some(v),
         _ => None,
     };
     assert!(value == Some(12));
     
     Ok(())
 }
+
+#[test]
+fn comments () -> Result<(), TestError> {
+    let source = "// A comment before the code.
+        fn main() { // after a brace
+            let a = 5; // after a statement
+            // on a line of its own, holding code: a = 1;
+            a + 2;
+        }
+        // at the end without a line break";
+    let mut vm = Vm::new(parse_colang(source)?);
+    vm.run()?;
+    assert!(matches!(vm.top(Kind::I64), Ok(Value::I64(7))));
+    Ok(())
+}
+
+#[test]
+fn generic_functions () -> Result<(), TestError> {
+    let instances = |module: &Module| {
+        let ptrs: std::collections::BTreeSet<usize> = module.code.iter()
+            .filter_map(|op| match op {
+                Op::CallFn { ptr, .. } => Some(*ptr),
+                _ => None,
+            })
+            .collect();
+        ptrs.len()
+    };
+
+    // Args have no declared types, a function is compiled once for each
+    // set of argument types it is called with.
+    let module = parse_colang("
+        fn add(a, b) { return a + b; }
+        fn main() { let x = add(1u32, 2u32); let y = add(1, 2); let z = add(3, 4); y + z; }
+    ")?;
+    assert!(instances(&module) == 2);
+    let mut vm = Vm::new(module);
+    vm.run()?;
+    assert!(matches!(vm.top(Kind::I64), Ok(Value::I64(10))));
+
+    // An instance's types are checked as it is compiled, and a function
+    // never called is never compiled.
+    let compile = |source: &str| parse_colang(source);
+    assert!(compile("fn add(a, b) { return a + b; } fn main() { add(1u32, 2); }").is_err());
+    assert!(compile("fn add(a, b) { return a + b; } fn main() { 1; }").is_ok());
+    assert!(matches!(
+        compile("fn add(a, b) { return a + b; } fn main() { add(1); }"),
+        Err(LangError::WrongArgCount(name)) if name == "add"
+    ));
+
+    // The return type is the type of the first `return`, so a recursive
+    // call needs one compiled before it.
+    assert!(matches!(
+        compile("fn f(n) { let m = f(n); return m; } fn main() { f(1); }"),
+        Err(LangError::UnknownReturnType(name)) if name == "f"
+    ));
+    assert!(matches!(
+        compile("fn f(n) { if n < 1 { return 1u32; } return n; } fn main() { f(1); }"),
+        Err(LangError::TypeMismatch(..))
+    ));
+    Ok(())
+}
+
+#[test]
+fn unsupported_operators () {
+    // Operators without an op are refused rather than compiled to nothing.
+    for operator in ["*", "/", "^"] {
+        let result = parse_colang(&format!("fn main() {{ 6 {} 3; }}", operator));
+        assert!(matches!(&result, Err(LangError::UnsupportedOperator(op)) if op == operator));
+        assert!(result.unwrap_err().to_string() == format!("unsupported operator `{}`", operator));
+    }
+}
+
+#[test]
+fn source_map () -> Result<(), TestError> {
+    let mut sources = crate::source::SourceMap::new();
+    sources.add("lib.co", "fn add(a, b) { return a + b; }");
+    sources.add("main.co", "fn main() { add(7, 5); }");
+
+    let mut vm = Vm::new(compile::compile_sources(&sources)?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 12);
+
+    // Parse errors name the source they were found in.
+    sources.add("lib.co", "fn add(a, b) {");
+    let error = compile::compile_sources(&sources).unwrap_err();
+    assert!(matches!(error, LangError::ParserError(_)));
+    assert!(error.to_string().contains("lib.co:1:"));
+
+    let error = compile::compile_str("generated", "fn main() { 1 +; }").unwrap_err();
+    assert!(error.to_string().contains("generated:1:"));
+
+    // Semantic errors are located too, in the source of the function
+    // they're in, even when it's compiled for a call from another.
+    let error = compile::compile_str("generated", "fn main() {\n  x;\n}").unwrap_err();
+    assert!(error.to_string() == "generated:2:3: unknown variable `x`", "{}", error);
+
+    let mut sources = crate::source::SourceMap::new();
+    sources.add("lib.co", "fn f(a) {\n  let b = 1u32;\n  b = a;\n}");
+    sources.add("main.co", "fn main() { f(1); }");
+    let error = compile::compile_sources(&sources).unwrap_err();
+    assert!(matches!(&error, LangError::Located(at, inner)
+        if at.source == "lib.co" && matches!(**inner, LangError::TypeMismatch(..))));
+    assert!(error.to_string() == "lib.co:3:3: expected U32 found I64", "{}", error);
+
+    // A function can only be defined once across all the sources.
+    let mut sources = crate::source::SourceMap::new();
+    sources.add("a.co", "fn f() { 1; }");
+    sources.add("b.co", "fn main() { f(); }\nfn f() { 2; }");
+    let error = compile::compile_sources(&sources).unwrap_err();
+    assert!(error.to_string() == "b.co:2:1: function `f` is already defined at a.co:1:1", "{}", error);
+
+    let error = parse_colang("fn f() { 1; } fn f() { 2; } fn main() { f(); }").unwrap_err();
+    assert!(matches!(&error, LangError::FunctionAlreadyDefined(name, None) if name == "f"));
+    Ok(())
+}
+
+#[test]
+fn missing_file () {
+    let error = parse_colang_file("src/lang/corpus/missing.co").unwrap_err();
+    assert!(matches!(&error, LangError::Io(io) if io.kind() == std::io::ErrorKind::NotFound));
+    assert!(std::error::Error::source(&error).is_some());
+}
+
+#[test]
+fn table_declarations () -> Result<(), TestError> {
+    let module = parse_colang("
+        table people(id: u32, age: i64, score: f64) index(age) index(id, age);
+        table tags(id: u32, tag: u64);
+        fn main() { 1; }
+    ")?;
+
+    assert!(module.types.len() == 2);
+    assert!(module.types[&0] == TableType::new("people", vec![Type::U32, Type::I64, Type::F64])
+        .with_names(&["id", "age", "score"])
+        .with_index(&[1])
+        .with_index(&[0, 1]));
+    assert!(module.types[&1].indexes.is_empty());
+
+    let error = parse_colang("table t(a: u32) index(b); fn main() { 1; }").unwrap_err();
+    assert!(matches!(error, LangError::UnknownField(name) if name == "b"));
+
+    let error = parse_colang("table t(a: u32, a: u32); fn main() { 1; }").unwrap_err();
+    assert!(matches!(error, LangError::FieldAlreadyDeclared(_)));
+
+    let error = parse_colang("table t(a: u32); table t(b: u32); fn main() { 1; }").unwrap_err();
+    assert!(matches!(error, LangError::TableAlreadyDeclared(_)));
+
+    // The dyn compiler accepts and ignores declarations.
+    crate::dyn_vm::compile::parse_colang("table t(a: u32) index(a); fn main() { 1; }")?;
+
+    Ok(())
+}
+
+const SHOP: &str = "
+    table customers(id: u32, region: u32) index(id);
+    table orders(customer: u32, amount: i64);
+
+    fn main() {
+        insert customers{id: 1u32, region: 10u32};
+        insert customers{id: 2u32, region: 20u32};
+        insert customers{id: 3u32, region: 10u32};
+        insert orders{customer: 1u32, amount: 5};
+        insert orders{customer: 2u32, amount: 7};
+        insert orders{customer: 3u32, amount: 11};
+        insert orders{customer: 3u32, amount: 13};
+        let total = 0;
+        for orders{customer: c, amount: a}, customers{id: c, region: 10u32} {
+            total = total + a;
+        }
+        total;
+    }
+";
+
+#[test]
+fn join () -> Result<(), TestError> {
+    let mut vm = Vm::checked(parse_colang(SHOP)?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 29);
+
+    // Vars bound outside the loop constrain the query.
+    let mut vm = Vm::checked(parse_colang("
+        table t(k: u32, v: i64);
+        fn main() {
+            insert t{k: 1u32, v: 3};
+            insert t{k: 2u32, v: 4};
+            let k = 2u32;
+            let total = 0;
+            for t{k: k, v: v} { total = total + v; }
+            total;
+        }
+    ")?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 4);
+
+    Ok(())
+}
+
+/// The type indexes of the tables the module's loops take, after the
+/// seven inserts of `SHOP`.
+fn join_order(module: &Module) -> Vec<u32> {
+    let taken: Vec<_> = module.code.iter()
+        .filter_map(|op| match op {
+            Op::TakeTable(type_index) => Some(*type_index),
+            _ => None,
+        })
+        .collect();
+    taken[7..].to_vec()
+}
+
+#[test]
+fn join_plan () -> Result<(), TestError> {
+    // customers has a bound field, so it's queried first and binds c for
+    // orders.
+    let module = parse_colang(SHOP)?;
+    assert!(join_order(&module) == [0, 1]);
+
+    // With a field of each bound, customers is preferred as an index
+    // covers its bound field.
+    let module = parse_colang(&SHOP.replace(
+        "orders{customer: c, amount: a}, customers{id: c, region: 10u32}",
+        "orders{customer: 3u32, amount: a}, customers{id: 3u32, region: r}",
+    ))?;
+    assert!(join_order(&module) == [0, 1]);
+
+    let mut vm = Vm::checked(module);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 24);
+
+    // Otherwise the first written is queried first.
+    let module = parse_colang(&SHOP.replace(
+        "orders{customer: c, amount: a}, customers{id: c, region: 10u32}",
+        "orders{customer: c, amount: a}, customers{id: c, region: r}",
+    ))?;
+    assert!(join_order(&module) == [1, 0]);
+
+    Ok(())
+}
+
+#[test]
+fn join_errors () {
+    let compile = |body: &str| parse_colang(&format!(
+        "table t(a: u32, b: u32); table u(a: u32); fn main() {{ {} 1; }}", body
+    ));
+
+    assert!(matches!(compile("for x{a: a} { 1; }"), Err(LangError::UnknownTable(_))));
+    assert!(matches!(compile("for t{c: a} { 1; }"), Err(LangError::UnknownField(_))));
+    assert!(matches!(compile("for t{a: a} { insert t{a: a, b: a}; }"), Err(LangError::TableInQuery(_))));
+    assert!(matches!(compile("for t{a: a} { for t{b: a} { 1; } }"), Err(LangError::TableInQuery(_))));
+    assert!(matches!(compile("for t{a: a}, u{a: a} { let n = count() from t{}; }"), Err(LangError::TableInQuery(_))));
+    assert!(matches!(compile("let n = count() from t{a: x, b: x};"), Err(LangError::RepeatedVar(_))));
+    assert!(matches!(compile("for t{a: 1} { 1; }"), Err(LangError::TypeMismatch(..))));
+    assert!(matches!(compile("insert t{a: 1u32};"), Err(LangError::MissingField(_))));
+    assert!(matches!(compile("for u{a: a} { return 1; }"), Err(LangError::ReturnInQuery)));
+
+    // Vars bound by a join are only in scope in its loop.
+    assert!(matches!(compile("for u{a: a} { 1; } a;"), Err(LangError::UnknownVar(_))));
+
+    let error = crate::dyn_vm::compile::parse_colang(
+        "table u(a: u32); fn main() { insert u{a: 1u32}; }"
+    ).unwrap_err();
+    assert!(error.to_string().contains("insert"));
+}
+
+#[test]
+fn self_join () -> Result<(), TestError> {
+    // The first pattern reads a copy of the table, so the second can match
+    // it again.
+    let mut vm = Vm::checked(parse_colang("
+        table edge(a: u32, b: u32);
+        fn main() {
+            insert edge{a: 1u32, b: 2u32};
+            insert edge{a: 2u32, b: 3u32};
+            insert edge{a: 3u32, b: 4u32};
+            for edge{a: a, b: b}, edge{a: b, b: c} { yield a + c; }
+            1;
+        }
+    ")?);
+    assert!(yields(&mut vm)? == [Value::U32(4), Value::U32(6)]);
+
+    Ok(())
+}
+
+#[test]
+fn table_in_use () -> Result<(), TestError> {
+    // A function called from a loop can't use the table the loop has.
+    let mut vm = Vm::checked(parse_colang("
+        table t(a: u32);
+        fn add(a) { insert t{a: a}; 1; }
+        fn main() {
+            insert t{a: 1u32};
+            for t{a: a} { add(a); }
+            1;
+        }
+    ")?);
+    assert!(matches!(vm.run(), Err(VmError::TableInUse(0))));
+
+    Ok(())
+}
+
+const FAMILY: &str = "
+    table parent(a: u32, b: u32);
+    table ancestor(a: u32, c: u32);
+
+    ancestor(a, b) :- parent(a, b);
+    ancestor(a, c) :- parent(a, b), ancestor(b, c);
+
+    fn main() {
+        insert parent{a: 1u32, b: 2u32};
+        insert parent{a: 2u32, b: 3u32};
+        insert parent{a: 3u32, b: 4u32};
+        insert parent{a: 10u32, b: 11u32};
+        let count = 0;
+        for ancestor{a: 1u32, c: c} { count = count + 1; }
+        count;
+    }
+";
+
+#[test]
+fn rules () -> Result<(), TestError> {
+    let module = parse_colang(FAMILY)?;
+    assert!(!module.types[&0].is_derived());
+    assert!(module.types[&1].rules == [
+        TableRule {
+            head: vec![Term::Var(0), Term::Var(1)],
+            body: vec![Atom { type_index: 0, terms: vec![Term::Var(0), Term::Var(1)] }],
+        },
+        TableRule {
+            head: vec![Term::Var(0), Term::Var(2)],
+            body: vec![
+                Atom { type_index: 0, terms: vec![Term::Var(0), Term::Var(1)] },
+                Atom { type_index: 1, terms: vec![Term::Var(1), Term::Var(2)] },
+            ],
+        },
+    ]);
+
+    let mut vm = Vm::checked(module);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 3);
+
+    // Derived tables join with base tables, and are derived again from
+    // the records inserted since.
+    let mut vm = Vm::checked(parse_colang(&FAMILY.replace(
+        "for ancestor{a: 1u32, c: c} { count = count + 1; }",
+        "insert parent{a: 4u32, b: 5u32};
+        for parent{a: x, b: y}, ancestor{a: y, c: z} { count = count + 1; }",
+    ))?);
+    vm.run()?;
+    // 1-2 has 3 ancestors below, 2-3 has 2, 3-4 has 1.
+    assert!(vm.result::<i64>()? == 6);
+
+    Ok(())
+}
+
+#[test]
+fn mutual_rules () -> Result<(), TestError> {
+    // Rules of tables which match each other are derived together.
+    let mut vm = Vm::checked(parse_colang("
+        table next(a: u32, b: u32);
+        table even(n: u32);
+        table odd(n: u32);
+
+        even(0u32) :- next(0u32, n);
+        odd(b) :- even(a), next(a, b);
+        even(b) :- odd(a), next(a, b);
+
+        fn main() {
+            let n = 0u32;
+            while n < 9u32 {
+                let m = n + 1u32;
+                insert next{a: n, b: m};
+                n = m;
+            }
+            let count = 0;
+            for even{n: x} { count = count + 1; }
+            for odd{n: 7u32} { count = count + 100; }
+            for odd{n: 8u32} { count = count + 1000; }
+            count;
+        }
+    ")?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 105);
+
+    Ok(())
+}
+
+#[test]
+fn rule_errors () {
+    let compile = |rules: &str| parse_colang(&format!(
+        "table t(a: u32, b: u32); table d(a: u32); {} fn main() {{ 1; }}", rules
+    ));
+
+    assert!(matches!(compile("d(a) :- x(a);"), Err(LangError::UnknownTable(_))));
+    assert!(matches!(compile("d(a) :- t(a);"), Err(LangError::WrongArgCount(_))));
+    assert!(matches!(compile("d(a, b) :- t(a, b);"), Err(LangError::WrongArgCount(_))));
+    assert!(matches!(compile("d(c) :- t(a, b);"), Err(LangError::UnknownVar(_))));
+    assert!(matches!(compile("d(a) :- t(a, 1);"), Err(LangError::TypeMismatch(..))));
+    assert!(compile("d(1u32) :- t(a, a);").is_ok());
+
+    let error = parse_colang("
+        table t(a: u32); table d(a: u32);
+        d(a) :- t(a);
+        fn main() { insert d{a: 1u32}; }
+    ").unwrap_err();
+    assert!(matches!(error, LangError::InsertIntoDerived(name) if name == "d"));
+
+    // The dyn compiler accepts and ignores rules.
+    crate::dyn_vm::compile::parse_colang("table t(a: u32); t(1u32) :- t(a); fn main() { 1; }")
+        .unwrap();
+}
+
+#[test]
+fn transactions () -> Result<(), TestError> {
+    let mut vm = Vm::checked(parse_colang("
+        table t(a: u32);
+        fn main() {
+            insert t{a: 1u32};
+            transaction { insert t{a: 2u32}; }
+            transaction {
+                insert t{a: 3u32};
+                rollback;
+                insert t{a: 4u32};
+            }
+            transaction {
+                insert t{a: 5u32};
+                transaction { insert t{a: 6u32}; }
+                if 1 < 2 { rollback; }
+            }
+            let count = 0;
+            for t{a: a} { count = count + 1; }
+            count;
+        }
+    ")?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 2);
+
+    // An error inside a transaction rolls it back.
+    let limits = crate::limits::Limits::unlimited().with_max_table_rows(2);
+    let mut vm = Vm::checked_with_limits(parse_colang("
+        table t(a: u32);
+        fn main() {
+            insert t{a: 1u32};
+            transaction {
+                insert t{a: 2u32};
+                insert t{a: 3u32};
+            }
+            1;
+        }
+    ")?, limits);
+    assert!(matches!(vm.run(), Err(VmError::TableFull)));
+    assert!(vm.held_rows(0).len() == 1);
+    assert!(matches!(vm.run(), Err(VmError::Failed)));
+
+    Ok(())
+}
+
+#[test]
+fn transaction_errors () {
+    let compile = |body: &str| parse_colang(&format!(
+        "table t(a: u32); fn main() {{ {} 1; }}", body
+    ));
+
+    assert!(matches!(compile("rollback;"), Err(LangError::RollbackOutsideTransaction)));
+    assert!(matches!(compile("transaction { return 1; }"), Err(LangError::ReturnInTransaction)));
+    assert!(matches!(
+        compile("for t{a: a} { transaction { 1; } }"),
+        Err(LangError::TransactionInQuery)
+    ));
+    assert!(matches!(
+        compile("transaction { for t{a: a} { rollback; } }"),
+        Err(LangError::RollbackInQuery)
+    ));
+
+    let error = crate::dyn_vm::compile::parse_colang("fn main() { transaction { 1; } }")
+        .unwrap_err();
+    assert!(error.to_string().contains("transaction"));
+}
+
+#[test]
+fn aggregates () -> Result<(), TestError> {
+    let source = |result: &str| format!("
+        table orders(customer: u32, amount: i64, paid: bool);
+        table totals(customer: u32, total: i64, orders: i64, largest: i64);
+        fn main() {{
+            let yes = 1 < 2;
+            let no = 2 < 1;
+            insert orders{{customer: 1u32, amount: 5, paid: yes}};
+            insert orders{{customer: 2u32, amount: 7, paid: no}};
+            insert orders{{customer: 1u32, amount: 9, paid: no}};
+            {}
+        }}
+    ", result);
+    let run = |result: &str| -> Result<Vm, TestError> {
+        let mut vm = Vm::checked(parse_colang(&source(result))?);
+        vm.run()?;
+        Ok(vm)
+    };
+
+    assert!(run("count() from orders{};")?.result::<i64>()? == 3);
+    assert!(run("sum(a) from orders{amount: a};")?.result::<i64>()? == 21);
+    assert!(run("let c = 1u32; sum(a) from orders{customer: c, amount: a};")?.result::<i64>()? == 14);
+    assert!(run("min(a) from orders{amount: a};")?.result::<i64>()? == 5);
+    assert!(run("max(p) from orders{paid: p};")?.result::<bool>()?);
+    assert!(run("avg(a) from orders{amount: a};")?.result::<f64>()? == 7.0);
+    assert!(run("let n = count() from orders{customer: 2u32}; n + 1;")?.result::<i64>()? == 2);
+
+    let vm = run("
+        into totals{customer: c, total: sum(a), orders: count(), largest: max(a)}
+            from orders{customer: c, amount: a} group by c;
+        let rows = 0;
+        for totals{total: t} { rows = rows + 1; }
+        rows;
+    ")?;
+    assert!(vm.result::<i64>()? == 2);
+    assert!(vm.held_rows(1) == [
+        vec![Value::U32(1), Value::I64(14), Value::I64(2), Value::I64(9)],
+        vec![Value::U32(2), Value::I64(7), Value::I64(1), Value::I64(7)],
+    ]);
+
+    // Without a group by every record is in one group, replacing the
+    // groups made before.
+    let vm = run("
+        into totals{customer: c, total: sum(a), orders: count(), largest: max(a)}
+            from orders{customer: c, amount: a} group by c;
+        into totals{customer: min(c), total: sum(a), orders: count(), largest: max(a)}
+            from orders{customer: c, amount: a};
+        1;
+    ")?;
+    assert!(vm.held_rows(1) == [vec![Value::U32(1), Value::I64(21), Value::I64(3), Value::I64(9)]]);
+
+    // Taking the max of no records fails.
+    let mut vm = Vm::checked(parse_colang(&source("max(a) from orders{customer: 3u32, amount: a};"))?);
+    assert!(matches!(vm.run(), Err(VmError::NoRecords)));
+
+    Ok(())
+}
+
+#[test]
+fn aggregate_errors () {
+    let compile = |body: &str| parse_colang(&format!("
+        table t(a: u32, b: bool);
+        table g(a: u32, n: i64);
+        fn main() {{ {} 1; }}
+    ", body));
+
+    assert!(matches!(compile("count(a) from t{a: a};"), Err(LangError::WrongArgCount(_))));
+    assert!(matches!(compile("sum() from t{};"), Err(LangError::WrongArgCount(_))));
+    assert!(matches!(compile("sum(b) from t{b: b};"), Err(LangError::UnsupportedType(Type::Bool))));
+    assert!(matches!(compile("let a = 1u32; sum(a) from t{a: a};"), Err(LangError::NotBound(_))));
+    assert!(matches!(compile("sum(x) from u{a: x};"), Err(LangError::UnknownTable(_))));
+    assert!(matches!(
+        compile("into g{a: a, n: count()} from t{a: a};"),
+        Err(LangError::NotGrouped(_))
+    ));
+    assert!(matches!(
+        compile("into g{a: a, n: count()} from t{a: a} group by c;"),
+        Err(LangError::NotBound(_))
+    ));
+    assert!(matches!(
+        compile("into g{a: a} from t{a: a} group by a;"),
+        Err(LangError::MissingField(_))
+    ));
+    assert!(matches!(
+        compile("into g{a: a, n: sum(a)} from t{a: a} group by a;"),
+        Err(LangError::TypeMismatch(..))
+    ));
+    assert!(matches!(
+        compile("into t{a: a, b: max(b)} from t{a: a, b: b} group by a;"),
+        Err(LangError::TableUsedTwice(_))
+    ));
+    assert!(compile("into g{n: count(), a: a} from t{a: a} group by a;").is_ok());
+
+    let error = crate::dyn_vm::compile::parse_colang("fn main() { count() from t{}; }")
+        .unwrap_err();
+    assert!(error.to_string().contains("count"));
+}
+
+/// Runs the Vm to the end, resuming each yield with the value yielded,
+/// and returns the values yielded.
+fn yields(vm: &mut Vm) -> Result<Vec<Value>, VmError> {
+    let mut values = Vec::new();
+    let mut status = vm.run()?;
+    while let Status::Yielded(value) = status {
+        values.push(value);
+        status = vm.resume(value)?;
+    }
+    Ok(values)
+}
+
+#[test]
+fn ordered_loops () -> Result<(), TestError> {
+    let source = |tables: &str, body: &str| format!("
+        {}
+        fn main() {{
+            insert t{{a: 1, b: 5}};
+            insert t{{a: 2, b: 9}};
+            insert t{{a: 3, b: 7}};
+            insert t{{a: 4, b: 7}};
+            {}
+            1;
+        }}
+    ", tables, body);
+    let run = |tables: &str, body: &str| -> Result<Vec<Value>, TestError> {
+        let mut vm = Vm::checked(parse_colang(&source(tables, body))?);
+        Ok(yields(&mut vm)?)
+    };
+    let values = |values: &[i64]| values.iter().copied().map(Value::I64).collect::<Vec<_>>();
+
+    let unordered = "table t(a: i64, b: i64);";
+    assert!(run(unordered, "for t{a: a} { yield a; }")? == values(&[1, 2, 3, 4]));
+    assert!(run(unordered, "for t{a: a, b: b} order by b { yield a; }")? == values(&[1, 3, 4, 2]));
+    assert!(run(unordered, "for t{a: a, b: b} order by b desc, a desc { yield a; }")? == values(&[2, 4, 3, 1]));
+    assert!(run(unordered, "for t{a: a, b: b} order by b limit 2 { yield a; }")? == values(&[1, 3]));
+    assert!(run(unordered, "for t{a: a} limit 2 offset 1 { yield a; }")? == values(&[2, 3]));
+    assert!(run(unordered, "let b = 7; for t{a: a, b: b} order by a desc { yield a; }")? == values(&[4, 3]));
+
+    let ordered = "table t(a: i64, b: i64) order(b desc, a);";
+    assert!(run(ordered, "for t{a: a} { yield a; }")? == values(&[2, 3, 4, 1]));
+
+    // An ordered loop reads a copy of the records, so the table can be
+    // changed inside it.
+    let body = "
+        for t{a: a} order by a { let c = a + 10; insert t{a: c, b: 0}; }
+        for t{a: a} { yield a; }
+    ";
+    assert!(run(unordered, body)? == values(&[1, 2, 3, 4, 11, 12, 13, 14]));
+
+    // A snapshot taken inside the loop carries on in the same order.
+    let module = Arc::new(parse_colang(&source(unordered, "for t{a: a, b: b} order by b desc { yield a; }"))?);
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    let bytes = vm.snapshot();
+    let mut restored = Vm::restore(module, crate::limits::Limits::unlimited(), &bytes)?;
+    let mut rest = Vec::new();
+    let mut status = restored.resume(Value::I64(2))?;
+    while let Status::Yielded(value) = status {
+        rest.push(value);
+        status = restored.resume(value)?;
+    }
+    assert!(rest == values(&[3, 4, 1]));
+
+    Ok(())
+}
+
+#[test]
+fn order_errors () {
+    let compile = |tables: &str, body: &str| parse_colang(&format!(
+        "table t(a: u32); {} fn main() {{ {} 1; }}", tables, body
+    ));
+
+    assert!(matches!(compile("table u(b: u32) order(c);", ""), Err(LangError::UnknownField(_))));
+    assert!(matches!(
+        compile("table u(b: u32);", "for t{a: a}, u{b: a} order by a { 1; }"),
+        Err(LangError::OrderedJoin)
+    ));
+    assert!(matches!(compile("", "for t{a: a} order by b { 1; }"), Err(LangError::NotBound(_))));
+    assert!(matches!(
+        compile("", "for t{a: a} limit 99999999999999999999999 { 1; }"),
+        Err(LangError::InvalidNumber(_))
+    ));
+    assert!(compile("table u(b: u32) order(b desc);", "for t{a: a} order by a desc limit 1 { 1; }").is_ok());
+}
+
+#[test]
+fn where_clauses () -> Result<(), TestError> {
+    let source = |tables: &str, body: &str| format!("
+        table people(name: string, age: i64, team: u32) {};
+        table pairs(a: i64, b: i64);
+        fn main() {{
+            insert people{{name: \"ada\", age: 36, team: 1u32}};
+            insert people{{name: \"alan\", age: 41, team: 2u32}};
+            insert people{{name: \"grace\", age: 85, team: 1u32}};
+            insert people{{name: \"al\", age: 12, team: 3u32}};
+            insert pairs{{a: 1, b: 1}};
+            insert pairs{{a: 1, b: 2}};
+            insert pairs{{a: 3, b: 3}};
+            {}
+            1;
+        }}
+    ", tables, body);
+    let run = |tables: &str, body: &str| -> Result<Vec<Value>, TestError> {
+        let mut vm = Vm::checked(parse_colang(&source(tables, body))?);
+        Ok(yields(&mut vm)?)
+    };
+    let values = |values: &[i64]| values.iter().copied().map(Value::I64).collect::<Vec<_>>();
+
+    // Conditions joined by `&&` on one var make a range, with or without
+    // an index to answer it.
+    let adults = "for people{age: a} where a >= 18 && a < 65 { yield a; }";
+    assert!(run("", adults)? == values(&[36, 41]));
+    assert!(run("index(age)", adults)? == values(&[36, 41]));
+    assert!(run("", "for people{age: a} where a > 36 && a <= 85 { yield a; }")? == values(&[41, 85]));
+    assert!(run("", "for people{age: a} where a == 12 { yield a; }")? == values(&[12]));
+
+    // Sets, prefixes and ranges of text.
+    assert!(run("", "for people{age: a, team: t} where t in [1u32, 3u32] { yield a; }")? == values(&[36, 85, 12]));
+    assert!(run("", "for people{name: n, age: a} where n starts_with \"al\" { yield a; }")? == values(&[41, 12]));
+    assert!(run("", "for people{name: n, age: a} where n < \"b\" && n > \"ada\" { yield a; }")? == values(&[41, 12]));
+
+    // Operands can be vars in scope, and the conditions apply to
+    // aggregates and groups too.
+    assert!(run("", "let min = 40; for people{age: a} where a >= min { yield a; }")? == values(&[41, 85]));
+    assert!(run("", "let n = count() from people{age: a} where a < 50; yield n;")? == values(&[3]));
+    assert!(run("", "
+        into pairs{a: a, b: count()} from people{age: a} where a > 40 group by a;
+        for pairs{a: a, b: b} { yield a; yield b; }
+    ")? == values(&[41, 1, 85, 1]));
+
+    // A var given to two fields matches records whose fields are equal.
+    assert!(run("", "for pairs{a: x, b: x} { yield x; }")? == values(&[1, 3]));
+    assert!(run("", "for pairs{a: x, b: x} where x > 1 { yield x; }")? == values(&[3]));
+
+    Ok(())
+}
+
+#[test]
+fn where_errors () {
+    let compile = |body: &str| parse_colang(&format!(
+        "table t(a: u32, s: string); fn main() {{ {} 1; }}", body
+    ));
+
+    assert!(matches!(compile("for t{a: a} where b < 1u32 { 1; }"), Err(LangError::NotBound(_))));
+    assert!(matches!(compile("let a = 1u32; for t{a: a} where a < 2u32 { 1; }"), Err(LangError::NotBound(_))));
+    assert!(matches!(compile("for t{a: a} where a < c { 1; }"), Err(LangError::UnknownVar(_))));
+    assert!(matches!(compile("for t{a: a, s: s} where a < s { 1; }"), Err(LangError::UnknownVar(_))));
+    assert!(matches!(compile("for t{a: a} where a < 1 { 1; }"), Err(LangError::TypeMismatch(..))));
+    assert!(matches!(compile("for t{a: a} where a starts_with \"x\" { 1; }"), Err(LangError::NotAString(_))));
+    assert!(matches!(compile("into t{a: a, s: s} from t{a: a, s: s} where a in [1u32, 2] group by a, s;"),
+        Err(LangError::TableUsedTwice(_))));
+    assert!(compile("for t{a: a, s: s} where a in [1u32, 2u32] && s starts_with \"x\" { 1; }").is_ok());
+}
+
+#[test]
+fn control_flow () -> Result<(), TestError> {
+    let run = |body: &str| -> Result<Vm, TestError> {
+        let mut vm = Vm::checked(parse_colang(&format!("fn main() {{ {} }}", body))?);
+        vm.run()?;
+        Ok(vm)
+    };
+
+    assert!(run("10 - 3;")?.result::<i64>()? == 7);
+    assert!(run("2.5 - 4.0;")?.result::<f64>()? == -1.5);
+    assert!(run("let yes = 3u64 < 4u64; yes;")?.result::<bool>()?);
+    assert!(!run("let no = 2.0 < 1.5; no;")?.result::<bool>()?);
+
+    assert!(run("let a = 1; if 2 < 1 { a = 2; } a;")?.result::<i64>()? == 1);
+    assert!(run("let a = 1; if 1 < 2 { a = 2; } else { a = 3; } a;")?.result::<i64>()? == 2);
+    assert!(run("let a = 1; if 2 < 1 { a = 2; } else { a = 3; } a;")?.result::<i64>()? == 3);
+
+    // A var declared in a loop's body is declared again each time round.
+    let vm = run("
+        let i = 0;
+        let total = 0;
+        while i < 5 {
+            let twice = i + i;
+            total = total + twice;
+            i = i + 1;
+        }
+        total;
+    ")?;
+    assert!(vm.result::<i64>()? == 20);
+
+    let compile = |body: &str| parse_colang(&format!("fn main() {{ {} }}", body));
+    assert!(matches!(compile("b = 1;"), Err(LangError::UnknownVar(name)) if name == "b"));
+    assert!(matches!(compile("if 1 < 2 { let b = 1; } b;"), Err(LangError::UnknownVar(_))));
+    assert!(matches!(compile("let a = 1; a = 1.5;"), Err(LangError::TypeMismatch(..))));
+    assert!(matches!(compile("if 1 { 2; }"), Err(LangError::TypeMismatch(..))));
+    assert!(matches!(compile("let a = 1; while a { a = 0; }"), Err(LangError::TypeMismatch(..))));
+    assert!(matches!(compile("1u32 < 2;"), Err(LangError::TypeMismatch(..))));
+    Ok(())
+}
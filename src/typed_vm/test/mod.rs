@@ -1,4 +1,9 @@
 use super::*;
 
 mod bytecode_test;
-//mod lang_test;
\ No newline at end of file
+mod convert_test;
+mod table_test;
+mod storage_test;
+mod lang_test;
+mod optimize_test;
+mod format_test;
\ No newline at end of file
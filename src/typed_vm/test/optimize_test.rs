@@ -0,0 +1,99 @@
+use super::*;
+
+fn optimized(mut code: Vec<Op>) -> String {
+    optimize::optimize(&mut code);
+    format!("{:?}", code)
+}
+
+#[test]
+fn fold_constants () {
+    let code = vec![
+        Op::U32(3),
+        Op::U32(10),
+        Op::SubU32,
+        Op::F64(1.5),
+        Op::F64(2.0),
+        Op::LtF64,
+        Op::Return,
+    ];
+    // Subtraction wraps as it does in the Vm.
+    assert_eq!(
+        optimized(code),
+        format!("{:?}", [Op::U32(3u32.wrapping_sub(10)), Op::Bool(true), Op::Return])
+    );
+
+    let code = vec![Op::I64(1), Op::I32(2), Op::AddI64, Op::Return];
+    assert_eq!(optimized(code), "[I64(1), I32(2), AddI64, Return]");
+}
+
+#[test]
+fn forward_store () {
+    let code = vec![
+        Op::None,
+        Op::I64(2),
+        Op::StoreImm(0),
+        Op::LoadImm(0),
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), "[None, I64(2), Copy, StoreImm(0), Return]");
+}
+
+#[test]
+fn add_immediate () {
+    let code = vec![
+        Op::LoadImm(0),
+        Op::I64(1),
+        Op::AddI64,
+        Op::I64(-3),
+        Op::AddI64,
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), "[LoadImm(0), AddImmI64(-2), Return]");
+
+    let code = vec![Op::I64(5), Op::LoadImm(0), Op::AddI64, Op::Return];
+    assert_eq!(optimized(code), "[I64(5), LoadImm(0), AddI64, Return]");
+}
+
+#[test]
+fn remove_dead_code () {
+    let code = vec![
+        Op::I64(1),
+        Op::Halt,
+        Op::Jump(0),
+        Op::I64(2),
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), "[I64(1), Halt]");
+}
+
+#[test]
+fn constant_branch () {
+    let code = vec![
+        Op::Bool(false),
+        Op::JumpIfFalse(4),
+        Op::I64(1),
+        Op::Return,
+        Op::I64(2),
+        Op::Pop,
+        Op::I64(3),
+        Op::Return,
+    ];
+    assert_eq!(optimized(code), "[I64(3), Return]");
+}
+
+#[test]
+fn jump_into_window () {
+    // The load is jumped to, so the store can't be forwarded to it.
+    let code = vec![
+        Op::I64(2),
+        Op::StoreImm(0),
+        Op::LoadImm(0),
+        Op::LoadImm(1),
+        Op::JumpIfFalse(2),
+        Op::Return,
+    ];
+    assert_eq!(
+        optimized(code),
+        "[I64(2), StoreImm(0), LoadImm(0), LoadImm(1), JumpIfFalse(2), Return]"
+    );
+}
@@ -0,0 +1,322 @@
+use super::*;
+use super::storage::Log;
+use std::path::PathBuf;
+
+/// A path in the temp dir for the test's table file, removing any file
+/// left there by an earlier run.
+fn table_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("colang-{}-{}.table", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// A program over a table stored in the file which inserts the values,
+/// then counts the records and sums their values.
+fn program(path: &std::path::Path, inserts: &[i64]) -> String {
+    let inserts: String = inserts.iter()
+        .map(|value| format!("insert t{{v: {}}};", value))
+        .collect();
+    format!("
+        table t(v: i64) file(\"{}\");
+        fn main() {{
+            {}
+            let total = 0;
+            for t{{v: v}} {{ total = total + v; }}
+            total;
+        }}
+    ", path.display(), inserts)
+}
+
+#[test]
+fn persistent_table () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("persistent");
+
+    let module = parse_colang(&program(&path, &[1, 2]))?;
+    assert!(module.types[&0] == TableType::new("t", vec![Type::I64])
+        .with_names(&["v"])
+        .with_file(&path));
+
+    let mut vm = Vm::checked(module);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 3);
+    drop(vm);
+
+    // The header names the magic, the version and the type of each field.
+    assert!(std::fs::read(&path)?[..10] == [b'C', b'O', b'T', b'B', 2, 1, 0, 0, 0, 7]);
+
+    // A new Vm carries on from the records the last one left.
+    let mut vm = Vm::checked(parse_colang(&program(&path, &[4]))?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 7);
+
+    // A table of different fields can't be stored in the same file.
+    let mut vm = Vm::checked(parse_colang(&format!(
+        "table t(v: u32) file(\"{}\"); fn main() {{ insert t{{v: 1u32}}; 1; }}",
+        path.display(),
+    ))?);
+    assert!(matches!(vm.run(), Err(VmError::Storage(_))));
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn torn_write () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("torn");
+    let schema = [Type::U32, Type::I64];
+
+    let (mut log, _) = Log::open(&path, &schema)?;
+    log.insert(0, &[Value::U32(1), Value::I64(10)])?;
+    log.insert(1, &[Value::U32(2), Value::I64(20)])?;
+    log.update(0, &[Value::U32(1), Value::I64(11)])?;
+    drop(log);
+    let whole = std::fs::metadata(&path)?.len();
+
+    // Cut the last record short, as a crash part way through writing it
+    // would.
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.set_len(whole - 3)?;
+    drop(file);
+
+    let (mut log, rows) = Log::open(&path, &schema)?;
+    assert!(rows == [vec![Value::U32(1), Value::I64(10)], vec![Value::U32(2), Value::I64(20)]]);
+    log.delete(0)?;
+    drop(log);
+
+    let (_, rows) = Log::open(&path, &schema)?;
+    assert!(rows == [vec![Value::U32(2), Value::I64(20)]]);
+
+    // Only the fields of numbers and bools can be stored.
+    let error = Log::open(&table_file("strings"), &[Type::StringRef]).unwrap_err();
+    assert!(error.kind() == std::io::ErrorKind::InvalidInput);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn compaction () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("compaction");
+    let schema = [Type::U64];
+
+    let (mut log, _) = Log::open(&path, &schema)?;
+    log.insert(0, &[Value::U64(0)])?;
+    let small = std::fs::metadata(&path)?.len();
+
+    for value in 1..3000 {
+        log.update(0, &[Value::U64(value)])?;
+        if log.needs_compaction(1) {
+            log.compact(&[vec![Value::U64(value)]])?;
+        }
+    }
+    drop(log);
+
+    assert!(std::fs::metadata(&path)?.len() < small * 1100);
+    let (_, rows) = Log::open(&path, &schema)?;
+    assert!(rows == [vec![Value::U64(2999)]]);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn persistent_rollback () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("rollback");
+    let source = program(&path, &[1]).replace(
+        "let total = 0;",
+        "transaction { insert t{v: 5}; rollback; } let total = 0;",
+    );
+
+    let mut vm = Vm::checked(parse_colang(&source)?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 1);
+    drop(vm);
+
+    let (_, rows) = Log::open(&path, &[Type::I64])?;
+    assert!(rows == [vec![Value::I64(1)]]);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn persistent_snapshot () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("snapshot");
+    let source = program(&path, &[1]).replace(
+        "let total = 0;",
+        "yield 0; insert t{v: 2}; let total = 0;",
+    );
+
+    let module = Arc::new(parse_colang(&source)?);
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    let bytes = vm.snapshot();
+    vm.resume(Value::I64(0))?;
+    assert!(vm.result::<i64>()? == 3);
+    drop(vm);
+
+    // Restoring puts the file back to the rows the snapshot has.
+    let limits = crate::limits::Limits::unlimited();
+    let mut vm = Vm::restore(module, limits, &bytes)?;
+    vm.resume(Value::I64(0))?;
+    assert!(vm.result::<i64>()? == 3);
+    drop(vm);
+
+    let (_, rows) = Log::open(&path, &[Type::I64])?;
+    assert!(rows.len() == 2);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn ordered_file () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("ordered");
+    let (mut log, _) = Log::open(&path, &[Type::I64])?;
+    for (at, value) in [3, 1, 2].into_iter().enumerate() {
+        log.insert(at, &[Value::I64(value)])?;
+    }
+    drop(log);
+
+    // Rows written before the table was ordered are sorted when it is
+    // opened, and the file rewritten in order.
+    let source = format!("
+        table t(v: i64) order(v desc) file(\"{}\");
+        fn main() {{
+            insert t{{v: 4}};
+            for t{{v: v}} {{ yield v; }}
+            1;
+        }}
+    ", path.display());
+    let mut vm = Vm::checked(parse_colang(&source)?);
+    let mut values = Vec::new();
+    let mut status = vm.run()?;
+    while let Status::Yielded(value) = status {
+        values.push(value);
+        status = vm.resume(value)?;
+    }
+    assert!(values == [4, 3, 2, 1].map(Value::I64));
+    drop(vm);
+
+    let (_, rows) = Log::open(&path, &[Type::I64])?;
+    assert!(rows == [4, 3, 2, 1].map(|v| vec![Value::I64(v)]));
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn uncommitted_transaction () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("uncommitted");
+    let source = program(&path, &[1]).replace(
+        "let total = 0;",
+        "transaction { insert t{v: 5}; yield 1; transaction { insert t{v: 6}; } insert t{v: 7}; }
+        let total = 0;",
+    );
+    let module = Arc::new(parse_colang(&source)?);
+    let rows = |path| -> std::io::Result<Vec<i64>> {
+        let (_, rows) = Log::open(path, &[Type::I64])?;
+        Ok(rows.iter().map(|row| row[0].bits() as i64).collect())
+    };
+
+    // A Vm dropped inside a transaction leaves none of its changes.
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    drop(vm);
+    assert!(rows(&path)? == [1]);
+
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    let bytes = vm.snapshot();
+    assert!(rows(&path)? == [1, 1]);
+    vm.resume(Value::I64(0))?;
+    assert!(vm.result::<i64>()? == 20);
+    assert!(rows(&path)? == [1, 1, 5, 6, 7]);
+    drop(vm);
+
+    // Restoring a snapshot taken inside a transaction puts the file back
+    // to the rows the transaction began with.
+    let limits = crate::limits::Limits::unlimited();
+    let vm = Vm::restore(module.clone(), limits.clone(), &bytes)?;
+    assert!(rows(&path)? == [1, 1]);
+    drop(vm);
+
+    let mut vm = Vm::restore(module, limits, &bytes)?;
+    vm.resume(Value::I64(0))?;
+    assert!(vm.result::<i64>()? == 20);
+    drop(vm);
+    assert!(rows(&path)? == [1, 1, 5, 6, 7]);
+
+    // A transaction cut short while being written is dropped whole.
+    let (mut log, _) = Log::open(&path, &[Type::I64])?;
+    log.begin();
+    log.insert(5, &[Value::I64(8)])?;
+    log.delete(0)?;
+    log.commit()?;
+    drop(log);
+    let whole = std::fs::metadata(&path)?.len();
+    assert!(rows(&path)? == [1, 5, 6, 7, 8]);
+
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.set_len(whole - 3)?;
+    drop(file);
+    assert!(rows(&path)? == [1, 1, 5, 6, 7]);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn untouched_tables () -> Result<(), Box<dyn std::error::Error>> {
+    let (changed, untouched) = (table_file("changed"), table_file("untouched"));
+    let source = format!("
+        table t(v: i64) file(\"{}\");
+        table u(v: i64) file(\"{}\");
+        table m(v: i64);
+        fn main() {{
+            insert m{{v: 1}};
+            transaction {{ yield 0; insert t{{v: 5}}; yield 1; }}
+            1;
+        }}
+    ", changed.display(), untouched.display());
+
+    // A transaction only copies the tables it changes, when it first
+    // changes them, and opens no file it doesn't use.
+    let mut vm = Vm::checked(parse_colang(&source)?);
+    vm.run()?;
+    assert!(vm.transactions[0].tables.is_empty());
+    vm.resume(Value::I64(0))?;
+    assert!(vm.transactions[0].tables.keys().eq([&0]));
+    assert!(changed.exists() && !untouched.exists());
+    vm.resume(Value::I64(0))?;
+    drop(vm);
+
+    let (_, rows) = Log::open(&changed, &[Type::I64])?;
+    assert!(rows == [vec![Value::I64(5)]]);
+    assert!(!untouched.exists());
+
+    std::fs::remove_file(&changed)?;
+    Ok(())
+}
+
+#[test]
+fn derived_from_file () -> Result<(), Box<dyn std::error::Error>> {
+    let path = table_file("derived");
+    let (mut log, _) = Log::open(&path, &[Type::U32, Type::U32])?;
+    log.insert(0, &[Value::U32(1), Value::U32(2)])?;
+    drop(log);
+
+    // A table stored in a file has records before the Vm first takes it.
+    let source = format!("
+        table edge(a: u32, b: u32) file(\"{}\");
+        table reach(a: u32, b: u32);
+        reach(x, y) :- edge(x, y);
+        fn main() {{ count() from reach{{}}; }}
+    ", path.display());
+    let mut vm = Vm::checked(parse_colang(&source)?);
+    vm.run()?;
+    assert!(vm.result::<i64>()? == 1);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
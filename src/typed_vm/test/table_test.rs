@@ -0,0 +1,1281 @@
+use super::*;
+
+fn people() -> TableType {
+    TableType::new("people", vec![Type::U32, Type::I64])
+        .with_names(&["id", "age"])
+}
+
+fn people_module(code: Vec<Op>) -> Module {
+    table_module(code, people())
+}
+
+fn table_module(code: Vec<Op>, table_type: TableType) -> Module {
+    let mut types = BTreeMap::new();
+    types.insert(0, table_type);
+
+    Module {
+        start: 0,
+        code,
+        functions: FnTable::new(),
+        types,
+    }
+}
+
+/// Ops which create table 0, query it for everything and insert the
+/// records (1, 10), (2, 20) and (3, 30).
+fn populate() -> Vec<Op> {
+    populate_with([(1, 10), (2, 20), (3, 30)].map(|(id, age)| (Op::U32(id), Op::I64(age))))
+}
+
+/// Ops which create table 0, query it for everything and insert records
+/// of two fields.
+fn populate_with(records: impl IntoIterator<Item = (Op, Op)>) -> Vec<Op> {
+    let mut code = vec![
+        Op::Usize(0),
+        Op::Table,
+        Op::None,
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b11),
+        Op::Query,
+    ];
+
+    for (id, field) in records {
+        code.push(id);
+        code.push(field);
+        code.push(Op::Usize(2));
+        code.push(Op::Struct);
+        code.push(Op::Insert);
+    }
+
+    code
+}
+
+/// The ids of the records from the cursor on the top of the stack on,
+/// and whether the cursor's query was answered from an index.
+fn cursor_ids(vm: &mut Vm) -> Result<(Vec<u32>, bool), VmError> {
+    let Ok(Value::Cursor { handle }) = vm.top(Kind::Cursor) else {
+        panic!("expected a cursor");
+    };
+
+    let cursor = vm.handles.cursor_mut(handle)?;
+    let CursorTypes::Mem(mem_cursor) = &*cursor else {
+        panic!("expected a mem cursor");
+    };
+    let indexed = mem_cursor.index.is_some();
+
+    let mut ids = Vec::new();
+    while cursor.found() {
+        let mut record = Vec::new();
+        cursor.read(&mut record)?;
+        if let Value::U32(id) = record[0] {
+            ids.push(id);
+        }
+        cursor.advance()?;
+    }
+    Ok((ids, indexed))
+}
+
+fn top_bool(vm: &Vm) -> Option<bool> {
+    match vm.stack().last() {
+        Some(Value::Bool(v)) => Some(*v),
+        _ => None,
+    }
+}
+
+#[test]
+fn insert_and_query () -> Result<(), VmError> {
+    let mut code = populate();
+    code.extend([
+        Op::Close,
+        Op::U32(2),
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b10),
+        Op::Query,
+        Op::Found,
+        Op::Halt,
+    ]);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.run()?;
+
+    assert!(top_bool(&vm) == Some(true));
+
+    let Some(Value::Cursor { handle }) = vm.stack().iter().nth_back(1).copied() else {
+        panic!("expected a cursor");
+    };
+
+    let cursor = vm.handles.cursor(handle)?;
+    let mut record = Vec::new();
+    cursor.read(&mut record)?;
+    assert!(matches!(record[..], [Value::U32(2), Value::I64(20), Value::Struct { field_count: 2 }]));
+
+    Ok(())
+}
+
+#[test]
+fn advance_delete_update () -> Result<(), VmError> {
+    let mut code = populate();
+    code.extend([
+        Op::Close,
+        Op::None,
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b11),
+        Op::Query,
+        // Remove (1, 10) leaving the cursor at (2, 20)
+        Op::Delete,
+        // Replace (2, 20) with (2, 21)
+        Op::U32(2),
+        Op::I64(21),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Update,
+        Op::Advance,
+        Op::Advance,
+        Op::Found,
+        Op::Halt,
+    ]);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.run()?;
+
+    assert!(top_bool(&vm) == Some(false));
+
+    let Some(Value::Cursor { handle }) = vm.stack().iter().nth_back(1).copied() else {
+        panic!("expected a cursor");
+    };
+
+    let TableTypes::Mem(table) = vm.handles.take_cursor(handle)?.close() else {
+        panic!("expected a mem table");
+    };
+
+    assert!(table.len() == 2);
+
+    let mut cursor = table.find(&mut vec![Value::U32(2), Value::None]).unwrap();
+    let mut record = Vec::new();
+    cursor.read(&mut record)?;
+    assert!(matches!(record[..], [Value::U32(2), Value::I64(21), Value::Struct { field_count: 2 }]));
+    assert!(!cursor.advance()?);
+
+    Ok(())
+}
+
+#[test]
+fn insert_type_check () -> Result<(), VmError> {
+    let code = vec![
+        Op::Usize(0),
+        Op::Table,
+        Op::None,
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b11),
+        Op::Query,
+        Op::I64(1),
+        Op::I64(10),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Insert,
+        Op::Halt,
+    ];
+
+    let mut vm = Vm::checked(people_module(code));
+    assert!(matches!(vm.run(), Err(VmError::TypeCheck)));
+
+    Ok(())
+}
+
+#[test]
+fn find_errors () -> Result<(), VmError> {
+    // A query which doesn't fit the table is an error, not a panic, so a
+    // host implementing `Table` can refuse queries too.
+    let table = || MemTable::new(0, &people(), None);
+    assert!(matches!(table()?.find(&mut vec![Value::None]), Err(VmError::TypeCheck)));
+    assert!(matches!(table()?.find(&mut vec![Value::I64(1), Value::None]), Err(VmError::TypeCheck)));
+    assert!(table()?.find(&mut vec![Value::U32(1), Value::None]).is_ok());
+
+    let mut functions = FnTable::new();
+    functions.add_fn(0, 0);
+    let table = || TableTypes::Fn(functions.clone());
+    assert!(matches!(table().find(&mut vec![Value::U32(0)]), Err(VmError::TypeCheck)));
+    assert!(matches!(table().find(&mut vec![Value::None, Value::I64(0)]), Err(VmError::TypeCheck)));
+    assert!(matches!(table().find(&mut vec![Value::None, Value::U32(1)]), Err(VmError::InvalidOperation)));
+    assert!(table().find(&mut vec![Value::None, Value::U32(0)]).is_ok());
+    Ok(())
+}
+
+#[test]
+fn unknown_type () {
+    let run = |index: usize| Vm::checked(people_module(vec![Op::Usize(index), Op::Table, Op::Halt])).run();
+    assert!(run(0).is_ok());
+    assert!(matches!(run(1), Err(VmError::UnknownType(1))));
+    // Not truncated to type 0.
+    if let Some(index) = 1usize.checked_shl(32) {
+        assert!(matches!(run(index), Err(VmError::UnknownType(u32::MAX))));
+    }
+}
+
+#[test]
+fn row_limit () -> Result<(), VmError> {
+    let mut code = populate();
+    code.push(Op::Halt);
+
+    let limits = crate::limits::Limits::unlimited().with_max_table_rows(2);
+    let mut vm = Vm::with_limits(people_module(code), limits);
+
+    assert!(matches!(vm.run(), Err(VmError::TableFull)));
+
+    // The insert which failed isn't skipped by running again.
+    assert!(matches!(vm.run(), Err(VmError::Failed)));
+    assert!(matches!(vm.step(), Err(VmError::Failed)));
+    assert!(matches!(vm.resume(Value::None), Err(VmError::Failed)));
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_restore () -> Result<(), VmError> {
+    let mut code = populate();
+    code.extend([
+        Op::Close,
+        Op::U32(2),
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b10),
+        Op::Query,
+        Op::U32(0),
+        Op::Yield(Kind::U32),
+        Op::Pop,
+        Op::Read,
+        Op::Halt,
+    ]);
+
+    let module = Arc::new(people_module(code));
+    let mut vm = Vm::checked(module.clone());
+
+    let Status::Yielded(Value::U32(0)) = vm.run()? else {
+        panic!("expected a yield");
+    };
+
+    let bytes = vm.snapshot();
+    drop(vm);
+
+    let limits = crate::limits::Limits::unlimited();
+    let mut restored = Vm::restore(module.clone(), limits, &bytes).unwrap();
+    assert!(restored.snapshot() == bytes);
+
+    // The instruction and frame pointers follow the header.
+    let corrupt = |at: usize| {
+        let mut corrupt = bytes.clone();
+        corrupt[at..at + 8].copy_from_slice(&1000u64.to_le_bytes());
+        Vm::restore(module.clone(), crate::limits::Limits::unlimited(), &corrupt)
+    };
+    assert!(matches!(corrupt(14), Err(crate::snapshot::SnapshotError::InvalidInstruction(1000))));
+    assert!(matches!(corrupt(22), Err(crate::snapshot::SnapshotError::InvalidFrame(1000))));
+
+    let Status::Halted = restored.resume(Value::None)? else {
+        panic!("expected halt");
+    };
+
+    assert!(matches!(
+        restored.stack()[..],
+        [Value::Table { .. }, Value::U32(2), Value::I64(20), Value::Struct { field_count: 2 }, Value::Cursor { .. }]
+    ));
+
+    let other = people_module(vec![Op::Halt]);
+    let limits = crate::limits::Limits::unlimited();
+    assert!(matches!(
+        Vm::restore(other, limits, &bytes),
+        Err(crate::snapshot::SnapshotError::ModuleMismatch)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn unchecked_query () -> Result<(), VmError> {
+    // The free fields hold values of the wrong kind which are ignored.
+    let mut code = populate();
+    code.extend([
+        Op::Close,
+        Op::U32(3),
+        Op::Bool(true),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b10),
+        Op::Query,
+        Op::Read,
+        Op::Pop,
+        Op::Pop,
+        Op::Halt,
+    ]);
+
+    let mut vm = Vm::new(people_module(code));
+    vm.run()?;
+
+    assert!(!vm.is_checked());
+    assert!(matches!(vm.top(Kind::I64), Ok(Value::I64(30))));
+    assert!(vm.stack_len() == 3);
+
+    Ok(())
+}
+
+#[test]
+fn invalid_handle () -> Result<(), VmError> {
+    // Even without tags the stack knows which slots hold handles, so a
+    // Usize can't be passed off as one.
+    let code = vec![
+        Op::Usize(5),
+        Op::None,
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b11),
+        Op::Query,
+        Op::Halt,
+    ];
+
+    let module = Arc::new(people_module(code));
+    let mut vm = Vm::new(module.clone());
+    assert!(matches!(vm.run(), Err(VmError::TypeCheck)));
+
+    let mut vm = Vm::checked(module);
+    assert!(matches!(vm.run(), Err(VmError::TypeCheck)));
+
+    Ok(())
+}
+
+#[test]
+fn indexed_query () -> Result<(), VmError> {
+    let mut code = populate();
+    code.extend([
+        Op::Close,
+        Op::None,
+        Op::I64(20),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b01),
+        Op::Query,
+        Op::Found,
+        Op::Halt,
+    ]);
+
+    let mut vm = Vm::checked(table_module(code, people().with_index(&[1])));
+    vm.run()?;
+
+    assert!(top_bool(&vm) == Some(true));
+
+    let Some(Value::Cursor { handle }) = vm.stack().iter().nth_back(1).copied() else {
+        panic!("expected a cursor");
+    };
+
+    let cursor = vm.handles.cursor_mut(handle)?;
+    let CursorTypes::Mem(mem_cursor) = &*cursor else {
+        panic!("expected a mem cursor");
+    };
+    assert!(mem_cursor.index.is_some());
+
+    let mut record = Vec::new();
+    cursor.read(&mut record)?;
+    assert!(matches!(record[..], [Value::U32(2), Value::I64(20), Value::Struct { field_count: 2 }]));
+    assert!(!cursor.advance()?);
+
+    Ok(())
+}
+
+#[test]
+fn index_choice () -> Result<(), VmError> {
+    let people = people().with_index(&[1]).with_index(&[0, 1]);
+    let table = MemTable::new(0, &people, None)?;
+
+    // Only indexes whose fields are all bound are used, the one covering
+    // the most fields first.
+    let cursor = table.find(&mut vec![Value::U32(1), Value::None])?;
+    assert!(cursor.index.is_none());
+
+    let cursor = cursor.close().find(&mut vec![Value::None, Value::I64(1)])?;
+    assert!(matches!(cursor.index, Some((0, _))));
+
+    let cursor = cursor.close().find(&mut vec![Value::U32(1), Value::I64(1)])?;
+    assert!(matches!(cursor.index, Some((1, _))));
+
+    let invalid = TableType::new("t", vec![Type::U32]).with_index(&[1]);
+    assert!(matches!(MemTable::new(0, &invalid, None), Err(VmError::InvalidIndex(0))));
+
+    Ok(())
+}
+
+#[test]
+fn index_maintenance () -> Result<(), VmError> {
+    let mut code = populate();
+    code.extend([
+        Op::Close,
+        Op::None,
+        Op::None,
+        Op::Usize(2),
+        Op::Struct,
+        Op::Usize(0b11),
+        Op::Query,
+        // Remove (1, 10) leaving the cursor at (2, 20)
+        Op::Delete,
+        // Insert (4, 20) after it, moving (3, 30) along
+        Op::U32(4),
+        Op::I64(20),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Insert,
+        // Replace (4, 20) with (4, 30)
+        Op::U32(4),
+        Op::I64(30),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Update,
+        Op::Halt,
+    ]);
+
+    let mut vm = Vm::checked(table_module(code, people().with_index(&[1])));
+    vm.run()?;
+
+    let Ok(Value::Cursor { handle }) = vm.top(Kind::Cursor) else {
+        panic!("expected a cursor");
+    };
+
+    let TableTypes::Mem(table) = vm.handles.take_cursor(handle)?.close() else {
+        panic!("expected a mem table");
+    };
+
+    let ids = |table: MemTable, age: i64| -> Result<(Vec<u32>, MemTable), VmError> {
+        let mut cursor = table.find(&mut vec![Value::None, Value::I64(age)])?;
+        let mut ids = Vec::new();
+        while cursor.found() {
+            let mut record = Vec::new();
+            cursor.read(&mut record)?;
+            if let Value::U32(id) = record[0] {
+                ids.push(id);
+            }
+            cursor.advance()?;
+        }
+        Ok((ids, cursor.close()))
+    };
+
+    let (found, table) = ids(table, 10)?;
+    assert!(found.is_empty());
+    let (found, table) = ids(table, 20)?;
+    assert!(found == [2]);
+    let (found, _) = ids(table, 30)?;
+    assert!(found == [4, 3]);
+
+    Ok(())
+}
+
+#[test]
+fn range_select () -> Result<(), VmError> {
+    for indexed in [false, true] {
+        let mut code = populate();
+        code.extend([
+            Op::Close,
+            Op::I64(15),
+            Op::I64(30),
+            Op::Select(vec![Filter::new(1, Test::Ge), Filter::new(1, Test::Lt)]),
+            Op::Halt,
+        ]);
+
+        let table_type = if indexed { people().with_index(&[1]) } else { people() };
+        let mut vm = Vm::checked(table_module(code, table_type));
+        vm.run()?;
+
+        assert!(cursor_ids(&mut vm)? == (vec![2], indexed));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn set_select () -> Result<(), VmError> {
+    for indexed in [false, true] {
+        let mut code = populate();
+        code.extend([
+            Op::Close,
+            Op::U32(3),
+            Op::U32(1),
+            Op::U32(4),
+            Op::I64(25),
+            Op::Select(vec![Filter::new(0, Test::In(3)), Filter::new(1, Test::Le)]),
+            Op::Halt,
+        ]);
+
+        // An index over both fields covers a set and a range on its last.
+        let table_type = if indexed { people().with_index(&[0, 1]) } else { people() };
+        let mut vm = Vm::checked(table_module(code, table_type));
+        vm.run()?;
+
+        assert!(cursor_ids(&mut vm)? == (vec![1], indexed));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn combined_filters () -> Result<(), VmError> {
+    // Filters on the same field must all pass.
+    let cases = [
+        ([20, 25], vec![Filter::new(1, Test::Eq), Filter::new(1, Test::Gt)], vec![]),
+        ([10, 30], vec![Filter::new(1, Test::Ge), Filter::new(1, Test::Le)], vec![1, 2, 3]),
+        ([20, 20], vec![Filter::new(1, Test::Ge), Filter::new(1, Test::Lt)], vec![]),
+        ([0, 20], vec![Filter::new(1, Test::Gt), Filter::new(1, Test::Gt)], vec![3]),
+    ];
+
+    for (operands, filters, expected) in cases {
+        for indexed in [false, true] {
+            let mut code = populate();
+            code.push(Op::Close);
+            code.extend(operands.map(Op::I64));
+            code.extend([Op::Select(filters.clone()), Op::Halt]);
+
+            let table_type = if indexed { people().with_index(&[1]) } else { people() };
+            let mut vm = Vm::checked(table_module(code, table_type));
+            vm.run()?;
+
+            assert!(cursor_ids(&mut vm)?.0 == expected);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn string_select () -> Result<(), VmError> {
+    let names = TableType::new("names", vec![Type::U32, Type::StringRef])
+        .with_names(&["id", "name"])
+        .with_index(&[1]);
+
+    let records = [(1, "bob"), (2, "alice"), (3, "carol"), (4, "alan")];
+    let cases = [
+        (Test::Prefix, "al", vec![2, 4]),
+        (Test::Eq, "carol", vec![3]),
+        (Test::Gt, "alice", vec![1, 3]),
+        (Test::Lt, "b", vec![2, 4]),
+        (Test::Prefix, "d", vec![]),
+    ];
+
+    for (test, operand, expected) in cases {
+        let mut code = populate_with(
+            records.map(|(id, name)| (Op::U32(id), Op::Str(name.to_string())))
+        );
+        code.extend([
+            Op::Close,
+            Op::Str(operand.to_string()),
+            Op::Select(vec![Filter::new(1, test)]),
+            Op::Halt,
+        ]);
+
+        let mut vm = Vm::checked(table_module(code, names.clone()));
+        vm.run()?;
+        assert!(cursor_ids(&mut vm)? == (expected, true));
+    }
+
+    // Tests of one field combine, by their text where they need it.
+    let combined = [
+        (vec![(Test::Ge, "alb"), (Test::Prefix, "al")], vec![2]),
+        (vec![(Test::Prefix, "a"), (Test::Prefix, "ala")], vec![4]),
+        (vec![(Test::Prefix, "a"), (Test::Prefix, "b")], vec![]),
+        (vec![(Test::Eq, "carol"), (Test::Gt, "b")], vec![3]),
+        (vec![(Test::Eq, "carol"), (Test::Prefix, "b")], vec![]),
+    ];
+    for (tests, expected) in combined {
+        let mut code = populate_with(
+            records.map(|(id, name)| (Op::U32(id), Op::Str(name.to_string())))
+        );
+        code.push(Op::Close);
+        code.extend(tests.iter().map(|(_, operand)| Op::Str(operand.to_string())));
+        code.extend([
+            Op::Select(tests.iter().map(|(test, _)| Filter::new(1, *test)).collect()),
+            Op::Halt,
+        ]);
+
+        let mut vm = Vm::checked(table_module(code, names.clone()));
+        vm.run()?;
+        assert!(cursor_ids(&mut vm)?.0 == expected);
+    }
+
+    // Records holding strings can be read back.
+    let mut code = populate_with([(Op::U32(1), Op::Str("bob".to_string()))]);
+    code.extend([Op::Read, Op::Halt]);
+    let mut vm = Vm::checked(table_module(code, names));
+    vm.run()?;
+    let name = vm.stack()[vm.stack_len() - 3];
+    assert!(vm.string(&name) == Some("bob"));
+    assert!(vm.intern("bob") == name);
+
+    // Only strings have prefixes.
+    let mut code = populate();
+    code.extend([Op::Close, Op::I64(1), Op::Select(vec![Filter::new(1, Test::Prefix)]), Op::Halt]);
+    let mut vm = Vm::checked(people_module(code));
+    assert!(matches!(vm.run(), Err(VmError::TypeCheck)));
+
+    Ok(())
+}
+
+#[test]
+fn text_constraints () {
+    let prefix = |text: &str| Constraint::Prefix(text.to_string());
+    let text = |text: &str| text.to_string();
+
+    assert!(prefix("al").accepts_text("alan"));
+    assert!(!prefix("al").accepts_text("a"));
+    assert!(!prefix("al").accepts(&Value::StringRef { index: 0 }));
+
+    // A prefix combined with a range becomes the range of the strings
+    // starting with it, which ends at the next string after the prefix.
+    let range = Constraint::TextRange(Bound::Excluded(text("alan")), Bound::Unbounded);
+    let both = prefix("al").and(range);
+    assert!(both == Constraint::TextRange(Bound::Excluded(text("alan")), Bound::Excluded(text("am"))));
+    assert!(both.accepts_text("alice") && !both.accepts_text("alan") && !both.accepts_text("am"));
+
+    let last = format!("a{}", char::MAX);
+    let range = Constraint::TextRange(Bound::Unbounded, Bound::Unbounded);
+    assert!(prefix(&last).and(range) == Constraint::TextRange(Bound::Included(last), Bound::Excluded(text("b"))));
+    let range = Constraint::TextRange(Bound::Unbounded, Bound::Unbounded);
+    let after_surrogates = Constraint::TextRange(Bound::Included(text("\u{d7ff}")), Bound::Excluded(text("\u{e000}")));
+    assert!(prefix("\u{d7ff}").and(range) == after_surrogates);
+
+    // No value is both a number and a string.
+    let numbers = Constraint::Range(Bound::Included(Value::I64(1)), Bound::Unbounded);
+    assert!(numbers.and(prefix("a")) == Constraint::In(Vec::new()));
+}
+
+/// A host table of names which keeps the queries it is given.
+#[derive(Clone, Default)]
+struct Names {
+    queries: std::rc::Rc<std::cell::RefCell<Vec<Vec<Constraint<Value>>>>>,
+}
+
+impl Table<Value, VmError> for Names {
+    type Cursor = Names;
+
+    fn find(self, _: &mut Vec<Value>) -> Result<Names, VmError> {
+        Err(VmError::InvalidOperation)
+    }
+
+    fn select(self, query: Vec<Constraint<Value>>) -> Result<Names, VmError> {
+        self.queries.borrow_mut().push(query);
+        Ok(self)
+    }
+}
+
+impl Cursor<Value, VmError> for Names {
+    type Table = Names;
+
+    fn found(&self) -> bool {
+        false
+    }
+
+    fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        stack.push(Value::None);
+        Ok(())
+    }
+
+    fn insert(&mut self, _: &mut Vec<Value>) -> Result<(), VmError> {
+        Err(VmError::InvalidOperation)
+    }
+
+    fn update(&mut self, _: &mut Vec<Value>) -> Result<(), VmError> {
+        Err(VmError::InvalidOperation)
+    }
+
+    fn delete(&mut self) -> Result<(), VmError> {
+        Err(VmError::InvalidOperation)
+    }
+
+    fn advance(&mut self) -> Result<bool, VmError> {
+        Ok(false)
+    }
+
+    fn close(self) -> Names {
+        self
+    }
+}
+
+#[test]
+fn host_text_select () -> Result<(), VmError> {
+    // Host tables are given the text to test strings against, however
+    // many strings the Vm has.
+    let names = TableType::new("names", vec![Type::U32, Type::StringRef]);
+    let mut code: Vec<Op> = (0..100).map(|i| Op::Str(format!("al{}", i))).collect();
+    code.extend([
+        Op::TakeTable(0),
+        Op::Str("al".to_string()),
+        Op::Select(vec![Filter::new(1, Test::Prefix)]),
+        Op::Halt,
+    ]);
+
+    let host = Names::default();
+    let mut vm = Vm::checked(table_module(code, names));
+    vm.register_table(0, host.clone(), Access::ReadOnly)?;
+    vm.run()?;
+
+    let queries = host.queries.borrow();
+    assert!(queries[..] == [vec![Constraint::Any, Constraint::Prefix("al".to_string())]]);
+    Ok(())
+}
+
+#[test]
+fn select_snapshot () -> Result<(), VmError> {
+    let mut code = populate_with([(Op::U32(1), Op::I64(10)), (Op::U32(2), Op::I64(20))]);
+    code.extend([
+        Op::Close,
+        Op::I64(5),
+        Op::Select(vec![Filter::new(1, Test::Gt)]),
+        Op::U32(0),
+        Op::Yield(Kind::U32),
+        Op::Pop,
+        Op::Advance,
+        Op::Found,
+        Op::Halt,
+    ]);
+
+    let module = Arc::new(table_module(code, people().with_index(&[1])));
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+
+    let bytes = vm.snapshot();
+    let limits = crate::limits::Limits::unlimited();
+    let mut restored = Vm::restore(module.clone(), limits, &bytes).unwrap();
+    assert!(restored.snapshot() == bytes);
+
+    // The instruction and frame pointers follow the header.
+    let corrupt = |at: usize| {
+        let mut corrupt = bytes.clone();
+        corrupt[at..at + 8].copy_from_slice(&1000u64.to_le_bytes());
+        Vm::restore(module.clone(), crate::limits::Limits::unlimited(), &corrupt)
+    };
+    assert!(matches!(corrupt(14), Err(crate::snapshot::SnapshotError::InvalidInstruction(1000))));
+    assert!(matches!(corrupt(22), Err(crate::snapshot::SnapshotError::InvalidFrame(1000))));
+
+    restored.resume(Value::None)?;
+    assert!(top_bool(&restored) == Some(true));
+
+    Ok(())
+}
+
+#[test]
+fn derived_table () -> Result<(), VmError> {
+    let edge = TableType::new("edge", vec![Type::U32, Type::U32]).with_names(&["a", "b"]);
+    let atom = |type_index, terms: [usize; 2]| Atom {
+        type_index,
+        terms: terms.map(Term::Var).to_vec(),
+    };
+    let path = TableType::new("path", vec![Type::U32, Type::U32])
+        .with_names(&["a", "b"])
+        .with_rule(TableRule {
+            head: vec![Term::Var(0), Term::Var(1)],
+            body: vec![atom(0, [0, 1])],
+        })
+        .with_rule(TableRule {
+            head: vec![Term::Var(0), Term::Var(2)],
+            body: vec![atom(0, [0, 1]), atom(1, [1, 2])],
+        });
+
+    // Only two paths start at 1, though three are derived along the way.
+    let from_one = TableType::new("from_one", vec![Type::U32])
+        .with_names(&["b"])
+        .with_rule(TableRule {
+            head: vec![Term::Var(0)],
+            body: vec![Atom { type_index: 1, terms: vec![Term::Value(Value::U32(1)), Term::Var(0)] }],
+        });
+
+    let edges_then_take = |taken: u32| {
+        let mut code = vec![Op::TakeTable(0), Op::Select(vec![Filter::new(0, Test::In(0))])];
+        for (a, b) in [(1, 2), (2, 3)] {
+            code.extend([Op::U32(a), Op::U32(b), Op::Usize(2), Op::Struct, Op::Insert]);
+        }
+        code.extend([
+            Op::Close,
+            Op::PutTable(0),
+            Op::TakeTable(taken),
+            Op::Select(Vec::new()),
+            Op::U32(0),
+            Op::Yield(Kind::U32),
+            Op::Pop,
+            Op::Halt,
+        ]);
+
+        let mut module = table_module(code, edge.clone());
+        module.types.insert(1, path.clone());
+        module.types.insert(2, from_one.clone());
+        Arc::new(module)
+    };
+    let module = edges_then_take(1);
+
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    let bytes = vm.snapshot();
+    assert!(cursor_ids(&mut vm)?.0 == [1, 2, 1]);
+
+    // The held flag survives a snapshot so the restored Vm finds the
+    // edges to derive from.
+    let limits = crate::limits::Limits::unlimited();
+    let restored = Vm::restore(module.clone(), limits, &bytes).unwrap();
+    assert!(restored.snapshot() == bytes);
+    let edges = restored.held_rows(0);
+    assert!(edges.len() == 2);
+
+    // Derived records count towards the row limit.
+    let limits = crate::limits::Limits::unlimited().with_max_table_rows(2);
+    let mut vm = Vm::with_limits(module.clone(), limits);
+    assert!(matches!(vm.run(), Err(VmError::TableFull)));
+
+    // Deriving burns fuel for each record it matches. A derivation which
+    // runs out gives the fuel back, so topping up a little at a time
+    // finishes it once there is enough.
+    let ops = module.code.len() as u64;
+    let limits = crate::limits::Limits::unlimited().with_fuel(ops);
+    let mut vm = Vm::with_limits(module, limits);
+    let mut top_ups = 0;
+    loop {
+        match vm.run() {
+            Err(VmError::OutOfFuel) => {
+                vm.add_fuel(2);
+                top_ups += 1;
+            },
+            result => {
+                result?;
+                break;
+            },
+        }
+    }
+    assert!(top_ups > 1);
+    assert!(cursor_ids(&mut vm)?.0 == [1, 2, 1]);
+
+    // The tables derived along the way are held to the row limit too.
+    let limits = crate::limits::Limits::unlimited().with_max_table_rows(2);
+    let mut vm = Vm::with_limits(edges_then_take(2), limits.clone());
+    assert!(matches!(vm.run(), Err(VmError::TableFull)));
+    let mut vm = Vm::with_limits(edges_then_take(2), limits.with_max_table_rows(3));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0 == [2, 3]);
+
+    Ok(())
+}
+
+/// Ops appending a record with the id to the Vm's table of people.
+fn insert_person(id: u32) -> Vec<Op> {
+    vec![
+        Op::TakeTable(0),
+        Op::Select(vec![Filter::new(0, Test::In(0))]),
+        Op::U32(id),
+        Op::I64(0),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Insert,
+        Op::Close,
+        Op::PutTable(0),
+    ]
+}
+
+/// Ops pushing a cursor over every record of the Vm's table of people.
+fn select_people() -> Vec<Op> {
+    vec![Op::TakeTable(0), Op::Select(Vec::new())]
+}
+
+#[test]
+fn transaction_ops () -> Result<(), VmError> {
+    let mut code = vec![Op::Begin];
+    code.extend(insert_person(1));
+    code.extend([Op::Begin]);
+    code.extend(insert_person(2));
+    code.extend([Op::Rollback, Op::Begin]);
+    code.extend(insert_person(3));
+    code.extend([Op::Commit, Op::Commit]);
+    code.extend(select_people());
+    code.push(Op::Halt);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0 == [1, 3]);
+
+    // A table created inside a rolled back transaction is dropped.
+    let mut code = vec![Op::Begin];
+    code.extend(insert_person(1));
+    code.extend([Op::Rollback]);
+    code.extend(select_people());
+    code.push(Op::Halt);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0.is_empty());
+
+    let mut vm = Vm::checked(people_module(vec![Op::Commit]));
+    assert!(matches!(vm.run(), Err(VmError::NoTransaction)));
+
+    // Tables changed inside the transaction must be put back to roll
+    // back, while one it didn't change can stay taken.
+    let mut code = insert_person(1);
+    code.push(Op::Begin);
+    code.extend(insert_person(2));
+    code.extend(select_people());
+    code.push(Op::Rollback);
+
+    let mut vm = Vm::checked(people_module(code));
+    assert!(matches!(vm.run(), Err(VmError::TableInUse(0))));
+
+    let mut code = insert_person(1);
+    code.push(Op::Begin);
+    code.extend(select_people());
+    code.extend([Op::Rollback, Op::Halt]);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0 == [1]);
+
+    Ok(())
+}
+
+#[test]
+fn transaction_abort () -> Result<(), VmError> {
+    // A snapshot taken inside a transaction restores it, and the error
+    // after the yield rolls back to before it began, replacing the table
+    // the cursor has.
+    let mut code = insert_person(1);
+    code.push(Op::Begin);
+    code.extend(insert_person(2));
+    code.extend([Op::U32(0), Op::Yield(Kind::U32), Op::Pop]);
+    code.extend(select_people());
+    code.extend([Op::PutTable(0), Op::Commit]);
+
+    let module = Arc::new(people_module(code));
+    let mut vm = Vm::checked(module.clone());
+    vm.run()?;
+    assert!(vm.held_rows(0).len() == 2);
+
+    let bytes = vm.snapshot();
+    let limits = crate::limits::Limits::unlimited();
+    let mut restored = Vm::restore(module.clone(), limits, &bytes).unwrap();
+    assert!(restored.snapshot() == bytes);
+
+    // The instruction and frame pointers follow the header.
+    let corrupt = |at: usize| {
+        let mut corrupt = bytes.clone();
+        corrupt[at..at + 8].copy_from_slice(&1000u64.to_le_bytes());
+        Vm::restore(module.clone(), crate::limits::Limits::unlimited(), &corrupt)
+    };
+    assert!(matches!(corrupt(14), Err(crate::snapshot::SnapshotError::InvalidInstruction(1000))));
+    assert!(matches!(corrupt(22), Err(crate::snapshot::SnapshotError::InvalidFrame(1000))));
+
+    assert!(matches!(restored.resume(Value::None), Err(VmError::TypeCheck)));
+    assert!(restored.held_rows(0).len() == 1);
+
+    Ok(())
+}
+
+#[test]
+fn released_handles () -> Result<(), VmError> {
+    // Dropping the slot of a taken table puts the table back, and
+    // dropping a cursor closes it, whether the Vm keeps tags or not.
+    let mut code = vec![Op::TakeTable(0), Op::Pop, Op::TakeTable(0), Op::PutTable(0)];
+    code.extend(insert_person(1));
+    code.extend(select_people());
+    code.extend([Op::U32(7), Op::Usize(2), Op::PopN]);
+    code.extend(select_people());
+    code.push(Op::Halt);
+
+    let module = Arc::new(people_module(code));
+    for mut vm in [Vm::checked(module.clone()), Vm::new(module.clone())] {
+        vm.run()?;
+
+        // Snapshots keep which slots hold handles.
+        let bytes = vm.snapshot();
+        let limits = crate::limits::Limits::unlimited();
+        let mut restored = Vm::restore(module.clone(), limits, &bytes).unwrap();
+        assert!(restored.snapshot() == bytes);
+        assert!(cursor_ids(&mut restored)?.0 == [1]);
+    }
+
+    // A handle can't be copied, so there's only ever one slot to drop.
+    for copy in [Op::Copy, Op::LoadImm(0)] {
+        let module = Arc::new(people_module(vec![Op::TakeTable(0), copy, Op::Halt]));
+        for mut vm in [Vm::checked(module.clone()), Vm::new(module)] {
+            assert!(matches!(vm.run(), Err(VmError::InvalidOperation)));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn host_table_checks () -> Result<(), VmError> {
+    // The host's table holds records of other fields than its type's, so
+    // reading them fails.
+    let other = TableType::new("other", vec![Type::I64, Type::I64]);
+    let mut table = MemTable::new(0, &other, None)?;
+    table.push_row(vec![Value::I64(1), Value::I64(2)]);
+
+    let mut code = select_people();
+    code.extend([Op::U32(0), Op::Yield(Kind::U32), Op::Pop, Op::Read, Op::Halt]);
+    let module = Arc::new(people_module(code));
+
+    let mut vm = Vm::checked(module.clone());
+    vm.register_table(0, table, Access::ReadWrite)?;
+    assert!(matches!(vm.register_table(1, MemTable::new(0, &other, None)?, Access::ReadOnly),
+        Err(VmError::UnknownType(1))));
+    vm.run()?;
+
+    // Host tables can't be restored from a snapshot.
+    let bytes = vm.snapshot();
+    let limits = crate::limits::Limits::unlimited();
+    let restored = Vm::restore(module, limits, &bytes);
+    assert!(matches!(restored, Err(crate::snapshot::SnapshotError::HostTable(0))));
+
+    assert!(matches!(vm.resume(Value::None), Err(VmError::TypeCheck)));
+
+    Ok(())
+}
+
+#[test]
+fn host_table_refusals () -> Result<(), VmError> {
+    // Rolling back couldn't undo changes to a host table, so they're
+    // refused inside a transaction, while reads are fine.
+    let mut code = insert_person(1);
+    code.push(Op::Begin);
+    code.extend(select_people());
+    code.extend([Op::Close, Op::PutTable(0)]);
+    code.extend(insert_person(2));
+    code.push(Op::Commit);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.register_table(0, MemTable::new(0, &people(), None)?, Access::ReadWrite)?;
+    assert!(matches!(vm.run(), Err(VmError::HostTableInTransaction(0))));
+
+    // Rules only match the Vm's own tables.
+    let ids = TableType::new("ids", vec![Type::U32]).with_rule(TableRule {
+        head: vec![Term::Var(0)],
+        body: vec![Atom { type_index: 0, terms: vec![Term::Var(0), Term::Var(1)] }],
+    });
+    let mut module = people_module(vec![Op::Halt]);
+    module.types.insert(1, ids);
+
+    let mut vm = Vm::checked(module);
+    let registered = vm.register_table(0, MemTable::new(0, &people(), None)?, Access::ReadOnly);
+    assert!(matches!(registered, Err(VmError::HostTableInRule(0))));
+
+    Ok(())
+}
+
+#[test]
+fn aggregate_ops () -> Result<(), VmError> {
+    let aggregates = vec![
+        Aggregate::Count,
+        Aggregate::Sum(1),
+        Aggregate::Min(1),
+        Aggregate::Max(0),
+        Aggregate::Avg(1),
+    ];
+    let mut code = populate();
+    code.extend([Op::Close, Op::Select(Vec::new()), Op::Aggregate(aggregates), Op::Halt]);
+
+    let mut vm = Vm::checked(people_module(code));
+    vm.run()?;
+    let stack = vm.stack();
+    assert!(stack[1..7] == [
+        Value::I64(3),
+        Value::I64(60),
+        Value::I64(10),
+        Value::U32(3),
+        Value::F64(20.0),
+        Value::Struct { field_count: 5 },
+    ]);
+    assert!(matches!(stack[7], Value::Table { .. }));
+
+    // Over no records counts and sums are zero, the rest have no value.
+    let no_records = |aggregate: Aggregate| {
+        let mut code = populate();
+        code.extend([
+            Op::Close,
+            Op::U32(9),
+            Op::Select(vec![Filter::new(0, Test::Eq)]),
+            Op::Aggregate(vec![aggregate]),
+            Op::Halt,
+        ]);
+        let mut vm = Vm::checked(people_module(code));
+        vm.run().map(|_| vm.stack()[1])
+    };
+    assert!(no_records(Aggregate::Count)? == Value::I64(0));
+    assert!(no_records(Aggregate::Sum(0))? == Value::U32(0));
+    assert!(matches!(no_records(Aggregate::Max(1)), Err(VmError::NoRecords)));
+    assert!(matches!(no_records(Aggregate::Avg(1)), Err(VmError::NoRecords)));
+    assert!(matches!(no_records(Aggregate::Sum(2)), Err(VmError::TypeCheck)));
+    assert!(matches!(no_records(Aggregate::Key(0)), Err(VmError::InvalidOperation)));
+
+    Ok(())
+}
+
+#[test]
+fn group_ops () -> Result<(), VmError> {
+    let module = |code: Vec<Op>| {
+        let mut module = people_module(code);
+        let ages = TableType::new("ages", vec![Type::I64, Type::I64, Type::U32]);
+        module.types.insert(1, ages);
+        module
+    };
+    let group = |columns: Vec<Aggregate>| Op::Group { keys: vec![1], columns, type_index: 1 };
+
+    let mut code = populate_with([(1, 10), (2, 20), (3, 10)].map(|(id, age)| (Op::U32(id), Op::I64(age))));
+    code.extend([
+        Op::Close,
+        Op::Select(Vec::new()),
+        group(vec![Aggregate::Key(1), Aggregate::Count, Aggregate::Max(0)]),
+        Op::SetTable(1),
+        Op::Halt,
+    ]);
+
+    let mut vm = Vm::checked(module(code));
+    vm.run()?;
+    assert!(vm.held_rows(1) == [
+        vec![Value::I64(10), Value::I64(2), Value::U32(3)],
+        vec![Value::I64(20), Value::I64(1), Value::U32(2)],
+    ]);
+
+    // The columns must have the types of the table's fields, and only
+    // give the value of a field grouped by.
+    let run = |op: Op| {
+        let mut code = populate();
+        code.extend([Op::Close, Op::Select(Vec::new()), op]);
+        Vm::checked(module(code)).run()
+    };
+    assert!(matches!(run(group(vec![Aggregate::Count])), Err(VmError::TypeCheck)));
+    assert!(matches!(
+        run(group(vec![Aggregate::Key(1), Aggregate::Count, Aggregate::Key(0)])),
+        Err(VmError::InvalidOperation)
+    ));
+
+    // Only a table the Vm doesn't hold, of the type, can replace the Vm's.
+    let mut code = select_people();
+    code.extend([Op::Usize(0), Op::Table, Op::SetTable(0)]);
+    let mut vm = Vm::checked(module(code));
+    assert!(matches!(vm.run(), Err(VmError::TableInUse(0))));
+
+    let mut vm = Vm::checked(module(vec![Op::Usize(0), Op::Table, Op::SetTable(1)]));
+    assert!(matches!(vm.run(), Err(VmError::TypeCheck)));
+
+    Ok(())
+}
+
+#[test]
+fn ordered_table () -> Result<(), VmError> {
+    let by_age = people().with_order(&[Sort::desc(1), Sort::asc(0)]);
+    let records = [(1, 20), (2, 30), (3, 20), (4, 10)];
+
+    let mut code = populate_with(records.map(|(id, age)| (Op::U32(id), Op::I64(age))));
+    code.extend([Op::Close, Op::Select(Vec::new()), Op::Halt]);
+    let mut vm = Vm::checked(table_module(code, by_age.clone()));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0 == [2, 1, 3, 4]);
+
+    // Updating the age of 1 moves it after 3, and the cursor with it.
+    let mut code = populate_with(records.map(|(id, age)| (Op::U32(id), Op::I64(age))));
+    code.extend([
+        Op::Close,
+        Op::U32(1),
+        Op::Select(vec![Filter::new(0, Test::Eq)]),
+        Op::U32(1),
+        Op::I64(15),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Update,
+        Op::Read,
+        Op::Close,
+        Op::Select(Vec::new()),
+        Op::Halt,
+    ]);
+    let mut vm = Vm::checked(table_module(code, by_age.clone()));
+    vm.run()?;
+    assert!(vm.stack()[1..4] == [Value::U32(1), Value::I64(15), Value::Struct { field_count: 2 }]);
+    assert!(cursor_ids(&mut vm)?.0 == [2, 3, 1, 4]);
+
+    // Moving 2 to the end takes the cursor past the records in between.
+    let mut code = populate_with(records.map(|(id, age)| (Op::U32(id), Op::I64(age))));
+    code.extend([
+        Op::Close,
+        Op::Select(Vec::new()),
+        Op::U32(2),
+        Op::I64(5),
+        Op::Usize(2),
+        Op::Struct,
+        Op::Update,
+        Op::Halt,
+    ]);
+    let mut vm = Vm::checked(table_module(code, by_age));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0 == [2]);
+
+    let bad = people().with_order(&[Sort::asc(2)]);
+    let mut vm = Vm::checked(table_module(vec![Op::Usize(0), Op::Table], bad));
+    assert!(matches!(vm.run(), Err(VmError::InvalidIndex(0))));
+
+    // Tables compare strings by their intern position, so can't be
+    // ordered by them.
+    let by_name = TableType::new("names", vec![Type::U32, Type::StringRef])
+        .with_order(&[Sort::asc(1)]);
+    let mut vm = Vm::checked(table_module(vec![Op::Usize(0), Op::Table], by_name));
+    assert!(matches!(vm.run(), Err(VmError::InvalidIndex(0))));
+
+    Ok(())
+}
+
+#[test]
+fn order_op () -> Result<(), VmError> {
+    let records = [(1, 20), (2, 30), (3, 20), (4, 10), (5, 40)];
+    let ordered = |order: Vec<Sort>, offset: usize, limit: Option<usize>| {
+        let mut code = populate_with(records.map(|(id, age)| (Op::U32(id), Op::I64(age))));
+        code.extend([Op::Close, Op::Select(Vec::new()), Op::Order { order, offset, limit }, Op::Halt]);
+        let mut vm = Vm::checked(people_module(code));
+        vm.run()?;
+        assert!(matches!(vm.stack()[1], Value::Table { .. }));
+        cursor_ids(&mut vm).map(|(ids, _)| ids)
+    };
+
+    assert!(ordered(vec![Sort::asc(1)], 0, None)? == [4, 1, 3, 2, 5]);
+    assert!(ordered(vec![Sort::desc(1)], 1, Some(3))? == [2, 1, 3]);
+    assert!(ordered(vec![Sort::asc(1), Sort::desc(0)], 0, Some(3))? == [4, 3, 1]);
+    assert!(ordered(Vec::new(), 3, Some(10))? == [4, 5]);
+    assert!(ordered(vec![Sort::asc(1)], 9, None)?.is_empty());
+    assert!(matches!(ordered(vec![Sort::asc(2)], 0, None), Err(VmError::InvalidOperation)));
+
+    // Strings are sorted by their text rather than when they were
+    // interned.
+    let names = TableType::new("names", vec![Type::U32, Type::StringRef]);
+    let records = [(1, "bob"), (2, "alice"), (3, "carol"), (4, "alan")];
+    let mut code = populate_with(records.map(|(id, name)| (Op::U32(id), Op::Str(name.to_string()))));
+    code.extend([
+        Op::Close,
+        Op::Select(Vec::new()),
+        Op::Order { order: vec![Sort::desc(1)], offset: 0, limit: None },
+        Op::Halt,
+    ]);
+    let mut vm = Vm::checked(table_module(code, names));
+    vm.run()?;
+    assert!(cursor_ids(&mut vm)?.0 == [3, 1, 2, 4]);
+
+    // The copy can be dropped once its cursor is closed, the Vm's tables
+    // can't.
+    let mut code = select_people();
+    code.extend([
+        Op::Order { order: vec![Sort::asc(0)], offset: 0, limit: None },
+        Op::Close,
+        Op::DropTable,
+        Op::PutTable(0),
+        Op::TakeTable(0),
+        Op::DropTable,
+    ]);
+    let mut vm = Vm::checked(people_module(code));
+    assert!(matches!(vm.run(), Err(VmError::InvalidOperation)));
+    assert!(vm.stack_len() == 1);
+
+    Ok(())
+}
@@ -0,0 +1,166 @@
+//! Transactions over the Vm's tables, the tables `Op::TakeTable` takes.
+//! `Op::Begin` starts a transaction and the first change to a table
+//! inside it copies the table as it was, `Op::Commit` drops the copies and
+//! `Op::Rollback` puts them back in place of the tables. Tables a
+//! transaction doesn't change cost nothing. Transactions nest, rolling
+//! back undoes the changes since the innermost began.
+//!
+//! The changes to a table stored in a file are held back until the
+//! outermost transaction commits, so the file never holds changes which
+//! might yet be rolled back.
+//!
+//! An error ending execution inside a transaction rolls back every open
+//! transaction, so the tables are as they were before the outermost
+//! began. Running out of fuel doesn't, as the Vm can continue once it has
+//! more.
+
+use super::*;
+use self::table::MemTable;
+
+/// The Vm's tables changed inside a transaction as they were when it
+/// began.
+#[derive(Debug, Default)]
+pub(super) struct Transaction {
+    /// A copy of each table by type index.
+    pub(super) tables: BTreeMap<u32, MemTable>,
+}
+
+impl Vm {
+    pub(super) fn begin(&mut self) -> Result<(), VmError> {
+        self.transactions.push(Transaction::default());
+        Ok(())
+    }
+
+    /// Copies the Vm's table of the type in to each open transaction
+    /// without a copy, before the table is changed. A table is copied in
+    /// to every transaction once it is, so the transactions holding a
+    /// copy are always the outermost ones.
+    pub(super) fn track(&mut self, type_index: u32) -> Result<(), VmError> {
+        let copied = self.transactions.iter()
+            .take_while(|transaction| transaction.tables.contains_key(&type_index))
+            .count();
+        if copied == self.transactions.len() {
+            return Ok(());
+        }
+
+        let module = self.module.clone();
+        let Some(table_type) = module.types.get(&type_index) else {
+            return Err(VmError::UnknownType(type_index));
+        };
+        if table_type.is_derived() {
+            return Ok(());
+        }
+        if !self.tables.contains_key(&type_index) {
+            let table = MemTable::open(type_index, table_type, self.limits.max_table_rows)?;
+            let handle = self.handles.insert(Resource::Table(TableTypes::Mem(table)));
+            self.tables.insert(type_index, Some(handle));
+        }
+
+        let uncopied = copied..self.transactions.len();
+        let Some(table) = self.held_table_mut(type_index) else {
+            return Ok(());
+        };
+        let copies: Vec<MemTable> = uncopied.clone().map(|_| table.copy()).collect();
+        if let Some(log) = &mut table.log {
+            for _ in uncopied.clone() {
+                log.begin();
+            }
+        }
+        for (depth, copy) in uncopied.zip(copies) {
+            self.transactions[depth].tables.insert(type_index, copy);
+        }
+        Ok(())
+    }
+
+    /// Tracks the table a cursor is over before the cursor changes it,
+    /// if it is the Vm's table of its type.
+    pub(super) fn track_cursor(&mut self, handle: usize) -> Result<(), VmError> {
+        match self.handles.cursor(handle)? {
+            CursorTypes::Mem(cursor) if cursor.table.held => {
+                let type_index = cursor.table.type_index;
+                self.track(type_index)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn commit(&mut self) -> Result<(), VmError> {
+        let transaction = self.transactions.pop().ok_or(VmError::NoTransaction)?;
+        let mut result = Ok(());
+        for type_index in transaction.tables.keys() {
+            if let Some(log) = self.held_table_mut(*type_index).and_then(|table| table.log.as_mut()) {
+                if let Err(error) = log.commit() {
+                    result = result.and(Err(VmError::Storage(error)));
+                }
+            }
+        }
+        result
+    }
+
+    /// Rolls back the innermost transaction, which needs every table it
+    /// changed to have been put back.
+    pub(super) fn rollback(&mut self) -> Result<(), VmError> {
+        let Some(transaction) = self.transactions.last() else {
+            return Err(VmError::NoTransaction);
+        };
+        let taken = transaction.tables.keys()
+            .find(|type_index| matches!(self.tables.get(type_index), Some(None)));
+        if let Some(type_index) = taken {
+            return Err(VmError::TableInUse(*type_index));
+        }
+
+        let transaction = self.transactions.pop().unwrap();
+        self.restore_tables(transaction, self.transactions.len());
+        Ok(())
+    }
+
+    /// Rolls back every open transaction after an error. Tables which are
+    /// taken are left with whatever has them and replaced by their copy.
+    pub(super) fn abort(&mut self) {
+        let mut transactions = std::mem::take(&mut self.transactions);
+        transactions.truncate(1);
+        if let Some(transaction) = transactions.pop() {
+            self.restore_tables(transaction, 0);
+        }
+    }
+
+    /// Puts back the tables as the transaction at the depth copied them,
+    /// dropping the changes to their files held back since it began.
+    fn restore_tables(&mut self, transaction: Transaction, depth: usize) {
+        for (type_index, mut copy) in transaction.tables {
+            let log = match self.tables.get(&type_index) {
+                Some(Some(handle)) => match self.handles.take_table(*handle) {
+                    Ok(TableTypes::Mem(table)) => table.log,
+                    _ => None,
+                },
+                _ => self.held_table_mut(type_index).and_then(|table| {
+                    table.held = false;
+                    table.log.take()
+                }),
+            };
+
+            if let Some(mut log) = log {
+                log.rollback(depth);
+                copy.log = Some(log);
+            }
+
+            let handle = self.handles.insert(Resource::Table(TableTypes::Mem(copy)));
+            self.tables.insert(type_index, Some(handle));
+        }
+    }
+
+    /// The Vm's table of the type, whether it is held for `TakeTable` or
+    /// has been taken in to a cursor.
+    pub(super) fn held_table_mut(&mut self, type_index: u32) -> Option<&mut MemTable> {
+        self.handles.entries.iter_mut()
+            .flatten()
+            .find_map(|resource| {
+                let table = match resource {
+                    Resource::Table(TableTypes::Mem(table)) => table,
+                    Resource::Cursor(CursorTypes::Mem(cursor)) => &mut cursor.table,
+                    _ => return None,
+                };
+                (table.held && table.type_index == type_index).then_some(table)
+            })
+    }
+}
@@ -0,0 +1,332 @@
+//! Embeds colang using only the crate's public items.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use colang::limits::Limits;
+use colang::{compile, compile_sources, compile_str, Kind, LangError, SourceMap, Status, Value, Vm, VmError};
+use colang::{Access, Constraint, Cursor, Format, FormatError, Table};
+
+const FIB: &str = "
+fn fib(n) {
+    if n < 2 {
+        return n;
+    }
+
+    let a = n - 1;
+    let b = n - 2;
+    let x = fib(a);
+    let y = fib(b);
+    return x + y;
+}
+
+fn main() {
+    fib(20);
+}
+";
+
+#[test]
+fn run_and_read_result () -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::new(compile(FIB)?);
+
+    assert!(matches!(vm.run()?, Status::Halted));
+    assert_eq!(vm.result::<i64>()?, 6765);
+    assert!(matches!(vm.top(Kind::I64)?, Value::I64(6765)));
+    Ok(())
+}
+
+#[test]
+fn shared_module () -> Result<(), Box<dyn Error>> {
+    let module = Arc::new(compile("fn main() { 1.5 + 2.25; }")?);
+
+    for _ in 0..2 {
+        let mut vm = Vm::checked(module.clone());
+        vm.run()?;
+        assert_eq!(vm.result::<f64>()?, 3.75);
+        // The checked Vm knows the result isn't an integer.
+        assert!(matches!(vm.result::<i64>(), Err(VmError::TypeCheck)));
+    }
+    Ok(())
+}
+
+#[test]
+fn yield_and_resume () -> Result<(), Box<dyn Error>> {
+    let source = "fn main() { let a = yield 1; let b = yield a + 1; a + b; }";
+    let mut vm = Vm::new(compile(source)?);
+
+    let Status::Yielded(Value::I64(1)) = vm.run()? else {
+        panic!("expected the first yield");
+    };
+    let Status::Yielded(Value::I64(11)) = vm.resume(Value::I64(10))? else {
+        panic!("expected the second yield");
+    };
+    assert!(matches!(vm.resume(Value::I64(5))?, Status::Halted));
+    assert_eq!(vm.result::<i64>()?, 15);
+    Ok(())
+}
+
+#[test]
+fn compile_errors () {
+    let Err(error) = compile("fn main() { missing(1); }") else {
+        panic!("expected an error");
+    };
+    assert!(matches!(error, LangError::UnknownFunction(ref name) if name == "missing"));
+    assert_eq!(error.to_string(), "unknown function `missing`");
+
+    assert!(matches!(compile("fn other() {}"), Err(LangError::NoMain)));
+    assert!(matches!(compile("fn main() {"), Err(LangError::ParserError(_))));
+
+    // Errors convert to a boxed error for `?` in host code.
+    let boxed: Box<dyn Error> = Box::new(LangError::NoMain);
+    assert_eq!(boxed.to_string(), "no main function");
+}
+
+#[test]
+fn limits () -> Result<(), Box<dyn Error>> {
+    let module = compile(FIB)?;
+    let mut vm = Vm::with_limits(module, Limits::unlimited().with_fuel(100));
+
+    let error = vm.run().unwrap_err();
+    assert!(matches!(error, VmError::OutOfFuel));
+    assert_eq!(error.to_string(), "out of fuel");
+
+    vm.add_fuel(1_000_000);
+    vm.run()?;
+    assert_eq!(vm.result::<i64>()?, 6765);
+    Ok(())
+}
+
+#[test]
+fn dyn_vm () -> Result<(), Box<dyn Error>> {
+    let module = colang::dynamic::compile(FIB)?;
+    let mut vm = colang::dynamic::Vm::new(module);
+
+    vm.run()?;
+    assert!(matches!(vm.stack().last(), Some(colang::dynamic::Value::I64(6765))));
+
+    assert_eq!(vm.call::<(i64,), i64>("fib", (10,))?, 55);
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+struct Pair {
+    left: i64,
+    right: Option<u32>,
+}
+
+colang::value_struct!(Pair { left, right });
+
+#[test]
+fn dyn_values () -> Result<(), Box<dyn Error>> {
+    use colang::dynamic::{self, FromValue, ToValue};
+
+    let source = "fn same(a) { return a; } fn main() {}";
+    let mut vm = dynamic::Vm::new(dynamic::compile(source)?);
+
+    let pair = Pair { left: 1, right: None };
+    assert_eq!(vm.call::<(Pair,), Pair>("same", (pair,))?, Pair { left: 1, right: None });
+    assert_eq!(vm.call::<(&str,), String>("same", ("text",))?, "text");
+
+    let value = (1u32, "a").to_value();
+    assert_eq!(<(u32, String)>::from_value(value)?, (1, "a".to_string()));
+    Ok(())
+}
+
+#[test]
+fn in_memory_sources () -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::new(compile_str("generated", "fn main() { 2.5f32 + 1.5f32; }")?);
+    vm.run()?;
+    assert_eq!(vm.result::<f32>()?, 4.0);
+
+    let mut sources = SourceMap::new();
+    sources.add("double.co", "fn double(a) { return a + a; }");
+    sources.add("main.co", "fn main() { double(21); }");
+    assert_eq!(sources.len(), 2);
+    assert_eq!(sources.get("double.co"), Some("fn double(a) { return a + a; }"));
+
+    let mut vm = Vm::new(compile_sources(&sources)?);
+    vm.run()?;
+    assert_eq!(vm.result::<i64>()?, 42);
+
+    let error = compile_str("wire", "fn main() {").unwrap_err();
+    assert!(error.to_string().contains("wire:1:"));
+    Ok(())
+}
+
+#[test]
+fn missing_file () {
+    let mut sources = SourceMap::new();
+    let error = sources.add_file("missing.co").unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+    let error = LangError::from(error);
+    assert!(matches!(error, LangError::Io(_)));
+    assert!(error.source().is_some());
+}
+
+/// Records the host keeps in its own structure, shared with the scripts
+/// that query them.
+#[derive(Clone, Default)]
+struct Scores {
+    rows: Rc<RefCell<Vec<(u32, i64)>>>,
+}
+
+struct ScoresCursor {
+    scores: Scores,
+    query: Vec<Constraint<Value>>,
+    position: usize,
+}
+
+impl ScoresCursor {
+    fn seek(&mut self) {
+        let rows = self.scores.rows.borrow();
+        while let Some((id, score)) = rows.get(self.position) {
+            let record = [Value::U32(*id), Value::I64(*score)];
+            if self.query.iter().zip(&record).all(|(constraint, value)| constraint.accepts(value)) {
+                break;
+            }
+            self.position += 1;
+        }
+    }
+}
+
+impl Table<Value, VmError> for Scores {
+    type Cursor = ScoresCursor;
+
+    fn find(self, query: &mut Vec<Value>) -> Result<ScoresCursor, VmError> {
+        let query = query.drain(..)
+            .map(|value| match value {
+                Value::None => Constraint::Any,
+                value => Constraint::Eq(value),
+            })
+            .collect();
+        self.select(query)
+    }
+
+    fn select(self, query: Vec<Constraint<Value>>) -> Result<ScoresCursor, VmError> {
+        let mut cursor = ScoresCursor { scores: self, query, position: 0 };
+        cursor.seek();
+        Ok(cursor)
+    }
+}
+
+impl Cursor<Value, VmError> for ScoresCursor {
+    type Table = Scores;
+
+    fn found(&self) -> bool {
+        self.position < self.scores.rows.borrow().len()
+    }
+
+    fn read(&self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        match self.scores.rows.borrow().get(self.position) {
+            Some((id, score)) => {
+                stack.extend([Value::U32(*id), Value::I64(*score), Value::Struct { field_count: 2 }]);
+            },
+            None => stack.push(Value::None),
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        let [.., Value::U32(id), Value::I64(score), Value::Struct { .. }] = stack[..] else {
+            return Err(VmError::TypeCheck);
+        };
+        stack.truncate(stack.len() - 3);
+        let mut rows = self.scores.rows.borrow_mut();
+        rows.push((id, score));
+        self.position = rows.len() - 1;
+        Ok(())
+    }
+
+    fn update(&mut self, stack: &mut Vec<Value>) -> Result<(), VmError> {
+        let [.., Value::U32(id), Value::I64(score), Value::Struct { .. }] = stack[..] else {
+            return Err(VmError::TypeCheck);
+        };
+        stack.truncate(stack.len() - 3);
+        self.scores.rows.borrow_mut()[self.position] = (id, score);
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<(), VmError> {
+        self.scores.rows.borrow_mut().remove(self.position);
+        self.seek();
+        Ok(())
+    }
+
+    fn advance(&mut self) -> Result<bool, VmError> {
+        self.position += 1;
+        self.seek();
+        Ok(self.found())
+    }
+
+    fn close(self) -> Scores {
+        self.scores
+    }
+}
+
+const SCORES: &str = "
+table scores(id: u32, score: i64);
+
+fn main() {
+    let total = 0;
+    for scores{id: 2u32, score: s} { total = total + s; }
+    for scores{id: i, score: s} { total = total + s; }
+    insert scores{id: 3u32, score: 30};
+    total;
+}
+";
+
+#[test]
+fn host_tables () -> Result<(), Box<dyn Error>> {
+    let scores = Scores::default();
+    scores.rows.borrow_mut().extend([(1, 10), (2, 20)]);
+
+    let mut vm = Vm::checked(compile(SCORES)?);
+    vm.register_table_named("scores", scores.clone(), Access::ReadWrite)?;
+    vm.run()?;
+    assert_eq!(vm.result::<i64>()?, 50);
+    assert_eq!(*scores.rows.borrow(), [(1, 10), (2, 20), (3, 30)]);
+
+    // A read-only table can be queried but not changed.
+    let mut vm = Vm::checked(compile(SCORES)?);
+    vm.register_table(0, scores.clone(), Access::ReadOnly)?;
+    assert!(matches!(vm.run(), Err(VmError::ReadOnly(0))));
+    assert_eq!(scores.rows.borrow().len(), 3);
+
+    let mut vm = Vm::new(compile(SCORES)?);
+    let error = vm.register_table_named("missing", scores, Access::ReadOnly).unwrap_err();
+    assert!(matches!(error, VmError::UnknownTable(_)));
+    Ok(())
+}
+
+const ORDERS: &str = "
+table orders(customer: u32, amount: i64);
+table totals(customer: u32, total: i64);
+fn main() {
+    into totals{customer: c, total: sum(a)} from orders{customer: c, amount: a} group by c;
+    count() from totals{};
+}
+";
+
+#[test]
+fn import_export () -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::checked(compile(ORDERS)?);
+    let orders = vm.type_index("orders").unwrap();
+    let csv = "customer,amount\n1,5\n2,7\n1,9\n";
+    assert_eq!(vm.import(orders, Format::Csv, csv.as_bytes())?, 3);
+    vm.run()?;
+    assert_eq!(vm.result::<i64>()?, 2);
+
+    let mut json = Vec::new();
+    vm.export(vm.type_index("totals").unwrap(), Format::JsonLines, &mut json)?;
+    assert_eq!(json, b"{\"customer\":1,\"total\":14}\n{\"customer\":2,\"total\":7}\n");
+
+    // Errors say which record and field didn't convert.
+    let mut vm = Vm::checked(compile(ORDERS)?);
+    let error = vm.import(orders, Format::Csv, "customer,amount\n1,5\n2,lots\n".as_bytes()).unwrap_err();
+    assert!(matches!(&error, FormatError::Field { row: 2, column, .. } if column == "amount"));
+    assert_eq!(error.to_string(), "row 2, column `amount`: expected i64, found `lots`");
+    Ok(())
+}